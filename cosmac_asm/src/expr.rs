@@ -0,0 +1,300 @@
+//! Tokens and expressions
+
+use std::collections::HashMap;
+
+/// A lexical token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Ident(String),
+    Number(i64),
+    /// A quoted string. Single characters may be used as numbers.
+    Str(Vec<u8>),
+    /// The location counter, `$`.
+    Here,
+    Punct(&'static str),
+}
+
+/// Punctuation, longest first.
+const PUNCT: [&str; 16] = [
+    "<<", ">>", "(", ")", ",", ":", "=", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+];
+
+/// Splits a line of source into tokens, stopping at a comment.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = line;
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else {
+            break;
+        };
+        if c == ';' {
+            break;
+        }
+        if c == '"' || c == '\'' {
+            let end = rest[1..]
+                .find(c)
+                .ok_or_else(|| "unterminated string".to_string())?;
+            tokens.push(Token::Str(rest.as_bytes()[1..=end].to_vec()));
+            rest = &rest[end + 2..];
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$' {
+            let len = rest[1..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .map_or(rest.len(), |n| n + 1);
+            let word = &rest[..len];
+            tokens.push(if word == "$" {
+                Token::Here
+            } else if c == '$' || c.is_ascii_digit() {
+                Token::Number(parse_number(word)?)
+            } else {
+                Token::Ident(word.to_string())
+            });
+            rest = &rest[len..];
+        } else if let Some(p) = PUNCT.iter().find(|p| rest.starts_with(**p)) {
+            tokens.push(Token::Punct(p));
+            rest = &rest[p.len()..];
+        } else {
+            return Err(format!("unexpected character '{c}'"));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a number: decimal, hex (`0x1f`, `$1f`, `1fh`), or binary (`0b101`).
+fn parse_number(word: &str) -> Result<i64, String> {
+    let lower = word.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (bin, 2)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| format!("invalid number '{word}'"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    High,
+    Low,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Xor,
+    Or,
+}
+impl BinaryOp {
+    /// Returns the operator and its precedence, where higher binds tighter.
+    fn from_punct(p: &str) -> Option<(Self, u8)> {
+        Some(match p {
+            "*" => (Self::Mul, 6),
+            "/" => (Self::Div, 6),
+            "%" => (Self::Rem, 6),
+            "+" => (Self::Add, 5),
+            "-" => (Self::Sub, 5),
+            "<<" => (Self::Shl, 4),
+            ">>" => (Self::Shr, 4),
+            "&" => (Self::And, 3),
+            "^" => (Self::Xor, 2),
+            "|" => (Self::Or, 1),
+            _ => return None,
+        })
+    }
+}
+
+/// An expression, evaluated once all symbols are known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Here,
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+impl Expr {
+    /// Parses an expression, which must consume all of the tokens.
+    pub fn parse(tokens: &[Token]) -> Result<Self, String> {
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {}", describe(token))),
+        }
+    }
+
+    /// Evaluates the expression. The error is the name of an undefined symbol, or a description
+    /// of an arithmetic error.
+    pub fn eval(&self, symbols: &HashMap<String, u16>, here: u16) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Symbol(name) => symbols
+                .get(name)
+                .map(|v| i64::from(*v))
+                .ok_or_else(|| format!("undefined symbol '{name}'"))?,
+            Expr::Here => i64::from(here),
+            Expr::Unary(op, expr) => {
+                let v = expr.eval(symbols, here)?;
+                match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Not => !v,
+                    UnaryOp::High => (v >> 8) & 0xff,
+                    UnaryOp::Low => v & 0xff,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(symbols, here)?;
+                let rhs = rhs.eval(symbols, here)?;
+                match op {
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                        return Err("division by zero".into());
+                    }
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div => lhs.wrapping_div(rhs),
+                    BinaryOp::Rem => lhs.wrapping_rem(rhs),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::And => lhs & rhs,
+                    BinaryOp::Xor => lhs ^ rhs,
+                    BinaryOp::Or => lhs | rhs,
+                }
+            }
+        })
+    }
+}
+
+/// A precedence-climbing expression parser.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+impl Parser<'_> {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn binary(&mut self, min_prec: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Punct(p)) = self.tokens.get(self.pos)
+            && let Some((op, prec)) = BinaryOp::from_punct(p)
+            && prec > min_prec
+        {
+            self.pos += 1;
+            let rhs = self.binary(prec)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let unary = |op, expr| Ok(Expr::Unary(op, Box::new(expr)));
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(*n)),
+            Some(Token::Str(s)) if s.len() == 1 => Ok(Expr::Number(i64::from(s[0]))),
+            Some(Token::Here) => Ok(Expr::Here),
+            Some(Token::Ident(name)) => match name.to_ascii_lowercase().as_str() {
+                "high" => unary(UnaryOp::High, self.unary()?),
+                "low" => unary(UnaryOp::Low, self.unary()?),
+                _ => Ok(Expr::Symbol(name.clone())),
+            },
+            Some(Token::Punct("(")) => {
+                let expr = self.binary(0)?;
+                match self.next() {
+                    Some(Token::Punct(")")) => Ok(expr),
+                    _ => Err("expected ')'".into()),
+                }
+            }
+            Some(Token::Punct("-")) => unary(UnaryOp::Neg, self.unary()?),
+            Some(Token::Punct("~")) => unary(UnaryOp::Not, self.unary()?),
+            Some(Token::Punct("+")) => self.unary(),
+            Some(token) => Err(format!("unexpected {}", describe(token))),
+            None => Err("expected expression".into()),
+        }
+    }
+}
+
+/// Describes a token, for error messages.
+pub fn describe(token: &Token) -> String {
+    match token {
+        Token::Ident(name) => format!("'{name}'"),
+        Token::Number(n) => format!("'{n}'"),
+        Token::Str(_) => "string".into(),
+        Token::Here => "'$'".into(),
+        Token::Punct(p) => format!("'{p}'"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<i64, String> {
+        let symbols = HashMap::from([("buf".to_string(), 0x1234)]);
+        Expr::parse(&tokenize(text)?)?.eval(&symbols, 0x8000)
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("loop: ldi 'A' ; comment").unwrap(),
+            [
+                Token::Ident("loop".into()),
+                Token::Punct(":"),
+                Token::Ident("ldi".into()),
+                Token::Str(b"A".to_vec()),
+            ]
+        );
+        assert_eq!(
+            tokenize("$ $ff 0ffh 0x10 0b101 10").unwrap(),
+            [
+                Token::Here,
+                Token::Number(0xff),
+                Token::Number(0xff),
+                Token::Number(0x10),
+                Token::Number(0b101),
+                Token::Number(10),
+            ]
+        );
+        assert!(tokenize("db \"abc").is_err());
+        assert!(tokenize("db 12x").is_err());
+        assert!(tokenize("ldi @").is_err());
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("1 << 4 | 1"), Ok(0x11));
+        assert_eq!(eval("-1"), Ok(-1));
+        assert_eq!(eval("~0 & 0xff"), Ok(0xff));
+        assert_eq!(eval("high buf"), Ok(0x12));
+        assert_eq!(eval("low(buf + 1)"), Ok(0x35));
+        assert_eq!(eval("$ + 2"), Ok(0x8002));
+        assert_eq!(eval("'a' - 'A'"), Ok(0x20));
+        assert!(eval("nope").unwrap_err().contains("nope"));
+        assert!(eval("1 / 0").is_err());
+        assert_eq!(eval("(-9223372036854775807 - 1) / -1"), Ok(i64::MIN));
+        assert_eq!(eval("(-9223372036854775807 - 1) % -1"), Ok(0));
+        assert!(eval("(1").is_err());
+        assert!(eval("1 2").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! 1802 assembler core
+//!
+//! The tokenizer, expressions and operand checks shared by the text assembler in `cosmac_emu` and
+//! the compile-time assembler in `cosmac_emu_macros`, so that both accept the same operands and
+//! report the same errors.
+
+mod expr;
+
+pub use expr::{BinaryOp, Expr, Token, UnaryOp, describe, tokenize};
+
+pub fn to_byte(value: i64) -> Result<u8, String> {
+    if (-0x80..=0xff).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("value {value} out of range for a byte"))
+    }
+}
+
+pub fn to_word(value: i64) -> Result<u16, String> {
+    if (-0x8000..=0xffff).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("value {value} out of range for a word"))
+    }
+}
+
+pub fn to_addr(value: i64) -> Result<u16, String> {
+    u16::try_from(value).map_err(|_| format!("address {value} out of range"))
+}
+
+/// Returns the immediate operand of a short branch of `size` bytes at `addr`. The target must be
+/// on the same page as the branch's immediate operand.
+pub fn short_branch(addr: u16, size: u8, target: i64) -> Result<u8, String> {
+    let target = to_addr(target)?;
+    let page = addr.wrapping_add(u16::from(size) - 1) >> 8;
+    if target >> 8 != page {
+        return Err(format!(
+            "short branch target {target:04x} is not on page {page:02x}"
+        ));
+    }
+    Ok(target as u8)
+}
+
+/// Parses a register operand, which is written as `rN` or as an expression.
+pub fn parse_register(tokens: &[Token]) -> Result<Expr, String> {
+    if let [Token::Ident(name)] = tokens
+        && let Some(digits) = name.strip_prefix(['r', 'R'])
+    {
+        let n = match digits.len() {
+            1 => u8::from_str_radix(digits, 16).ok(),
+            _ => digits.parse().ok().filter(|n| *n < 16),
+        };
+        if let Some(n) = n {
+            return Ok(Expr::Number(n.into()));
+        }
+    }
+    Expr::parse(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(to_byte(-0x80), Ok(0x80));
+        assert_eq!(to_byte(0xff), Ok(0xff));
+        assert!(to_byte(0x100).is_err());
+        assert_eq!(to_word(-1), Ok(0xffff));
+        assert!(to_word(0x10000).is_err());
+        assert!(to_addr(-1).is_err());
+    }
+
+    #[test]
+    fn test_short_branch() {
+        assert_eq!(short_branch(0x00ff, 2, 0x01ff), Ok(0xff));
+        assert_eq!(short_branch(0x01fe, 3, 0x0210), Ok(0x10));
+        assert_eq!(
+            short_branch(0x00fe, 2, 0x01ff),
+            Err("short branch target 01ff is not on page 00".into())
+        );
+        assert!(short_branch(0x0000, 2, -1).is_err());
+    }
+
+    #[test]
+    fn test_parse_register() {
+        let register = |text| parse_register(&tokenize(text).unwrap());
+        assert_eq!(register("r7"), Ok(Expr::Number(7)));
+        assert_eq!(register("Rf"), Ok(Expr::Number(15)));
+        assert_eq!(register("r12"), Ok(Expr::Number(12)));
+        assert_eq!(register("r16"), Ok(Expr::Symbol("r16".into())));
+        assert_eq!(register("3"), Ok(Expr::Number(3)));
+    }
+}
@@ -0,0 +1,724 @@
+//! 1802 assembler
+//!
+//! A two-pass assembler. The first pass assigns an address to every line and defines labels, and
+//! the second evaluates operands and encodes instructions. Instructions are constructed as
+//! [`Instr`] values and encoded with [`InstrSchema::encode`], so the assembler and the
+//! disassembler always agree about encodings.
+//!
+//! Each line of source has the form `[label[:]] [mnemonic [operand, ...]] [; comment]`. A label
+//! without a colon must start in the first column. The supported directives are:
+//!
+//! - `org expr`: sets the location counter.
+//! - `db expr|"string", ...`: emits bytes.
+//! - `dw expr, ...`: emits big-endian words.
+//! - `ds expr`: reserves bytes, without emitting anything.
+//! - `name equ expr`, or `name = expr`: defines a symbol.
+//! - `include "path"`: assembles another file, relative to the including file.
+//!
+//! Expressions are made of numbers (`10`, `0x1f`, `$1f`, `1fh`, `0b101`, `'c'`), symbols, the
+//! location counter `$`, the unary operators `-`, `~`, `high` and `low`, and the binary operators
+//! `* / % + - << >> & ^ |`, with C precedence. Registers may be written as `r0`..`r15` or `ra`..
+//! `rf`, or as plain expressions.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use cosmac_asm::{
+    Expr, Token, describe, parse_register, short_branch, to_addr, to_byte, to_word, tokenize,
+};
+use itertools::Itertools;
+
+use crate::chips::cdp1802::Variant;
+use crate::image::{Image, Segment};
+use crate::instr::{Instr, InstrSchema, Layout};
+
+/// Maximum depth of nested includes.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Number of bytes shown per line of the listing.
+const LISTING_BYTES: usize = 4;
+
+#[derive(Debug, thiserror::Error)]
+#[error("{}:{line}: {message}", file.display())]
+pub struct AsmError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+/// A data item for `db`.
+#[derive(Debug)]
+enum Data {
+    Expr(Expr),
+    Str(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum Op {
+    None,
+    Org(Expr),
+    Db(Vec<Data>),
+    Dw(Vec<Expr>),
+    Ds(Expr),
+    Equ(String, Expr),
+    Include(PathBuf),
+    Instr {
+        mnemonic: String,
+        layout: Layout,
+        operands: Vec<Expr>,
+    },
+}
+
+#[derive(Debug)]
+struct Stmt {
+    label: Option<String>,
+    op: Op,
+}
+
+/// A line of source, and what became of it.
+#[derive(Debug)]
+struct Line {
+    file: usize,
+    number: usize,
+    text: String,
+    stmt: Option<Stmt>,
+    addr: u16,
+    bytes: Vec<u8>,
+}
+
+/// The output of the assembler.
+#[derive(Debug)]
+pub struct Assembly {
+    pub image: Image,
+    pub symbols: BTreeMap<String, u16>,
+    files: Vec<PathBuf>,
+    lines: Vec<Line>,
+}
+impl Assembly {
+    /// Writes a listing of the source, with addresses and encoded bytes, followed by a symbol
+    /// table.
+    pub fn write_listing(&self, mut w: impl Write) -> std::io::Result<()> {
+        for line in &self.lines {
+            if line.file != 0 && line.number == 1 {
+                writeln!(w, "; {}", self.files[line.file].display())?;
+            }
+            let addr = match &line.stmt {
+                Some(Stmt {
+                    op: Op::Equ(name, _),
+                    ..
+                }) => self
+                    .symbols
+                    .get(name)
+                    .map_or(String::new(), |v| format!("={v:04x}")),
+                Some(Stmt {
+                    label: None,
+                    op: Op::None,
+                })
+                | Some(Stmt {
+                    op: Op::Include(_), ..
+                })
+                | None => String::new(),
+                Some(_) => format!("{:04x}", line.addr),
+            };
+            let mut chunks = line.bytes.chunks(LISTING_BYTES);
+            let bytes = chunks.next().map_or(String::new(), hex_bytes);
+            writeln!(w, "{:>5} {addr:<5} {bytes:<11}  {}", line.number, line.text)?;
+            for (n, chunk) in chunks.enumerate() {
+                let addr = line.addr.wrapping_add(((n + 1) * LISTING_BYTES) as u16);
+                writeln!(w, "{:>5} {addr:04x}  {}", "", hex_bytes(chunk))?;
+            }
+        }
+        if !self.symbols.is_empty() {
+            writeln!(w)?;
+            writeln!(w, "Symbols:")?;
+            for (name, value) in &self.symbols {
+                writeln!(w, "{name:<24} {value:04x}")?;
+            }
+        }
+        w.flush()
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).join(" ")
+}
+
+pub struct Assembler {
+    variant: Variant,
+    files: Vec<PathBuf>,
+    lines: Vec<Line>,
+    errors: Vec<AsmError>,
+    symbols: HashMap<String, u16>,
+}
+impl Assembler {
+    pub fn new(variant: Variant) -> Self {
+        Self {
+            variant,
+            files: vec![],
+            lines: vec![],
+            errors: vec![],
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// Assembles source text. Includes are resolved relative to the path of the source.
+    pub fn assemble(mut self, path: &Path, text: &str) -> Result<Assembly, Vec<AsmError>> {
+        self.load(path, text, 0);
+        self.define_symbols();
+        self.resolve_equates();
+        let image = self.encode();
+        if !self.errors.is_empty() {
+            let files = &self.files;
+            self.errors.sort_by_key(|e| {
+                let file = files.iter().position(|f| *f == e.file);
+                (file, e.line)
+            });
+            return Err(self.errors);
+        }
+        Ok(Assembly {
+            image,
+            symbols: self.symbols.into_iter().collect(),
+            files: self.files,
+            lines: self.lines,
+        })
+    }
+
+    fn error(&mut self, file: usize, line: usize, message: impl Into<String>) {
+        self.errors.push(AsmError {
+            file: self.files[file].clone(),
+            line,
+            message: message.into(),
+        });
+    }
+
+    /// Parses a file, recursively loading includes.
+    fn load(&mut self, path: &Path, text: &str, depth: usize) {
+        let file = self.files.len();
+        self.files.push(path.to_path_buf());
+        for (n, text) in text.lines().enumerate() {
+            let stmt = match parse_line(text) {
+                Ok(stmt) => Some(stmt),
+                Err(message) => {
+                    self.error(file, n + 1, message);
+                    None
+                }
+            };
+            let include = match &stmt {
+                Some(Stmt {
+                    op: Op::Include(include),
+                    ..
+                }) => Some(path.parent().unwrap_or(Path::new("")).join(include)),
+                _ => None,
+            };
+            self.lines.push(Line {
+                file,
+                number: n + 1,
+                text: text.to_string(),
+                stmt,
+                addr: 0,
+                bytes: vec![],
+            });
+            if let Some(include) = include {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    self.error(file, n + 1, "includes nested too deeply");
+                    continue;
+                }
+                match std::fs::read_to_string(&include) {
+                    Ok(text) => self.load(&include, &text, depth + 1),
+                    Err(err) => {
+                        let message = format!("cannot read {}: {err}", include.display());
+                        self.error(file, n + 1, message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Defines a symbol, returning an error if it's already defined.
+    fn define(&mut self, name: &str, value: u16) -> Result<(), String> {
+        match self.symbols.insert(name.to_string(), value) {
+            Some(_) => Err(format!("symbol '{name}' already defined")),
+            None => Ok(()),
+        }
+    }
+
+    /// The first pass, which assigns addresses to lines and defines labels.
+    fn define_symbols(&mut self) {
+        let mut lines = std::mem::take(&mut self.lines);
+        let mut pc: u32 = 0;
+        for line in &mut lines {
+            let Some(stmt) = &line.stmt else {
+                continue;
+            };
+            line.addr = pc as u16;
+            let mut errors = vec![];
+            if let Some(label) = &stmt.label
+                && let Err(err) = self.define(label, pc as u16)
+            {
+                errors.push(err);
+            }
+            let size = match &stmt.op {
+                Op::Equ(name, expr) => {
+                    // Equates that refer to symbols defined later are resolved after this pass.
+                    if let Ok(value) = expr.eval(&self.symbols, pc as u16) {
+                        let result = to_word(value).and_then(|v| self.define(name, v));
+                        errors.extend(result.err());
+                    }
+                    0
+                }
+                Op::Org(expr) => {
+                    match expr.eval(&self.symbols, pc as u16).and_then(to_addr) {
+                        Ok(addr) => {
+                            pc = addr.into();
+                            line.addr = addr;
+                        }
+                        Err(err) => errors.push(format!("org: {err}")),
+                    }
+                    0
+                }
+                Op::Ds(expr) => match expr.eval(&self.symbols, pc as u16) {
+                    Ok(size) if (0..=0x10000).contains(&size) => size as u32,
+                    Ok(size) => {
+                        errors.push(format!("ds: invalid size {size}"));
+                        0
+                    }
+                    Err(err) => {
+                        errors.push(format!("ds: {err}"));
+                        0
+                    }
+                },
+                Op::Db(data) => data
+                    .iter()
+                    .map(|d| match d {
+                        Data::Expr(_) => 1,
+                        Data::Str(s) => s.len() as u32,
+                    })
+                    .sum(),
+                Op::Dw(words) => 2 * words.len() as u32,
+                Op::Instr { layout, .. } => layout.size.into(),
+                Op::None | Op::Include(_) => 0,
+            };
+            pc += size;
+            if pc > 0x10000 {
+                errors.push("address out of range".into());
+                pc &= 0xffff;
+            }
+            for err in errors {
+                self.error(line.file, line.number, err);
+            }
+        }
+        self.lines = lines;
+    }
+
+    /// Defines equates that refer to symbols defined after them.
+    fn resolve_equates(&mut self) {
+        loop {
+            let mut progress = false;
+            let mut errors = vec![];
+            for line in &self.lines {
+                let Some(Stmt {
+                    op: Op::Equ(name, expr),
+                    ..
+                }) = &line.stmt
+                else {
+                    continue;
+                };
+                if self.symbols.contains_key(name) {
+                    continue;
+                }
+                match expr.eval(&self.symbols, line.addr) {
+                    Ok(value) => {
+                        progress = true;
+                        if let Err(err) = to_word(value) {
+                            errors.push((line.file, line.number, err));
+                        }
+                        self.symbols.insert(name.clone(), value as u16);
+                    }
+                    Err(err) => errors.push((line.file, line.number, err)),
+                }
+            }
+            if !progress {
+                for (file, number, err) in errors {
+                    self.error(file, number, err);
+                }
+                return;
+            }
+        }
+    }
+
+    /// The second pass, which encodes each line.
+    fn encode(&mut self) -> Image {
+        let mut image = Image::default();
+        let mut used = vec![false; 0x10000];
+        let mut errors = vec![];
+        let mut lines = std::mem::take(&mut self.lines);
+        for line in &mut lines {
+            let Some(stmt) = &line.stmt else {
+                continue;
+            };
+            let result = match &stmt.op {
+                Op::Db(data) => self.encode_db(line.addr, data),
+                Op::Dw(words) => self.encode_dw(line.addr, words),
+                Op::Instr {
+                    mnemonic,
+                    layout,
+                    operands,
+                } => self.encode_instr(line.addr, mnemonic, *layout, operands),
+                _ => Ok(vec![]),
+            };
+            line.bytes = match result {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    errors.push((line.file, line.number, err));
+                    continue;
+                }
+            };
+            let start = usize::from(line.addr);
+            let end = (start + line.bytes.len()).min(used.len());
+            if let Some(offset) = used[start..end].iter().position(|u| *u) {
+                let err = format!("overlaps code at {:04x}", start + offset);
+                errors.push((line.file, line.number, err));
+            }
+            used[start..end].fill(true);
+            push(&mut image, line.addr, &line.bytes);
+        }
+        self.lines = lines;
+        for (file, number, err) in errors {
+            self.error(file, number, err);
+        }
+        image
+    }
+
+    fn eval(&self, expr: &Expr, here: u16) -> Result<i64, String> {
+        expr.eval(&self.symbols, here)
+    }
+
+    fn encode_db(&self, addr: u16, data: &[Data]) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        for d in data {
+            match d {
+                Data::Expr(expr) => bytes.push(to_byte(self.eval(expr, addr)?)?),
+                Data::Str(s) => bytes.extend(s),
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn encode_dw(&self, addr: u16, words: &[Expr]) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        for expr in words {
+            bytes.extend(to_word(self.eval(expr, addr)?)?.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+
+    fn encode_instr(
+        &self,
+        addr: u16,
+        mnemonic: &str,
+        layout: Layout,
+        operands: &[Expr],
+    ) -> Result<Vec<u8>, String> {
+        let mut operands = operands.iter();
+        let mut fields = vec![];
+        if layout.registers != 0 {
+            let n = self.eval(operands.next().unwrap(), addr)?;
+            if !(0..16).contains(&n) || layout.registers & (1 << n) == 0 {
+                return Err(format!("invalid register {n} for '{mnemonic}'"));
+            }
+            fields.push(n as u8);
+        }
+        match layout.immediates {
+            0 => (),
+            1 => {
+                let value = self.eval(operands.next().unwrap(), addr)?;
+                let template = fields.iter().copied().chain([0]).collect::<Vec<_>>();
+                let branch =
+                    Instr::from_fields(mnemonic, &template).is_some_and(|i| i.is_short_branch());
+                fields.push(if branch {
+                    short_branch(addr, layout.size, value)?
+                } else {
+                    to_byte(value)?
+                });
+            }
+            _ => {
+                let value = to_word(self.eval(operands.next().unwrap(), addr)?)?;
+                fields.extend(value.to_be_bytes());
+            }
+        }
+        let instr = Instr::from_fields(mnemonic, &fields)
+            .ok_or_else(|| format!("invalid operands for '{mnemonic}'"))?;
+        if instr.is_extended() && !self.variant.has_extended_isa() {
+            return Err(format!(
+                "'{mnemonic}' requires an extended CPU (--cpu 1805)"
+            ));
+        }
+        Ok(instr.encode())
+    }
+}
+
+/// Appends bytes to an image, extending the last segment if it's contiguous.
+fn push(image: &mut Image, addr: u16, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    match image.segments.last_mut() {
+        Some(seg) if usize::from(seg.addr) + seg.data.len() == usize::from(addr) => {
+            seg.data.extend_from_slice(bytes);
+        }
+        _ => image.segments.push(Segment {
+            addr,
+            data: bytes.to_vec(),
+        }),
+    }
+}
+
+/// Returns true if the word is a directive or a mnemonic.
+fn is_keyword(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    matches!(
+        word.as_str(),
+        "org" | "db" | "dw" | "ds" | "equ" | "include"
+    ) || Instr::layout(&word).is_some()
+}
+
+/// Parses a line of source.
+fn parse_line(text: &str) -> Result<Stmt, String> {
+    let tokens = tokenize(text)?;
+    let mut rest = tokens.as_slice();
+    let mut label = None;
+    match rest {
+        [Token::Ident(name), Token::Ident(equ), expr @ ..] if equ.eq_ignore_ascii_case("equ") => {
+            let op = Op::Equ(name.clone(), Expr::parse(expr)?);
+            return Ok(Stmt { label: None, op });
+        }
+        [Token::Ident(name), Token::Punct("="), expr @ ..] => {
+            let op = Op::Equ(name.clone(), Expr::parse(expr)?);
+            return Ok(Stmt { label: None, op });
+        }
+        [Token::Ident(name), Token::Punct(":"), tail @ ..] => {
+            label = Some(name.clone());
+            rest = tail;
+        }
+        // Otherwise, a word in the first column is a label, unless it's followed by operands.
+        [Token::Ident(name), tail @ ..]
+            if !text.starts_with(char::is_whitespace)
+                && !is_keyword(name)
+                && matches!(tail, [] | [Token::Ident(_), ..]) =>
+        {
+            label = Some(name.clone());
+            rest = tail;
+        }
+        _ => (),
+    }
+    let op = match rest {
+        [] => Op::None,
+        [Token::Ident(word), args @ ..] => parse_op(word, args, &mut label)?,
+        [token, ..] => return Err(format!("unexpected {}", describe(token))),
+    };
+    Ok(Stmt { label, op })
+}
+
+/// Parses a directive or instruction. A label on an `equ` line is the name of the symbol.
+fn parse_op(word: &str, args: &[Token], label: &mut Option<String>) -> Result<Op, String> {
+    let word = word.to_ascii_lowercase();
+    let operands: Vec<&[Token]> = if args.is_empty() {
+        vec![]
+    } else {
+        args.split(|t| *t == Token::Punct(",")).collect()
+    };
+    let single = || match operands.as_slice() {
+        [tokens] => Expr::parse(tokens),
+        _ => Err(format!("'{word}' takes one operand")),
+    };
+    Ok(match word.as_str() {
+        "org" => Op::Org(single()?),
+        "ds" => Op::Ds(single()?),
+        "equ" => {
+            let name = label.take().ok_or("'equ' requires a name")?;
+            Op::Equ(name, single()?)
+        }
+        "db" => Op::Db(
+            operands
+                .iter()
+                .map(|tokens| match tokens {
+                    [Token::Str(s)] => Ok(Data::Str(s.clone())),
+                    tokens => Expr::parse(tokens).map(Data::Expr),
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        "dw" => Op::Dw(
+            operands
+                .iter()
+                .map(|tokens| Expr::parse(tokens))
+                .collect::<Result<_, _>>()?,
+        ),
+        "include" => match args {
+            [Token::Str(path)] => {
+                Op::Include(PathBuf::from(String::from_utf8_lossy(path).as_ref()))
+            }
+            [Token::Ident(path)] => Op::Include(PathBuf::from(path)),
+            _ => return Err("'include' takes a path".into()),
+        },
+        _ => {
+            let layout =
+                Instr::layout(&word).ok_or_else(|| format!("unknown instruction '{word}'"))?;
+            let expected = usize::from(layout.registers != 0) + usize::from(layout.immediates > 0);
+            if operands.len() != expected {
+                return Err(format!("'{word}' takes {expected} operand(s)"));
+            }
+            let mut exprs = vec![];
+            for (n, tokens) in operands.into_iter().enumerate() {
+                exprs.push(if n == 0 && layout.registers != 0 {
+                    parse_register(tokens)?
+                } else {
+                    Expr::parse(tokens)?
+                });
+            }
+            Op::Instr {
+                mnemonic: word,
+                layout,
+                operands: exprs,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageFormat;
+
+    fn assemble(text: &str) -> Result<Assembly, Vec<AsmError>> {
+        Assembler::new(Variant::Cdp1805).assemble(Path::new("test.asm"), text)
+    }
+
+    fn errors(text: &str) -> Vec<(usize, String)> {
+        assemble(text)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| (e.line, e.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_assemble() {
+        let text = "
+; Counts down from 8.
+count   equ 8
+        org 0x10
+start:  ldi count
+        plo r2
+loop    dec 2
+        glo r2
+        bnz loop
+        lbr done
+        db 1, 'a', \"bc\"
+        dw done, -1
+        ds 2
+done:   idl
+        sep rf
+        rldi 3, done
+        ";
+        let asm = assemble(text).unwrap();
+        assert_eq!(
+            asm.image.segments,
+            [
+                Segment {
+                    addr: 0x10,
+                    data: vec![
+                        0xf8, 0x08, 0xa2, 0x22, 0x82, 0x3a, 0x13, 0xc0, 0x00, 0x24, 0x01, 0x61,
+                        0x62, 0x63, 0x00, 0x24, 0xff, 0xff
+                    ]
+                },
+                Segment {
+                    addr: 0x24,
+                    data: vec![0x00, 0xdf, 0x68, 0xc3, 0x00, 0x24]
+                },
+            ]
+        );
+        assert_eq!(asm.symbols["start"], 0x10);
+        assert_eq!(asm.symbols["loop"], 0x13);
+        assert_eq!(asm.symbols["done"], 0x24);
+        assert_eq!(asm.symbols["count"], 8);
+    }
+
+    #[test]
+    fn test_bin() {
+        let asm = assemble("org 0x10\nldi 1\nds 2\nsep 3\norg 0x08\ndb 0xaa").unwrap();
+        let mut buf = vec![];
+        asm.image.write(ImageFormat::Bin, &mut buf).unwrap();
+        let mut expected = [0; 13];
+        expected[0x00] = 0xaa;
+        expected[0x08..].copy_from_slice(&[0xf8, 0x01, 0x00, 0x00, 0xd3]);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_forward_equ() {
+        let asm = assemble("a equ b + 1\nb = c * 2\nldi a\nc: idl").unwrap();
+        assert_eq!(asm.image.segments[0].data, [0xf8, 0x05, 0x00]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            errors("org 0xf0\nbr 0x100\nnop\nfoo 1\nldi 256\nldn 0\nout 8"),
+            [
+                (2, "short branch target 0100 is not on page 00".into()),
+                (4, "unknown instruction 'foo'".into()),
+                (5, "value 256 out of range for a byte".into()),
+                (6, "invalid register 0 for 'ldn'".into()),
+                (7, "invalid register 8 for 'out'".into()),
+            ]
+        );
+        assert_eq!(
+            errors("x: idl\nx: idl\nldi y\nsep\norg 1\nidl"),
+            [
+                (2, "symbol 'x' already defined".into()),
+                (3, "undefined symbol 'y'".into()),
+                (4, "'sep' takes 1 operand(s)".into()),
+                (6, "overlaps code at 0001".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_short_branch_page() {
+        // The immediate operand is on page 01, so the branch may target it.
+        let asm = assemble("org 0xff\nbr 0x1ff").unwrap();
+        assert_eq!(asm.image.segments[0].data, [0x30, 0xff]);
+        assert!(assemble("org 0xfe\nbr 0x1ff").is_err());
+    }
+
+    #[test]
+    fn test_extended() {
+        let text = Path::new("test.asm");
+        let asm = Assembler::new(Variant::Cdp1802).assemble(text, "stpc");
+        assert_eq!(
+            asm.unwrap_err()[0].message,
+            "'stpc' requires an extended CPU (--cpu 1805)"
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("cosmac_asm_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("defs.inc"), "value equ 0x42\n").unwrap();
+        let path = dir.join("main.asm");
+        let asm =
+            Assembler::new(Variant::Cdp1802).assemble(&path, "include \"defs.inc\"\nldi value");
+        std::fs::remove_dir_all(&dir).unwrap();
+        let asm = asm.unwrap();
+        assert_eq!(asm.image.segments[0].data, [0xf8, 0x42]);
+
+        let mut buf = vec![];
+        asm.write_listing(&mut buf).unwrap();
+        let listing = String::from_utf8(buf).unwrap();
+        assert!(listing.contains("defs.inc"));
+        assert!(listing.contains("    2 0000  f8 42        ldi value"));
+        assert!(listing.contains("value                    0042"));
+    }
+}
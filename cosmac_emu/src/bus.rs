@@ -0,0 +1,908 @@
+//! Memory-mapped device bus
+//!
+//! The bus decodes the address presented by the CPU, and routes memory accesses to the device
+//! mapped at that address. RAM ([`Memory`]), ROM ([`Rom`]), and memory-mapped I/O are all
+//! [`Device`]s, and each of them takes part in the TPA/MRD/MWR handshake by way of the
+//! [`AddressLatch`]. Devices may also observe [`Signal`]s from the rest of the system, which is how
+//! a [`ResetOverlay`] knows when to get out of the way.
+
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+use color_eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chips::cdp1802::{Cdp1802Pins, Memory, Variant},
+    cli::ImageArg,
+    image::{Image, ImageFormat, Segment},
+    instr::Instr,
+    snapshot::{self, SnapshotError},
+};
+
+/// The value read from the data bus when it's pulled up.
+const PULL_UP: u8 = 0xff;
+
+/// Behavior of reads from addresses with no device mapped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Unmapped {
+    /// Reads return 0xff, as if the data bus were pulled up.
+    #[default]
+    #[value(name = "ff")]
+    PullUp,
+    /// Reads return the last value driven onto the data bus.
+    Float,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccessMode {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+pub struct MemoryAccess {
+    pub mode: MemoryAccessMode,
+    pub addr: u16,
+    pub data: u8,
+    /// The value at the address before the access.
+    pub old: u8,
+    /// Whether the address was initialized before the access.
+    pub initialized: bool,
+}
+impl MemoryAccess {
+    fn read(addr: u16, data: u8, initialized: bool) -> Self {
+        Self {
+            mode: MemoryAccessMode::Read,
+            addr,
+            data,
+            old: data,
+            initialized,
+        }
+    }
+    fn write(addr: u16, data: u8, old: u8, initialized: bool) -> Self {
+        Self {
+            mode: MemoryAccessMode::Write,
+            addr,
+            data,
+            old,
+            initialized,
+        }
+    }
+}
+
+/// System activity that every device observes, regardless of address decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// The CPU was reset.
+    Reset,
+    /// A memory access at the address.
+    Access(u16),
+    /// An OUT instruction strobed the data bus with the N lines set.
+    Output(u8),
+    /// Q changed.
+    Q(bool),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryAccessError {
+    #[error("write protection fault at {0:04x}")]
+    WriteProtectionFault(u16),
+}
+
+/// A device attached to the bus.
+///
+/// Addresses are offsets from the start of the address range at which the device is mapped.
+pub trait Device: std::fmt::Debug {
+    /// Reads a byte. Unlike [`Device::peek`], this may have side effects.
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    /// Reads a byte without side effects, for the debugger and disassembly.
+    fn peek(&self, addr: u16) -> u8;
+
+    /// Writes a byte.
+    fn write(&mut self, addr: u16, data: u8) -> Result<(), MemoryAccessError>;
+
+    /// Writes a byte without side effects, bypassing write protection, for the debugger.
+    fn poke(&mut self, _addr: u16, _data: u8) {}
+
+    /// Restores a byte and whether it was initialized, to undo a write. Devices that don't keep
+    /// track of initialization just poke the byte.
+    fn restore_byte(&mut self, addr: u16, data: u8, _initialized: bool) {
+        self.poke(addr, data);
+    }
+
+    /// Returns false if the device is currently not responding to its address range, in which
+    /// case accesses fall through to the device mapped beneath it.
+    fn is_mapped(&self) -> bool {
+        true
+    }
+
+    /// Returns false if the byte has never been written, and wasn't loaded from an image. Devices
+    /// that don't keep track are always initialized.
+    fn is_initialized(&self, _addr: u16) -> bool {
+        true
+    }
+
+    /// Observes a system signal.
+    fn signal(&mut self, _signal: Signal) {}
+
+    /// Captures the state of the device for a snapshot, if it has any.
+    fn state(&self) -> Option<DeviceState> {
+        None
+    }
+
+    /// Restores the state of the device from a snapshot. Returns false if the state is for a
+    /// different kind of device.
+    fn restore(&mut self, _state: DeviceState) -> bool {
+        false
+    }
+
+    /// Captures the state of the device apart from its memory contents, if it has any. This is
+    /// recorded before every instruction for stepping backwards, so it must be cheap.
+    fn control(&self) -> Option<DeviceControl> {
+        None
+    }
+
+    /// Restores the state captured by [`Device::control`]. Returns false if the state is for a
+    /// different kind of device.
+    fn restore_control(&mut self, _control: DeviceControl) -> bool {
+        false
+    }
+
+    /// Decodes the instruction at the specified address.
+    fn get_instr_at(&self, addr: u16, variant: Variant) -> Option<Instr> {
+        let mut bin = [0; 4];
+        let len = (0..4)
+            .map_while(|n| addr.checked_add(n))
+            .zip(&mut bin)
+            .map(|(addr, byte)| *byte = self.peek(addr))
+            .count();
+        Instr::decode_for(&bin[..len], variant)
+    }
+
+    /// Exports a range of memory as an image, without side effects.
+    fn export(
+        &self,
+        range: RangeInclusive<u16>,
+        format: ImageFormat,
+        w: impl Write,
+    ) -> std::io::Result<()>
+    where
+        Self: Sized,
+    {
+        let data = range.clone().map(|addr| self.peek(addr)).collect();
+        Image::from_bin(*range.start(), data).write(format, w)
+    }
+}
+
+/// The saved state of a [`Device`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceState {
+    Ram(Memory),
+    Rom(Rom),
+    Overlay(ResetOverlay),
+}
+
+/// The state of a [`Device`] apart from its memory contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceControl {
+    /// Whether a [`ResetOverlay`] is mapped.
+    Overlay { active: bool },
+}
+
+/// Latches the high address byte on TPA, and performs the memory read or write signaled by MRD
+/// or MWR.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct AddressLatch {
+    addr_hi: u8,
+}
+impl AddressLatch {
+    pub fn tick<D: Device + ?Sized>(
+        &mut self,
+        pins: &mut Cdp1802Pins,
+        write_enable: bool,
+        device: &mut D,
+    ) -> Result<Option<MemoryAccess>, MemoryAccessError> {
+        // TPA strobe to latch high address byte.
+        if pins.get_tpa() {
+            self.addr_hi = pins.get_ma();
+        }
+
+        if !pins.get_mrd() {
+            // MRD low to read data from memory.
+            let addr = self.addr(*pins);
+            let initialized = device.is_initialized(addr);
+            let data = device.read(addr);
+            pins.set_bus(data);
+            Ok(Some(MemoryAccess::read(addr, data, initialized)))
+        } else if write_enable && !pins.get_mwr() {
+            // MWR low to write data to memory.
+            let addr = self.addr(*pins);
+            let data = pins.get_bus();
+            let old = device.peek(addr);
+            let initialized = device.is_initialized(addr);
+            device.write(addr, data)?;
+            Ok(Some(MemoryAccess::write(addr, data, old, initialized)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn addr(self, pins: Cdp1802Pins) -> u16 {
+        (u16::from(self.addr_hi) << 8) | u16::from(pins.get_ma())
+    }
+}
+
+/// Read-only memory. Writes raise a write protection fault.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rom {
+    #[serde(with = "snapshot::hex")]
+    data: Vec<u8>,
+}
+impl Rom {
+    pub fn new(data: impl Into<Vec<u8>>) -> Self {
+        Self { data: data.into() }
+    }
+}
+impl Device for Rom {
+    fn peek(&self, addr: u16) -> u8 {
+        self.data[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, _data: u8) -> Result<(), MemoryAccessError> {
+        Err(MemoryAccessError::WriteProtectionFault(addr))
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.data[addr as usize] = data;
+    }
+
+    fn state(&self) -> Option<DeviceState> {
+        Some(DeviceState::Rom(self.clone()))
+    }
+
+    fn restore(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::Rom(rom) => {
+                *self = rom;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Condition that removes a [`ResetOverlay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlayTrigger {
+    /// The first memory access at or above the address.
+    Access(u16),
+    /// An OUT instruction with the N lines set to the port.
+    Output(u8),
+    /// Q is set.
+    Q,
+}
+
+/// Serves a ROM image from reset until a trigger condition, and then disappears, exposing the
+/// device mapped beneath it. The overlay comes back on the next reset.
+///
+/// Many boards map ROM over the reset vector at 0x0000 like this, and then switch it back to its
+/// usual address once the firmware has jumped there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetOverlay {
+    rom: Rom,
+    trigger: OverlayTrigger,
+    active: bool,
+}
+impl ResetOverlay {
+    pub fn new(data: impl Into<Vec<u8>>, trigger: OverlayTrigger) -> Self {
+        Self {
+            rom: Rom::new(data),
+            trigger,
+            active: true,
+        }
+    }
+}
+impl Device for ResetOverlay {
+    fn peek(&self, addr: u16) -> u8 {
+        self.rom.peek(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) -> Result<(), MemoryAccessError> {
+        self.rom.write(addr, data)
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.rom.poke(addr, data)
+    }
+
+    fn is_mapped(&self) -> bool {
+        self.active
+    }
+
+    fn signal(&mut self, signal: Signal) {
+        match (signal, self.trigger) {
+            (Signal::Reset, _) => self.active = true,
+            (Signal::Access(addr), OverlayTrigger::Access(min)) if addr >= min => {
+                self.active = false
+            }
+            (Signal::Output(n), OverlayTrigger::Output(port)) if n == port => self.active = false,
+            (Signal::Q(true), OverlayTrigger::Q) => self.active = false,
+            _ => (),
+        }
+    }
+
+    fn state(&self) -> Option<DeviceState> {
+        Some(DeviceState::Overlay(self.clone()))
+    }
+
+    fn restore(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::Overlay(overlay) => {
+                *self = overlay;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn control(&self) -> Option<DeviceControl> {
+        Some(DeviceControl::Overlay {
+            active: self.active,
+        })
+    }
+
+    fn restore_control(&mut self, control: DeviceControl) -> bool {
+        let DeviceControl::Overlay { active } = control;
+        self.active = active;
+        true
+    }
+}
+
+#[cfg(test)]
+type MmioRead = Box<dyn FnMut(u16) -> u8>;
+#[cfg(test)]
+type MmioWrite = Box<dyn FnMut(u16, u8)>;
+
+/// A memory-mapped I/O device, implemented with callbacks.
+///
+/// Reads may have side effects, so the device can't be peeked, and always appears as 0xff in the
+/// debugger.
+#[cfg(test)]
+pub struct Mmio {
+    read: MmioRead,
+    write: MmioWrite,
+}
+#[cfg(test)]
+impl Mmio {
+    pub fn new(
+        read: impl FnMut(u16) -> u8 + 'static,
+        write: impl FnMut(u16, u8) + 'static,
+    ) -> Self {
+        Self {
+            read: Box::new(read),
+            write: Box::new(write),
+        }
+    }
+}
+#[cfg(test)]
+impl std::fmt::Debug for Mmio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mmio").finish_non_exhaustive()
+    }
+}
+#[cfg(test)]
+impl Device for Mmio {
+    fn read(&mut self, addr: u16) -> u8 {
+        (self.read)(addr)
+    }
+
+    fn peek(&self, _addr: u16) -> u8 {
+        PULL_UP
+    }
+
+    fn write(&mut self, addr: u16, data: u8) -> Result<(), MemoryAccessError> {
+        (self.write)(addr, data);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Region {
+    range: RangeInclusive<u16>,
+    mask: u16,
+    device: Box<dyn Device>,
+}
+
+/// The saved state of a [`DeviceBus`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceBusState {
+    latch: AddressLatch,
+    data: u8,
+    regions: Vec<(RangeInclusive<u16>, Option<DeviceState>)>,
+}
+
+/// The state of a [`DeviceBus`] apart from memory contents. See [`Device::control`].
+#[derive(Debug, Clone)]
+pub struct DeviceBusControl {
+    latch: AddressLatch,
+    data: u8,
+    /// The control state of each device that has any, by region index.
+    devices: Vec<(usize, DeviceControl)>,
+}
+
+/// A set of devices, each mapped to an address range.
+///
+/// Where address ranges overlap, the device that was added last takes precedence. Writes to
+/// unmapped addresses are ignored, and reads behave according to [`Unmapped`].
+#[derive(Debug, Default)]
+pub struct DeviceBus {
+    regions: Vec<Region>,
+    latch: AddressLatch,
+    unmapped: Unmapped,
+    /// The last value driven onto the data bus.
+    data: u8,
+}
+impl From<Memory> for DeviceBus {
+    fn from(memory: Memory) -> Self {
+        Self::default().with_ram(memory)
+    }
+}
+impl DeviceBus {
+    /// Sets the behavior of reads from unmapped addresses.
+    pub fn with_unmapped(self, unmapped: Unmapped) -> Self {
+        Self { unmapped, ..self }
+    }
+
+    /// Maps a device to an address range.
+    pub fn with_device(self, range: RangeInclusive<u16>, device: impl Device + 'static) -> Self {
+        self.with_mirrored_device(range, u16::MAX, device)
+    }
+
+    /// Maps a device to an address range, with partial address decoding. The device only sees
+    /// the address bits that are set in `mask`, so when `mask` is one less than a power of two,
+    /// the device is mirrored every `mask + 1` bytes throughout the range.
+    pub fn with_mirrored_device(
+        mut self,
+        range: RangeInclusive<u16>,
+        mask: u16,
+        device: impl Device + 'static,
+    ) -> Self {
+        let device = Box::new(device);
+        self.regions.push(Region {
+            range,
+            mask,
+            device,
+        });
+        self
+    }
+
+    /// Maps RAM at the bottom of the address space.
+    pub fn with_ram(self, memory: Memory) -> Self {
+        let end = (memory.as_slice().len() - 1) as u16;
+        self.with_device(0..=end, memory)
+    }
+
+    /// Maps RAM throughout the address space, mirrored as if the address lines above its
+    /// capacity were not decoded.
+    pub fn with_mirrored_ram(self, memory: Memory) -> Self {
+        let mask = (memory.as_slice().len() - 1) as u16;
+        self.with_mirrored_device(0..=u16::MAX, mask, memory)
+    }
+
+    /// Maps ROM images at their base addresses.
+    pub fn with_rom_images(mut self, images: &[ImageArg]) -> color_eyre::Result<Self> {
+        for image in images {
+            for segment in image.load()?.segments {
+                let range = segment_range(&segment)?;
+                self = self.with_device(range, Rom::new(segment.data));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Maps an image as a [`ResetOverlay`] at its base address. Each segment of the image is
+    /// mapped as a separate overlay with the same trigger.
+    pub fn with_reset_overlay(
+        mut self,
+        image: &ImageArg,
+        trigger: OverlayTrigger,
+    ) -> color_eyre::Result<Self> {
+        let image = image.load()?;
+        if image.segments.is_empty() {
+            eyre::bail!("reset overlay image is empty");
+        }
+        for segment in image.segments {
+            let range = segment_range(&segment)?;
+            self = self.with_device(range, ResetOverlay::new(segment.data, trigger));
+        }
+        Ok(self)
+    }
+
+    /// Delivers a signal to every device on the bus.
+    pub fn signal(&mut self, signal: Signal) {
+        for r in &mut self.regions {
+            r.device.signal(signal);
+        }
+    }
+
+    /// Returns the region index and device address for a bus address.
+    fn decode(&self, addr: u16) -> Option<(usize, u16)> {
+        self.regions
+            .iter()
+            .rposition(|r| r.range.contains(&addr) && r.device.is_mapped())
+            .map(|idx| {
+                let r = &self.regions[idx];
+                (idx, (addr - r.range.start()) & r.mask)
+            })
+    }
+
+    /// Returns the value read from an unmapped address.
+    fn unmapped(&self) -> u8 {
+        match self.unmapped {
+            Unmapped::PullUp => PULL_UP,
+            Unmapped::Float => self.data,
+        }
+    }
+
+    /// Ticks the address latch, and routes any memory access to the appropriate device.
+    pub fn tick(
+        &mut self,
+        pins: &mut Cdp1802Pins,
+        write_enable: bool,
+    ) -> Result<Option<MemoryAccess>, MemoryAccessError> {
+        let mut latch = self.latch;
+        let result = latch.tick(pins, write_enable, self);
+        self.latch = latch;
+        result
+    }
+
+    /// Captures the state of the bus and its devices.
+    pub fn state(&self) -> DeviceBusState {
+        DeviceBusState {
+            latch: self.latch,
+            data: self.data,
+            regions: self
+                .regions
+                .iter()
+                .map(|r| (r.range.clone(), r.device.state()))
+                .collect(),
+        }
+    }
+
+    /// Restores the state of the bus and its devices. The devices must be mapped exactly as they
+    /// were when the state was captured.
+    pub fn restore(&mut self, state: DeviceBusState) -> Result<(), SnapshotError> {
+        if state.regions.len() != self.regions.len()
+            || state
+                .regions
+                .iter()
+                .zip(&self.regions)
+                .any(|((range, _), r)| *range != r.range)
+        {
+            return Err(SnapshotError::DeviceBus);
+        }
+        for ((_, device_state), r) in state.regions.into_iter().zip(&mut self.regions) {
+            if let Some(device_state) = device_state
+                && !r.device.restore(device_state)
+            {
+                return Err(SnapshotError::DeviceBus);
+            }
+        }
+        self.latch = state.latch;
+        self.data = state.data;
+        Ok(())
+    }
+
+    /// Captures the state of the bus and its devices apart from memory contents.
+    pub fn control(&self) -> DeviceBusControl {
+        DeviceBusControl {
+            latch: self.latch,
+            data: self.data,
+            devices: self
+                .regions
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, r)| Some((idx, r.device.control()?)))
+                .collect(),
+        }
+    }
+
+    /// Restores the state captured by [`DeviceBus::control`], without signaling any devices. The
+    /// devices must be mapped as they were when the state was captured.
+    pub fn restore_control(&mut self, control: DeviceBusControl) {
+        for (idx, device_control) in control.devices {
+            let restored = self.regions[idx].device.restore_control(device_control);
+            debug_assert!(restored, "device control state doesn't match the device");
+        }
+        self.latch = control.latch;
+        self.data = control.data;
+    }
+}
+/// Returns the address range spanned by an image segment.
+fn segment_range(segment: &Segment) -> color_eyre::Result<RangeInclusive<u16>> {
+    let end = usize::from(segment.addr) + segment.data.len() - 1;
+    let Ok(end) = u16::try_from(end) else {
+        eyre::bail!("image at 0x{:x} out of range", segment.addr);
+    };
+    Ok(segment.addr..=end)
+}
+
+impl Device for DeviceBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.signal(Signal::Access(addr));
+        self.data = match self.decode(addr) {
+            Some((idx, addr)) => self.regions[idx].device.read(addr),
+            None => self.unmapped(),
+        };
+        self.data
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match self.decode(addr) {
+            Some((idx, addr)) => self.regions[idx].device.peek(addr),
+            None => self.unmapped(),
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) -> Result<(), MemoryAccessError> {
+        self.signal(Signal::Access(addr));
+        self.data = data;
+        match self.decode(addr) {
+            Some((idx, offset)) => {
+                self.regions[idx]
+                    .device
+                    .write(offset, data)
+                    .map_err(|err| match err {
+                        MemoryAccessError::WriteProtectionFault(_) => {
+                            MemoryAccessError::WriteProtectionFault(addr)
+                        }
+                    })
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        if let Some((idx, addr)) = self.decode(addr) {
+            self.regions[idx].device.poke(addr, data);
+        }
+    }
+
+    fn restore_byte(&mut self, addr: u16, data: u8, initialized: bool) {
+        if let Some((idx, addr)) = self.decode(addr) {
+            self.regions[idx]
+                .device
+                .restore_byte(addr, data, initialized);
+        }
+    }
+
+    fn is_initialized(&self, addr: u16) -> bool {
+        self.decode(addr)
+            .is_none_or(|(idx, addr)| self.regions[idx].device.is_initialized(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    fn bus() -> DeviceBus {
+        let ram = Memory::builder()
+            .with_capacity(0x1000)
+            .unwrap()
+            .build()
+            .unwrap();
+        DeviceBus::from(ram).with_device(0x8000..=0x8003, Rom::new([1, 2, 3, 4]))
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut bus = bus();
+        bus.write(0x0fff, 0xaa).unwrap();
+        assert_eq!(bus.read(0x0fff), 0xaa);
+        assert_eq!(bus.read(0x8000), 1);
+        assert_eq!(bus.read(0x8003), 4);
+        assert_eq!(bus.peek(0x1000), PULL_UP);
+        assert_eq!(bus.read(0x8004), PULL_UP);
+        bus.write(0x1000, 0xaa).unwrap();
+        assert_eq!(bus.peek(0x1000), PULL_UP);
+    }
+
+    #[test]
+    fn test_rom() {
+        let mut bus = bus();
+        assert_matches!(
+            bus.write(0x8002, 0xaa),
+            Err(MemoryAccessError::WriteProtectionFault(0x8002))
+        );
+        assert_eq!(bus.peek(0x8002), 3);
+        bus.poke(0x8002, 0xaa);
+        assert_eq!(bus.peek(0x8002), 0xaa);
+    }
+
+    #[test]
+    fn test_overlap() {
+        let mut bus = bus().with_device(0x0800..=0x0801, Rom::new([5, 6]));
+        assert_eq!(bus.read(0x0800), 5);
+        assert_eq!(bus.read(0x0801), 6);
+        bus.poke(0x07ff, 7);
+        bus.poke(0x0802, 8);
+        assert_eq!(bus.read(0x07ff), 7);
+        assert_eq!(bus.read(0x0802), 8);
+    }
+
+    #[test]
+    fn test_mirror() {
+        let ram = Memory::builder()
+            .with_capacity(0x8000)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut bus = DeviceBus::default()
+            .with_mirrored_ram(ram)
+            .with_mirrored_device(0xc000..=0xffff, 0x3, Rom::new([1, 2, 3, 4]));
+        bus.write(0x0123, 0xaa).unwrap();
+        assert_eq!(bus.read(0x8123), 0xaa);
+        bus.write(0xbfff, 0x55).unwrap();
+        assert_eq!(bus.read(0x3fff), 0x55);
+        assert_eq!(bus.read(0xc000), 1);
+        assert_eq!(bus.read(0xc005), 2);
+        assert_eq!(bus.read(0xfffe), 3);
+        assert_matches!(
+            bus.write(0xd002, 0),
+            Err(MemoryAccessError::WriteProtectionFault(0xd002))
+        );
+    }
+
+    #[test]
+    fn test_unmapped() {
+        let mut bus = bus();
+        assert_eq!(bus.read(0x4000), 0xff);
+
+        let mut bus = bus.with_unmapped(Unmapped::Float);
+        assert_eq!(bus.read(0x8002), 3);
+        assert_eq!(bus.read(0x4000), 3);
+        bus.write(0x0000, 0x42).unwrap();
+        assert_eq!(bus.read(0x4000), 0x42);
+        assert_eq!(bus.peek(0x4000), 0x42);
+    }
+
+    #[test]
+    fn test_mmio() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let (r, w) = (log.clone(), log.clone());
+        let mmio = Mmio::new(
+            move |addr| {
+                r.borrow_mut().push((addr, None));
+                addr as u8 + 0x10
+            },
+            move |addr, data| w.borrow_mut().push((addr, Some(data))),
+        );
+        let mut bus = bus().with_device(0xc000..=0xc001, mmio);
+        assert_eq!(bus.read(0xc001), 0x11);
+        assert_eq!(bus.peek(0xc001), PULL_UP);
+        bus.write(0xc000, 0x55).unwrap();
+        assert_eq!(*log.borrow(), [(1, None), (0, Some(0x55))]);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut bus = bus();
+        let mut pins = Cdp1802Pins::default();
+
+        // Latch the high address byte, then read the low byte.
+        pins.set_tpa(true);
+        pins.set_ma(0x80);
+        assert_matches!(bus.tick(&mut pins, true), Ok(None));
+        pins.set_tpa(false);
+        pins.set_ma(0x01);
+        pins.set_mrd(false);
+        assert_matches!(bus.tick(&mut pins, true), Ok(Some(_)));
+        assert_eq!(pins.get_bus(), 2);
+
+        // Writing to ROM faults.
+        pins.set_mrd(true);
+        pins.set_mwr(false);
+        assert_matches!(
+            bus.tick(&mut pins, true),
+            Err(MemoryAccessError::WriteProtectionFault(0x8001))
+        );
+        assert_matches!(bus.tick(&mut pins, false), Ok(None));
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut a = bus();
+        a.write(0x0010, 0x55).unwrap();
+        let state = a.state();
+
+        let mut b = bus();
+        b.restore(state.clone()).unwrap();
+        assert_eq!(b.peek(0x0010), 0x55);
+
+        let mut c = DeviceBus::from(Memory::default());
+        assert_matches!(c.restore(state), Err(SnapshotError::DeviceBus));
+    }
+
+    #[test]
+    fn test_reset_overlay() {
+        let overlay = ResetOverlay::new([0xc0, 0x80, 0x00], OverlayTrigger::Access(0x8000));
+        let mut bus = bus().with_device(0x0000..=0x0002, overlay);
+        assert_eq!(bus.read(0x0000), 0xc0);
+        assert_matches!(bus.write(0x0001, 0xaa), Err(_));
+        assert_eq!(bus.read(0x0002), 0x00);
+        let state = bus.state();
+
+        // The first access above the trigger address removes the overlay.
+        assert_eq!(bus.read(0x8000), 1);
+        assert_eq!(bus.read(0x0000), 0x00);
+        bus.write(0x0001, 0xaa).unwrap();
+        assert_eq!(bus.read(0x0001), 0xaa);
+
+        bus.signal(Signal::Reset);
+        assert_eq!(bus.read(0x0001), 0x80);
+
+        bus.restore(state).unwrap();
+        assert_eq!(bus.peek(0x0000), 0xc0);
+    }
+
+    #[test]
+    fn test_reset_overlay_triggers() {
+        let mut overlay = ResetOverlay::new([0xc0], OverlayTrigger::Output(3));
+        overlay.signal(Signal::Output(2));
+        overlay.signal(Signal::Q(true));
+        assert!(overlay.is_mapped());
+        overlay.signal(Signal::Output(3));
+        assert!(!overlay.is_mapped());
+
+        let mut overlay = ResetOverlay::new([0xc0], OverlayTrigger::Q);
+        overlay.signal(Signal::Access(0xffff));
+        overlay.signal(Signal::Q(false));
+        assert!(overlay.is_mapped());
+        overlay.signal(Signal::Q(true));
+        assert!(!overlay.is_mapped());
+        overlay.signal(Signal::Reset);
+        assert!(overlay.is_mapped());
+    }
+
+    #[test]
+    fn test_reset_overlay_boot() {
+        use std::time::Duration;
+
+        use crate::chips::cdp1802::{Cdp1802, Core};
+        use crate::systems::basic::{BasicSystem, Status};
+
+        for core in [Core::Tick, Core::Fast] {
+            let ram = Memory::builder().build().unwrap();
+            let overlay = ResetOverlay::new([0xc0, 0x80, 0x03], OverlayTrigger::Access(0x8000));
+            let bus = DeviceBus::from(ram)
+                .with_device(0x8000..=0x8004, Rom::new([0, 0, 0, 0x7b, 0x00]))
+                .with_device(0x0000..=0x0002, overlay);
+            let cpu = Cdp1802::new(Variant::Cdp1802);
+            let mut sys = BasicSystem::new(cpu, bus, Duration::from_micros(1)).with_core(core);
+            // Runs until the IDL after SEQ.
+            for _ in 0..100 {
+                if matches!(sys.step(), Status::Idle) {
+                    break;
+                }
+            }
+            assert_eq!(sys.cpu().rp(), 0x8004);
+            assert!(sys.pins().get_q());
+            assert_eq!(sys.bus().peek(0x0000), 0x00);
+            assert_eq!(sys.bus().peek(0x0001), 0x00);
+
+            sys.reset();
+            assert_eq!(sys.bus().peek(0x0000), 0xc0);
+        }
+    }
+}
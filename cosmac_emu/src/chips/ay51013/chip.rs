@@ -1,10 +1,12 @@
 use std::fmt::Display;
 
+use serde::{Deserialize, Serialize};
+
 use crate::uart::{Parity, UartMode};
 
 use super::Ay51013Pins;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Ay51013 {
     ctrl: UartMode,
     tx: Tx,
@@ -12,7 +14,7 @@ pub struct Ay51013 {
     cycle: u64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 struct Rx {
     // internal state
     state: RxState,
@@ -27,7 +29,7 @@ struct Rx {
     or: bool,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
 enum RxState {
     #[default]
     Idle,
@@ -39,7 +41,7 @@ enum RxState {
     Dav,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Tx {
     // internal state
     state: TxState,
@@ -54,7 +56,7 @@ struct Tx {
     so: bool,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
 enum TxState {
     #[default]
     Idle,
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::chips::bits;
 
 #[allow(dead_code)]
@@ -44,11 +46,11 @@ enum Pin {
     Xr,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Ay51013Pins(pub u64);
 impl Default for Ay51013Pins {
     fn default() -> Self {
-        Self(u64::MAX & Self::mask_all())
+        Self(Self::mask_all())
     }
 }
 impl std::fmt::Debug for Ay51013Pins {
@@ -1,4 +1,6 @@
-use crate::uart::{Uart, UartMode, UartRxError};
+use serde::{Deserialize, Serialize};
+
+use crate::uart::{Uart, UartMode, UartRxError, UartState};
 
 use super::{Ay51013, Ay51013Pins};
 
@@ -53,7 +55,7 @@ impl Builder {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Ay51013Uart {
     chip: Ay51013,
     pins: Ay51013Pins,
@@ -143,4 +145,8 @@ impl Uart for Ay51013Uart {
     fn is_tx_idle(&self) -> bool {
         self.chip.is_tx_idle()
     }
+
+    fn state(&self) -> UartState {
+        UartState::Ay51013(self.clone())
+    }
 }
@@ -5,8 +5,8 @@ pub fn set_1(pins: u64, bit: u8, val: bool) -> u64 {
 }
 #[inline(always)]
 fn set_n(pins: u64, lsb: u8, mask: u64, val: u8) -> u64 {
+    let val = ((val as u64) & mask) << lsb;
     let mask = mask << lsb;
-    let val = (val as u64) << lsb;
     (pins & !mask) | val
 }
 pub fn set_2(pins: u64, lsb: u8, val: u8) -> u64 {
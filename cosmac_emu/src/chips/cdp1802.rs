@@ -19,23 +19,61 @@
 use std::fmt::Display;
 
 use rand::distr::{Distribution, StandardUniform};
+use serde::{Deserialize, Serialize};
 
 use super::math;
 
+mod counter;
+mod fast;
 mod memory;
 mod micro_ops;
 mod pins;
 #[cfg(test)]
 mod tests;
 
+pub use counter::{Counter, CounterMode};
+pub use fast::Bus;
 pub use memory::{Memory, MemoryRange};
 use micro_ops::{
-    Access, AluOp, Bit, Cycle, DMA_IN_CYCLE, DMA_OUT_CYCLE, FETCH_CYCLE, INSTR_CYCLE_TABLE,
-    MicroOp, Reg,
+    Access, AluOp, Bit, Cycle, DMA_IN_CYCLE, DMA_OUT_CYCLE, EXT_INSTR_CYCLE_TABLE, FETCH_CYCLE,
+    INSTR_CYCLE_TABLE, MicroOp, Reg, TimerOp,
 };
 pub use pins::Cdp1802Pins;
 
-#[derive(Debug, Clone, Copy)]
+/// The opcode prefix for extended instructions.
+const EXT_PREFIX: u8 = 0x68;
+
+/// Execution core.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Core {
+    /// Tick-accurate execution, driving the pins through every machine cycle.
+    #[default]
+    Tick,
+    /// Instruction-level execution, which is considerably faster, but only samples input pins at
+    /// instruction boundaries.
+    Fast,
+}
+
+/// CPU variant.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum Variant {
+    /// The original CDP1802.
+    #[default]
+    #[value(name = "1802")]
+    Cdp1802,
+    /// The CDP1804/1805/1806, which share an extended instruction set and on-chip
+    /// counter/timer. On-chip ROM and RAM are not emulated.
+    #[value(name = "1805", alias = "1804", alias = "1806")]
+    Cdp1805,
+}
+impl Variant {
+    /// Returns true if the variant implements the 0x68-prefixed extended instructions.
+    pub fn has_extended_isa(self) -> bool {
+        matches!(self, Self::Cdp1805)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 enum Mode {
     Load,
     Reset,
@@ -51,7 +89,7 @@ fn mode(pins: Cdp1802Pins) -> Mode {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum State {
     Init(u8),
     Fetch(u8),
@@ -107,8 +145,10 @@ impl State {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cdp1802 {
+    variant: Variant,
+
     pub state: State,
 
     // Standard registers (q is in pins_out).
@@ -123,6 +163,13 @@ pub struct Cdp1802 {
     pub t: u8,
     pub ie: bool,
 
+    // Extended registers (CDP1804/1805/1806).
+    /// The second opcode byte of the extended instruction being executed, if any.
+    pub ext: Option<u8>,
+    pub xie: bool,
+    pub cie: bool,
+    pub counter: Counter,
+
     /// Buffered pin outputs
     out: Cdp1802Pins,
 
@@ -133,9 +180,10 @@ impl Display for Cdp1802 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{s} ie={ie} d={d:02x}.{df} x={x:02x}:{rx:04x} p={p:02x}:{rp:04x} in={i:02x}",
+            "{s} ie={ie} q={q} d={d:02x}.{df} x={x:02x}:{rx:04x} p={p:02x}:{rp:04x} in={i:02x}",
             s = self.state,
             ie = self.ie as u8,
+            q = self.out.get_q() as u8,
             d = self.d,
             df = self.df as u8,
             x = self.x,
@@ -149,6 +197,7 @@ impl Display for Cdp1802 {
 impl Distribution<Cdp1802> for StandardUniform {
     fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Cdp1802 {
         Cdp1802 {
+            variant: Variant::default(),
             state: rng.random(),
             d: rng.random(),
             df: rng.random(),
@@ -160,6 +209,10 @@ impl Distribution<Cdp1802> for StandardUniform {
             instr: rng.random(),
             t: rng.random(),
             ie: rng.random(),
+            ext: None,
+            xie: rng.random(),
+            cie: rng.random(),
+            counter: Counter::default(),
             out: Cdp1802Pins(rng.random::<u64>() & Cdp1802Pins::mask_bus_out()),
             prev_mode: Mode::Reset,
         }
@@ -168,6 +221,7 @@ impl Distribution<Cdp1802> for StandardUniform {
 impl Default for Cdp1802 {
     fn default() -> Self {
         Self {
+            variant: Variant::default(),
             state: State::Init(0),
             d: 0,
             df: false,
@@ -179,12 +233,27 @@ impl Default for Cdp1802 {
             instr: 0,
             t: 0,
             ie: true,
+            ext: None,
+            xie: true,
+            cie: true,
+            counter: Counter::default(),
             out: Cdp1802Pins::default(),
             prev_mode: Mode::Reset,
         }
     }
 }
 impl Cdp1802 {
+    pub fn new(variant: Variant) -> Self {
+        Self {
+            variant,
+            ..Self::default()
+        }
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
     pub fn reset(&mut self, pins: &mut Cdp1802Pins) {
         self.tick_reset();
         self.update_pins(pins);
@@ -192,14 +261,19 @@ impl Cdp1802 {
 
     /// Returns true if the device is waiting for an external event.
     pub fn is_waiting(&self, pins: Cdp1802Pins) -> bool {
-        let dma_intr = !pins.get_dma_in() || !pins.get_dma_out() || !pins.get_intr();
+        let dma_intr = !pins.get_dma_in()
+            || !pins.get_dma_out()
+            || !pins.get_intr()
+            || (self.counter.ci && self.cie);
+        // A running timer advances with every machine cycle.
+        let timed = self.counter.is_timed();
         match (mode(pins), self.state) {
             (Mode::Load, State::Execute(7)) => !dma_intr,
             (Mode::Load, _) => false,
             (Mode::Reset, State::Init(0)) => true,
             (Mode::Reset, _) => false,
             (Mode::Pause, _) => true,
-            (Mode::Run, State::Execute(7)) if self.instr == 0 => !dma_intr,
+            (Mode::Run, State::Execute(0 | 7)) if self.instr == 0 => !dma_intr && !timed,
             (Mode::Run, _) => false,
         }
     }
@@ -221,6 +295,11 @@ impl Cdp1802 {
         self.r[self.p as usize]
     }
 
+    /// Sets the Q output, which is driven onto the pins at the next tick.
+    pub fn set_q(&mut self, q: bool) {
+        self.out.set_q(q);
+    }
+
     pub fn tick(&mut self, pins: &mut Cdp1802Pins) {
         let mode = mode(*pins);
         match mode {
@@ -239,7 +318,10 @@ impl Cdp1802 {
         // Drive the bus during write cycles...
         let mwr = !self.out.get_mwr();
         // ... but not during INP instructions.
-        let inp = matches!(self.state, State::Execute(_)) && (0x69..0x70).contains(&self.instr);
+        let inp = match self.state {
+            State::Execute(t) => matches!(self.exec_access(t), Access::Inp(..)),
+            _ => false,
+        };
         let mask = if init || (mwr && !inp) {
             Cdp1802Pins::mask_bus_out()
         } else {
@@ -251,6 +333,10 @@ impl Cdp1802 {
     fn tick_reset(&mut self) {
         self.instr = 0;
         self.ie = true;
+        self.ext = None;
+        self.xie = true;
+        self.cie = true;
+        self.counter.reset();
         self.state = State::Init(0);
         self.out = Cdp1802Pins(0);
         self.out.set_mrd(true);
@@ -296,10 +382,18 @@ impl Cdp1802 {
 
     fn tick_run(&mut self, pins: Cdp1802Pins) {
         self.tick_timing_pulses(true);
+        // The counter is clocked by TPA.
+        if self.state.tick() & 7 == 1 {
+            let underflow = self.counter.tick(pins.get_ef1(), pins.get_ef2());
+            self.toggle_q_on_underflow(underflow);
+        }
         match self.state {
             State::Init(t) => self.tick_init(t),
             State::Fetch(t) => self.tick_cycle(t, pins, &FETCH_CYCLE),
-            State::Execute(t) => self.tick_cycle(t, pins, &INSTR_CYCLE_TABLE[self.instr as usize]),
+            State::Execute(t) => match self.ext {
+                Some(ext) => self.tick_cycle(t, pins, &EXT_INSTR_CYCLE_TABLE[ext as usize]),
+                None => self.tick_cycle(t, pins, &INSTR_CYCLE_TABLE[self.instr as usize]),
+            },
             State::DmaIn(t) => self.tick_cycle(t, pins, &DMA_IN_CYCLE),
             State::DmaOut(t) => self.tick_cycle(t, pins, &DMA_OUT_CYCLE),
             State::Interrupt(t) => self.tick_interrupt(t),
@@ -313,18 +407,21 @@ impl Cdp1802 {
                 .sample_dma_intr(pins, self.ie)
                 .unwrap_or(State::Execute(0)),
 
-            // Non-long-branch instructions return to S0.
-            State::Execute(7) if (self.instr >> 4) != 0xc => self
+            // All other S1/S2/S3 completions return to S0.
+            State::Execute(t) if t + 1 == self.exec_ticks() => self
                 .sample_dma_intr(pins, self.ie)
                 .unwrap_or(State::Fetch(0)),
-
-            // All other S1/S2/S3 completions return to S0.
-            State::Execute(15) | State::DmaIn(7) | State::DmaOut(7) | State::Interrupt(7) => self
+            State::DmaIn(7) | State::DmaOut(7) | State::Interrupt(7) => self
                 .sample_dma_intr(pins, self.ie)
                 .unwrap_or(State::Fetch(0)),
 
+            // Extended instructions fetch a second opcode byte in another S0.
+            State::Fetch(7) if self.instr == EXT_PREFIX && self.variant.has_extended_isa() => {
+                State::Fetch(8)
+            }
+
             // S0 always goes to S1.
-            State::Fetch(7) => State::Execute(0),
+            State::Fetch(7) | State::Fetch(15) => State::Execute(0),
 
             // Increment tick counts.
             State::Init(t) => State::Init(t + 1),
@@ -336,6 +433,22 @@ impl Cdp1802 {
         }
     }
 
+    /// Returns the number of ticks in the current instruction's execute cycles.
+    fn exec_ticks(&self) -> u8 {
+        match self.ext {
+            Some(ext) => EXT_INSTR_CYCLE_TABLE[ext as usize].ticks(),
+            None => INSTR_CYCLE_TABLE[self.instr as usize].ticks(),
+        }
+    }
+
+    /// Returns the memory access at a tick of the current instruction's execute cycles.
+    fn exec_access(&self, tick: u8) -> Access {
+        match self.ext {
+            Some(ext) => EXT_INSTR_CYCLE_TABLE[ext as usize].access(tick),
+            None => INSTR_CYCLE_TABLE[self.instr as usize].access(tick),
+        }
+    }
+
     fn tick_timing_pulses(&mut self, enable_tpa: bool) {
         match self.state.tick() & 7 {
             0 => {
@@ -370,7 +483,7 @@ impl Cdp1802 {
         }
     }
 
-    fn tick_cycle(&mut self, tick: u8, pins: Cdp1802Pins, cycle: &Cycle) {
+    fn tick_cycle<const N: usize>(&mut self, tick: u8, pins: Cdp1802Pins, cycle: &Cycle<N>) {
         // Helper to resolve register reference to register index.
         let reg = |reg| match reg {
             Reg::R(n) => n,
@@ -380,7 +493,7 @@ impl Cdp1802 {
 
         // Memory addressing and MWR/MRD/N signaling.
         let phase = tick & 7;
-        match cycle.access {
+        match cycle.access(tick) {
             Access::None => (),
             Access::Read(r) => match phase {
                 0 => self.out.set_ma(self.ghi(reg(r))),
@@ -421,15 +534,20 @@ impl Cdp1802 {
         }
 
         // Micro-ops
-        match cycle.ticks[tick as usize] {
+        match cycle.op(tick) {
             MicroOp::Idle => (),
             MicroOp::Fetch => {
                 self.instr = pins.get_bus();
+                self.ext = None;
                 // Don't increment the program counter for the IDL instruction.
                 if self.instr != 0 {
                     self.inc(self.p);
                 }
             }
+            MicroOp::FetchExt => {
+                self.ext = Some(pins.get_bus());
+                self.inc(self.p);
+            }
             MicroOp::Inc(r) => self.inc(reg(r)),
             MicroOp::Dec(r) => self.dec(reg(r)),
             MicroOp::SetBusD => self.out.set_bus(self.d),
@@ -439,64 +557,15 @@ impl Cdp1802 {
             MicroOp::GetHi(r) => self.d = self.ghi(reg(r)),
             MicroOp::PutLo(r) => self.plo(reg(r), self.d),
             MicroOp::PutHi(r) => self.phi(reg(r), self.d),
+            MicroOp::LoadLo(r) => self.plo(reg(r), pins.get_bus()),
+            MicroOp::LoadHi(r) => self.phi(reg(r), pins.get_bus()),
+            MicroOp::SetBusLo(r) => self.out.set_bus(self.glo(reg(r))),
+            MicroOp::SetBusHi(r) => self.out.set_bus(self.ghi(reg(r))),
+            MicroOp::Copy { from, to } => self.r[reg(to) as usize] = self.r[reg(from) as usize],
             MicroOp::SetQ(q) => self.out.set_q(q),
             MicroOp::SetP(n) => self.p = n,
             MicroOp::SetX(n) => self.x = n,
-            MicroOp::Alu(alu) => match alu {
-                AluOp::Or => self.d |= pins.get_bus(),
-                AluOp::And => self.d &= pins.get_bus(),
-                AluOp::Xor => self.d ^= pins.get_bus(),
-                AluOp::Shl => (self.d, self.df) = (self.d << 1, self.d & 0x80 != 0),
-                AluOp::Shr => (self.d, self.df) = (self.d >> 1, self.d & 0x1 != 0),
-                AluOp::Shlc => {
-                    let df = self.d & 0x80 != 0;
-                    self.d <<= 1;
-                    if self.df {
-                        self.d |= 0x01;
-                    }
-                    self.df = df;
-                }
-                AluOp::Shrc => {
-                    let df = self.d & 0x01 != 0;
-                    self.d >>= 1;
-                    if self.df {
-                        self.d |= 0x80;
-                    }
-                    self.df = df;
-                }
-                AluOp::Add => {
-                    let m = pins.get_bus();
-                    (self.d, self.df) = math::add(self.d, m);
-                }
-                AluOp::Adc => {
-                    let m = pins.get_bus();
-                    (self.d, self.df) = math::addc(self.d, m, self.df);
-                }
-                AluOp::Sd => {
-                    let m = pins.get_bus();
-                    let (d, borrow) = math::sub(m, self.d);
-                    self.d = d;
-                    self.df = !borrow;
-                }
-                AluOp::Sdb => {
-                    let m = pins.get_bus();
-                    let (d, borrow) = math::subb(m, self.d, !self.df);
-                    self.d = d;
-                    self.df = !borrow;
-                }
-                AluOp::Sm => {
-                    let m = pins.get_bus();
-                    let (d, borrow) = math::sub(self.d, m);
-                    self.d = d;
-                    self.df = !borrow;
-                }
-                AluOp::Smb => {
-                    let m = pins.get_bus();
-                    let (d, borrow) = math::subb(self.d, m, !self.df);
-                    self.d = d;
-                    self.df = !borrow;
-                }
-            },
+            MicroOp::Alu(alu) => self.alu(alu, pins.get_bus()),
             MicroOp::Test { bit, inv } => {
                 self.br = match bit {
                     Bit::True => true,
@@ -508,6 +577,9 @@ impl Cdp1802 {
                     Bit::NEF2 => !pins.get_ef2(),
                     Bit::NEF3 => !pins.get_ef3(),
                     Bit::NEF4 => !pins.get_ef4(),
+                    Bit::NZ(r) => self.r[reg(r) as usize] != 0,
+                    Bit::CI => self.counter.ci,
+                    Bit::XI => !pins.get_intr(),
                 };
                 if inv {
                     self.br = !self.br;
@@ -554,6 +626,98 @@ impl Cdp1802 {
                 self.inc(self.x);
                 self.ie = ie;
             }
+            MicroOp::Timer(op) => self.tick_timer_op(op),
+            MicroOp::SetXie(xie) => self.xie = xie,
+            MicroOp::SetCie(cie) => self.cie = cie,
+        }
+    }
+
+    fn alu(&mut self, op: AluOp, m: u8) {
+        match op {
+            AluOp::Or => self.d |= m,
+            AluOp::And => self.d &= m,
+            AluOp::Xor => self.d ^= m,
+            AluOp::Shl => (self.d, self.df) = (self.d << 1, self.d & 0x80 != 0),
+            AluOp::Shr => (self.d, self.df) = (self.d >> 1, self.d & 0x1 != 0),
+            AluOp::Shlc => {
+                let df = self.d & 0x80 != 0;
+                self.d <<= 1;
+                if self.df {
+                    self.d |= 0x01;
+                }
+                self.df = df;
+            }
+            AluOp::Shrc => {
+                let df = self.d & 0x01 != 0;
+                self.d >>= 1;
+                if self.df {
+                    self.d |= 0x80;
+                }
+                self.df = df;
+            }
+            AluOp::Add => (self.d, self.df) = math::add(self.d, m),
+            AluOp::Adc => (self.d, self.df) = math::addc(self.d, m, self.df),
+            AluOp::Sd => {
+                let (d, borrow) = math::sub(m, self.d);
+                self.d = d;
+                self.df = !borrow;
+            }
+            AluOp::Sdb => {
+                let (d, borrow) = math::subb(m, self.d, !self.df);
+                self.d = d;
+                self.df = !borrow;
+            }
+            AluOp::Sm => {
+                let (d, borrow) = math::sub(self.d, m);
+                self.d = d;
+                self.df = !borrow;
+            }
+            AluOp::Smb => {
+                let (d, borrow) = math::subb(self.d, m, !self.df);
+                self.d = d;
+                self.df = !borrow;
+            }
+            AluOp::Dadd => (self.d, self.df) = math::daddc(self.d, m, false),
+            AluOp::Dadc => (self.d, self.df) = math::daddc(self.d, m, self.df),
+            AluOp::Dsm => {
+                let (d, borrow) = math::dsubb(self.d, m, false);
+                self.d = d;
+                self.df = !borrow;
+            }
+            AluOp::Dsmb => {
+                let (d, borrow) = math::dsubb(self.d, m, !self.df);
+                self.d = d;
+                self.df = !borrow;
+            }
+        }
+    }
+
+    fn tick_timer_op(&mut self, op: TimerOp) {
+        match op {
+            TimerOp::Stpc => self.counter.stop(),
+            TimerOp::Dtc => {
+                let underflow = self.counter.decrement();
+                self.toggle_q_on_underflow(underflow);
+            }
+            TimerOp::Spm2 => self.counter.start(CounterMode::PulseEf2),
+            TimerOp::Scm2 => self.counter.start(CounterMode::EventEf2),
+            TimerOp::Spm1 => self.counter.start(CounterMode::PulseEf1),
+            TimerOp::Scm1 => self.counter.start(CounterMode::EventEf1),
+            TimerOp::Ldc => self.counter.load(self.d),
+            TimerOp::Stm => self.counter.start(CounterMode::Timer),
+            TimerOp::Gec => self.d = self.counter.count,
+            TimerOp::Etq => self.counter.etq = true,
+            TimerOp::AckCi => {
+                if self.br {
+                    self.counter.acknowledge();
+                }
+            }
+        }
+    }
+
+    fn toggle_q_on_underflow(&mut self, underflow: bool) {
+        if underflow && self.counter.etq {
+            self.out.set_q(!self.out.get_q());
         }
     }
 
@@ -563,7 +727,8 @@ impl Cdp1802 {
         // state transition, e.g. at the end of an S1/Execute machine cycle.
         let dma_in = !pins.get_dma_in();
         let dma_out = !pins.get_dma_out();
-        let intr = !pins.get_intr();
+        // External interrupts are masked by XIE, and counter interrupts by CIE.
+        let intr = (!pins.get_intr() && self.xie) || (self.counter.ci && self.cie);
         let state = match (dma_in, dma_out, intr, ie) {
             (true, _, _, _) => State::DmaIn(0),
             (_, true, _, _) => State::DmaOut(0),
@@ -0,0 +1,142 @@
+//! CDP1804/1805/1806 on-chip counter/timer
+
+use serde::{Deserialize, Serialize};
+
+/// Timer mode prescaler, in machine cycles.
+const PRESCALE: u8 = 32;
+
+/// Counter operating mode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CounterMode {
+    /// The counter is stopped.
+    #[default]
+    Stopped,
+    /// Decrement once every 32 machine cycles (STM).
+    Timer,
+    /// Decrement on each high-to-low transition of EF1 (SCM1).
+    EventEf1,
+    /// Decrement on each high-to-low transition of EF2 (SCM2).
+    EventEf2,
+    /// Decrement every 32 machine cycles while EF1 is low (SPM1).
+    PulseEf1,
+    /// Decrement every 32 machine cycles while EF2 is low (SPM2).
+    PulseEf2,
+}
+
+/// Counter/timer registers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter {
+    /// Counter holding register, reloaded into the counter on underflow.
+    pub ch: u8,
+    /// Current count.
+    pub count: u8,
+    /// Operating mode.
+    pub mode: CounterMode,
+    /// Enable toggle Q on underflow.
+    pub etq: bool,
+    /// Counter interrupt flip-flop.
+    pub ci: bool,
+    /// Machine cycles elapsed since the last prescaler output.
+    pub prescaler: u8,
+    /// EF1 level at the previous machine cycle.
+    ef1: bool,
+    /// EF2 level at the previous machine cycle.
+    ef2: bool,
+}
+impl Counter {
+    pub fn reset(&mut self) {
+        self.mode = CounterMode::Stopped;
+        self.etq = false;
+        self.ci = false;
+        self.prescaler = 0;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        matches!(self.mode, CounterMode::Stopped)
+    }
+
+    /// Returns true if the counter is clocked by the prescaler, rather than by external events.
+    pub fn is_timed(&self) -> bool {
+        matches!(
+            self.mode,
+            CounterMode::Timer | CounterMode::PulseEf1 | CounterMode::PulseEf2
+        )
+    }
+
+    /// Stops the counter and resets the prescaler (STPC).
+    pub fn stop(&mut self) {
+        self.mode = CounterMode::Stopped;
+        self.prescaler = 0;
+    }
+
+    /// Starts the counter in the specified mode (STM, SCM1, SCM2, SPM1, SPM2).
+    pub fn start(&mut self, mode: CounterMode) {
+        self.mode = mode;
+    }
+
+    /// Loads the holding register (LDC). If the counter is stopped, the counter is loaded as
+    /// well, and the interrupt flip-flop and toggle enable are reset.
+    pub fn load(&mut self, d: u8) {
+        self.ch = d;
+        if self.is_stopped() {
+            self.count = d;
+            self.ci = false;
+            self.etq = false;
+        }
+    }
+
+    /// Acknowledges the counter interrupt (BCI taken), which also disables Q toggling.
+    pub fn acknowledge(&mut self) {
+        self.ci = false;
+        self.etq = false;
+    }
+
+    /// Decrements the counter (DTC). Returns true on underflow, which occurs when the counter
+    /// decrements from 0x01. On underflow, the counter is reloaded from the holding register,
+    /// and the interrupt flip-flop is set.
+    pub fn decrement(&mut self) -> bool {
+        if self.count == 1 {
+            self.count = self.ch;
+            self.ci = true;
+            true
+        } else {
+            self.count = self.count.wrapping_sub(1);
+            false
+        }
+    }
+
+    /// Clocks the counter for one machine cycle, given the current EF1 and EF2 pin levels.
+    /// Returns true on underflow.
+    ///
+    /// In the pulse duration modes, the counter stops and the interrupt flip-flop is set when
+    /// the selected flag returns high.
+    pub fn tick(&mut self, ef1: bool, ef2: bool) -> bool {
+        let (prev_ef1, prev_ef2) = (self.ef1, self.ef2);
+        (self.ef1, self.ef2) = (ef1, ef2);
+        match self.mode {
+            CounterMode::Stopped => false,
+            CounterMode::Timer => self.prescale(),
+            CounterMode::EventEf1 => prev_ef1 && !ef1 && self.decrement(),
+            CounterMode::EventEf2 => prev_ef2 && !ef2 && self.decrement(),
+            CounterMode::PulseEf1 => self.pulse(prev_ef1, ef1),
+            CounterMode::PulseEf2 => self.pulse(prev_ef2, ef2),
+        }
+    }
+
+    fn prescale(&mut self) -> bool {
+        self.prescaler = (self.prescaler + 1) % PRESCALE;
+        self.prescaler == 0 && self.decrement()
+    }
+
+    fn pulse(&mut self, prev_ef: bool, ef: bool) -> bool {
+        if !ef {
+            self.prescale()
+        } else {
+            if !prev_ef {
+                self.stop();
+                self.ci = true;
+            }
+            false
+        }
+    }
+}
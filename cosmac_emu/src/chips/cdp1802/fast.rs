@@ -0,0 +1,415 @@
+//! Instruction-level execution core
+//!
+//! Rather than walking each instruction through the micro-op tables one tick at a time, this core
+//! decodes the instruction once and executes it against a [`Bus`] in a single step, accounting for
+//! machine cycles in bulk. Architectural state at instruction boundaries is identical to that of
+//! the tick-accurate core. Pin activity within an instruction is not modeled, and input pins are
+//! only sampled once per step.
+
+use crate::bus::Device;
+use crate::instr::Instr;
+
+use super::micro_ops::{AluOp, EXT_INSTR_CYCLE_TABLE, INSTR_CYCLE_TABLE, TimerOp};
+use super::{Cdp1802, Cdp1802Pins, EXT_PREFIX, Memory, Mode, State, mode};
+
+/// Ticks per machine cycle.
+const TICKS: u32 = 8;
+
+/// Memory and I/O port access for the instruction-level core.
+pub trait Bus {
+    /// Reads a byte from memory.
+    fn read(&mut self, addr: u16) -> u8;
+
+    /// Writes a byte to memory.
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// Handles the data strobe for an OUT instruction, with the byte that was read from memory.
+    fn output(&mut self, _n: u8, _data: u8) {}
+
+    /// Handles an INP instruction. Returns `None` if no device drives the data bus, in which case
+    /// the last value on the bus is used.
+    fn input(&mut self, _n: u8) -> Option<u8> {
+        None
+    }
+}
+
+impl Bus for Memory {
+    fn read(&mut self, addr: u16) -> u8 {
+        Device::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        Device::write(self, addr, data).ok();
+    }
+}
+
+/// Tracks the last value driven onto the data bus, which INP picks up when no device responds.
+struct DataBus<'a, B> {
+    bus: &'a mut B,
+    data: u8,
+}
+impl<B: Bus> DataBus<'_, B> {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.data = self.bus.read(addr);
+        self.data
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.data = data;
+        self.bus.write(addr, data);
+    }
+}
+
+impl Cdp1802 {
+    /// Returns true if the device is running, and is at a machine cycle boundary from which
+    /// [`Cdp1802::step`] can proceed.
+    pub fn can_step(&self, pins: Cdp1802Pins) -> bool {
+        matches!(mode(pins), Mode::Run)
+            && match self.state {
+                State::Fetch(0) | State::DmaIn(0) | State::DmaOut(0) | State::Interrupt(0) => true,
+                State::Execute(0) => self.instr == 0,
+                _ => false,
+            }
+    }
+
+    /// Executes a complete instruction (or a single DMA, interrupt, or idle machine cycle), and
+    /// returns the number of clock ticks consumed.
+    ///
+    /// Must only be called when [`Cdp1802::can_step`] is true.
+    pub fn step(&mut self, pins: &mut Cdp1802Pins, bus: &mut impl Bus) -> u32 {
+        debug_assert!(self.can_step(*pins));
+        let mut bus = DataBus {
+            bus,
+            data: pins.get_bus(),
+        };
+        let cycles = match self.state {
+            State::Fetch(_) => self.step_instr(*pins, &mut bus),
+            State::Execute(_) => {
+                // IDL
+                bus.read(self.r[0]);
+                self.tick_counter(*pins, 1);
+                1
+            }
+            State::DmaIn(_) => {
+                bus.write(self.r[0], pins.get_bus());
+                self.inc(0);
+                self.tick_counter(*pins, 1);
+                1
+            }
+            State::DmaOut(_) => {
+                bus.read(self.r[0]);
+                self.inc(0);
+                self.tick_counter(*pins, 1);
+                1
+            }
+            State::Interrupt(_) => {
+                self.tick_interrupt(0);
+                self.tick_counter(*pins, 1);
+                1
+            }
+            State::Init(_) => unreachable!(),
+        };
+
+        self.state = match self.instr {
+            0 if matches!(self.state, State::Fetch(_) | State::Execute(_)) => self
+                .sample_dma_intr(*pins, self.ie)
+                .unwrap_or(State::Execute(0)),
+            _ => self
+                .sample_dma_intr(*pins, self.ie)
+                .unwrap_or(State::Fetch(0)),
+        };
+        self.prev_mode = Mode::Run;
+        self.out.set_bus(bus.data);
+        pins.set_masked(self.out, Cdp1802Pins::mask_bus_out());
+        cycles * TICKS
+    }
+
+    /// Fetches and executes an instruction, returning the number of machine cycles consumed.
+    fn step_instr<B: Bus>(&mut self, pins: Cdp1802Pins, bus: &mut DataBus<B>) -> u32 {
+        let mut bin = [0; 4];
+        bin[0] = bus.read(self.rp());
+        self.instr = bin[0];
+        self.ext = None;
+        if self.instr != 0 {
+            self.inc(self.p);
+        }
+        let exec = if self.instr == EXT_PREFIX && self.variant.has_extended_isa() {
+            let ext = bus.read(self.rp());
+            self.inc(self.p);
+            self.ext = Some(ext);
+            bin[1] = ext;
+            1 + EXT_INSTR_CYCLE_TABLE[ext as usize].len
+        } else {
+            INSTR_CYCLE_TABLE[self.instr as usize].len
+        };
+
+        // The counter is clocked once per machine cycle, and nothing that observes it happens
+        // before the final machine cycle of an instruction.
+        let cycles = 1 + u32::from(exec);
+        self.tick_counter(pins, cycles);
+
+        // Immediate operands are read from memory during execution, in the same order as the
+        // tick-accurate core, so they're left as zeroes here.
+        let instr = Instr::decode_for(&bin, self.variant).expect("all opcodes are defined");
+        self.execute(instr, pins, bus);
+        cycles
+    }
+
+    fn execute<B: Bus>(&mut self, instr: Instr, pins: Cdp1802Pins, bus: &mut DataBus<B>) {
+        let (x, p) = (self.x, self.p);
+        match instr {
+            Instr::Idl => {
+                bus.read(self.r[0]);
+            }
+            Instr::Ldn(n) => self.d = bus.read(self.r[n as usize]),
+            Instr::Inc(n) => self.inc(n),
+            Instr::Dec(n) => self.dec(n),
+            Instr::Br(_) => self.short_branch(bus, true),
+            Instr::Bq(_) => self.short_branch(bus, self.out.get_q()),
+            Instr::Bz(_) => self.short_branch(bus, self.d == 0),
+            Instr::Bdf(_) => self.short_branch(bus, self.df),
+            Instr::B1(_) => self.short_branch(bus, !pins.get_ef1()),
+            Instr::B2(_) => self.short_branch(bus, !pins.get_ef2()),
+            Instr::B3(_) => self.short_branch(bus, !pins.get_ef3()),
+            Instr::B4(_) => self.short_branch(bus, !pins.get_ef4()),
+            Instr::Skp => self.short_branch(bus, false),
+            Instr::Bnq(_) => self.short_branch(bus, !self.out.get_q()),
+            Instr::Bnz(_) => self.short_branch(bus, self.d != 0),
+            Instr::Bnf(_) => self.short_branch(bus, !self.df),
+            Instr::Bn1(_) => self.short_branch(bus, pins.get_ef1()),
+            Instr::Bn2(_) => self.short_branch(bus, pins.get_ef2()),
+            Instr::Bn3(_) => self.short_branch(bus, pins.get_ef3()),
+            Instr::Bn4(_) => self.short_branch(bus, pins.get_ef4()),
+            Instr::Lda(n) => {
+                self.d = bus.read(self.r[n as usize]);
+                self.inc(n);
+            }
+            Instr::Str(n) => bus.write(self.r[n as usize], self.d),
+            Instr::Irx => self.inc(x),
+            Instr::Out(n) => {
+                let data = bus.read(self.r[x as usize]);
+                bus.bus.output(n, data);
+                self.inc(x);
+            }
+            Instr::Resv68 => (),
+            Instr::Inp(n) => {
+                let data = bus.bus.input(n).unwrap_or(bus.data);
+                bus.write(self.r[x as usize], data);
+                self.d = data;
+            }
+            Instr::Ret | Instr::Dis => {
+                let m = bus.read(self.r[x as usize]);
+                self.x = m >> 4;
+                self.p = m & 0xf;
+                self.inc(self.x);
+                self.ie = matches!(instr, Instr::Ret);
+            }
+            Instr::Ldxa => {
+                self.d = bus.read(self.r[x as usize]);
+                self.inc(x);
+            }
+            Instr::Stxd => {
+                bus.write(self.r[x as usize], self.d);
+                self.dec(x);
+            }
+            Instr::Adc => self.alu_rx(bus, AluOp::Adc),
+            Instr::Sdb => self.alu_rx(bus, AluOp::Sdb),
+            Instr::Shrc => self.alu(AluOp::Shrc, 0),
+            Instr::Smb => self.alu_rx(bus, AluOp::Smb),
+            Instr::Sav => bus.write(self.r[x as usize], self.t),
+            Instr::Mark => {
+                self.t = (x << 4) | (p & 0xf);
+                bus.write(self.r[2], self.t);
+                self.x = p;
+                self.dec(2);
+            }
+            Instr::Req => self.out.set_q(false),
+            Instr::Seq => self.out.set_q(true),
+            Instr::Adci(_) => self.alu_imm(bus, AluOp::Adc),
+            Instr::Sdbi(_) => self.alu_imm(bus, AluOp::Sdb),
+            Instr::Shlc => self.alu(AluOp::Shlc, 0),
+            Instr::Smbi(_) => self.alu_imm(bus, AluOp::Smb),
+            Instr::Glo(n) => self.d = self.glo(n),
+            Instr::Ghi(n) => self.d = self.ghi(n),
+            Instr::Plo(n) => self.plo(n, self.d),
+            Instr::Phi(n) => self.phi(n, self.d),
+            Instr::Lbr(..) => self.long_branch(bus, true),
+            Instr::Lbq(..) => self.long_branch(bus, self.out.get_q()),
+            Instr::Lbz(..) => self.long_branch(bus, self.d == 0),
+            Instr::Lbdf(..) => self.long_branch(bus, self.df),
+            Instr::Nop => (),
+            Instr::Lsnq => self.long_skip(!self.out.get_q()),
+            Instr::Lsnz => self.long_skip(self.d != 0),
+            Instr::Lsnf => self.long_skip(!self.df),
+            Instr::Lskp => self.long_branch(bus, false),
+            Instr::Lbnq(..) => self.long_branch(bus, !self.out.get_q()),
+            Instr::Lbnz(..) => self.long_branch(bus, self.d != 0),
+            Instr::Lbnf(..) => self.long_branch(bus, !self.df),
+            Instr::Lsie => self.long_skip(self.ie),
+            Instr::Lsq => self.long_skip(self.out.get_q()),
+            Instr::Lsz => self.long_skip(self.d == 0),
+            Instr::Lsdf => self.long_skip(self.df),
+            Instr::Sep(n) => self.p = n,
+            Instr::Sex(n) => self.x = n,
+            Instr::Ldx => self.d = bus.read(self.r[x as usize]),
+            Instr::Or => self.alu_rx(bus, AluOp::Or),
+            Instr::And => self.alu_rx(bus, AluOp::And),
+            Instr::Xor => self.alu_rx(bus, AluOp::Xor),
+            Instr::Add => self.alu_rx(bus, AluOp::Add),
+            Instr::Sd => self.alu_rx(bus, AluOp::Sd),
+            Instr::Shr => self.alu(AluOp::Shr, 0),
+            Instr::Sm => self.alu_rx(bus, AluOp::Sm),
+            Instr::Ldi(_) => {
+                self.d = bus.read(self.rp());
+                self.inc(p);
+            }
+            Instr::Ori(_) => self.alu_imm(bus, AluOp::Or),
+            Instr::Ani(_) => self.alu_imm(bus, AluOp::And),
+            Instr::Xri(_) => self.alu_imm(bus, AluOp::Xor),
+            Instr::Adi(_) => self.alu_imm(bus, AluOp::Add),
+            Instr::Sdi(_) => self.alu_imm(bus, AluOp::Sd),
+            Instr::Shl => self.alu(AluOp::Shl, 0),
+            Instr::Smi(_) => self.alu_imm(bus, AluOp::Sm),
+
+            // CDP1804/1805/1806 extended instructions.
+            Instr::Stpc => self.tick_timer_op(TimerOp::Stpc),
+            Instr::Dtc => self.tick_timer_op(TimerOp::Dtc),
+            Instr::Spm2 => self.tick_timer_op(TimerOp::Spm2),
+            Instr::Scm2 => self.tick_timer_op(TimerOp::Scm2),
+            Instr::Spm1 => self.tick_timer_op(TimerOp::Spm1),
+            Instr::Scm1 => self.tick_timer_op(TimerOp::Scm1),
+            Instr::Ldc => self.tick_timer_op(TimerOp::Ldc),
+            Instr::Stm => self.tick_timer_op(TimerOp::Stm),
+            Instr::Gec => self.tick_timer_op(TimerOp::Gec),
+            Instr::Etq => self.tick_timer_op(TimerOp::Etq),
+            Instr::Xie => self.xie = true,
+            Instr::Xid => self.xie = false,
+            Instr::Cie => self.cie = true,
+            Instr::Cid => self.cie = false,
+            Instr::Dbnz(n, ..) => {
+                self.dec(n);
+                self.long_branch(bus, self.r[n as usize] != 0);
+            }
+            Instr::Bci(_) => {
+                let ci = self.counter.ci;
+                self.short_branch(bus, ci);
+                if ci {
+                    self.counter.acknowledge();
+                }
+            }
+            Instr::Bxi(_) => self.short_branch(bus, !pins.get_intr()),
+            Instr::Rlxa(n) => {
+                let m = bus.read(self.r[self.x as usize]);
+                self.phi(n, m);
+                self.inc(self.x);
+                let m = bus.read(self.r[self.x as usize]);
+                self.plo(n, m);
+                self.inc(self.x);
+            }
+            Instr::Dadc => self.alu_rx(bus, AluOp::Dadc),
+            Instr::Dsav => {
+                self.dec(x);
+                bus.write(self.r[x as usize], self.t);
+                self.dec(x);
+                bus.write(self.r[x as usize], self.d);
+                self.dec(x);
+                self.alu(AluOp::Shrc, 0);
+                bus.write(self.r[x as usize], self.d);
+            }
+            Instr::Dsmb => self.alu_rx(bus, AluOp::Dsmb),
+            Instr::Daci(_) => self.alu_imm(bus, AluOp::Dadc),
+            Instr::Dsbi(_) => self.alu_imm(bus, AluOp::Dsmb),
+            Instr::Scal(n, ..) => {
+                self.push_reg(bus, n);
+                self.r[n as usize] = self.rp();
+                let m = bus.read(self.r[n as usize]);
+                self.phi(self.p, m);
+                self.inc(n);
+                let m = bus.read(self.r[n as usize]);
+                self.plo(self.p, m);
+                self.inc(n);
+            }
+            Instr::Sret(n) => {
+                self.r[p as usize] = self.r[n as usize];
+                self.inc(x);
+                let m = bus.read(self.r[x as usize]);
+                self.phi(n, m);
+                self.inc(x);
+                let m = bus.read(self.r[x as usize]);
+                self.plo(n, m);
+            }
+            Instr::Rsxd(n) => self.push_reg(bus, n),
+            Instr::Rnx(n) => self.r[x as usize] = self.r[n as usize],
+            Instr::Rldi(n, ..) => {
+                let m = bus.read(self.rp());
+                self.phi(n, m);
+                self.inc(self.p);
+                let m = bus.read(self.rp());
+                self.plo(n, m);
+                self.inc(self.p);
+            }
+            Instr::Dadd => self.alu_rx(bus, AluOp::Dadd),
+            Instr::Dsm => self.alu_rx(bus, AluOp::Dsm),
+            Instr::Dadi(_) => self.alu_imm(bus, AluOp::Dadd),
+            Instr::Dsmi(_) => self.alu_imm(bus, AluOp::Dsm),
+        }
+    }
+
+    /// Clocks the counter for the specified number of machine cycles.
+    fn tick_counter(&mut self, pins: Cdp1802Pins, cycles: u32) {
+        for _ in 0..cycles {
+            let underflow = self.counter.tick(pins.get_ef1(), pins.get_ef2());
+            self.toggle_q_on_underflow(underflow);
+        }
+    }
+
+    /// Applies an ALU operation to D and M(R(X)).
+    fn alu_rx<B: Bus>(&mut self, bus: &mut DataBus<B>, op: AluOp) {
+        let m = bus.read(self.r[self.x as usize]);
+        self.alu(op, m);
+    }
+
+    /// Applies an ALU operation to D and an immediate operand.
+    fn alu_imm<B: Bus>(&mut self, bus: &mut DataBus<B>, op: AluOp) {
+        let m = bus.read(self.rp());
+        self.alu(op, m);
+        self.inc(self.p);
+    }
+
+    fn short_branch<B: Bus>(&mut self, bus: &mut DataBus<B>, cond: bool) {
+        let m = bus.read(self.rp());
+        if cond {
+            self.plo(self.p, m);
+        } else {
+            self.inc(self.p);
+        }
+    }
+
+    fn long_branch<B: Bus>(&mut self, bus: &mut DataBus<B>, cond: bool) {
+        self.b = bus.read(self.rp());
+        self.inc(self.p);
+        let m = bus.read(self.rp());
+        if cond {
+            self.plo(self.p, m);
+            self.phi(self.p, self.b);
+        } else {
+            self.inc(self.p);
+        }
+    }
+
+    fn long_skip(&mut self, cond: bool) {
+        if cond {
+            self.inc(self.p);
+            self.inc(self.p);
+        }
+    }
+
+    /// Stores R(N) at M(R(X)), low byte first, decrementing R(X) after each byte.
+    fn push_reg<B: Bus>(&mut self, bus: &mut DataBus<B>, n: u8) {
+        let x = self.x;
+        bus.write(self.r[x as usize], self.glo(n));
+        self.dec(x);
+        bus.write(self.r[x as usize], self.ghi(n));
+        self.dec(x);
+    }
+}
@@ -1,46 +1,15 @@
-use std::{fs::File, io::Read, ops::RangeInclusive};
+use std::ops::RangeInclusive;
 
 use color_eyre::eyre;
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    bus::{Device, DeviceState, MemoryAccessError},
     cli::ImageArg,
-    instr::{Instr, InstrSchema as _},
+    snapshot,
 };
 
-use super::Cdp1802Pins;
-
-#[derive(Debug)]
-pub enum MemoryAccessMode {
-    Read,
-    Write,
-}
-
-#[allow(dead_code)]
-#[derive(Debug)]
-pub struct MemoryAccess {
-    mode: MemoryAccessMode,
-    addr: u16,
-    data: u8,
-}
-impl MemoryAccess {
-    fn new(mode: MemoryAccessMode, addr: u16, data: u8) -> Self {
-        Self { mode, addr, data }
-    }
-    fn read(addr: u16, data: u8) -> Self {
-        Self::new(MemoryAccessMode::Read, addr, data)
-    }
-    fn write(addr: u16, data: u8) -> Self {
-        Self::new(MemoryAccessMode::Write, addr, data)
-    }
-}
-
-#[derive(Debug, thiserror::Error)]
-pub enum MemoryAccessError {
-    #[error("write protection fault at {0:04x}")]
-    WriteProtectionFault(u16),
-}
-
 #[derive(Debug, thiserror::Error)]
 pub enum MemoryBuilderError {
     #[error("image at 0x{0:x} out of range")]
@@ -53,7 +22,7 @@ pub struct MemoryRange {
     pub end: Option<u16>,
 }
 impl MemoryRange {
-    fn into_range_inclusive(self, max: u16) -> RangeInclusive<u16> {
+    pub fn into_range_inclusive(self, max: u16) -> RangeInclusive<u16> {
         self.start.unwrap_or(0)..=self.end.unwrap_or(max)
     }
 }
@@ -100,18 +69,17 @@ impl Builder {
         self
     }
 
-    pub fn with_image_args(mut self, images: &[ImageArg]) -> std::io::Result<Self> {
+    pub fn with_image_args(mut self, images: &[ImageArg]) -> color_eyre::Result<Self> {
         for image in images {
-            let mut file = File::open(&image.path)?;
-            let mut buf = vec![];
-            file.read_to_end(&mut buf)?;
-            if image.write_protect && !buf.is_empty() {
-                let start = Some(image.base_addr);
-                let end = Some(image.base_addr.saturating_add((buf.len() as u16) - 1));
-                let range = MemoryRange { start, end };
-                self.write_protect.push(range);
+            for segment in image.load()?.segments {
+                if image.write_protect {
+                    let start = Some(segment.addr);
+                    let end = Some(segment.addr.saturating_add((segment.data.len() as u16) - 1));
+                    let range = MemoryRange { start, end };
+                    self.write_protect.push(range);
+                }
+                self = self.with_image(segment.addr, segment.data);
             }
-            self.images.push((image.base_addr, buf));
         }
         Ok(self)
     }
@@ -141,6 +109,7 @@ impl Builder {
         } else {
             vec![0; self.capacity]
         };
+        let mut initialized = vec![0; self.capacity.div_ceil(8)];
         for (addr, image) in self.images {
             let start = addr as usize;
             let end = start + image.len();
@@ -148,6 +117,7 @@ impl Builder {
                 return Err(MemoryBuilderError::ImageOutOfRange(addr));
             }
             data.splice(start..end, image);
+            (start..end).for_each(|idx| set_bit(&mut initialized, idx));
         }
         let max_addr = (self.capacity - 1) as u16;
         let write_protect = self
@@ -155,15 +125,31 @@ impl Builder {
             .into_iter()
             .map(|mr| mr.into_range_inclusive(max_addr))
             .collect();
-        Ok(Memory::new(data, write_protect))
+        Ok(Memory::new(data, initialized, write_protect))
+    }
+}
+
+fn set_bit(bits: &mut [u8], idx: usize) {
+    if let Some(byte) = bits.get_mut(idx / 8) {
+        *byte |= 1 << (idx % 8);
     }
 }
 
-#[derive(Debug, Clone)]
+fn clear_bit(bits: &mut [u8], idx: usize) {
+    if let Some(byte) = bits.get_mut(idx / 8) {
+        *byte &= !(1 << (idx % 8));
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Memory {
+    #[serde(with = "snapshot::hex")]
     data: Vec<u8>,
+    /// One bit per byte, set once the byte has been written or loaded from an image. Snapshots
+    /// that predate shadow tracking have none, in which case all memory is initialized.
+    #[serde(with = "snapshot::hex", default)]
+    initialized: Vec<u8>,
     write_protect: Vec<RangeInclusive<u16>>,
-    addr_hi: u8,
 }
 impl Default for Memory {
     fn default() -> Self {
@@ -171,11 +157,11 @@ impl Default for Memory {
     }
 }
 impl Memory {
-    fn new(data: Vec<u8>, write_protect: Vec<RangeInclusive<u16>>) -> Self {
+    fn new(data: Vec<u8>, initialized: Vec<u8>, write_protect: Vec<RangeInclusive<u16>>) -> Self {
         Memory {
             data,
+            initialized,
             write_protect,
-            addr_hi: 0,
         }
     }
 
@@ -183,57 +169,61 @@ impl Memory {
         Builder::default()
     }
 
-    pub fn tick(
-        &mut self,
-        pins: &mut Cdp1802Pins,
-        write_enable: bool,
-    ) -> Result<Option<MemoryAccess>, MemoryAccessError> {
-        // TPA strobe to latch high address byte.
-        if pins.get_tpa() {
-            self.addr_hi = pins.get_ma();
-        }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
 
-        if !pins.get_mrd() {
-            // MRD low to read data from memory.
-            let addr_lo = pins.get_ma();
-            let addr = ((self.addr_hi as u16) << 8) | (addr_lo as u16);
-            let data = self.data[addr as usize];
-            pins.set_bus(data);
-            Ok(Some(MemoryAccess::read(addr, data)))
-        } else if write_enable && !pins.get_mwr() {
-            // MWR low to write data to memory.
-            let addr_lo = pins.get_ma();
-            let addr = ((self.addr_hi as u16) << 8) | (addr_lo as u16);
-            if !self.is_writable(addr) {
-                return Err(MemoryAccessError::WriteProtectionFault(addr));
-            }
-            let data = pins.get_bus();
-            self.data[addr as usize] = data;
-            Ok(Some(MemoryAccess::write(addr, data)))
-        } else {
-            Ok(None)
+    pub fn is_writable(&self, addr: u16) -> bool {
+        !self.write_protect.iter().any(|r| r.contains(&addr))
+    }
+}
+impl Device for Memory {
+    fn peek(&self, addr: u16) -> u8 {
+        self.data[addr as usize]
+    }
+
+    /// Writes a byte to memory, unless the address is write-protected.
+    fn write(&mut self, addr: u16, data: u8) -> Result<(), MemoryAccessError> {
+        if !self.is_writable(addr) {
+            return Err(MemoryAccessError::WriteProtectionFault(addr));
         }
+        self.data[addr as usize] = data;
+        set_bit(&mut self.initialized, addr as usize);
+        Ok(())
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.data[addr as usize] = data;
+        set_bit(&mut self.initialized, addr as usize);
     }
 
-    pub fn get_instr_at(&self, addr: u16) -> Option<Instr> {
-        let start = addr as usize;
-        let end = (start + 2).min(self.data.len() - 1);
-        if start >= end {
-            None
+    fn restore_byte(&mut self, addr: u16, data: u8, initialized: bool) {
+        self.data[addr as usize] = data;
+        if initialized {
+            set_bit(&mut self.initialized, addr as usize);
         } else {
-            Instr::decode(&self.data[start..=end])
+            clear_bit(&mut self.initialized, addr as usize);
         }
     }
 
-    pub fn as_slice(&self) -> &[u8] {
-        &self.data
+    fn is_initialized(&self, addr: u16) -> bool {
+        let idx = addr as usize;
+        self.initialized
+            .get(idx / 8)
+            .is_none_or(|byte| byte & (1 << (idx % 8)) != 0)
     }
 
-    pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        &mut self.data
+    fn state(&self) -> Option<DeviceState> {
+        Some(DeviceState::Ram(self.clone()))
     }
 
-    pub fn is_writable(&self, addr: u16) -> bool {
-        !self.write_protect.iter().any(|r| r.contains(&addr))
+    fn restore(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::Ram(memory) => {
+                *self = memory;
+                true
+            }
+            _ => false,
+        }
     }
 }
@@ -24,20 +24,44 @@ pub enum AluOp {
     Sdb,
     Sm,
     Smb,
+    Dadd,
+    Dadc,
+    Dsm,
+    Dsmb,
 }
 
 /// Sampled bit for test ops.
 #[derive(Clone, Copy, Debug)]
 pub enum Bit {
-    True, // true
-    Q,    // Q
-    DZ,   // D == 0
-    DF,   // DF
-    IE,   // IE
-    NEF1, // !EF1
-    NEF2, // !EF2
-    NEF3, // !EF3
-    NEF4, // !EF4
+    True,    // true
+    Q,       // Q
+    DZ,      // D == 0
+    DF,      // DF
+    IE,      // IE
+    NEF1,    // !EF1
+    NEF2,    // !EF2
+    NEF3,    // !EF3
+    NEF4,    // !EF4
+    NZ(Reg), // R(N) != 0
+    CI,      // Counter interrupt
+    XI,      // External interrupt
+}
+
+/// Counter/timer operation.
+#[derive(Clone, Copy, Debug)]
+pub enum TimerOp {
+    Stpc,
+    Dtc,
+    Spm2,
+    Scm2,
+    Spm1,
+    Scm1,
+    Ldc,
+    Stm,
+    Gec,
+    Etq,
+    /// Resets the counter interrupt flip-flop and toggle enable, if the preceding test succeeded.
+    AckCi,
 }
 
 /// Micro-operation which takes a single tick.
@@ -45,6 +69,7 @@ pub enum Bit {
 pub enum MicroOp {
     Idle,
     Fetch,
+    FetchExt,
     Inc(Reg),
     Dec(Reg),
     SetBusD,
@@ -54,6 +79,11 @@ pub enum MicroOp {
     GetHi(Reg),
     PutLo(Reg),
     PutHi(Reg),
+    LoadLo(Reg),
+    LoadHi(Reg),
+    SetBusLo(Reg),
+    SetBusHi(Reg),
+    Copy { from: Reg, to: Reg },
     SetQ(bool),
     SetP(u8),
     SetX(u8),
@@ -65,6 +95,9 @@ pub enum MicroOp {
     LbrLatch,
     Lbr,
     Ls,
+    Timer(TimerOp),
+    SetXie(bool),
+    SetCie(bool),
 }
 
 /// Memory access pattern.
@@ -82,27 +115,55 @@ pub enum Access {
     Inp(Reg, u8),
 }
 
-const TICKS: usize = 16;
+/// Ticks per machine cycle.
+const TICKS: usize = 8;
 
+/// A sequence of up to `N` machine cycles.
 #[derive(Debug, Clone, Copy)]
 #[repr(align(64))]
-pub struct Cycle {
-    pub access: Access,
-    pub ticks: [MicroOp; TICKS],
+pub struct Cycle<const N: usize> {
+    /// The number of machine cycles in the sequence.
+    pub len: u8,
+    pub access: [Access; N],
+    pub ticks: [[MicroOp; TICKS]; N],
 }
-impl Cycle {
+impl<const N: usize> Cycle<N> {
     const fn empty() -> Self {
         Self {
-            ticks: [MicroOp::Idle; 16],
-            access: None,
+            len: 1,
+            ticks: [[MicroOp::Idle; TICKS]; N],
+            access: [None; N],
         }
     }
 
     const fn set(&mut self, tick: u8, op: MicroOp) {
-        assert!(tick < 16);
-        let tick_op = &mut self.ticks[tick as usize];
+        let cycle = tick as usize / TICKS;
+        assert!(cycle < N);
+        let tick_op = &mut self.ticks[cycle][tick as usize % TICKS];
         assert!(matches!(tick_op, MicroOp::Idle), "redefined");
         *tick_op = op;
+        if self.len as usize <= cycle {
+            self.len = cycle as u8 + 1;
+        }
+    }
+
+    const fn set_access(&mut self, access: Access) {
+        const_for!(n in 0..N => {
+            self.access[n] = access;
+        });
+    }
+
+    /// Returns the number of ticks in the sequence.
+    pub const fn ticks(&self) -> u8 {
+        self.len * TICKS as u8
+    }
+
+    pub const fn access(&self, tick: u8) -> Access {
+        self.access[tick as usize / TICKS]
+    }
+
+    pub const fn op(&self, tick: u8) -> MicroOp {
+        self.ticks[tick as usize / TICKS][tick as usize % TICKS]
     }
 }
 
@@ -117,7 +178,7 @@ macro_rules! define_cycle {
         $( $tick:literal: $op:expr),* $(,)?
     }) => {{
         let mut c = Cycle::empty();
-        c.access = $access;
+        c.set_access($access);
         $( c.set($tick, $op); )*
         c
     }};
@@ -128,11 +189,28 @@ macro_rules! define_instr {
         $( $tick:literal: $op:expr),* $(,)?
     }) => {
         let cycle = &mut $table[$opcode as usize];
-        cycle.access = $access;
+        cycle.set_access($access);
         $( cycle.set($tick, $op); )*
     };
 }
 
+/// Defines an extended instruction as a sequence of machine cycles, each with its own access
+/// pattern. Ticks are numbered relative to the start of each machine cycle.
+macro_rules! define_ext_instr {
+    ($table:ident, $opcode:expr, [
+        $( $access:expr => { $( $tick:literal: $op:expr ),* $(,)? } ),+ $(,)?
+    ]) => {
+        let cycle = &mut $table[$opcode as usize];
+        let mut _n: u8 = 0;
+        $(
+            cycle.access[_n as usize] = $access;
+            $( cycle.set(_n * 8 + $tick, $op); )*
+            cycle.len = _n + 1;
+            _n += 1;
+        )+
+    };
+}
+
 macro_rules! define_bxx_instr {
     ($table:ident, $opcode:expr, $bit:path, $inv:literal) => {
         define_instr!($table, $opcode, Read(P), {
@@ -180,10 +258,12 @@ macro_rules! define_lsxx_instr {
     };
 }
 
-pub const FETCH_CYCLE: Cycle = define_cycle!(Read(P), { 3: Fetch });
-pub const DMA_IN_CYCLE: Cycle = define_cycle!(Write(R(0)), { 4: Inc(P) });
-pub const DMA_OUT_CYCLE: Cycle = define_cycle!(Read(R(0)), { 4: Inc(P) });
-pub const INSTR_CYCLE_TABLE: [Cycle; 256] = {
+/// The fetch cycle. The second machine cycle fetches the second opcode byte of an extended
+/// instruction.
+pub const FETCH_CYCLE: Cycle<2> = define_cycle!(Read(P), { 3: Fetch, 11: FetchExt });
+pub const DMA_IN_CYCLE: Cycle<1> = define_cycle!(Write(R(0)), { 4: Inc(R(0)) });
+pub const DMA_OUT_CYCLE: Cycle<1> = define_cycle!(Read(R(0)), { 4: Inc(R(0)) });
+pub static INSTR_CYCLE_TABLE: [Cycle<2>; 256] = {
     let mut t = [Cycle::empty(); 256];
 
     // IDL
@@ -234,7 +314,7 @@ pub const INSTR_CYCLE_TABLE: [Cycle; 256] = {
 
     // INP
     const_for!(n in 9..16 => {
-        define_instr!(t, 0x60 | n, Inp(X, n), { 3: GetBus });
+        define_instr!(t, 0x60 | n, Inp(X, n & 7), { 3: GetBus });
     });
 
     // RET, DIS
@@ -329,20 +409,143 @@ pub const INSTR_CYCLE_TABLE: [Cycle; 256] = {
     t
 };
 
+/// Execute cycles for the CDP1804/1805/1806 extended instructions, indexed by the second opcode
+/// byte. Undefined opcodes execute a single idle machine cycle.
+pub static EXT_INSTR_CYCLE_TABLE: [Cycle<8>; 256] = {
+    let mut t = [Cycle::empty(); 256];
+
+    // STPC, DTC, SPM2, SCM2, SPM1, SCM1, LDC, STM, GEC, ETQ
+    define_ext_instr!(t, 0x00, [None => { 3: Timer(TimerOp::Stpc) }]);
+    define_ext_instr!(t, 0x01, [None => { 3: Timer(TimerOp::Dtc) }]);
+    define_ext_instr!(t, 0x02, [None => { 3: Timer(TimerOp::Spm2) }]);
+    define_ext_instr!(t, 0x03, [None => { 3: Timer(TimerOp::Scm2) }]);
+    define_ext_instr!(t, 0x04, [None => { 3: Timer(TimerOp::Spm1) }]);
+    define_ext_instr!(t, 0x05, [None => { 3: Timer(TimerOp::Scm1) }]);
+    define_ext_instr!(t, 0x06, [None => { 3: Timer(TimerOp::Ldc) }]);
+    define_ext_instr!(t, 0x07, [None => { 3: Timer(TimerOp::Stm) }]);
+    define_ext_instr!(t, 0x08, [None => { 3: Timer(TimerOp::Gec) }]);
+    define_ext_instr!(t, 0x09, [None => { 3: Timer(TimerOp::Etq) }]);
+
+    // XIE, XID, CIE, CID
+    define_ext_instr!(t, 0x0a, [None => { 3: SetXie(true) }]);
+    define_ext_instr!(t, 0x0b, [None => { 3: SetXie(false) }]);
+    define_ext_instr!(t, 0x0c, [None => { 3: SetCie(true) }]);
+    define_ext_instr!(t, 0x0d, [None => { 3: SetCie(false) }]);
+
+    // DBNZ
+    const_for!(n in 0..16 => {
+        define_ext_instr!(t, 0x20 | n, [
+            Read(P) => { 0: Dec(R(n)), 1: Test { bit: NZ(R(n)), inv: false }, 3: LbrLatch },
+            Read(P) => { 3: Lbr },
+            None => {},
+        ]);
+    });
+
+    // BCI, BXI
+    define_ext_instr!(t, 0x3e, [
+        Read(P) => { 1: Test { bit: CI, inv: false }, 3: Br, 4: Timer(TimerOp::AckCi) },
+    ]);
+    define_ext_instr!(t, 0x3f, [Read(P) => { 1: Test { bit: XI, inv: false }, 3: Br }]);
+
+    // RLXA
+    const_for!(n in 0..16 => {
+        define_ext_instr!(t, 0x60 | n, [
+            Read(X) => { 3: LoadHi(R(n)), 4: Inc(X) },
+            Read(X) => { 3: LoadLo(R(n)), 4: Inc(X) },
+            None => {},
+        ]);
+    });
+
+    // DADC, DSMB, DACI, DSBI
+    define_ext_instr!(t, 0x74, [Read(X) => { 3: Alu(Dadc) }, None => {}]);
+    define_ext_instr!(t, 0x77, [Read(X) => { 3: Alu(Dsmb) }, None => {}]);
+    define_ext_instr!(t, 0x7c, [Read(P) => { 3: Alu(Dadc), 4: Inc(P) }, None => {}]);
+    define_ext_instr!(t, 0x7f, [Read(P) => { 3: Alu(Dsmb), 4: Inc(P) }, None => {}]);
+
+    // DSAV
+    define_ext_instr!(t, 0x76, [
+        None => { 4: Dec(X) },
+        Write(X) => { 3: SetBusT, 4: Dec(X) },
+        Write(X) => { 3: SetBusD, 4: Dec(X) },
+        Write(X) => { 2: Alu(Shrc), 3: SetBusD },
+    ]);
+
+    // SCAL, SRET, RSXD, RNX, RLDI
+    const_for!(n in 0..16 => {
+        define_ext_instr!(t, 0x80 | n, [
+            Write(X) => { 3: SetBusLo(R(n)), 4: Dec(X) },
+            Write(X) => { 3: SetBusHi(R(n)), 4: Dec(X) },
+            None => { 3: Copy { from: P, to: R(n) } },
+            Read(R(n)) => { 3: LoadHi(P), 4: Inc(R(n)) },
+            Read(R(n)) => { 3: LoadLo(P), 4: Inc(R(n)) },
+            None => {},
+            None => {},
+            None => {},
+        ]);
+        define_ext_instr!(t, 0x90 | n, [
+            None => { 3: Copy { from: R(n), to: P }, 4: Inc(X) },
+            Read(X) => { 3: LoadHi(R(n)), 4: Inc(X) },
+            Read(X) => { 3: LoadLo(R(n)) },
+            None => {},
+            None => {},
+            None => {},
+        ]);
+        define_ext_instr!(t, 0xa0 | n, [
+            Write(X) => { 3: SetBusLo(R(n)), 4: Dec(X) },
+            Write(X) => { 3: SetBusHi(R(n)), 4: Dec(X) },
+            None => {},
+        ]);
+        define_ext_instr!(t, 0xb0 | n, [
+            None => { 3: Copy { from: R(n), to: X } },
+            None => {},
+        ]);
+        define_ext_instr!(t, 0xc0 | n, [
+            Read(P) => { 3: LoadHi(R(n)), 4: Inc(P) },
+            Read(P) => { 3: LoadLo(R(n)), 4: Inc(P) },
+            None => {},
+        ]);
+    });
+
+    // DADD, DSM, DADI, DSMI
+    define_ext_instr!(t, 0xf4, [Read(X) => { 3: Alu(Dadd) }, None => {}]);
+    define_ext_instr!(t, 0xf7, [Read(X) => { 3: Alu(Dsm) }, None => {}]);
+    define_ext_instr!(t, 0xfc, [Read(P) => { 3: Alu(Dadd), 4: Inc(P) }, None => {}]);
+    define_ext_instr!(t, 0xff, [Read(P) => { 3: Alu(Dsm), 4: Inc(P) }, None => {}]);
+
+    t
+};
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::instr::{Instr, InstrSchema, Io, Mem};
     use assert_matches::assert_matches;
 
-    fn check_bus_access(name: &str, cycle: &Cycle) {
-        for tick in 0..TICKS {
-            let op = cycle.ticks[tick];
-            let is_write = matches!(op, SetBusD | SetBusT | Mark);
+    fn check_bus_access<const N: usize>(name: &str, cycle: &Cycle<N>) {
+        for tick in 0..cycle.ticks() {
+            let op = cycle.op(tick);
+            let access = cycle.access(tick);
+            let is_write = matches!(op, SetBusD | SetBusT | SetBusLo(_) | SetBusHi(_) | Mark);
             let is_read = matches!(
                 op,
                 Fetch
+                    | FetchExt
                     | GetBus
-                    | Alu(Or | And | Xor | Add | Adc | Sd | Sdb | Sm | Smb)
+                    | LoadLo(_)
+                    | LoadHi(_)
+                    | Alu(Or
+                        | And
+                        | Xor
+                        | Add
+                        | Adc
+                        | Sd
+                        | Sdb
+                        | Sm
+                        | Smb
+                        | Dadd
+                        | Dadc
+                        | Dsm
+                        | Dsmb)
                     | Br
                     | LbrLatch
                     | Lbr
@@ -356,18 +559,14 @@ mod tests {
             // Ops that read from the bus must declare read/inp access.
             if is_read {
                 assert_matches!(
-                    cycle.access,
+                    access,
                     Read(_) | Inp(_, _),
                     "{name}: {op:?} requires Read|Inp access"
                 );
             }
             // Ops that write to the bus must declare write access.
             if is_write {
-                assert_matches!(
-                    cycle.access,
-                    Write(_),
-                    "{name}: {op:?} requires Write|In access"
-                );
+                assert_matches!(access, Write(_), "{name}: {op:?} requires Write|In access");
             }
         }
     }
@@ -380,5 +579,45 @@ mod tests {
         for (opcode, instr) in INSTR_CYCLE_TABLE.iter().enumerate() {
             check_bus_access(&format!("instr {opcode:#04x}"), instr);
         }
+        for (opcode, instr) in EXT_INSTR_CYCLE_TABLE.iter().enumerate() {
+            check_bus_access(&format!("instr 0x68 {opcode:#04x}"), instr);
+        }
+    }
+
+    fn check_meta<const N: usize>(instr: &Instr, cycle: &Cycle<N>) {
+        let meta = instr.meta();
+        let fetch = if instr.is_extended() { 2 } else { 1 };
+        assert_eq!(meta.cycles, fetch + cycle.len, "{instr:?}: cycles");
+        let (mut reads, mut writes, mut io) = (false, false, Io::None);
+        for access in &cycle.access[..cycle.len as usize] {
+            match access {
+                None => (),
+                Read(_) => reads = true,
+                Write(_) => writes = true,
+                Out(..) => (reads, io) = (true, Io::Out),
+                Inp(..) => (writes, io) = (true, Io::Inp),
+            }
+        }
+        let mem = match (reads, writes) {
+            (false, false) => Mem::None,
+            (true, false) => Mem::Read,
+            (false, true) => Mem::Write,
+            (true, true) => Mem::ReadWrite,
+        };
+        assert_eq!((meta.mem, meta.io), (mem, io), "{instr:?}: access");
+    }
+
+    #[test]
+    fn test_instr_meta() {
+        for (opcode, cycle) in INSTR_CYCLE_TABLE.iter().enumerate() {
+            if let Some(instr) = Instr::decode(&[opcode as u8, 0, 0]).filter(|i| !i.is_extended()) {
+                check_meta(&instr, cycle);
+            }
+        }
+        for (opcode, cycle) in EXT_INSTR_CYCLE_TABLE.iter().enumerate() {
+            if let Some(instr) = Instr::decode(&[0x68, opcode as u8, 0, 0]) {
+                check_meta(&instr, cycle);
+            }
+        }
     }
 }
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::chips::bits;
 
 #[allow(dead_code)]
@@ -46,11 +48,11 @@ pub enum Pin {
     Wait,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Cdp1802Pins(pub u64);
 impl Default for Cdp1802Pins {
     fn default() -> Self {
-        Self(u64::MAX & Self::mask_all())
+        Self(Self::mask_all())
     }
 }
 impl std::fmt::Debug for Cdp1802Pins {
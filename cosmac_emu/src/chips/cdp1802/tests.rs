@@ -1,11 +1,14 @@
 use assert_matches::assert_matches;
 
-use super::{Cdp1802, Cdp1802Pins, Memory, State};
+use super::{Cdp1802, Cdp1802Pins, CounterMode, Memory, State, Variant};
+use crate::bus::{AddressLatch, Device};
+use crate::instr::asm1802;
 
 struct TestSystem {
     pins: Cdp1802Pins,
     cpu: Cdp1802,
     mem: Memory,
+    latch: AddressLatch,
     write_enable: bool,
 }
 impl TestSystem {
@@ -14,6 +17,7 @@ impl TestSystem {
             pins: Cdp1802Pins(Cdp1802Pins::mask_all()),
             cpu: Cdp1802::default(),
             mem: Memory::default(),
+            latch: AddressLatch::default(),
             write_enable: true,
         }
     }
@@ -24,13 +28,21 @@ impl TestSystem {
             pins: Cdp1802Pins(Cdp1802Pins::mask_all()),
             cpu: Cdp1802::default(),
             mem,
+            latch: AddressLatch::default(),
             write_enable: true,
         }
     }
 
+    fn with_variant(mut self, variant: Variant) -> Self {
+        self.cpu = Cdp1802::new(variant);
+        self
+    }
+
     fn tick(&mut self) {
         self.cpu.tick(&mut self.pins);
-        self.mem.tick(&mut self.pins, self.write_enable).ok();
+        self.latch
+            .tick(&mut self.pins, self.write_enable, &mut self.mem)
+            .ok();
     }
 
     fn tick_n(&mut self, n: usize) {
@@ -221,9 +233,7 @@ fn test_lbr_at_page_boundary() {
     // temporary register (b), and not r[p]. This is done by placing the high byte of the target
     // address at the edge of a page boundary. The chip must do a full 16-bit increment of r[p] to
     // successfully fetch the low byte on the subsequent page.
-    let mut prog = vec![0xc0, 0x01, 0xfe];
-    prog.extend([0; 256 + 251]);
-    prog.extend([0xc0, 0x55, 0xaa]);
+    let (prog, _) = asm1802! { lbr 0x01fe; org 0x01fe; lbr 0x55aa };
     assert_eq!(prog[0x1ff], 0x55);
 
     let mut sys = TestSystem::new_with_program(prog);
@@ -327,3 +337,408 @@ fn test_lsdf() {
     expect_lskp(0xcf, |sys| sys.cpu.df = true);
     expect_lcnt(0xcf, |sys| sys.cpu.df = false);
 }
+
+fn new_1805_with_program(data: impl Into<Vec<u8>>) -> TestSystem {
+    let mut sys = TestSystem::new_with_program(data).with_variant(Variant::Cdp1805);
+    sys.reset();
+    sys
+}
+
+#[test]
+fn test_resv68_on_1802() {
+    let mut sys = TestSystem::new_with_program([0x68, 0xc3, 0x12, 0x34]);
+    sys.reset();
+    sys.tick_n(16);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.rp(), 1);
+    assert_eq!(sys.cpu.r[3], 0);
+}
+
+#[test]
+fn test_rldi() {
+    let (prog, _) = asm1802! { rldi r3, 0x1234 };
+    let mut sys = new_1805_with_program(prog);
+    sys.tick_n(39);
+    assert_matches!(sys.cpu.state, State::Execute(23));
+    sys.tick();
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.rp(), 4);
+    assert_eq!(sys.cpu.r[3], 0x1234);
+}
+
+#[test]
+fn test_rlxa_rsxd_rnx() {
+    let (prog, _) = asm1802! { rldi r2, 0x0020; sex r2; rlxa r5; rsxd r5; rnx r5 };
+    let mut sys = new_1805_with_program(prog);
+    sys.mem.poke(0x20, 0xbe);
+    sys.mem.poke(0x21, 0xef);
+    sys.tick_n(40 + 16 + 40);
+    assert_eq!(sys.cpu.r[5], 0xbeef);
+    assert_eq!(sys.cpu.r[2], 0x0022);
+    sys.tick_n(40);
+    assert_eq!(sys.mem.as_slice()[0x21..0x23], [0xbe, 0xef]);
+    assert_eq!(sys.cpu.r[2], 0x0020);
+    sys.tick_n(32);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.r[2], 0xbeef);
+}
+
+#[test]
+fn test_scal_sret() {
+    let (prog, _) = asm1802! {
+        rldi r2, 0x00ff;
+        rldi r6, 0xabcd;
+        sex r2;
+        scal r6, sub;
+        idl;
+        org 0x20;
+        sub: sret r6
+    };
+    let mut sys = new_1805_with_program(prog);
+    sys.tick_n(40 + 40 + 16);
+
+    // Call
+    sys.tick_n(80);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.rp(), 0x0020);
+    assert_eq!(sys.cpu.r[6], 0x000d);
+    assert_eq!(sys.cpu.r[2], 0x00fd);
+    assert_eq!(sys.mem.as_slice()[0xfe..0x100], [0xab, 0xcd]);
+
+    // Return
+    sys.tick_n(64);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.rp(), 0x000d);
+    assert_eq!(sys.cpu.r[6], 0xabcd);
+    assert_eq!(sys.cpu.r[2], 0x00ff);
+}
+
+#[test]
+fn test_dbnz() {
+    let (prog, _) = asm1802! { start: dbnz r3, start };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.r[3] = 2;
+    sys.tick_n(40);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.r[3], 1);
+    assert_eq!(sys.cpu.rp(), 0);
+    sys.tick_n(40);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.r[3], 0);
+    assert_eq!(sys.cpu.rp(), 4);
+}
+
+#[test]
+fn test_decimal() {
+    let (prog, _) = asm1802! { ldi 0x45; dadi 0x55; daci 0x09; dsmi 0x11 };
+    let mut sys = new_1805_with_program(prog);
+    sys.tick_n(16 + 32);
+    assert_eq!((sys.cpu.d, sys.cpu.df), (0x00, true));
+    sys.tick_n(32);
+    assert_eq!((sys.cpu.d, sys.cpu.df), (0x10, false));
+    sys.tick_n(32);
+    assert_eq!((sys.cpu.d, sys.cpu.df), (0x99, false));
+}
+
+#[test]
+fn test_dsav() {
+    let (prog, _) = asm1802! { rldi r2, 0x0020; sex r2; ldi 0x81; dsav };
+    let mut sys = new_1805_with_program(prog);
+    sys.tick_n(40 + 16 + 16);
+    sys.cpu.t = 0x5a;
+    sys.cpu.df = true;
+    sys.tick_n(48);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.r[2], 0x001d);
+    assert_eq!(sys.mem.as_slice()[0x1d..0x20], [0xc0, 0x81, 0x5a]);
+    assert_eq!((sys.cpu.d, sys.cpu.df), (0xc0, true));
+}
+
+#[test]
+fn test_ldc_gec() {
+    let (prog, _) = asm1802! { ldi 7; ldc; dtc; ldi 0; gec };
+    let mut sys = new_1805_with_program(prog);
+    sys.tick_n(16 + 24 + 24 + 16 + 24);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.counter.ch, 7);
+    assert_eq!(sys.cpu.d, 6);
+}
+
+#[test]
+fn test_xid() {
+    let (prog, _) = asm1802! { xid; nop };
+    let mut sys = new_1805_with_program(prog);
+    sys.tick_n(24);
+    assert!(!sys.cpu.xie);
+    sys.pins.set_intr(false);
+    sys.tick_n(24);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert!(sys.cpu.ie);
+    assert_eq!(sys.cpu.rp(), 3);
+}
+
+/// Ticks the system until the predicate is satisfied, returning the number of ticks taken.
+fn tick_until(sys: &mut TestSystem, max: usize, pred: impl Fn(&TestSystem) -> bool) -> usize {
+    for n in 0..max {
+        if pred(sys) {
+            return n;
+        }
+        sys.tick();
+    }
+    panic!("condition not met after {max} ticks");
+}
+
+#[test]
+fn test_timer() {
+    let (prog, _) = asm1802! { ldi 2; ldc; stm; halt: br halt };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.ie = false;
+    sys.tick_n(16 + 24 + 24);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(sys.cpu.counter.mode, CounterMode::Timer);
+
+    // The counter decrements once every 32 machine cycles.
+    sys.tick_n(31 * 8);
+    assert_eq!(sys.cpu.counter.count, 2);
+    sys.tick_n(8);
+    assert_eq!(sys.cpu.counter.count, 1);
+    assert!(!sys.cpu.counter.ci);
+
+    // On underflow, the counter is reloaded and the interrupt flip-flop is set.
+    sys.tick_n(32 * 8);
+    assert_eq!(sys.cpu.counter.count, 2);
+    assert!(sys.cpu.counter.ci);
+    assert!(!sys.pins.get_q());
+}
+
+#[test]
+fn test_timer_etq() {
+    let (prog, _) = asm1802! { ldi 1; ldc; etq; stm; halt: br halt };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.ie = false;
+    sys.tick_n(16 + 24 + 24 + 24);
+    assert!(sys.cpu.counter.etq);
+    assert!(!sys.pins.get_q());
+    sys.tick_n(32 * 8);
+    assert!(sys.pins.get_q());
+    sys.tick_n(32 * 8);
+    assert!(!sys.pins.get_q());
+}
+
+#[test]
+fn test_dtc_etq() {
+    let (prog, _) = asm1802! { etq; dtc; dtc };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.ie = false;
+    sys.cpu.counter.ch = 5;
+    sys.cpu.counter.count = 2;
+    sys.tick_n(24 + 24);
+    assert_eq!(sys.cpu.counter.count, 1);
+    assert!(!sys.pins.get_q());
+    sys.tick_n(24);
+    assert_eq!(sys.cpu.counter.count, 5);
+    assert!(sys.cpu.counter.ci);
+    assert!(sys.pins.get_q());
+}
+
+#[test]
+fn test_event_counter() {
+    let (prog, _) = asm1802! { ldi 3; ldc; scm1; halt: br halt };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.ie = false;
+    sys.tick_n(16 + 24 + 24);
+    assert_eq!(sys.cpu.counter.mode, CounterMode::EventEf1);
+
+    // Time alone doesn't advance the counter.
+    sys.tick_n(64 * 8);
+    assert_eq!(sys.cpu.counter.count, 3);
+
+    // Each high-to-low transition of EF1 decrements the counter.
+    for expect in [2, 1, 3] {
+        sys.pins.set_ef1(false);
+        sys.tick_n(16);
+        assert_eq!(sys.cpu.counter.count, expect);
+        sys.pins.set_ef1(true);
+        sys.tick_n(16);
+        assert_eq!(sys.cpu.counter.count, expect);
+    }
+    assert!(sys.cpu.counter.ci);
+
+    // EF2 is ignored.
+    sys.pins.set_ef2(false);
+    sys.tick_n(16);
+    assert_eq!(sys.cpu.counter.count, 3);
+}
+
+#[test]
+fn test_pulse_duration() {
+    let (prog, _) = asm1802! { ldi 0; ldc; spm2; halt: br halt };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.ie = false;
+    sys.tick_n(16 + 24 + 24);
+    assert_eq!(sys.cpu.counter.mode, CounterMode::PulseEf2);
+
+    // The counter doesn't run until EF2 goes low.
+    sys.tick_n(64 * 8);
+    assert_eq!(sys.cpu.counter.count, 0);
+
+    // 3 prescaler periods of EF2 low.
+    sys.pins.set_ef2(false);
+    sys.tick_n(3 * 32 * 8);
+    assert_eq!(sys.cpu.counter.count, 0xfd);
+    assert!(!sys.cpu.counter.ci);
+
+    // Counting stops and the interrupt flip-flop is set when EF2 returns high.
+    sys.pins.set_ef2(true);
+    sys.tick_n(8);
+    assert!(sys.cpu.counter.is_stopped());
+    assert!(sys.cpu.counter.ci);
+    sys.pins.set_ef2(false);
+    sys.tick_n(64 * 8);
+    assert_eq!(sys.cpu.counter.count, 0xfd);
+}
+
+#[test]
+fn test_counter_interrupt() {
+    let (prog, _) = asm1802! { ldi 1; ldc; stm; halt: br halt };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.r[1] = 0x0010;
+    let n = tick_until(&mut sys, 64 * 8, |sys| {
+        matches!(sys.cpu.state, State::Interrupt(0))
+    });
+    assert!(n > 32 * 8);
+    assert!(sys.cpu.counter.ci);
+    sys.tick_n(8);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert!(!sys.cpu.ie);
+    assert_eq!((sys.cpu.x, sys.cpu.p), (2, 1));
+    assert_eq!(sys.cpu.rp(), 0x0010);
+}
+
+#[test]
+fn test_counter_interrupt_masked() {
+    let (prog, _) = asm1802! { cid; ldi 1; ldc; stm; halt: br halt };
+    let mut sys = new_1805_with_program(prog);
+    sys.tick_n(24 + 16 + 24 + 24 + 64 * 8);
+    assert!(sys.cpu.counter.ci);
+    assert!(sys.cpu.ie);
+    assert_eq!(sys.cpu.p, 0);
+
+    // External interrupts are still enabled.
+    sys.pins.set_intr(false);
+    tick_until(&mut sys, 32, |sys| {
+        matches!(sys.cpu.state, State::Interrupt(0))
+    });
+}
+
+#[test]
+fn test_bci() {
+    let (prog, labels) = asm1802! { bci taken; bci 0x20; taken: idl };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.ie = false;
+    sys.cpu.counter.ci = true;
+    sys.cpu.counter.etq = true;
+    sys.tick_n(24);
+    assert_matches!(sys.cpu.state, State::Fetch(0));
+    assert_eq!(labels, [("taken", 0x06)]);
+    assert_eq!(sys.cpu.rp(), 0x06);
+    assert!(!sys.cpu.counter.ci);
+    assert!(!sys.cpu.counter.etq);
+}
+
+#[test]
+fn test_idle_with_timer() {
+    let (prog, _) = asm1802! { ldi 1; ldc; stm; idl };
+    let mut sys = new_1805_with_program(prog);
+    sys.cpu.r[1] = 0x0010;
+    sys.tick_n(16 + 24 + 24 + 16);
+    assert_matches!(sys.cpu.state, State::Execute(_));
+    assert!(!sys.is_waiting());
+
+    // The counter interrupt wakes the processor.
+    tick_until(&mut sys, 32 * 8, |sys| {
+        matches!(sys.cpu.state, State::Interrupt(0))
+    });
+}
+
+fn assert_same_state(tick: &TestSystem, fast: &TestSystem, msg: &str) {
+    let (a, b) = (&tick.cpu, &fast.cpu);
+    assert_eq!(a.state.to_string(), b.state.to_string(), "{msg}: state");
+    assert_eq!((a.d, a.df), (b.d, b.df), "{msg}: d/df");
+    assert_eq!(a.r, b.r, "{msg}: r");
+    assert_eq!((a.p, a.x, a.t), (b.p, b.x, b.t), "{msg}: p/x/t");
+    assert_eq!(
+        (a.ie, a.xie, a.cie),
+        (b.ie, b.xie, b.cie),
+        "{msg}: ie/xie/cie"
+    );
+    assert_eq!(a.counter, b.counter, "{msg}: counter");
+    assert_eq!(tick.pins.get_q(), fast.pins.get_q(), "{msg}: q");
+}
+
+fn check_fast_core(variant: Variant, seed: u64) {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut program = vec![0; 0x10000];
+    rng.fill(&mut program[..]);
+    let mut tick = TestSystem::new_with_program(program).with_variant(variant);
+    tick.pins.set_ef(rng.random_range(0..16));
+    tick.reset();
+    tick.cpu.d = rng.random();
+    tick.cpu.df = rng.random();
+    tick.cpu.r = rng.random();
+    tick.cpu.x = rng.random_range(0..16);
+    tick.cpu.p = rng.random_range(0..16);
+    tick.cpu.t = rng.random();
+    let mut fast = TestSystem {
+        pins: tick.pins,
+        cpu: Cdp1802 {
+            out: tick.cpu.out,
+            ..Cdp1802::new(variant)
+        },
+        mem: tick.mem.clone(),
+        latch: AddressLatch::default(),
+        write_enable: true,
+    };
+    fast.cpu.state = tick.cpu.state;
+    fast.cpu.d = tick.cpu.d;
+    fast.cpu.df = tick.cpu.df;
+    fast.cpu.r = tick.cpu.r;
+    fast.cpu.x = tick.cpu.x;
+    fast.cpu.p = tick.cpu.p;
+    fast.cpu.t = tick.cpu.t;
+    fast.cpu.ie = tick.cpu.ie;
+
+    for step in 0..2000 {
+        assert!(fast.cpu.can_step(fast.pins));
+        let msg = format!(
+            "seed {seed} step {step} at {} {:?}",
+            fast.cpu,
+            fast.mem.get_instr_at(fast.cpu.rp(), variant)
+        );
+        let ticks = fast.cpu.step(&mut fast.pins, &mut fast.mem);
+        for _ in 0..ticks {
+            tick.tick();
+        }
+        assert_same_state(&tick, &fast, &msg);
+    }
+    assert!(
+        tick.mem.as_slice() == fast.mem.as_slice(),
+        "seed {seed}: memory"
+    );
+}
+
+#[test]
+fn test_fast_core_1802() {
+    for seed in 0..50 {
+        check_fast_core(Variant::Cdp1802, seed);
+    }
+}
+
+#[test]
+fn test_fast_core_1805() {
+    for seed in 0..50 {
+        check_fast_core(Variant::Cdp1805, seed);
+    }
+}
@@ -5,6 +5,7 @@
 #![allow(dead_code)]
 
 use flume::{Receiver, Sender, TryRecvError, TrySendError};
+use serde::{Deserialize, Serialize};
 
 #[cfg(test)]
 mod tests;
@@ -111,7 +112,7 @@ impl Pins {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Parity {
     Even,
     Odd,
@@ -128,7 +129,7 @@ pub enum Parity {
 /// - 5: IE: Interrupt enable
 /// - 6: TBRK: Transmit break
 /// - 7: TR: Transmit request
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Control {
     /// Word length in bits, on the range [5..8].
     word_length: u8,
@@ -354,7 +355,7 @@ impl From<u8> for Status {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Cdp1854 {
     /// Control register.
     control: Control,
@@ -370,7 +371,7 @@ pub struct Cdp1854 {
     prev_cts: bool,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct Interrupts {
     da: bool,
     tx_ready: bool,
@@ -384,7 +385,7 @@ impl Interrupts {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct Rx {
     /// Current state.
     state: RxState,
@@ -405,10 +406,11 @@ struct Rx {
     /// Framing error.
     fe: bool,
     /// Paravirt receiver.
+    #[serde(skip)]
     pv_rx: Option<Receiver<u8>>,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 enum RxState {
     /// Waiting for SDI to go high.
     #[default]
@@ -427,7 +429,7 @@ enum RxState {
     Stop,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Tx {
     /// Current state.
     state: TxState,
@@ -448,10 +450,11 @@ struct Tx {
     /// Serial line output.
     sdo: bool,
     /// Paravirt sender.
+    #[serde(skip)]
     pv_tx: Option<Sender<u8>>,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 enum TxState {
     /// Waiting for !thre and cts.
     #[default]
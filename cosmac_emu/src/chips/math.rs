@@ -20,9 +20,39 @@ pub fn subb(x: u8, y: u8, b: bool) -> (u8, bool) {
     (acc, b1 || b2)
 }
 
+// decimal (BCD) add with carry
+pub fn daddc(x: u8, y: u8, c: bool) -> (u8, bool) {
+    let mut lo = (x & 0xf) + (y & 0xf) + u8::from(c);
+    let mut hi = (x >> 4) + (y >> 4);
+    if lo > 9 {
+        lo -= 10;
+        hi += 1;
+    }
+    let carry = hi > 9;
+    if carry {
+        hi -= 10;
+    }
+    (((hi & 0xf) << 4) | (lo & 0xf), carry)
+}
+
+// decimal (BCD) subtract with borrow
+pub fn dsubb(x: u8, y: u8, b: bool) -> (u8, bool) {
+    let mut lo = (x & 0xf) as i8 - (y & 0xf) as i8 - i8::from(b);
+    let mut hi = (x >> 4) as i8 - (y >> 4) as i8;
+    if lo < 0 {
+        lo += 10;
+        hi -= 1;
+    }
+    let borrow = hi < 0;
+    if borrow {
+        hi += 10;
+    }
+    ((((hi as u8) & 0xf) << 4) | ((lo as u8) & 0xf), borrow)
+}
+
 #[cfg(test)]
 mod test {
-    use super::{add, addc, sub, subb};
+    use super::{add, addc, daddc, dsubb, sub, subb};
 
     #[test]
     fn test_add() {
@@ -65,4 +95,25 @@ mod test {
         assert_eq!(subb(0, 1, false), (0xff, true));
         assert_eq!(subb(0, 1, true), (0xfe, true));
     }
+
+    #[test]
+    fn test_daddc() {
+        assert_eq!(daddc(0x00, 0x00, false), (0x00, false));
+        assert_eq!(daddc(0x05, 0x05, false), (0x10, false));
+        assert_eq!(daddc(0x19, 0x01, false), (0x20, false));
+        assert_eq!(daddc(0x45, 0x54, true), (0x00, true));
+        assert_eq!(daddc(0x99, 0x99, false), (0x98, true));
+        assert_eq!(daddc(0x99, 0x99, true), (0x99, true));
+        assert_eq!(daddc(0x12, 0x34, true), (0x47, false));
+    }
+
+    #[test]
+    fn test_dsubb() {
+        assert_eq!(dsubb(0x00, 0x00, false), (0x00, false));
+        assert_eq!(dsubb(0x10, 0x01, false), (0x09, false));
+        assert_eq!(dsubb(0x10, 0x01, true), (0x08, false));
+        assert_eq!(dsubb(0x00, 0x01, false), (0x99, true));
+        assert_eq!(dsubb(0x42, 0x58, false), (0x84, true));
+        assert_eq!(dsubb(0x58, 0x42, true), (0x15, false));
+    }
 }
@@ -10,17 +10,24 @@ use byte_unit::Byte;
 use clap::{Parser, Subcommand};
 use color_eyre::{
     Result,
-    eyre::{self, OptionExt},
+    eyre::{self, OptionExt, WrapErr},
 };
 use regex::Regex;
 
-use crate::chips::cdp1802::MemoryRange;
+use crate::bus::{Device, DeviceBus, OverlayTrigger, Unmapped};
+use crate::chips::cdp1802::{Core, Memory, MemoryRange, Variant};
+use crate::fault::FaultPolicy;
+use crate::image::{Image, ImageFormat};
+use crate::source_map::SourceMap;
+use crate::symbols::Symbols;
 
+mod asm;
 mod dbg;
 mod dis;
 mod run;
 mod tui;
 
+use asm::AsmArgs;
 use dbg::DbgArgs;
 use dis::DisArgs;
 use run::RunArgs;
@@ -39,7 +46,8 @@ impl Cli {
 
 #[derive(Subcommand)]
 pub enum Command {
-    //Asm(AsmArgs),
+    /// Assembler
+    Asm(AsmArgs),
     /// Debugger
     Dbg(DbgArgs),
     /// Disassembler
@@ -52,7 +60,7 @@ pub enum Command {
 impl Command {
     pub fn run(self) -> Result<()> {
         match self {
-            //Command::Asm(_) => todo!(),
+            Command::Asm(args) => asm::run(args),
             Command::Dbg(args) => dbg::run(args),
             Command::Dis(args) => dis::run(args),
             Command::Run(args) => run::run(args),
@@ -67,33 +75,171 @@ struct CommonRunArgs {
     ///
     /// By default the image is loaded at base address 0x0000. To load an image at an alternative
     /// base address, specify the image as `<path>@0x8000`. RAM images are loaded in the order they
-    /// are provided on the command line. RAM images are loaded before ROM images.
+    /// are provided on the command line.
+    ///
+    /// Intel HEX and Motorola S-record images are detected by extension or content, and are
+    /// loaded at the addresses embedded in the file, ignoring the base address.
     #[arg(long, value_parser=parse_ram_image)]
     pub ram: Vec<ImageArg>,
 
     /// ROM image file to load.
     ///
     /// By default the image is loaded at base address 0x8000. To load an image at an alternative
-    /// base address, specify the image as `<path>@0x0000`. ROM images are loaded in the order they
-    /// are provided on the command line. ROM images are loaded after RAM images.
+    /// base address, specify the image as `<path>@0x0000`. Intel HEX and Motorola S-record images
+    /// are loaded at the addresses embedded in the file.
     ///
-    /// ROM images are automatically write-protected, as if the user had given the --write-protect
-    /// option.
+    /// Each ROM image segment is mapped as a read-only device spanning the size of the segment.
+    /// ROM images take precedence over RAM, and later ROM images take precedence over earlier
+    /// ones.
     #[arg(long, value_parser=parse_rom_image)]
     pub rom: Vec<ImageArg>,
 
-    /// Write protect region. May be provided multiple times.
+    /// ROM image to overlay at reset.
+    ///
+    /// By default the image is mapped at base address 0x0000. The overlay takes precedence over
+    /// RAM and ROM from reset until the trigger condition is met, after which it disappears and
+    /// leaves the underlying memory untouched. It reappears on the next reset.
+    #[arg(long, value_parser=parse_ram_image)]
+    pub reset_overlay: Option<ImageArg>,
+
+    /// The address to start execution at, by setting R0 after reset.
+    ///
+    /// Defaults to the start address of the last RAM or ROM image that specifies one, i.e. an
+    /// Intel HEX or S-record image, and otherwise 0x0000.
+    #[arg(long, value_parser=parse_addr)]
+    pub start: Option<u16>,
+
+    /// Condition that removes the reset overlay.
+    ///
+    /// One of `access:<addr>` (the first memory access at or above the address), `out:<n>` (an
+    /// OUT instruction to port N), or `q` (Q is set).
+    #[arg(long, value_parser=parse_overlay_trigger, default_value="access:0x8000")]
+    pub reset_overlay_trigger: OverlayTrigger,
+
+    /// Write protect RAM region. May be provided multiple times.
     #[arg(short, long, value_parser=parse_memory_range)]
     pub write_protect: Vec<MemoryRange>,
 
-    /// The size of addressable memory attached to the 1802. Must be a power of 2, and cannot
-    /// exceed 64KiB.
+    /// The size of RAM attached to the 1802, mapped from address 0x0000. Must be a power of 2, and
+    /// cannot exceed 64KiB.
     #[arg(short, long, value_parser=parse_memory_size, default_value="64KiB")]
     pub memory_size: usize,
 
+    /// Mirror RAM throughout the address space, as if the address lines above its capacity were
+    /// not decoded. ROM images take precedence over mirrored RAM.
+    #[arg(long)]
+    pub mirror_ram: bool,
+
+    /// Value read from addresses with no RAM or ROM mapped.
+    ///
+    /// With "ff", the data bus is pulled up. With "float", reads return the last value driven
+    /// onto the data bus.
+    #[arg(long, default_value = "ff")]
+    pub unmapped: Unmapped,
+
+    /// What to do about writes to ROM or write-protected RAM.
+    ///
+    /// Defaults to "count" for run and dbg, which summarize faults on exit, and "log" for tui. In
+    /// the debugger, halt behaves like break. In the TUI, halt and break both engage the wait
+    /// switch.
+    #[arg(long)]
+    pub write_fault: Option<FaultPolicy>,
+
     /// Clock frequency
     #[arg(long, default_value = "4MHz", value_parser=parse_hz)]
     pub clock_freq: u32,
+
+    /// CPU variant.
+    #[arg(long, default_value = "1802")]
+    pub cpu: Variant,
+
+    /// Execution core.
+    ///
+    /// The tick core emulates every clock cycle, and is the most accurate. The fast core executes
+    /// an instruction at a time, and only samples inputs at instruction boundaries.
+    #[arg(long, default_value = "tick")]
+    pub core: Core,
+
+    /// Snapshot file to restore before running.
+    ///
+    /// The snapshot replaces the complete machine state, including the CPU variant and memory
+    /// contents, so images and memory options are effectively ignored.
+    #[arg(long)]
+    pub load_snapshot: Option<PathBuf>,
+
+    /// Snapshot file to save on exit.
+    #[arg(long)]
+    pub save_snapshot_on_exit: Option<PathBuf>,
+
+    /// Symbol file to load. May be provided multiple times.
+    ///
+    /// Either an assembler listing with a symbol table (A18, asl, or this project's `asm`), or
+    /// `name = addr` definitions, one per line. Symbols are shown in listings, and may be used
+    /// wherever the debugger expects an address.
+    #[arg(long)]
+    pub symbols: Vec<PathBuf>,
+
+    /// Assembler listing to load for source-level debugging. May be provided multiple times.
+    ///
+    /// A18, asl, and this project's `asm` listings are supported. The source line for the next
+    /// instruction is shown in the debugger and the TUI listing.
+    #[arg(long)]
+    pub listing: Vec<PathBuf>,
+}
+
+impl CommonRunArgs {
+    /// Maps RAM, ROM images, and the reset overlay onto a device bus.
+    fn build_bus(&self, ram: Memory) -> Result<DeviceBus> {
+        let bus = DeviceBus::default().with_unmapped(self.unmapped);
+        let bus = if self.mirror_ram {
+            bus.with_mirrored_ram(ram)
+        } else {
+            bus.with_ram(ram)
+        };
+        let bus = bus.with_rom_images(&self.rom)?;
+        match &self.reset_overlay {
+            Some(image) => bus.with_reset_overlay(image, self.reset_overlay_trigger),
+            None => Ok(bus),
+        }
+    }
+
+    /// Returns the address to start execution at.
+    fn start_addr(&self) -> Result<u16> {
+        if let Some(start) = self.start {
+            return Ok(start);
+        }
+        let mut start = 0;
+        for image in self.ram.iter().chain(&self.rom) {
+            if let Some(addr) = image.load()?.start {
+                start = addr;
+            }
+        }
+        Ok(start)
+    }
+}
+
+/// Loads symbol files.
+fn load_symbols(paths: &[PathBuf]) -> Result<Symbols> {
+    let mut symbols = Symbols::default();
+    for path in paths {
+        std::fs::read_to_string(path)
+            .map_err(eyre::Report::from)
+            .and_then(|text| Ok(symbols.extend(&text)?))
+            .wrap_err_with(|| format!("failed to load symbols from {}", path.display()))?;
+    }
+    Ok(symbols)
+}
+
+/// Loads assembler listings into a source map.
+fn load_listings(paths: &[PathBuf]) -> Result<SourceMap> {
+    let mut source = SourceMap::default();
+    for path in paths {
+        std::fs::read_to_string(path)
+            .map_err(eyre::Report::from)
+            .and_then(|text| Ok(source.extend(&text)?))
+            .wrap_err_with(|| format!("failed to load listing {}", path.display()))?;
+    }
+    Ok(source)
 }
 
 fn parse_addr(s: &str) -> Result<u16> {
@@ -104,7 +250,19 @@ fn parse_addr(s: &str) -> Result<u16> {
     }
 }
 
-fn parse_memory_range(s: &str) -> Result<MemoryRange> {
+fn parse_overlay_trigger(s: &str) -> Result<OverlayTrigger> {
+    match s.to_lowercase().split_once(':') {
+        None if s.eq_ignore_ascii_case("q") => Ok(OverlayTrigger::Q),
+        Some(("access", addr)) => Ok(OverlayTrigger::Access(parse_addr(addr)?)),
+        Some(("out", n)) => match n.parse()? {
+            n @ 1..=7 => Ok(OverlayTrigger::Output(n)),
+            _ => eyre::bail!("port must be between 1 and 7"),
+        },
+        _ => eyre::bail!("must be one of access:<addr>, out:<n>, or q"),
+    }
+}
+
+pub fn parse_memory_range(s: &str) -> Result<MemoryRange> {
     static REGEX: LazyLock<Regex> = LazyLock::new(|| {
         Regex::new(
             r"(?x)
@@ -159,6 +317,45 @@ pub struct ImageArg {
     /// Whether the imgae should be write-protected.
     pub write_protect: bool,
 }
+impl ImageArg {
+    /// Reads and parses the image, detecting its format.
+    pub fn load(&self) -> Result<Image> {
+        let load = || -> Result<Image> {
+            let data = std::fs::read(&self.path)?;
+            let format = ImageFormat::detect(&self.path, &data);
+            Ok(Image::parse(format, self.base_addr, data)?)
+        };
+        load().wrap_err_with(|| format!("failed to load image {}", self.path.display()))
+    }
+}
+
+/// A memory range to be written to a file.
+#[derive(Debug, Clone)]
+pub struct MemoryDump {
+    pub range: MemoryRange,
+    pub path: PathBuf,
+}
+impl MemoryDump {
+    /// Exports the memory range, in the format implied by the file extension.
+    pub fn save(&self, device: &impl Device) -> Result<()> {
+        let format = ImageFormat::from_path(&self.path).unwrap_or(ImageFormat::Bin);
+        let range = self.range.into_range_inclusive(u16::MAX);
+        std::fs::File::create(&self.path)
+            .and_then(|file| device.export(range, format, std::io::BufWriter::new(file)))
+            .wrap_err_with(|| format!("failed to write {}", self.path.display()))
+    }
+}
+
+/// Parses a memory dump from a string of the form `RANGE:PATH`.
+fn parse_memory_dump(s: &str) -> Result<MemoryDump> {
+    let (range, path) = s
+        .split_once(':')
+        .ok_or_eyre("must be of the form RANGE:PATH")?;
+    Ok(MemoryDump {
+        range: parse_memory_range(range)?,
+        path: PathBuf::from(path),
+    })
+}
 
 /// Parses a byte size from a string with an optional unit specification.
 fn parse_memory_size(arg: &str) -> Result<usize> {
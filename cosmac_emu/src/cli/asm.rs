@@ -0,0 +1,75 @@
+// 1802 assembler
+
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use clap::Parser;
+use color_eyre::{
+    Result,
+    eyre::{self, WrapErr},
+};
+
+use crate::{asm::Assembler, chips::cdp1802::Variant, image::ImageFormat};
+
+#[derive(Parser, Debug)]
+pub struct AsmArgs {
+    /// Source file to assemble.
+    #[arg()]
+    file: PathBuf,
+
+    /// Output file. Defaults to the source file with the extension replaced by that of the
+    /// output format.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output format. Defaults to the format implied by the output file extension, or raw
+    /// binary.
+    ///
+    /// Raw binaries start at the lowest assembled address, which is the address to load them
+    /// at, and gaps left by `ds` or `org` are filled with zeros.
+    #[arg(long)]
+    format: Option<ImageFormat>,
+
+    /// Listing file to write, with addresses, encoded bytes, and a symbol table.
+    #[arg(short, long)]
+    listing: Option<PathBuf>,
+
+    /// CPU variant. Extended instructions are only accepted for the CDP1804/1805/1806.
+    #[arg(long, default_value = "1802")]
+    cpu: Variant,
+}
+
+pub fn run(args: AsmArgs) -> Result<()> {
+    let text = std::fs::read_to_string(&args.file)
+        .wrap_err_with(|| format!("failed to read {}", args.file.display()))?;
+    let assembly = match Assembler::new(args.cpu).assemble(&args.file, &text) {
+        Ok(assembly) => assembly,
+        Err(errors) => {
+            for err in &errors {
+                eprintln!("{err}");
+            }
+            eyre::bail!("{} error(s)", errors.len());
+        }
+    };
+
+    let format = args
+        .format
+        .or_else(|| args.output.as_deref().and_then(ImageFormat::from_path))
+        .unwrap_or(ImageFormat::Bin);
+    let output = args.output.unwrap_or_else(|| {
+        args.file.with_extension(match format {
+            ImageFormat::Bin => "bin",
+            ImageFormat::Ihex => "hex",
+            ImageFormat::Srec => "srec",
+        })
+    });
+    File::create(&output)
+        .and_then(|file| assembly.image.write(format, BufWriter::new(file)))
+        .wrap_err_with(|| format!("failed to write {}", output.display()))?;
+
+    if let Some(path) = &args.listing {
+        File::create(path)
+            .and_then(|file| assembly.write_listing(BufWriter::new(file)))
+            .wrap_err_with(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
@@ -6,10 +6,12 @@ use crate::{
     chips::cdp1802::{Cdp1802, Memory},
     debugger,
     event::InputEventLog,
-    systems::basic::BasicSystem,
+    fault::FaultPolicy,
+    snapshot::Snapshot,
+    systems::basic::{BasicSystem, UninitPolicy},
 };
 
-use super::CommonRunArgs;
+use super::{CommonRunArgs, load_listings, load_symbols};
 
 #[derive(Parser, Debug)]
 pub struct DbgArgs {
@@ -23,23 +25,47 @@ pub struct DbgArgs {
     /// An output event log to write on exit.
     #[arg(long)]
     pub output_events: Option<PathBuf>,
+
+    /// What to do about reads of RAM that has never been written, and wasn't loaded from an
+    /// image. Such reads see whatever noise RAM powered up with.
+    #[arg(long, default_value = "ignore")]
+    pub uninit_reads: UninitPolicy,
+
+    /// The number of instructions to record for reverse execution, or 0 to disable.
+    #[arg(long, default_value_t = 100000)]
+    pub history: usize,
 }
 
 pub fn run(args: DbgArgs) -> color_eyre::Result<()> {
-    let memory = Memory::builder()
+    let ram = Memory::builder()
         .with_capacity(args.common.memory_size)?
         .with_image_args(&args.common.ram)?
-        .with_image_args(&args.common.rom)?
         .with_write_protect_ranges(&args.common.write_protect)
         .with_random()
         .build()?;
-    let cdp1802 = Cdp1802::default();
+    let bus = args.common.build_bus(ram)?;
+    let cdp1802 = Cdp1802::new(args.common.cpu);
     let cycle_time = Duration::from_secs(1) / args.common.clock_freq;
-    let mut system = BasicSystem::new(cdp1802, memory, cycle_time);
+    let fault_policy = args.common.write_fault.unwrap_or(FaultPolicy::Count);
+    let mut system = BasicSystem::new(cdp1802, bus, cycle_time)
+        .with_core(args.common.core)
+        .with_start(args.common.start_addr()?)
+        .with_fault_policy(fault_policy)
+        .with_uninit_policy(args.uninit_reads)
+        .with_history(args.history)
+        .with_symbols(load_symbols(&args.common.symbols)?)
+        .with_source(load_listings(&args.common.listing)?);
     if let Some(path) = args.input_events {
         let events = InputEventLog::from_file(path)?;
         system = system.with_events(events);
     }
+    if let Some(path) = &args.common.load_snapshot {
+        system.load_snapshot(path)?;
+    }
     debugger::run(&mut system);
+    system.faults().print_summary();
+    if let Some(path) = &args.common.save_snapshot_on_exit {
+        system.save_snapshot(path)?;
+    }
     Ok(())
 }
@@ -3,13 +3,13 @@
 use std::{fs::File, io::Read, path::PathBuf};
 
 use clap::Parser;
-use color_eyre::{eyre, Result};
-use itertools::Itertools;
+use color_eyre::{Result, eyre};
 
 use crate::{
-    chips::cdp1802::MemoryRange,
-    cli::{parse_addr, parse_memory_range},
-    instr::{Instr, InstrSchema},
+    chips::cdp1802::{MemoryRange, Variant},
+    cli::{load_symbols, parse_addr, parse_memory_range},
+    disasm::{Disassembly, Mode},
+    symbols::Addr,
 };
 
 #[derive(Parser, Debug)]
@@ -25,6 +25,37 @@ pub struct DisArgs {
     /// Memory range.
     #[arg(long, value_parser=parse_memory_range)]
     range: Option<MemoryRange>,
+
+    /// CPU variant.
+    #[arg(long, default_value = "1802")]
+    cpu: Variant,
+
+    /// How code is found.
+    #[arg(long, default_value = "sweep")]
+    mode: Mode,
+
+    /// Output format.
+    #[arg(long, default_value = "listing")]
+    format: Format,
+
+    /// Entry point for tracing, as an address or symbol. May be provided multiple times. The
+    /// reset vector, 0x0000, is always an entry point if it's in range.
+    #[arg(long)]
+    entry: Vec<Addr>,
+
+    /// Symbol file to load. May be provided multiple times. Symbols name the lines and branch
+    /// targets at their addresses.
+    #[arg(long)]
+    symbols: Vec<PathBuf>,
+}
+
+/// The output format.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Format {
+    /// Addresses and bytes alongside the disassembly.
+    Listing,
+    /// Assembler source, which reassembles to the same bytes.
+    Source,
 }
 
 pub fn run(args: DisArgs) -> Result<()> {
@@ -65,25 +96,29 @@ pub fn run(args: DisArgs) -> Result<()> {
     assert!(base <= start);
     assert!(start <= end);
 
-    let mut offset = start - base;
-    let limit = end - base;
-    while offset < limit {
-        let instr_limit = (offset + 2).min(limit);
-        let instr = Instr::decode(&data[offset..=instr_limit]);
-        let (bytes, disasm, size) = match instr {
-            Some(instr) => (
-                instr
-                    .encode()
-                    .into_iter()
-                    .map(|v| format!("{v:02x}"))
-                    .join(" "),
-                instr.disasm(),
-                instr.size(),
-            ),
-            None => (format!("{:02x}", data[offset]), "??".to_string(), 1),
-        };
-        println!("{:04x} {bytes:<8} {disasm}", offset + base);
-        offset += size as usize;
+    let symbols = load_symbols(&args.symbols)?;
+    let range = start as u16..=end as u16;
+    let mut disasm = match args.mode {
+        Mode::Sweep => Disassembly::sweep(&data, args.base, range, args.cpu),
+        Mode::Trace => {
+            let mut entries = args
+                .entry
+                .iter()
+                .map(|addr| addr.resolve(&symbols))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.insert(0, 0);
+            entries.retain(|addr| range.contains(addr));
+            if entries.is_empty() {
+                eyre::bail!("no entry points in range, use --entry");
+            }
+            Disassembly::trace(&data, args.base, range, args.cpu, &entries)
+        }
+    };
+    disasm.apply_symbols(&symbols);
+    let stdout = std::io::stdout().lock();
+    match args.format {
+        Format::Listing => disasm.write_listing(stdout)?,
+        Format::Source => disasm.write_source(stdout)?,
     }
 
     Ok(())
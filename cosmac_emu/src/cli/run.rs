@@ -1,14 +1,19 @@
 use std::{path::PathBuf, time::Duration};
 
 use clap::Parser;
+use color_eyre::eyre;
 
 use crate::{
     chips::cdp1802::{Cdp1802, Memory},
     event::InputEventLog,
-    systems::basic::{BasicSystem, Status},
+    fault::FaultPolicy,
+    snapshot::Snapshot,
+    systems::basic::{BasicSystem, Status, UninitPolicy},
 };
 
-use super::{CommonRunArgs, parse_duration};
+use super::{
+    CommonRunArgs, MemoryDump, load_listings, load_symbols, parse_duration, parse_memory_dump,
+};
 
 #[derive(Parser, Debug)]
 pub struct RunArgs {
@@ -26,30 +31,73 @@ pub struct RunArgs {
     /// Runs until the specified duration, as measured from the controller's clock, then exits.
     #[arg(long, value_parser=parse_duration)]
     pub duration: Option<Duration>,
+
+    /// Memory to write to a file on exit, as `RANGE:PATH` (e.g. `0x0000..0x7fff:basic.hex`). May
+    /// be provided multiple times.
+    ///
+    /// The file is written as Intel HEX (.hex), Motorola S-record (.srec, .s19), or otherwise raw
+    /// binary, depending on its extension.
+    #[arg(long, value_parser=parse_memory_dump)]
+    pub dump_memory: Vec<MemoryDump>,
+
+    /// What to do about reads of RAM that has never been written, and wasn't loaded from an
+    /// image. Such reads see whatever noise RAM powered up with.
+    #[arg(long, default_value = "ignore")]
+    pub uninit_reads: UninitPolicy,
 }
 
 pub fn run(args: RunArgs) -> color_eyre::Result<()> {
-    let memory = Memory::builder()
+    let ram = Memory::builder()
         .with_capacity(args.common.memory_size)?
         .with_image_args(&args.common.ram)?
-        .with_image_args(&args.common.rom)?
         .with_write_protect_ranges(&args.common.write_protect)
         .with_random()
         .build()?;
-    let cdp1802 = Cdp1802::default();
+    let bus = args.common.build_bus(ram)?;
+    let cdp1802 = Cdp1802::new(args.common.cpu);
     let cycle_time = Duration::from_secs(1) / args.common.clock_freq;
-    let mut system = BasicSystem::new(cdp1802, memory, cycle_time);
+    let fault_policy = args.common.write_fault.unwrap_or(FaultPolicy::Count);
+    let mut system = BasicSystem::new(cdp1802, bus, cycle_time)
+        .with_core(args.common.core)
+        .with_start(args.common.start_addr()?)
+        .with_fault_policy(fault_policy)
+        .with_uninit_policy(args.uninit_reads)
+        .with_symbols(load_symbols(&args.common.symbols)?)
+        .with_source(load_listings(&args.common.listing)?);
     if let Some(path) = args.input_events {
         let events = InputEventLog::from_file(path)?;
         system = system.with_events(events);
     }
+    if let Some(path) = &args.common.load_snapshot {
+        system.load_snapshot(path)?;
+    }
+    let mut halted = None;
     while args.duration.is_none_or(|d| system.now() < d) {
-        if let Status::Idle = system.step() {
-            break;
+        match system.step() {
+            Status::Idle => break,
+            Status::Fault(fault) => {
+                halted = Some(fault.to_string());
+                break;
+            }
+            Status::Uninit(read) => {
+                halted = Some(format!("{read}: {}", system.listing_at(read.pc)));
+                break;
+            }
+            _ => (),
         }
     }
     if let Some(path) = args.output_events {
         system.write_output_events(&path)?;
     }
+    for dump in &args.dump_memory {
+        dump.save(system.bus())?;
+    }
+    if let Some(path) = &args.common.save_snapshot_on_exit {
+        system.save_snapshot(path)?;
+    }
+    system.faults().print_summary();
+    if let Some(reason) = halted {
+        eyre::bail!("halted on {reason}");
+    }
     Ok(())
 }
@@ -1,12 +1,15 @@
 use clap::Parser;
 use color_eyre::{Result, eyre};
 
+use crate::bus::{OverlayTrigger, ResetOverlay};
 use crate::chips::ay51013::Ay51013Uart;
+use crate::fault::FaultPolicy;
+use crate::snapshot::Snapshot;
 use crate::tui::mc::MembershipCardTui;
 use crate::uart::UartMode;
-use crate::{chips::cdp1802::Memory, cli::parse_addr, systems::mc::MembershipCard};
+use crate::{chips::cdp1802::Memory, systems::mc::MembershipCard};
 
-use super::CommonRunArgs;
+use super::{CommonRunArgs, load_listings, load_symbols, parse_addr};
 
 #[derive(Parser, Debug)]
 pub struct TuiArgs {
@@ -31,8 +34,9 @@ pub struct TuiArgs {
     #[arg(long)]
     uart_force_7bit_ascii: bool,
 
-    /// Address to jump to during reset.
-    #[arg(long, value_parser=parse_addr)]
+    /// Deprecated alias for a reset overlay holding a long branch to the address, which is
+    /// removed by the first access at or above the address. Use --reset-overlay instead.
+    #[arg(long, hide = true, value_parser=parse_addr, conflicts_with = "reset_overlay")]
     jump_to: Option<u16>,
 
     /// Whether to invert EF.
@@ -53,30 +57,48 @@ fn parse_speed(s: &str) -> Result<f64> {
 }
 
 pub fn run(args: TuiArgs) -> Result<()> {
-    let mut builder = Memory::builder()
+    let ram = Memory::builder()
         .with_capacity(args.common.memory_size)?
         .with_image_args(&args.common.ram)?
-        .with_image_args(&args.common.rom)?
-        .with_write_protect_ranges(&args.common.write_protect);
+        .with_write_protect_ranges(&args.common.write_protect)
+        .build()?;
+    let mut bus = args.common.build_bus(ram)?;
     if let Some(addr) = args.jump_to {
-        let hi = ((addr & 0xff00) >> 8) as u8;
-        let lo = (addr & 0xff) as u8;
-        builder = builder.with_image(0, [0xc0, hi, lo]);
+        eprintln!("warning: --jump-to is deprecated, use --reset-overlay");
+        if addr < 3 {
+            eyre::bail!("--jump-to address must be past the long branch at 0x0000");
+        }
+        let [hi, lo] = addr.to_be_bytes();
+        let overlay = ResetOverlay::new([0xc0, hi, lo], OverlayTrigger::Access(addr));
+        bus = bus.with_device(0..=2, overlay);
     }
-    let memory = builder.build()?;
     let uart = Ay51013Uart::builder()
         .with_baud(args.uart_baud, args.common.clock_freq)
         .with_mode(args.uart_mode)
         .with_force_7bit_ascii(args.uart_force_7bit_ascii)
         .build();
-    let mc = MembershipCard::builder()
+    let fault_policy = args.common.write_fault.unwrap_or(FaultPolicy::Log);
+    let mut mc = MembershipCard::builder()
         .with_clock_freq(args.common.clock_freq)
+        .with_core(args.common.core)
+        .with_cpu(args.common.cpu)
+        .with_start(args.common.start_addr()?)
         .with_invert_ef(args.invert_ef)
         .with_invert_q(args.invert_q)
-        .with_memory(memory)
+        .with_bus(bus)
         .with_speed(args.speed)
         .with_uart(uart.into_box())
+        .with_fault_policy(fault_policy)
         .build();
-    let tui = MembershipCardTui::new(mc);
-    tui.run()
+    if let Some(path) = &args.common.load_snapshot {
+        mc.load_snapshot(path)?;
+    }
+    let mut tui = MembershipCardTui::new(mc)
+        .with_symbols(load_symbols(&args.common.symbols)?)
+        .with_source(load_listings(&args.common.listing)?);
+    tui.run()?;
+    if let Some(path) = &args.common.save_snapshot_on_exit {
+        tui.mc().save_snapshot(path)?;
+    }
+    Ok(())
 }
@@ -1,4 +1,7 @@
 use std::fmt::Display;
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::mpsc;
@@ -6,14 +9,18 @@ use std::time::Duration;
 
 use clap::Parser;
 use color_eyre::{Result, eyre};
-use itertools::Itertools;
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
 
+use crate::bus::Device;
+use crate::chips::cdp1802::MemoryRange;
 use crate::cli::parse_duration;
 use crate::event::{InputEvent, InputKind};
+use crate::expr::{Expr, ExprError};
+use crate::image::ImageFormat;
 use crate::instr::InstrSchema;
-use crate::systems::basic::{BasicSystem, Status};
+use crate::symbols::{Addr, SymbolError, Symbols};
+use crate::systems::basic::{BasicSystem, Breakpoint, Register, Status, WatchKind, Watchpoint};
 
 #[derive(Debug, Clone, Parser)]
 enum Command {
@@ -25,13 +32,16 @@ enum Command {
     List {
         #[arg(default_value = "10")]
         count: u16,
-        #[arg(value_parser=parse_hex_u16)]
-        addr: Option<u16>,
+        addr: Option<Addr>,
     },
     #[command(alias = "s")]
     Step {
         #[arg(default_value = "1")]
         count: u16,
+
+        /// Step by source line, rather than by instruction. Requires a listing.
+        #[arg(short, long)]
+        line: bool,
     },
     #[command(alias = "t")]
     Tick {
@@ -40,22 +50,85 @@ enum Command {
     },
     #[command(alias = "c")]
     Continue,
+    /// Undoes instructions, using the execution history.
+    #[command(alias = "rs")]
+    ReverseStep {
+        #[arg(default_value = "1")]
+        count: u16,
+    },
+    /// Undoes instructions until a breakpoint or watchpoint, or the start of history.
+    #[command(alias = "rc")]
+    ReverseContinue,
+    /// Rewinds or runs to a clock cycle, in hex. Input events are replayed when running forwards.
+    Goto {
+        #[arg(value_parser=parse_hex_u64)]
+        cycle: u64,
+    },
     #[command(alias = "bl")]
     BreakpointList,
+    /// Sets a breakpoint, replacing any at the same address.
     #[command(alias = "b")]
     BreakpointSet {
-        #[arg(value_parser=parse_hex_u16)]
-        addr: u16,
+        addr: Addr,
+
+        /// Only stop when the expression is true, e.g. `if r[7] == 0x1234 && df`.
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        condition: Vec<String>,
+    },
+    /// Sets a breakpoint that's deleted when it stops execution.
+    #[command(alias = "tb")]
+    Tbreak {
+        addr: Addr,
+
+        /// Only stop when the expression is true, e.g. `if r[7] == 0x1234 && df`.
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        condition: Vec<String>,
     },
     #[command(alias = "bc")]
-    BreakpointClear {
-        #[arg(value_parser=parse_hex_u16)]
-        addr: u16,
+    BreakpointClear { addr: Addr },
+    /// Ignores the next hits of a breakpoint.
+    Ignore { addr: Addr, count: u32 },
+    /// Enables a breakpoint, or all breakpoints.
+    Enable { addr: Option<Addr> },
+    /// Disables a breakpoint, or all breakpoints.
+    Disable { addr: Option<Addr> },
+    /// Sets a write (or exec) watchpoint, or lists watchpoints.
+    Watch {
+        /// The address or range, e.g. 0x80, 0x80..0x8f or BUF..BUF_END.
+        range: Option<AddrRange>,
+
+        /// Only stop when this value is written (or executed).
+        #[arg(value_parser=parse_hex_u8)]
+        value: Option<u8>,
+
+        /// Watch for instructions executed in the range, rather than writes.
+        #[arg(long)]
+        exec: bool,
     },
+    /// Sets a read watchpoint.
+    Rwatch {
+        /// The address or range, e.g. 0x80, 0x80..0x8f or BUF..BUF_END.
+        range: AddrRange,
+
+        /// Only stop when this value is read.
+        #[arg(value_parser=parse_hex_u8)]
+        value: Option<u8>,
+    },
+    /// Removes a watchpoint by number, or all watchpoints.
+    Unwatch { index: Option<usize> },
+    /// Adds an expression to display after each step, or displays them all.
+    #[command(alias = "we")]
+    WatchExpr {
+        /// The expression, e.g. `m[r2]` or `w[0x7f00]`.
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        expr: Vec<String>,
+    },
+    /// Removes a displayed expression by number, or all of them.
+    #[command(alias = "uwe")]
+    UnwatchExpr { index: Option<usize> },
     #[command(alias = "x")]
     Examine {
-        #[arg(value_parser=parse_hex_u16)]
-        addr: u16,
+        addr: Addr,
         #[arg(value_parser=parse_hex_u16, default_value="8")]
         count: u16,
     },
@@ -65,13 +138,34 @@ enum Command {
     PokeFlag { flag: u8 },
     #[command(alias = "pm")]
     PokeMem {
-        #[arg(value_parser=parse_hex_u16)]
-        addr: u16,
+        addr: Addr,
         #[arg(value_parser=parse_hex_u8)]
         byte: u8,
     },
+    /// Sets a register or flag, e.g. `set r3 8000` or `set df 1`.
+    Set {
+        /// The register: r0..rf, d, df, p, x, t, ie, q, xie or cie.
+        register: Register,
+
+        /// The value, in hex.
+        #[arg(value_parser=parse_hex)]
+        value: u16,
+    },
     #[command(alias = "z")]
     Reset,
+    /// Writes a memory range to a file.
+    Save {
+        /// The memory range, e.g. 0x0000..0x7fff.
+        range: AddrRange,
+
+        /// Path to the file.
+        path: PathBuf,
+
+        /// The file format. If not specified, the format is implied by the file extension, or
+        /// defaults to raw binary.
+        #[arg(long)]
+        format: Option<ImageFormat>,
+    },
     /// Adds a single event.
     #[command(alias = "ie")]
     AddInputEvent {
@@ -165,6 +259,122 @@ fn parse_hex_u16(s: &str) -> Result<u16, std::num::ParseIntError> {
     }
 }
 
+/// Parses a hex value, with or without a `0x` prefix.
+fn parse_hex(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16)
+}
+
+fn parse_hex_u64(s: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16)
+}
+
+/// An address or range, whose bounds may be symbols.
+#[derive(Debug, Clone)]
+struct AddrRange {
+    start: Option<Addr>,
+    end: Option<Addr>,
+}
+impl FromStr for AddrRange {
+    type Err = SymbolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| (!s.is_empty()).then(|| s.parse()).transpose();
+        match s.split_once("..") {
+            Some((start, end)) => Ok(AddrRange {
+                start: parse(start)?,
+                end: parse(end)?,
+            }),
+            None => {
+                let addr = Some(s.parse()?);
+                Ok(AddrRange {
+                    start: addr.clone(),
+                    end: addr,
+                })
+            }
+        }
+    }
+}
+impl AddrRange {
+    fn resolve(&self, symbols: &Symbols) -> Result<RangeInclusive<u16>, SymbolError> {
+        let resolve = |addr: &Option<Addr>| addr.as_ref().map(|a| a.resolve(symbols)).transpose();
+        let range = MemoryRange {
+            start: resolve(&self.start)?,
+            end: resolve(&self.end)?,
+        };
+        Ok(range.into_range_inclusive(u16::MAX))
+    }
+}
+
+/// Resolves an address, printing an error if it's an undefined symbol.
+fn resolve(system: &BasicSystem, addr: &Addr) -> Option<u16> {
+    addr.resolve(system.symbols())
+        .inspect_err(|e| eprintln!("{e}"))
+        .ok()
+}
+
+/// Resolves an address range, printing an error if it refers to an undefined symbol.
+fn resolve_range(system: &BasicSystem, range: &AddrRange) -> Option<RangeInclusive<u16>> {
+    range
+        .resolve(system.symbols())
+        .inspect_err(|e| eprintln!("{e}"))
+        .ok()
+}
+
+/// Parses a breakpoint condition of the form `if EXPR`, printing an error if it's invalid. An
+/// empty condition is `Some(None)`.
+fn parse_condition(system: &BasicSystem, words: &[String]) -> Option<Option<Expr>> {
+    let Some((first, expr)) = words.split_first() else {
+        return Some(None);
+    };
+    if first != "if" || expr.is_empty() {
+        eprintln!("expected 'if EXPR'");
+        return None;
+    }
+    Expr::parse(&expr.join(" "), system.symbols())
+        .inspect_err(|e| eprintln!("{e}"))
+        .ok()
+        .map(Some)
+}
+
+/// Sets a breakpoint, given its address and condition.
+fn set_breakpoint(system: &mut BasicSystem, addr: &Addr, condition: &[String], temporary: bool) {
+    let Some(addr) = resolve(system, addr) else {
+        return;
+    };
+    let Some(condition) = parse_condition(system, condition) else {
+        return;
+    };
+    let bp = Breakpoint::default()
+        .with_condition(condition)
+        .with_temporary(temporary);
+    system.breakpoints_mut().insert(addr, bp);
+}
+
+/// Enables or disables a breakpoint, or all breakpoints.
+fn enable_breakpoints(system: &mut BasicSystem, addr: Option<Addr>, enabled: bool) {
+    let addr = match addr {
+        Some(addr) => match resolve(system, &addr) {
+            Some(addr) => Some(addr),
+            None => return,
+        },
+        None => None,
+    };
+    let mut found = false;
+    for (_, bp) in system
+        .breakpoints_mut()
+        .iter_mut()
+        .filter(|(a, _)| addr.is_none_or(|addr| **a == addr))
+    {
+        bp.enabled = enabled;
+        found = true;
+    }
+    if let Some(addr) = addr
+        && !found
+    {
+        println!("no breakpoint at {addr:04x}");
+    }
+}
+
 fn parse_hex_u8(s: &str) -> Result<u8, std::num::ParseIntError> {
     if let Some(s) = s.strip_prefix("0x") {
         u8::from_str_radix(s, 16)
@@ -173,6 +383,42 @@ fn parse_hex_u8(s: &str) -> Result<u8, std::num::ParseIntError> {
     }
 }
 
+/// Expressions that are displayed after each step, with the values they last displayed.
+#[derive(Debug, Default)]
+struct Displays(Vec<(Expr, Result<i64, ExprError>)>);
+impl Displays {
+    /// Evaluates and prints the expressions, highlighting values that changed.
+    fn print(&mut self, system: &BasicSystem) {
+        for (n, (expr, prev)) in self.0.iter_mut().enumerate() {
+            let value = system.eval(expr);
+            let text = format_value(&value);
+            if update(prev, value) {
+                println!("{n}: {expr} = {}", console::style(text).yellow().bold());
+            } else {
+                println!("{n}: {expr} = {text}");
+            }
+        }
+    }
+}
+
+/// Replaces the value a display last displayed, returning true if it changed, including to or
+/// from an error.
+fn update(prev: &mut Result<i64, ExprError>, value: Result<i64, ExprError>) -> bool {
+    let changed = *prev != value;
+    *prev = value;
+    changed
+}
+
+/// Formats the value of an expression in hex, and in decimal if that differs.
+fn format_value(value: &Result<i64, ExprError>) -> String {
+    match *value {
+        Ok(value @ 0..=9) => format!("{value}"),
+        Ok(value) if value < 0 => format!("{value}"),
+        Ok(value) => format!("{value:#x} ({value})"),
+        Err(ref e) => format!("<{e}>"),
+    }
+}
+
 fn ctrlc_channel() -> mpsc::Receiver<()> {
     let (tx, rx) = mpsc::sync_channel(1);
     ctrlc::set_handler(move || {
@@ -190,11 +436,12 @@ pub fn run(system: &mut BasicSystem) {
     let mut ctrlc = ctrlc_channel();
     system.print_next_cpu();
     let mut prev_cmd = None;
+    let mut displays = Displays::default();
     loop {
         match rl.readline(">> ") {
             Ok(line) => {
                 rl.add_history_entry(&line).ok();
-                handle_line(system, &line, &mut prev_cmd, &mut ctrlc);
+                handle_line(system, &line, &mut prev_cmd, &mut ctrlc, &mut displays);
             }
             Err(ReadlineError::Interrupted) => (),
             Err(ReadlineError::Eof) => break,
@@ -208,11 +455,12 @@ fn handle_line(
     line: &str,
     prev_cmd: &mut Option<Command>,
     ctrlc: &mut mpsc::Receiver<()>,
+    displays: &mut Displays,
 ) {
     if line.trim() == ""
         && let Some(cmd) = prev_cmd.clone()
     {
-        handle_command(system, cmd, ctrlc);
+        handle_command(system, cmd, ctrlc, displays);
         return;
     }
     match shlex::split(line) {
@@ -221,7 +469,7 @@ fn handle_line(
             match Command::try_parse_from(parts) {
                 Ok(c) => {
                     prev_cmd.replace(c.clone());
-                    handle_command(system, c, ctrlc);
+                    handle_command(system, c, ctrlc, displays);
                 }
                 Err(e) => eprintln!("{e}"),
             }
@@ -231,81 +479,253 @@ fn handle_line(
 }
 
 fn step(system: &mut BasicSystem) -> Status {
+    let status = step_instr(system);
+    system.print_next_cpu();
+    status
+}
+
+/// Runs until the next instruction fetch.
+fn step_instr(system: &mut BasicSystem) -> Status {
     let mut status = system.tick();
     while !system.cpu().is_fetch_tick0() && matches!(status, Status::Ready) {
         system.maybe_print_next_event();
         status = system.tick();
     }
+    status
+}
+
+/// Runs until the start of another source line, or back to the start of the current one.
+/// Instructions expanded from the same statement, e.g. a macro, are stepped over together.
+fn step_line(system: &mut BasicSystem, ctrlc: &mut mpsc::Receiver<()>) -> Status {
+    let start = system.cpu().rp();
+    let current = system.source().line(start).cloned();
+    let status = loop {
+        if ctrlc.try_recv().is_ok() {
+            println!("interrupted");
+            break Status::Ready;
+        }
+        let status = step_instr(system);
+        if !matches!(status, Status::Ready | Status::Event) {
+            break status;
+        }
+        let pc = system.cpu().rp();
+        if let Some(line) = system.source().line(pc)
+            && (pc == start || current.as_ref().is_none_or(|c| !c.same_statement(line)))
+        {
+            break status;
+        }
+    };
     system.print_next_cpu();
     status
 }
 
-fn handle_command(system: &mut BasicSystem, cmd: Command, ctrlc: &mut mpsc::Receiver<()>) {
+/// Runs until a breakpoint, watchpoint, fault or idle, or until interrupted.
+fn run_to_stop(system: &mut BasicSystem, ctrlc: &mut mpsc::Receiver<()>) {
+    loop {
+        if ctrlc.try_recv().is_ok() {
+            println!("interrupted");
+            break;
+        }
+        match step(system) {
+            Status::Breakpoint => {
+                println!("breakpoint");
+                break;
+            }
+            Status::Idle => {
+                println!("idle");
+                break;
+            }
+            Status::Watchpoint(hit) => {
+                println!("watchpoint: {hit}");
+                break;
+            }
+            Status::Fault(fault) => {
+                println!("{fault}");
+                break;
+            }
+            Status::Uninit(read) => {
+                println!("{read}: {}", system.listing_at(read.pc));
+                break;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Undoes instructions until a breakpoint or watchpoint, the start of history, or until
+/// interrupted.
+fn reverse_to_stop(system: &mut BasicSystem, ctrlc: &mut mpsc::Receiver<()>) {
+    loop {
+        if ctrlc.try_recv().is_ok() {
+            println!("interrupted");
+            break;
+        }
+        match system.step_back() {
+            Ok(Status::Breakpoint) => {
+                println!("breakpoint");
+                break;
+            }
+            Ok(Status::Watchpoint(hit)) => {
+                println!("watchpoint: {hit}");
+                break;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                println!("{e}");
+                break;
+            }
+        }
+    }
+    system.print_next_cpu();
+}
+
+fn handle_command(
+    system: &mut BasicSystem,
+    cmd: Command,
+    ctrlc: &mut mpsc::Receiver<()>,
+    displays: &mut Displays,
+) {
     match cmd {
         Command::Reset => {
             system.reset();
             system.print_next_cpu();
         }
-        Command::Continue => loop {
-            if ctrlc.try_recv().is_ok() {
-                println!("interrupted");
-                break;
+        Command::Continue => {
+            run_to_stop(system, ctrlc);
+            displays.print(system);
+        }
+        Command::ReverseStep { count } => {
+            for _ in 0..count {
+                match system.step_back() {
+                    Ok(status) => {
+                        system.print_next_cpu();
+                        displays.print(system);
+                        if let Status::Watchpoint(hit) = status {
+                            println!("watchpoint: {hit}");
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        println!("{e}");
+                        break;
+                    }
+                }
             }
-            match step(system) {
-                Status::Breakpoint => {
-                    println!("breakpoint");
+        }
+        Command::ReverseContinue => {
+            reverse_to_stop(system, ctrlc);
+            displays.print(system);
+        }
+        Command::Goto { cycle } => {
+            if cycle < system.clock_cycle()
+                && let Err(e) = system.rewind(cycle)
+            {
+                println!("{e}");
+                return;
+            }
+            while system.clock_cycle() < cycle {
+                if ctrlc.try_recv().is_ok() {
+                    println!("interrupted");
                     break;
                 }
-                Status::Idle => {
-                    println!("idle");
+                if let Status::Fault(fault) = system.tick() {
+                    println!("{fault}");
                     break;
                 }
-                _ => (),
             }
-        },
+            system.print_next_cpu();
+            displays.print(system);
+        }
         Command::Display => {
             println!("{}", system.display())
         }
         Command::List { count, addr } => {
-            let mut addr = addr.unwrap_or(system.cdp1802().rp());
+            let mut addr = match addr {
+                Some(addr) => {
+                    let Some(addr) = resolve(system, &addr) else {
+                        return;
+                    };
+                    addr
+                }
+                None => system.cdp1802().rp(),
+            };
             for _ in 0..count {
-                let bp = if system.has_breakpoint(addr) {
-                    "*"
-                } else {
-                    " "
+                if let Some(name) = system.symbols().name(addr) {
+                    println!("{name}:");
+                }
+                let bp = match system.breakpoints().get(&addr) {
+                    Some(bp) if bp.enabled => "*",
+                    Some(_) => "-",
+                    None => " ",
                 };
                 let (listing, size) = system
-                    .memory()
-                    .get_instr_at(addr)
-                    .map_or(("??".into(), 1), |i| (i.listing(), i.size()));
+                    .bus()
+                    .get_instr_at(addr, system.cpu().variant())
+                    .map_or(("??".into(), 1), |i| {
+                        (system.symbols().listing(&i, addr), i.size())
+                    });
                 println!("{addr:04x} {bp}{listing}");
                 addr += u16::from(size);
             }
         }
         Command::Examine { addr, count } => {
-            let addr = addr as usize;
-            let end = addr + count as usize;
-            let mem = &system.memory().as_slice()[addr..end];
-            for (ii, v) in mem.iter().enumerate() {
+            let Some(addr) = resolve(system, &addr) else {
+                return;
+            };
+            let mem = (0..count).map_while(|n| addr.checked_add(n));
+            for (ii, v) in mem.map(|addr| system.bus().peek(addr)).enumerate() {
                 if ii % 8 == 0 {
                     if ii != 0 {
                         println!();
                     }
-                    print!("{:04x}  ", addr + ii);
+                    print!("{:04x}  ", addr as usize + ii);
                 }
                 print!("{v:02x} ");
             }
             println!();
         }
-        Command::Step { count } => {
+        Command::Step { count, line } => {
+            if line && system.source().is_empty() {
+                eprintln!("no listing loaded, use --listing");
+                return;
+            }
             for _ in 0..count {
-                step(system);
+                let status = if line {
+                    step_line(system, ctrlc)
+                } else {
+                    step(system)
+                };
+                displays.print(system);
+                match status {
+                    Status::Breakpoint if line => {
+                        println!("breakpoint");
+                        break;
+                    }
+                    Status::Idle if line => {
+                        println!("idle");
+                        break;
+                    }
+                    Status::Watchpoint(hit) => {
+                        println!("watchpoint: {hit}");
+                        break;
+                    }
+                    Status::Fault(fault) => {
+                        println!("{fault}");
+                        break;
+                    }
+                    Status::Uninit(read) => {
+                        println!("{read}: {}", system.listing_at(read.pc));
+                        break;
+                    }
+                    _ => (),
+                }
             }
         }
         Command::Tick { count } => {
             for _ in 0..count {
                 system.tick();
                 system.print_next_cpu();
+                displays.print(system);
             }
         }
         Command::Registers => {
@@ -328,21 +748,103 @@ fn handle_command(system: &mut BasicSystem, cmd: Command, ctrlc: &mut mpsc::Rece
             }
         }
         Command::BreakpointList => {
-            let bps: Vec<_> = system.breakpoints().iter().sorted_unstable().collect();
             println!("breakpoints:");
-            for bp in bps {
-                let listing = system
-                    .memory()
-                    .get_instr_at(*bp)
-                    .map_or("??".into(), |instr| instr.listing());
-                println!("{bp:04x} *{listing}");
+            for (&addr, bp) in system.breakpoints() {
+                let listing = system.listing_at(addr);
+                let mark = if bp.enabled { "*" } else { "-" };
+                match system.symbols().describe(addr) {
+                    Some(name) => println!("{addr:04x} <{name}> {mark}{listing}"),
+                    None => println!("{addr:04x} {mark}{listing}"),
+                }
+                let details = bp.to_string();
+                if !details.is_empty() {
+                    println!("      {details}");
+                }
             }
         }
-        Command::BreakpointSet { addr } => {
-            system.breakpoints_mut().insert(addr);
+        Command::BreakpointSet { addr, condition } => {
+            set_breakpoint(system, &addr, &condition, false);
+        }
+        Command::Tbreak { addr, condition } => {
+            set_breakpoint(system, &addr, &condition, true);
+        }
+        Command::Ignore { addr, count } => {
+            let Some(addr) = resolve(system, &addr) else {
+                return;
+            };
+            match system.breakpoints_mut().get_mut(&addr) {
+                Some(bp) => bp.ignore = count,
+                None => println!("no breakpoint at {addr:04x}"),
+            }
         }
+        Command::Enable { addr } => enable_breakpoints(system, addr, true),
+        Command::Disable { addr } => enable_breakpoints(system, addr, false),
         Command::BreakpointClear { addr } => {
-            system.breakpoints_mut().remove(&addr);
+            if let Some(addr) = resolve(system, &addr) {
+                system.breakpoints_mut().remove(&addr);
+            }
+        }
+        Command::Watch {
+            range: None,
+            value: _,
+            exec: _,
+        } => {
+            println!("watchpoints:");
+            for (n, watchpoint) in system.watchpoints().iter().enumerate() {
+                println!("{n}: {watchpoint}");
+            }
+        }
+        Command::Watch {
+            range: Some(range),
+            value,
+            exec,
+        } => {
+            let Some(range) = resolve_range(system, &range) else {
+                return;
+            };
+            let kind = if exec {
+                WatchKind::Exec
+            } else {
+                WatchKind::Write
+            };
+            system
+                .watchpoints_mut()
+                .push(Watchpoint { kind, range, value });
+        }
+        Command::Rwatch { range, value } => {
+            let Some(range) = resolve_range(system, &range) else {
+                return;
+            };
+            system.watchpoints_mut().push(Watchpoint {
+                kind: WatchKind::Read,
+                range,
+                value,
+            });
+        }
+        Command::Unwatch { index: None } => system.watchpoints_mut().clear(),
+        Command::Unwatch { index: Some(n) } => {
+            if n < system.watchpoints().len() {
+                system.watchpoints_mut().remove(n);
+            } else {
+                println!("no watchpoint {n}");
+            }
+        }
+        Command::WatchExpr { expr } if expr.is_empty() => displays.print(system),
+        Command::WatchExpr { expr } => match Expr::parse(&expr.join(" "), system.symbols()) {
+            Ok(expr) => {
+                let value = system.eval(&expr);
+                println!("{}: {expr} = {}", displays.0.len(), format_value(&value));
+                displays.0.push((expr, value));
+            }
+            Err(e) => eprintln!("{e}"),
+        },
+        Command::UnwatchExpr { index: None } => displays.0.clear(),
+        Command::UnwatchExpr { index: Some(n) } => {
+            if n < displays.0.len() {
+                let _ = displays.0.remove(n);
+            } else {
+                println!("no expression {n}");
+            }
         }
         Command::Flags => {
             let pins = system.pins();
@@ -359,7 +861,30 @@ fn handle_command(system: &mut BasicSystem, cmd: Command, ctrlc: &mut mpsc::Rece
             };
         }
         Command::PokeMem { addr, byte } => {
-            system.memory_mut().as_mut_slice()[addr as usize] = byte;
+            if let Some(addr) = resolve(system, &addr) {
+                system.bus_mut().poke(addr, byte);
+            }
+        }
+        Command::Set { register, value } => match system.set_register(register, value) {
+            Ok(()) => system.print_next_cpu(),
+            Err(e) => eprintln!("{e}"),
+        },
+        Command::Save {
+            range,
+            path,
+            format,
+        } => {
+            let format = format
+                .or_else(|| ImageFormat::from_path(&path))
+                .unwrap_or(ImageFormat::Bin);
+            let Some(range) = resolve_range(system, &range) else {
+                return;
+            };
+            let result = File::create(&path)
+                .and_then(|file| system.bus().export(range, format, BufWriter::new(file)));
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
         }
         Command::AddInputEvent { when, kind, value } => {
             let timestamp = when.into_absolute(system.now());
@@ -375,3 +900,20 @@ fn handle_command(system: &mut BasicSystem, cmd: Command, ctrlc: &mut mpsc::Rece
         Command::ListOutputEvents => system.print_output_events(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_display() {
+        let mut prev = Ok(1);
+        assert!(!update(&mut prev, Ok(1)));
+        assert!(update(&mut prev, Ok(2)));
+        assert!(update(&mut prev, Err(ExprError::DivideByZero)));
+        assert!(!update(&mut prev, Err(ExprError::DivideByZero)));
+        assert!(update(&mut prev, Err(ExprError::Undefined("x".into()))));
+        assert!(update(&mut prev, Ok(2)));
+        assert_eq!(prev.unwrap(), 2);
+    }
+}
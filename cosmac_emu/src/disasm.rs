@@ -0,0 +1,584 @@
+//! Symbolic disassembly
+//!
+//! Branch and call targets are resolved into absolute addresses, and targets within the
+//! disassembled range are given `L_xxxx` labels, or the names of symbols at those addresses.
+//!
+//! Code is found either by linear sweep, which decodes every byte in the range as an instruction,
+//! or by tracing control flow from a set of entry points. When tracing, bytes that are never
+//! reached are treated as data.
+//!
+//! The disassembly can be written as a listing, or as source that reassembles to the same bytes.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+use itertools::Itertools;
+
+use crate::chips::cdp1802::Variant;
+use crate::instr::{Flow, Instr, InstrSchema, Operands};
+use crate::symbols::Symbols;
+
+/// Maximum number of bytes on a line of data.
+const DATA_LEN: usize = 4;
+
+/// Maximum number of characters on a line of string data.
+const STRING_LEN: usize = 16;
+
+/// Minimum length of a run of printable characters to be shown as a string.
+const MIN_STRING_LEN: usize = 4;
+
+/// How code is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Decode every byte in the range as an instruction.
+    Sweep,
+    /// Follow control flow from the entry points, treating unreached bytes as data.
+    Trace,
+}
+
+/// A disassembled instruction, or a run of data.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// The instruction, or `None` for data.
+    pub instr: Option<Instr>,
+}
+
+/// The output of the disassembler.
+#[derive(Debug, Default)]
+pub struct Disassembly {
+    pub lines: Vec<Line>,
+    /// Labels, by address.
+    pub labels: BTreeMap<u16, String>,
+    /// Symbols for branch targets that aren't the address of a line, by address.
+    pub externals: BTreeMap<u16, String>,
+}
+impl Disassembly {
+    /// Disassembles the given range of addresses by linear sweep. The data is loaded at `base`.
+    pub fn sweep(data: &[u8], base: u16, range: RangeInclusive<u16>, variant: Variant) -> Self {
+        let mut lines = vec![];
+        let end = usize::from(*range.end()) + 1;
+        let mut addr = usize::from(*range.start());
+        while addr < end {
+            let offset = addr - usize::from(base);
+            let bin = &data[offset..(end - usize::from(base)).min(data.len())];
+            let instr = Instr::decode_for(bin, variant).filter(|i| !matches!(i, Instr::Resv68));
+            let size = instr.map_or(1, |i| usize::from(i.size()));
+            lines.push(Line {
+                addr: addr as u16,
+                bytes: bin[..size].to_vec(),
+                instr,
+            });
+            addr += size;
+        }
+        let mut disasm = Self {
+            lines,
+            labels: BTreeMap::new(),
+            externals: BTreeMap::new(),
+        };
+        disasm.add_labels();
+        disasm
+    }
+
+    /// Disassembles the given range of addresses by following control flow from the entry
+    /// points. The data is loaded at `base`.
+    ///
+    /// Besides branches and calls, the tracer follows `sep` to registers whose value is known from
+    /// a preceding `ldi`/`phi`/`plo` sequence, or from `rldi`.
+    pub fn trace(
+        data: &[u8],
+        base: u16,
+        range: RangeInclusive<u16>,
+        variant: Variant,
+        entries: &[u16],
+    ) -> Self {
+        let mut code = BTreeMap::new();
+        let mut work: Vec<(u16, Regs)> = entries
+            .iter()
+            .map(|addr| (*addr, Regs::default()))
+            .collect();
+        while let Some((mut addr, mut regs)) = work.pop() {
+            while range.contains(&addr) && !code.contains_key(&addr) {
+                let Some(instr) = decode(data, base, &range, addr, variant) else {
+                    break;
+                };
+                code.insert(addr, instr);
+                regs.step(&instr);
+                let flow = Successors::of(&instr, addr, &regs);
+                work.extend(flow.targets.into_iter().map(|t| (t, regs.clone())));
+                match flow.next {
+                    Some(next) if next > addr => addr = next,
+                    _ => break,
+                }
+            }
+        }
+
+        // Instructions that overlap an earlier one are dropped.
+        let mut lines = vec![];
+        let mut data_start = None;
+        let mut addr = usize::from(*range.start());
+        let end = usize::from(*range.end()) + 1;
+        while addr < end {
+            let instr = code.get(&(addr as u16));
+            if let Some(instr) = instr {
+                if let Some(start) = data_start.take() {
+                    lines.extend(data_lines(start, offset(data, base, start, addr)));
+                }
+                let size = usize::from(instr.size());
+                lines.push(Line {
+                    addr: addr as u16,
+                    bytes: offset(data, base, addr, addr + size).to_vec(),
+                    instr: Some(*instr),
+                });
+                addr += size;
+            } else {
+                data_start.get_or_insert(addr);
+                addr += 1;
+            }
+        }
+        if let Some(start) = data_start {
+            lines.extend(data_lines(start, offset(data, base, start, end)));
+        }
+        let mut disasm = Self {
+            lines,
+            labels: BTreeMap::new(),
+            externals: BTreeMap::new(),
+        };
+        disasm.add_labels();
+        disasm
+    }
+
+    /// Labels each branch target that is the address of a line.
+    fn add_labels(&mut self) {
+        let targets: Vec<u16> = self
+            .lines
+            .iter()
+            .filter_map(|line| line.instr?.branch_target(line.addr))
+            .collect();
+        for target in targets {
+            if self.lines.binary_search_by_key(&target, |l| l.addr).is_ok() {
+                self.labels.entry(target).or_insert_with(|| label(target));
+            }
+        }
+    }
+
+    /// Names lines and branch targets after symbols, in place of generated labels.
+    pub fn apply_symbols(&mut self, symbols: &Symbols) {
+        for line in &self.lines {
+            if let Some(name) = symbols.name(line.addr) {
+                self.labels.insert(line.addr, name.to_string());
+            }
+        }
+        let targets: Vec<u16> = self
+            .lines
+            .iter()
+            .filter_map(|line| line.instr?.branch_target(line.addr))
+            .filter(|target| !self.labels.contains_key(target))
+            .collect();
+        for target in targets {
+            if let Some(name) = symbols.name(target) {
+                self.externals.insert(target, name.to_string());
+            }
+        }
+    }
+
+    /// Returns the disassembled instruction, with registers as `rN`, immediates in hex, and
+    /// branch targets as labels or absolute addresses.
+    pub fn format(&self, addr: u16, instr: &Instr) -> String {
+        let fields = instr.fields();
+        let layout = Instr::layout(instr.mnemonic()).expect("valid mnemonic");
+        let mut operands = vec![];
+        let mut immediates = fields.as_slice();
+        if layout.registers != 0 {
+            let n = fields[0];
+            operands.push(match instr {
+                Instr::Out(_) | Instr::Inp(_) => format!("{n}"),
+                _ => format!("r{n:x}"),
+            });
+            immediates = &fields[1..];
+        }
+        match (instr.branch_target(addr), immediates) {
+            (Some(target), _) => {
+                let label = self.labels.get(&target).or(self.externals.get(&target));
+                operands.push(label.cloned().unwrap_or_else(|| format!("{target:#06x}")));
+            }
+            (None, []) => (),
+            (None, [nn]) => operands.push(format!("{nn:#04x}")),
+            (None, [hh, ll]) => operands.push(format!("{:#06x}", u16::from_be_bytes([*hh, *ll]))),
+            _ => unreachable!(),
+        }
+        format!("{:<4} {}", instr.mnemonic(), operands.join(", "))
+            .trim_end()
+            .to_string()
+    }
+
+    /// Writes a listing, with a line for each label.
+    pub fn write_listing(&self, mut w: impl Write) -> std::io::Result<()> {
+        for line in &self.lines {
+            if let Some(label) = self.labels.get(&line.addr) {
+                writeln!(w, "{label}:")?;
+            }
+            let mut bytes = line.bytes.iter().take(DATA_LEN).map(|b| format!("{b:02x}"));
+            let bytes = if line.bytes.len() > DATA_LEN {
+                bytes.take(DATA_LEN - 1).chain(["..".into()]).join(" ")
+            } else {
+                bytes.join(" ")
+            };
+            let disasm = match &line.instr {
+                Some(instr) => self.format(line.addr, instr),
+                None => format_data(&line.bytes),
+            };
+            writeln!(w, "{:04x} {bytes:<11} {disasm}", line.addr)?;
+        }
+        w.flush()
+    }
+
+    /// Writes assembler source, with the address and bytes of each line in a comment. External
+    /// symbols are defined first.
+    pub fn write_source(&self, mut w: impl Write) -> std::io::Result<()> {
+        for (addr, name) in &self.externals {
+            writeln!(w, "{name:<7} equ  {addr:#06x}")?;
+        }
+        let mut pc = None;
+        for line in &self.lines {
+            if pc != Some(usize::from(line.addr)) {
+                writeln!(w, "{:8}org  {:#06x}", "", line.addr)?;
+            }
+            if let Some(label) = self.labels.get(&line.addr) {
+                writeln!(w, "{label}:")?;
+            }
+            let text = match &line.instr {
+                Some(instr) => self.format(line.addr, instr),
+                None => format_data(&line.bytes),
+            };
+            let bytes = line.bytes.iter().map(|b| format!("{b:02x}")).join(" ");
+            writeln!(w, "{:8}{text:<32}; {:04x}  {bytes}", "", line.addr)?;
+            pc = Some(usize::from(line.addr) + line.bytes.len());
+        }
+        w.flush()
+    }
+}
+
+/// Returns the generated label for an address.
+pub fn label(addr: u16) -> String {
+    format!("L_{addr:04x}")
+}
+
+/// Returns the bytes between two addresses.
+fn offset(data: &[u8], base: u16, start: usize, end: usize) -> &[u8] {
+    let base = usize::from(base);
+    &data[start - base..end - base]
+}
+
+/// Decodes the instruction at an address, if it lies entirely within the range.
+fn decode(
+    data: &[u8],
+    base: u16,
+    range: &RangeInclusive<u16>,
+    addr: u16,
+    variant: Variant,
+) -> Option<Instr> {
+    let end = (usize::from(*range.end()) + 1).min(usize::from(base) + data.len());
+    let instr = Instr::decode_for(offset(data, base, addr.into(), end), variant)?;
+    (!matches!(instr, Instr::Resv68)).then_some(instr)
+}
+
+/// Splits data into lines, with runs of printable characters as strings.
+fn data_lines(addr: usize, data: &[u8]) -> Vec<Line> {
+    let is_text = |b: &u8| b.is_ascii_graphic() || *b == b' ';
+    let mut lines = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let text = rest.iter().take_while(|b| is_text(b)).count();
+        let len = if text >= MIN_STRING_LEN {
+            text.min(STRING_LEN)
+        } else {
+            let binary = rest
+                .windows(MIN_STRING_LEN)
+                .position(|w| w.iter().all(is_text))
+                .unwrap_or(rest.len());
+            binary.clamp(1, DATA_LEN)
+        };
+        let (bytes, tail) = rest.split_at(len);
+        lines.push(Line {
+            addr: (addr + data.len() - rest.len()) as u16,
+            bytes: bytes.to_vec(),
+            instr: None,
+        });
+        rest = tail;
+    }
+    lines
+}
+
+/// Formats data as a `db` directive.
+fn format_data(bytes: &[u8]) -> String {
+    let is_text = bytes.len() >= MIN_STRING_LEN
+        && bytes
+            .iter()
+            .all(|b| (b.is_ascii_graphic() || *b == b' ') && *b != b'"' && *b != b';');
+    if is_text {
+        format!("db   \"{}\"", String::from_utf8_lossy(bytes))
+    } else {
+        format!(
+            "db   {}",
+            bytes.iter().map(|b| format!("{b:#04x}")).join(", ")
+        )
+    }
+}
+
+/// Register values known to the tracer.
+#[derive(Debug, Clone, Default)]
+struct Regs {
+    d: Option<u8>,
+    hi: [Option<u8>; 16],
+    lo: [Option<u8>; 16],
+}
+impl Regs {
+    fn get(&self, n: u8) -> Option<u16> {
+        let n = usize::from(n);
+        Some(u16::from_be_bytes([self.hi[n]?, self.lo[n]?]))
+    }
+
+    fn forget(&mut self, n: u8) {
+        self.hi[usize::from(n)] = None;
+        self.lo[usize::from(n)] = None;
+    }
+
+    /// Updates the known values after an instruction.
+    fn step(&mut self, instr: &Instr) {
+        match *instr {
+            Instr::Ldi(nn) => self.d = Some(nn),
+            Instr::Phi(n) => self.hi[usize::from(n)] = self.d,
+            Instr::Plo(n) => self.lo[usize::from(n)] = self.d,
+            Instr::Ghi(n) => self.d = self.hi[usize::from(n)],
+            Instr::Glo(n) => self.d = self.lo[usize::from(n)],
+            Instr::Rldi(n, hh, ll) => {
+                self.hi[usize::from(n)] = Some(hh);
+                self.lo[usize::from(n)] = Some(ll);
+            }
+            _ => {
+                let writes = instr.meta().writes;
+                if writes.contains(Operands::N) {
+                    self.forget(instr.fields()[0]);
+                }
+                if writes.contains(Operands::D) {
+                    self.d = None;
+                }
+            }
+        }
+    }
+}
+
+/// Where control goes after an instruction.
+struct Successors {
+    /// The next instruction, if control may fall through or skip.
+    next: Option<u16>,
+    /// Other destinations.
+    targets: Vec<u16>,
+}
+impl Successors {
+    fn of(instr: &Instr, addr: u16, regs: &Regs) -> Self {
+        let next = addr.wrapping_add(instr.size().into());
+        let target = instr.branch_target(addr);
+        let (next, targets) = match instr.meta().flow {
+            Flow::Branch | Flow::Return => (None, vec![]),
+            Flow::Skip(n) => (Some(next.wrapping_add(n.into())), vec![]),
+            Flow::SkipIf(n) => (Some(next), vec![next.wrapping_add(n.into())]),
+            // A SEP usually calls a subroutine that returns to the following instruction.
+            Flow::Call => match *instr {
+                Instr::Sep(n) => (Some(next), regs.get(n).into_iter().collect()),
+                _ => (Some(next), vec![]),
+            },
+            Flow::Next | Flow::BranchIf => (Some(next), vec![]),
+        };
+        let targets = targets.into_iter().chain(target).collect();
+        Self { next, targets }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn listing(disasm: &Disassembly) -> String {
+        let mut buf = vec![];
+        disasm.write_listing(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_sweep() {
+        let data = [
+            0xf8, 0x10, // ldi 0x10
+            0xa8, // plo r8
+            0x28, // dec r8
+            0x88, // glo r8
+            0x3a, 0x03, // bnz 0x8003
+            0xc0, 0x90, 0x00, // lbr 0x9000
+            0x61, // out 1
+            0x68, 0xca, 0x12, 0x34, // rldi ra, 0x1234
+        ];
+        let disasm = Disassembly::sweep(&data, 0x8000, 0x8000..=0x800e, Variant::Cdp1805);
+        assert_eq!(
+            listing(&disasm),
+            "\
+8000 f8 10       ldi  0x10
+8002 a8          plo  r8
+L_8003:
+8003 28          dec  r8
+8004 88          glo  r8
+8005 3a 03       bnz  L_8003
+8007 c0 90 00    lbr  0x9000
+800a 61          out  1
+800b 68 ca 12 34 rldi ra, 0x1234
+"
+        );
+    }
+
+    /// Disassembles to source and reassembles it.
+    fn reassemble(disasm: &Disassembly) -> Vec<u8> {
+        let mut buf = vec![];
+        disasm.write_source(&mut buf).unwrap();
+        let source = String::from_utf8(buf).unwrap();
+        let asm = Assembler::new(Variant::Cdp1805)
+            .assemble(Path::new("test.asm"), &source)
+            .unwrap_or_else(|errors| panic!("{source}\n{errors:?}"));
+        assert_eq!(asm.image.segments.len(), 1);
+        asm.image.segments[0].data.clone()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Every instruction, with immediates that make short branches target each other.
+        let mut data = vec![];
+        for prefix in [None, Some(0x68)] {
+            for opcode in 0..=0xff {
+                let bin: Vec<u8> = prefix.into_iter().chain([opcode, 0x12, 0x34]).collect();
+                if let Some(instr) = Instr::decode_for(&bin, Variant::Cdp1805)
+                    && instr.is_extended() == prefix.is_some()
+                    && !matches!(instr, Instr::Resv68)
+                {
+                    data.extend(instr.encode());
+                }
+            }
+        }
+        data.extend(b"\x68Some text; with \"quotes\"\xff");
+        let end = (0x8000 + data.len() - 1) as u16;
+        let disasm = Disassembly::sweep(&data, 0x8000, 0x8000..=end, Variant::Cdp1805);
+        assert!(disasm.lines.iter().any(|l| l.instr.is_none()));
+        assert_eq!(reassemble(&disasm), data);
+    }
+
+    #[test]
+    fn test_source() {
+        let data = [
+            0x30, 0x03, 0x00, 0x7b, 0x30, 0x03, 0x41, 0x42, 0x43, 0x44, 0x45,
+        ];
+        let disasm = Disassembly::trace(&data, 0x100, 0x100..=0x10a, Variant::Cdp1802, &[0x100]);
+        let mut buf = vec![];
+        disasm.write_source(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "        org  0x0100
+        br   L_0103                     ; 0100  30 03
+        db   0x00                       ; 0102  00
+L_0103:
+        seq                             ; 0103  7b
+        br   L_0103                     ; 0104  30 03
+        db   \"ABCDE\"                    ; 0106  41 42 43 44 45
+"
+        );
+        assert_eq!(reassemble(&disasm), data);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let data = [0x30, 0x03, 0x00, 0x7b, 0xc0, 0x90, 0x00];
+        let mut disasm = Disassembly::sweep(&data, 0x100, 0x100..=0x106, Variant::Cdp1802);
+        let symbols = Symbols::parse("START 0100 LOOP 0103 MONITOR 9000").unwrap();
+        disasm.apply_symbols(&symbols);
+        let mut buf = vec![];
+        disasm.write_source(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "MONITOR equ  0x9000
+        org  0x0100
+START:
+        br   LOOP                       ; 0100  30 03
+        idl                             ; 0102  00
+LOOP:
+        seq                             ; 0103  7b
+        lbr  MONITOR                    ; 0104  c0 90 00
+"
+        );
+        assert_eq!(reassemble(&disasm), data);
+    }
+
+    #[test]
+    fn test_sweep_truncated() {
+        let disasm = Disassembly::sweep(&[0x30, 0x00, 0xc0], 0, 0..=2, Variant::Cdp1802);
+        assert_eq!(
+            listing(&disasm),
+            "L_0000:\n0000 30 00       br   L_0000\n0002 c0          db   0xc0\n"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let data = [
+            0xf8, 0x10, // ldi 0x10
+            0xb3, // phi r3
+            0xf8, 0x14, // ldi 0x14
+            0xa3, // plo r3
+            0x32, 0x0a, // bz 0x100a
+            0xd3, // sep r3
+            0x00, // idl
+            0x30, 0x0a, // br 0x100a
+            0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x21, // "Hello!"
+            0x01, 0x02, // data
+            0x7b, // seq, reached via sep r3
+            0x30, 0x14, // br 0x1014
+            0x70, // ret (unreached)
+        ];
+        let disasm =
+            Disassembly::trace(&data, 0x1000, 0x1000..=0x1017, Variant::Cdp1802, &[0x1000]);
+        assert_eq!(
+            listing(&disasm),
+            "\
+1000 f8 10       ldi  0x10
+1002 b3          phi  r3
+1003 f8 14       ldi  0x14
+1005 a3          plo  r3
+1006 32 0a       bz   L_100a
+1008 d3          sep  r3
+1009 00          idl
+L_100a:
+100a 30 0a       br   L_100a
+100c 48 65 6c .. db   \"Hello!\"
+1012 01 02       db   0x01, 0x02
+L_1014:
+1014 7b          seq
+1015 30 14       br   L_1014
+1017 70          db   0x70
+"
+        );
+    }
+
+    #[test]
+    fn test_trace_skips() {
+        // lskp skips over the ldi, which is never reached.
+        let data = [0xc8, 0xf8, 0x01, 0x7b, 0x30, 0x03];
+        let disasm = Disassembly::trace(&data, 0, 0..=5, Variant::Cdp1802, &[0]);
+        let code: Vec<_> = disasm
+            .lines
+            .iter()
+            .map(|l| (l.addr, l.instr.is_some()))
+            .collect();
+        assert_eq!(code, [(0, true), (1, false), (3, true), (4, true)]);
+    }
+}
@@ -145,7 +145,7 @@ impl InputEventLog {
         let events = read_csv(file)?;
         let expired = Vec::with_capacity(events.len());
         let mut pending: Vec<_> = events.into_iter().collect();
-        pending.sort_unstable_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        pending.sort_unstable_by_key(|e| e.timestamp);
         Ok(Self {
             pending: pending.into_iter().collect(),
             expired,
@@ -196,7 +196,7 @@ impl InputEventLog {
     /// Moves all expired events back into the sorted pending list.
     pub fn reset(&mut self) {
         let mut expired: Vec<_> = self.expired.drain(..).collect();
-        expired.sort_unstable_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        expired.sort_unstable_by_key(|e| e.timestamp);
         assert!(
             expired
                 .last()
@@ -208,6 +208,20 @@ impl InputEventLog {
         }
     }
 
+    /// Rewinds the log, and then expires all events that occurred before `now`. Events at `now`
+    /// are expired too if `inclusive` is set, for a state in which they've already been applied.
+    pub fn seek(&mut self, now: Duration, inclusive: bool) {
+        self.reset();
+        while self
+            .pending
+            .front()
+            .is_some_and(|e| e.timestamp < now || (inclusive && e.timestamp == now))
+        {
+            let e = self.pending.pop_front().unwrap();
+            self.expired.push(e);
+        }
+    }
+
     /// Iterates over all events in the log, in no particular order.
     pub fn iter(&self) -> impl Iterator<Item = InputEvent> {
         self.expired.iter().chain(self.pending.iter()).copied()
@@ -234,6 +248,15 @@ impl OutputEventLog {
         self.0.clear();
     }
 
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Removes the events after the first `len`.
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+
     /// Iterates over all events in the log, in no particular order.
     pub fn iter(&self) -> impl Iterator<Item = OutputEvent> {
         self.0.iter().copied()
@@ -0,0 +1,461 @@
+//! Debugger expressions
+//!
+//! Expressions are evaluated over the state of the CPU, its pins, and memory, e.g.
+//! `r[7] == 0x1234 && df`. Values are signed 64-bit integers, and comparisons and logical
+//! operators return 0 or 1. The following operands are recognized:
+//!
+//! - Numbers, in decimal or hex as `0x1f`.
+//! - Registers: `r[N]` or `r0`..`rf`, `d`, `df`, `p`, `x`, `t`, `ie`, and `pc` for the register
+//!   selected by P. The CDP1804/1805/1806 interrupt enables are `xie` and `cie`.
+//! - Pins: `q`, `ef1`..`ef4`, `intr`, `dma_in` and `dma_out`, which are 1 when active.
+//! - Memory: `m[ADDR]` for a byte, and `w[ADDR]` for a big-endian word.
+//! - Symbols, which are resolved when the expression is parsed.
+//!
+//! Operators have the same precedence as in C: `!`, `~` and unary `-`, then `*`, `/`, `%`, then
+//! `+`, `-`, then `<<`, `>>`, then `<`, `<=`, `>`, `>=`, then `==`, `!=`, then `&`, `^`, `|`,
+//! `&&` and `||`.
+
+use std::fmt::Display;
+
+use crate::bus::Device;
+use crate::chips::cdp1802::{Cdp1802, Cdp1802Pins};
+use crate::symbols::Symbols;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ExprError {
+    #[error("unexpected '{0}'")]
+    Unexpected(String),
+    #[error("unexpected end of expression")]
+    UnexpectedEnd,
+    #[error("invalid number '{0}'")]
+    InvalidNumber(String),
+    #[error("undefined name '{0}'")]
+    Undefined(String),
+    #[error("division by zero")]
+    DivideByZero,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    D,
+    Df,
+    P,
+    X,
+    T,
+    Ie,
+    Xie,
+    Cie,
+    Pc,
+    Q,
+    Ef(u8),
+    Intr,
+    DmaIn,
+    DmaOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnOp {
+    Not,
+    Compl,
+    Neg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Xor,
+    Or,
+    LogicalAnd,
+    LogicalOr,
+}
+impl BinOp {
+    /// The operators at each precedence level, from lowest to highest.
+    const LEVELS: [&[(&str, BinOp)]; 10] = [
+        &[("||", BinOp::LogicalOr)],
+        &[("&&", BinOp::LogicalAnd)],
+        &[("|", BinOp::Or)],
+        &[("^", BinOp::Xor)],
+        &[("&", BinOp::And)],
+        &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+        &[
+            ("<=", BinOp::Le),
+            (">=", BinOp::Ge),
+            ("<", BinOp::Lt),
+            (">", BinOp::Gt),
+        ],
+        &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+        &[("+", BinOp::Add), ("-", BinOp::Sub)],
+        &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Num(i64),
+    Var(Var),
+    Reg(Box<Node>),
+    Byte(Box<Node>),
+    Word(Box<Node>),
+    Unary(UnOp, Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression, which displays as its source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+impl Expr {
+    /// Parses an expression, resolving any symbols it refers to.
+    pub fn parse(source: &str, symbols: &Symbols) -> Result<Self, ExprError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            symbols,
+        };
+        let root = parser.expr(0)?;
+        if let Some(token) = parser.tokens.first() {
+            return Err(ExprError::Unexpected(token.to_string()));
+        }
+        Ok(Expr {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval(
+        &self,
+        cpu: &Cdp1802,
+        pins: Cdp1802Pins,
+        bus: &impl Device,
+    ) -> Result<i64, ExprError> {
+        eval(&self.root, cpu, pins, bus)
+    }
+}
+
+fn eval(
+    node: &Node,
+    cpu: &Cdp1802,
+    pins: Cdp1802Pins,
+    bus: &impl Device,
+) -> Result<i64, ExprError> {
+    let eval = |node| eval(node, cpu, pins, bus);
+    let addr = |node| eval(node).map(|v| v as u16);
+    let value = match node {
+        Node::Num(n) => *n,
+        Node::Var(var) => match *var {
+            Var::D => cpu.d.into(),
+            Var::Df => cpu.df.into(),
+            Var::P => cpu.p.into(),
+            Var::X => cpu.x.into(),
+            Var::T => cpu.t.into(),
+            Var::Ie => cpu.ie.into(),
+            Var::Xie => cpu.xie.into(),
+            Var::Cie => cpu.cie.into(),
+            Var::Pc => cpu.rp().into(),
+            Var::Q => pins.get_q().into(),
+            // The flag inputs and requests are active low.
+            Var::Ef(1) => (!pins.get_ef1()).into(),
+            Var::Ef(2) => (!pins.get_ef2()).into(),
+            Var::Ef(3) => (!pins.get_ef3()).into(),
+            Var::Ef(_) => (!pins.get_ef4()).into(),
+            Var::Intr => (!pins.get_intr()).into(),
+            Var::DmaIn => (!pins.get_dma_in()).into(),
+            Var::DmaOut => (!pins.get_dma_out()).into(),
+        },
+        Node::Reg(n) => cpu.r[(eval(n)? & 0xf) as usize].into(),
+        Node::Byte(a) => bus.peek(addr(a)?).into(),
+        Node::Word(a) => {
+            let a = addr(a)?;
+            u16::from_be_bytes([bus.peek(a), bus.peek(a.wrapping_add(1))]).into()
+        }
+        Node::Unary(op, a) => {
+            let a = eval(a)?;
+            match op {
+                UnOp::Not => (a == 0).into(),
+                UnOp::Compl => !a,
+                UnOp::Neg => a.wrapping_neg(),
+            }
+        }
+        // Logical operators short-circuit.
+        Node::Binary(BinOp::LogicalAnd, a, b) => (eval(a)? != 0 && eval(b)? != 0).into(),
+        Node::Binary(BinOp::LogicalOr, a, b) => (eval(a)? != 0 || eval(b)? != 0).into(),
+        Node::Binary(op, a, b) => {
+            let (a, b) = (eval(a)?, eval(b)?);
+            match op {
+                BinOp::Mul => a.wrapping_mul(b),
+                BinOp::Div | BinOp::Rem if b == 0 => return Err(ExprError::DivideByZero),
+                BinOp::Div => a.wrapping_div(b),
+                BinOp::Rem => a.wrapping_rem(b),
+                BinOp::Add => a.wrapping_add(b),
+                BinOp::Sub => a.wrapping_sub(b),
+                BinOp::Shl => a.wrapping_shl(b as u32),
+                BinOp::Shr => a.wrapping_shr(b as u32),
+                BinOp::Lt => (a < b).into(),
+                BinOp::Le => (a <= b).into(),
+                BinOp::Gt => (a > b).into(),
+                BinOp::Ge => (a >= b).into(),
+                BinOp::Eq => (a == b).into(),
+                BinOp::Ne => (a != b).into(),
+                BinOp::And => a & b,
+                BinOp::Xor => a ^ b,
+                BinOp::Or => a | b,
+                BinOp::LogicalAnd | BinOp::LogicalOr => unreachable!(),
+            }
+        }
+    };
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Punct(&'static str),
+}
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{n}"),
+            Token::Ident(s) => f.write_str(s),
+            Token::Punct(p) => f.write_str(p),
+        }
+    }
+}
+
+/// Punctuation, with longer tokens first so that they're matched in preference.
+const PUNCT: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = vec![];
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            tokens.push(if c.is_ascii_digit() {
+                let value = match word.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                Token::Num(value.map_err(|_| ExprError::InvalidNumber(word.to_string()))?)
+            } else {
+                Token::Ident(word.to_string())
+            });
+            len
+        } else {
+            let Some(punct) = PUNCT.iter().find(|p| rest.starts_with(**p)) else {
+                return Err(ExprError::Unexpected(c.to_string()));
+            };
+            tokens.push(Token::Punct(punct));
+            punct.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    symbols: &'a Symbols,
+}
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Result<&'a Token, ExprError> {
+        let (token, rest) = self.tokens.split_first().ok_or(ExprError::UnexpectedEnd)?;
+        self.tokens = rest;
+        Ok(token)
+    }
+
+    /// Consumes the punctuation if it's next.
+    fn eat(&mut self, punct: &str) -> bool {
+        match self.tokens.first() {
+            Some(Token::Punct(p)) if *p == punct => {
+                self.tokens = &self.tokens[1..];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ExprError> {
+        match self.next()? {
+            Token::Punct(p) if *p == punct => Ok(()),
+            token => Err(ExprError::Unexpected(token.to_string())),
+        }
+    }
+
+    /// Parses binary operators at the precedence level, and above.
+    fn expr(&mut self, level: usize) -> Result<Node, ExprError> {
+        let Some(ops) = BinOp::LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut node = self.expr(level + 1)?;
+        'outer: loop {
+            for &(punct, op) in *ops {
+                if self.eat(punct) {
+                    let rhs = self.expr(level + 1)?;
+                    node = Node::Binary(op, Box::new(node), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(node);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        for (punct, op) in [("!", UnOp::Not), ("~", UnOp::Compl), ("-", UnOp::Neg)] {
+            if self.eat(punct) {
+                return Ok(Node::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    /// Parses a bracketed index, e.g. `[7]`.
+    fn index(&mut self) -> Result<Box<Node>, ExprError> {
+        self.expect("[")?;
+        let node = self.expr(0)?;
+        self.expect("]")?;
+        Ok(Box::new(node))
+    }
+
+    fn primary(&mut self) -> Result<Node, ExprError> {
+        let node = match self.next()? {
+            Token::Num(n) => Node::Num(*n),
+            Token::Punct("(") => {
+                let node = self.expr(0)?;
+                self.expect(")")?;
+                node
+            }
+            Token::Ident(name) => {
+                let lower = name.to_ascii_lowercase();
+                let indexed = matches!(self.tokens.first(), Some(Token::Punct("[")));
+                match lower.as_str() {
+                    "r" if indexed => Node::Reg(self.index()?),
+                    "m" if indexed => Node::Byte(self.index()?),
+                    "w" if indexed => Node::Word(self.index()?),
+                    _ => match variable(&lower) {
+                        Some(node) => node,
+                        None => Node::Num(
+                            self.symbols
+                                .get(name)
+                                .ok_or_else(|| ExprError::Undefined(name.clone()))?
+                                .into(),
+                        ),
+                    },
+                }
+            }
+            token => return Err(ExprError::Unexpected(token.to_string())),
+        };
+        Ok(node)
+    }
+}
+
+/// Returns the register, flag or pin with the (lowercase) name.
+fn variable(name: &str) -> Option<Node> {
+    let var = match name {
+        "d" => Var::D,
+        "df" => Var::Df,
+        "p" => Var::P,
+        "x" => Var::X,
+        "t" => Var::T,
+        "ie" => Var::Ie,
+        "xie" => Var::Xie,
+        "cie" => Var::Cie,
+        "pc" => Var::Pc,
+        "q" => Var::Q,
+        "ef1" => Var::Ef(1),
+        "ef2" => Var::Ef(2),
+        "ef3" => Var::Ef(3),
+        "ef4" => Var::Ef(4),
+        "intr" => Var::Intr,
+        "dma_in" => Var::DmaIn,
+        "dma_out" => Var::DmaOut,
+        _ => {
+            let n = name.strip_prefix('r').filter(|n| n.len() == 1)?;
+            let n = u8::from_str_radix(n, 16).ok()?;
+            return Some(Node::Reg(Box::new(Node::Num(n.into()))));
+        }
+    };
+    Some(Node::Var(var))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::cdp1802::{Memory, Variant};
+
+    fn eval(source: &str) -> Result<i64, ExprError> {
+        let symbols = Symbols::parse("BUF 0080").unwrap();
+        let mut cpu = Cdp1802::new(Variant::Cdp1802);
+        cpu.r[7] = 0x1234;
+        cpu.p = 3;
+        cpu.r[3] = 0x0100;
+        cpu.d = 0x42;
+        cpu.df = true;
+        let mut pins = Cdp1802Pins::default();
+        pins.set_ef1(true);
+        pins.set_ef2(false);
+        let memory = Memory::builder()
+            .with_image(0x80, [0xde, 0xad, 0xbe, 0xef])
+            .build()
+            .unwrap();
+        Expr::parse(source, &symbols)?.eval(&cpu, pins, &memory)
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("r[7] == 0x1234 && df").unwrap(), 1);
+        assert_eq!(eval("r7 + 1").unwrap(), 0x1235);
+        assert_eq!(eval("R[3 + 4] >> 8").unwrap(), 0x12);
+        assert_eq!(eval("pc").unwrap(), 0x100);
+        assert_eq!(eval("d & 0xf0 | 1").unwrap(), 0x41);
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2").unwrap(), 5);
+        assert_eq!(eval("(1 + 2) * 3 % 4").unwrap(), 1);
+        assert_eq!(eval("!df || -1 < 0").unwrap(), 1);
+        assert_eq!(eval("~0").unwrap(), -1);
+        assert_eq!(eval("1 < 2 == 2 > 1").unwrap(), 1);
+        assert_eq!(eval("m[BUF]").unwrap(), 0xde);
+        assert_eq!(eval("w[buf + 1]").unwrap(), 0xadbe);
+        assert_eq!(eval("ef1").unwrap(), 0);
+        assert_eq!(eval("ef2").unwrap(), 1);
+        assert_eq!(eval("0 && 1 / 0").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(eval("1 / 0"), Err(ExprError::DivideByZero)));
+        assert!(matches!(eval("nope"), Err(ExprError::Undefined(_))));
+        assert!(matches!(eval("1 +"), Err(ExprError::UnexpectedEnd)));
+        assert!(matches!(eval("(1"), Err(ExprError::UnexpectedEnd)));
+        assert!(matches!(eval("1 2"), Err(ExprError::Unexpected(_))));
+        assert!(matches!(eval("0x"), Err(ExprError::InvalidNumber(_))));
+        assert!(matches!(eval("d = 1"), Err(ExprError::Unexpected(_))));
+        let expr = Expr::parse(" r[7] == 0x1234 ", &Symbols::default()).unwrap();
+        assert_eq!(expr.to_string(), "r[7] == 0x1234");
+    }
+}
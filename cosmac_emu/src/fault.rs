@@ -0,0 +1,171 @@
+//! Write protection faults
+//!
+//! Writes to ROM, or to write-protected RAM, raise a [`MemoryAccessError::WriteProtectionFault`].
+//! How a system reacts is determined by its [`FaultPolicy`], and faults are tallied in a
+//! [`FaultLog`] so that they can be summarized after the fact.
+//!
+//! [`MemoryAccessError::WriteProtectionFault`]: crate::bus::MemoryAccessError::WriteProtectionFault
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// What to do about a write protection fault.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FaultPolicy {
+    /// Silently drop the write.
+    Ignore,
+    /// Report each fault as it happens.
+    Log,
+    /// Count faults, to be summarized later.
+    #[default]
+    Count,
+    /// Stop running, and exit with an error.
+    Halt,
+    /// Stop running, and break into the debugger.
+    Break,
+}
+
+/// A write protection fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fault {
+    /// The address of the write.
+    pub addr: u16,
+    /// The address of the instruction that made the write.
+    pub pc: u16,
+}
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "write protection fault at {:04x} (pc {:04x})",
+            self.addr, self.pc
+        )
+    }
+}
+
+/// Applies a fault policy, and counts faults by address and PC.
+#[derive(Debug, Default, Clone)]
+pub struct FaultLog {
+    policy: FaultPolicy,
+    counts: BTreeMap<Fault, u64>,
+}
+impl FaultLog {
+    pub fn new(policy: FaultPolicy) -> Self {
+        Self {
+            policy,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> FaultPolicy {
+        self.policy
+    }
+
+    /// Records a fault, and returns true if the policy calls for execution to stop.
+    pub fn record(&mut self, fault: Fault) -> bool {
+        if self.policy != FaultPolicy::Ignore {
+            *self.counts.entry(fault).or_default() += 1;
+        }
+        matches!(self.policy, FaultPolicy::Halt | FaultPolicy::Break)
+    }
+
+    /// Returns the total number of faults recorded.
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Prints a summary of faults, ordered by address.
+    pub fn print_summary(&self) {
+        if self.counts.is_empty() {
+            return;
+        }
+        eprintln!("write protection faults: {}", self.total());
+        eprintln!("addr pc   count");
+        for (fault, count) in &self.counts {
+            eprintln!("{:04x} {:04x} {count}", fault.addr, fault.pc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::bus::{DeviceBus, Rom};
+    use crate::chips::cdp1802::{Cdp1802, Core, Memory, Variant};
+    use crate::systems::basic::{BasicSystem, Status};
+
+    /// Writes to ROM at 0x8000 twice, and then idles.
+    const PROGRAM: [u8; 6] = [
+        0xf8, 0x80, // 0000 ldi 0x80
+        0xb4, //       0002 phi 4
+        0x54, //       0003 str 4
+        0x54, //       0004 str 4
+        0x00, //       0005 idl
+    ];
+
+    fn system(core: Core, policy: FaultPolicy) -> BasicSystem {
+        let memory = Memory::builder().with_image(0, PROGRAM).build().unwrap();
+        let bus = DeviceBus::from(memory).with_device(0x8000..=0x8000, Rom::new([0]));
+        let cpu = Cdp1802::new(Variant::Cdp1802);
+        BasicSystem::new(cpu, bus, Duration::from_micros(1))
+            .with_core(core)
+            .with_fault_policy(policy)
+    }
+
+    fn run(sys: &mut BasicSystem) -> Status {
+        loop {
+            match sys.step() {
+                Status::Ready => (),
+                status => return status,
+            }
+        }
+    }
+
+    #[test]
+    fn test_basic_system() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = system(core, FaultPolicy::Count);
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            assert_eq!(sys.faults().total(), 2, "{core:?}");
+
+            let mut sys = system(core, FaultPolicy::Halt);
+            let fault = Fault {
+                addr: 0x8000,
+                pc: 0x0003,
+            };
+            assert_matches!(run(&mut sys), Status::Fault(f) if f == fault, "{core:?}");
+            assert_eq!(sys.cpu().rp(), 0x0004);
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let fault = Fault {
+            addr: 0x8000,
+            pc: 0x0010,
+        };
+        let mut log = FaultLog::new(FaultPolicy::Ignore);
+        assert!(!log.record(fault));
+        assert_eq!(log.total(), 0);
+
+        let mut log = FaultLog::new(FaultPolicy::Count);
+        assert!(!log.record(fault));
+        assert!(!log.record(fault));
+        assert!(!log.record(Fault {
+            pc: 0x0020,
+            ..fault
+        }));
+        assert_eq!(log.counts[&fault], 2);
+        assert_eq!(log.total(), 3);
+
+        for policy in [FaultPolicy::Halt, FaultPolicy::Break] {
+            let mut log = FaultLog::new(policy);
+            assert!(log.record(fault));
+            assert_eq!(log.total(), 1);
+        }
+    }
+}
@@ -0,0 +1,514 @@
+//! Memory images
+//!
+//! An image is loaded from a raw binary, an Intel HEX file, or a Motorola S-record file. Raw
+//! binaries are loaded at a base address supplied by the user, whereas the text formats carry
+//! their own load addresses, which may describe any number of discontiguous segments.
+//!
+//! Images can also be written in any of these formats, for exporting memory contents.
+
+use std::io::Write;
+use std::path::Path;
+
+/// Maximum number of data bytes per record, when writing text formats.
+const RECORD_LEN: usize = 16;
+
+/// The file format of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    /// Raw binary.
+    Bin,
+    /// Intel HEX.
+    Ihex,
+    /// Motorola S-record.
+    Srec,
+}
+impl ImageFormat {
+    /// Returns the format implied by a file extension, if any.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("hex" | "ihex" | "ihx") => Some(Self::Ihex),
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => Some(Self::Srec),
+            Some("bin") => Some(Self::Bin),
+            _ => None,
+        }
+    }
+
+    /// Detects the format of an image from its file extension, or failing that, its contents.
+    ///
+    /// Content detection only recognizes a text format if the whole file is printable ASCII,
+    /// since `:` and `S` are perfectly good opcodes.
+    pub fn detect(path: &Path, data: &[u8]) -> Self {
+        if let Some(format) = Self::from_path(path) {
+            return format;
+        }
+        let is_text = data
+            .iter()
+            .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace());
+        let mut text = data.trim_ascii_start().iter();
+        match (is_text, text.next(), text.next()) {
+            (true, Some(b':'), Some(b)) if b.is_ascii_hexdigit() => Self::Ihex,
+            (true, Some(b'S'), Some(b)) if b.is_ascii_digit() => Self::Srec,
+            _ => Self::Bin,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImageError {
+    #[error("line {0}: {1}")]
+    Syntax(usize, &'static str),
+    #[error("line {line}: checksum mismatch (expected 0x{expected:02x}, found 0x{found:02x})")]
+    Checksum {
+        line: usize,
+        expected: u8,
+        found: u8,
+    },
+    #[error("line {0}: unsupported record type {1}")]
+    RecordType(usize, String),
+    #[error("line {0}: address 0x{1:x} out of range")]
+    OutOfRange(usize, u32),
+    #[error("missing end-of-file record")]
+    MissingEof,
+}
+
+/// The byte that fills gaps between segments in raw binaries.
+const BIN_FILL: u8 = 0x00;
+
+/// A contiguous run of bytes at a load address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u16,
+    pub data: Vec<u8>,
+}
+
+/// A memory image, made up of one or more segments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    /// Segments, in the order they appear in the file. Later segments may overlap earlier ones.
+    pub segments: Vec<Segment>,
+    /// The start address, if the image specifies one.
+    pub start: Option<u16>,
+}
+impl Image {
+    /// Parses an image. The base address only applies to raw binaries.
+    pub fn parse(format: ImageFormat, base_addr: u16, data: Vec<u8>) -> Result<Self, ImageError> {
+        match format {
+            ImageFormat::Bin => Ok(Self::from_bin(base_addr, data)),
+            ImageFormat::Ihex => Self::parse_ihex(&String::from_utf8_lossy(&data)),
+            ImageFormat::Srec => Self::parse_srec(&String::from_utf8_lossy(&data)),
+        }
+    }
+
+    /// Creates an image from a raw binary.
+    pub fn from_bin(addr: u16, data: Vec<u8>) -> Self {
+        let segments = if data.is_empty() {
+            vec![]
+        } else {
+            vec![Segment { addr, data }]
+        };
+        Self {
+            segments,
+            start: None,
+        }
+    }
+
+    /// Parses an Intel HEX file.
+    pub fn parse_ihex(text: &str) -> Result<Self, ImageError> {
+        let mut image = Self::default();
+        let mut base: u32 = 0;
+        for (line, record) in records(text) {
+            let Some(hex) = record.strip_prefix(':') else {
+                return Err(ImageError::Syntax(line, "expected ':'"));
+            };
+            let bytes = decode_hex(line, hex)?;
+            if bytes.len() < 5 || bytes.len() != usize::from(bytes[0]) + 5 {
+                return Err(ImageError::Syntax(line, "bad record length"));
+            }
+            let (body, checksum) = bytes.split_at(bytes.len() - 1);
+            let sum = body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            verify_checksum(line, sum.wrapping_neg(), checksum[0])?;
+
+            let offset = u32::from(u16::from_be_bytes([body[1], body[2]]));
+            let data = &body[4..];
+            match (body[3], data.len()) {
+                (0x00, _) => image.push(line, base + offset, data)?,
+                (0x01, _) => return Ok(image),
+                (0x02, 2) => base = u32::from(u16::from_be_bytes([data[0], data[1]])) << 4,
+                (0x04, 2) => base = u32::from(u16::from_be_bytes([data[0], data[1]])) << 16,
+                (0x03, 4) => {
+                    let cs = u32::from(u16::from_be_bytes([data[0], data[1]]));
+                    let ip = u32::from(u16::from_be_bytes([data[2], data[3]]));
+                    image.set_start(line, (cs << 4) + ip)?;
+                }
+                (0x05, 4) => image.set_start(
+                    line,
+                    u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                )?,
+                (0x02..=0x05, _) => return Err(ImageError::Syntax(line, "bad record length")),
+                (kind, _) => return Err(ImageError::RecordType(line, format!("{kind:02x}"))),
+            }
+        }
+        Err(ImageError::MissingEof)
+    }
+
+    /// Parses a Motorola S-record file.
+    pub fn parse_srec(text: &str) -> Result<Self, ImageError> {
+        let mut image = Self::default();
+        for (line, record) in records(text) {
+            let mut chars = record.chars();
+            if chars.next() != Some('S') {
+                return Err(ImageError::Syntax(line, "expected 'S'"));
+            }
+            let Some(kind) = chars.next() else {
+                return Err(ImageError::Syntax(line, "missing record type"));
+            };
+            let bytes = decode_hex(line, chars.as_str())?;
+            if bytes.is_empty() || bytes.len() != usize::from(bytes[0]) + 1 {
+                return Err(ImageError::Syntax(line, "bad record length"));
+            }
+            let (body, checksum) = bytes.split_at(bytes.len() - 1);
+            let sum = body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            verify_checksum(line, !sum, checksum[0])?;
+
+            let addr_len = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(ImageError::RecordType(line, format!("S{kind}"))),
+            };
+            if body.len() < addr_len + 1 {
+                return Err(ImageError::Syntax(line, "bad record length"));
+            }
+            let (addr, data) = body[1..].split_at(addr_len);
+            let addr = addr.iter().fold(0u32, |acc, b| (acc << 8) | u32::from(*b));
+            match kind {
+                '1' | '2' | '3' => image.push(line, addr, data)?,
+                '7' | '8' | '9' => image.set_start(line, addr)?,
+                _ => (),
+            }
+        }
+        Ok(image)
+    }
+
+    /// Writes the image. Raw binaries start at the lowest segment address, with gaps filled.
+    pub fn write(&self, format: ImageFormat, mut w: impl Write) -> std::io::Result<()> {
+        match format {
+            ImageFormat::Bin => w.write_all(&self.to_bin())?,
+            ImageFormat::Ihex => self.write_ihex(&mut w)?,
+            ImageFormat::Srec => self.write_srec(&mut w)?,
+        }
+        w.flush()
+    }
+
+    /// Returns the bytes from the lowest segment address to the end of the highest segment.
+    fn to_bin(&self) -> Vec<u8> {
+        let Some(base) = self.segments.iter().map(|s| s.addr).min() else {
+            return vec![];
+        };
+        let mut bin = vec![];
+        for segment in &self.segments {
+            let start = usize::from(segment.addr - base);
+            let end = start + segment.data.len();
+            if bin.len() < end {
+                bin.resize(end, BIN_FILL);
+            }
+            bin[start..end].copy_from_slice(&segment.data);
+        }
+        bin
+    }
+
+    fn write_ihex(&self, w: &mut impl Write) -> std::io::Result<()> {
+        for (addr, chunk) in self.records() {
+            let mut record = vec![chunk.len() as u8];
+            record.extend(addr.to_be_bytes());
+            record.push(0x00);
+            record.extend(chunk);
+            write_record(w, ":", &record, |sum| sum.wrapping_neg())?;
+        }
+        if let Some(start) = self.start {
+            let mut record = vec![4, 0, 0, 0x05, 0, 0];
+            record.extend(start.to_be_bytes());
+            write_record(w, ":", &record, |sum| sum.wrapping_neg())?;
+        }
+        writeln!(w, ":00000001FF")
+    }
+
+    fn write_srec(&self, w: &mut impl Write) -> std::io::Result<()> {
+        writeln!(w, "S0030000FC")?;
+        let mut count = 0u32;
+        for (addr, chunk) in self.records() {
+            let mut record = vec![chunk.len() as u8 + 3];
+            record.extend(addr.to_be_bytes());
+            record.extend(chunk);
+            write_record(w, "S1", &record, |sum| !sum)?;
+            count += 1;
+        }
+        if let Ok(count) = u16::try_from(count) {
+            let [hi, lo] = count.to_be_bytes();
+            write_record(w, "S5", &[3, hi, lo], |sum| !sum)?;
+        }
+        let [hi, lo] = self.start.unwrap_or(0).to_be_bytes();
+        write_record(w, "S9", &[3, hi, lo], |sum| !sum)
+    }
+
+    /// Returns the address and data of each record, when writing text formats.
+    fn records(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.segments.iter().flat_map(|segment| {
+            segment
+                .data
+                .chunks(RECORD_LEN)
+                .enumerate()
+                .map(|(n, chunk)| (segment.addr.wrapping_add((n * RECORD_LEN) as u16), chunk))
+        })
+    }
+
+    /// Appends data at an address, extending the last segment if it's contiguous.
+    fn push(&mut self, line: usize, addr: u32, data: &[u8]) -> Result<(), ImageError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = addr.checked_add(data.len() as u32 - 1);
+        if end.is_none_or(|end| end > u32::from(u16::MAX)) {
+            return Err(ImageError::OutOfRange(line, addr.max(0x1_0000)));
+        }
+        match self.segments.last_mut() {
+            Some(seg) if u32::from(seg.addr) + seg.data.len() as u32 == addr => {
+                seg.data.extend_from_slice(data)
+            }
+            _ => self.segments.push(Segment {
+                addr: addr as u16,
+                data: data.to_vec(),
+            }),
+        }
+        Ok(())
+    }
+
+    fn set_start(&mut self, line: usize, addr: u32) -> Result<(), ImageError> {
+        let addr = u16::try_from(addr).map_err(|_| ImageError::OutOfRange(line, addr))?;
+        self.start = Some(addr);
+        Ok(())
+    }
+}
+
+/// Returns the non-blank lines of a text image, with 1-based line numbers.
+fn records(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+fn decode_hex(line: usize, hex: &str) -> Result<Vec<u8>, ImageError> {
+    if hex.len() % 2 != 0 {
+        return Err(ImageError::Syntax(line, "odd number of hex digits"));
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .ok_or(ImageError::Syntax(line, "invalid hex digit"))
+        })
+        .collect()
+}
+
+/// Writes a text record, followed by its checksum.
+fn write_record(
+    w: &mut impl Write,
+    prefix: &str,
+    bytes: &[u8],
+    checksum: impl Fn(u8) -> u8,
+) -> std::io::Result<()> {
+    write!(w, "{prefix}")?;
+    for byte in bytes {
+        write!(w, "{byte:02X}")?;
+    }
+    let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    writeln!(w, "{:02X}", checksum(sum))
+}
+
+fn verify_checksum(line: usize, expected: u8, found: u8) -> Result<(), ImageError> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(ImageError::Checksum {
+            line,
+            expected,
+            found,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let detect = |path: &str, data: &[u8]| ImageFormat::detect(Path::new(path), data);
+        assert_eq!(detect("a.hex", b""), ImageFormat::Ihex);
+        assert_eq!(detect("a.S19", b""), ImageFormat::Srec);
+        assert_eq!(detect("a.bin", b":00000001FF"), ImageFormat::Bin);
+        assert_eq!(detect("a", b"\n:00000001FF\n"), ImageFormat::Ihex);
+        assert_eq!(detect("a", b"S9030000FC\n"), ImageFormat::Srec);
+        assert_eq!(detect("a", b":\x00\x01"), ImageFormat::Bin);
+        assert_eq!(detect("a", b"Sx"), ImageFormat::Bin);
+    }
+
+    #[test]
+    fn test_ihex() {
+        let text = "\
+            :0300000002000AF1\n\
+            :02000300BEEF4E\n\
+            \n\
+            :020000040000FA\n\
+            :0280000001027B\r\n\
+            :00000001FF\n\
+            garbage\n";
+        let image = Image::parse_ihex(text).unwrap();
+        assert_eq!(
+            image.segments,
+            [
+                Segment {
+                    addr: 0x0000,
+                    data: vec![0x02, 0x00, 0x0a, 0xbe, 0xef]
+                },
+                Segment {
+                    addr: 0x8000,
+                    data: vec![0x01, 0x02]
+                },
+            ]
+        );
+        assert_eq!(image.start, None);
+
+        let image = Image::parse_ihex(":040000050000800077\n:00000001FF").unwrap();
+        assert_eq!(image.start, Some(0x8000));
+    }
+
+    #[test]
+    fn test_ihex_errors() {
+        assert_matches!(
+            Image::parse_ihex(":0300000002000AF2\n:00000001FF"),
+            Err(ImageError::Checksum {
+                line: 1,
+                expected: 0xf1,
+                found: 0xf2
+            })
+        );
+        assert_matches!(
+            Image::parse_ihex("\n0300000002000AF1"),
+            Err(ImageError::Syntax(2, _))
+        );
+        assert_matches!(
+            Image::parse_ihex(":0400000002000AF1"),
+            Err(ImageError::Syntax(1, "bad record length"))
+        );
+        assert_matches!(
+            Image::parse_ihex(":020000040001F9\n:0100000000FF"),
+            Err(ImageError::OutOfRange(2, 0x10000))
+        );
+        assert_matches!(
+            Image::parse_ihex(":02000004FFFFFC\n:02FFFF000102FD"),
+            Err(ImageError::OutOfRange(2, 0xffff_ffff))
+        );
+        assert_matches!(
+            Image::parse_ihex(":0100000600F9"),
+            Err(ImageError::RecordType(1, _))
+        );
+        assert_matches!(
+            Image::parse_ihex(":0300000002000AF1"),
+            Err(ImageError::MissingEof)
+        );
+    }
+
+    #[test]
+    fn test_srec() {
+        let text = "\
+            S00600004844521B\n\
+            S1060000C08003B6\n\
+            S10480007B00\n\
+            S5030002FA\n\
+            S90380007C\n";
+        let image = Image::parse_srec(text).unwrap();
+        assert_eq!(
+            image.segments,
+            [
+                Segment {
+                    addr: 0x0000,
+                    data: vec![0xc0, 0x80, 0x03]
+                },
+                Segment {
+                    addr: 0x8000,
+                    data: vec![0x7b]
+                },
+            ]
+        );
+        assert_eq!(image.start, Some(0x8000));
+
+        let image = Image::parse_srec("S2050080007BFF").unwrap();
+        assert_eq!(image.segments[0].addr, 0x8000);
+    }
+
+    #[test]
+    fn test_write() {
+        let image = Image {
+            segments: vec![
+                Segment {
+                    addr: 0x0000,
+                    data: vec![0x02, 0x00, 0x0a],
+                },
+                Segment {
+                    addr: 0x8000,
+                    data: (0..40).collect(),
+                },
+            ],
+            start: Some(0x8000),
+        };
+
+        let mut buf = vec![];
+        image.write(ImageFormat::Ihex, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with(":0300000002000AF1\n:10800000000102"));
+        assert!(text.ends_with(":040000050000800077\n:00000001FF\n"));
+        assert_eq!(Image::parse_ihex(&text).unwrap(), image);
+
+        let mut buf = vec![];
+        image.write(ImageFormat::Srec, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("\nS5030004F8\nS90380007C\n"));
+        assert_eq!(Image::parse_srec(&text).unwrap(), image);
+
+        let mut buf = vec![];
+        image.write(ImageFormat::Bin, &mut buf).unwrap();
+        assert_eq!(buf.len(), 0x8028);
+        assert_eq!(buf[..4], [0x02, 0x00, 0x0a, BIN_FILL]);
+        assert_eq!(buf[0x7fff..0x8002], [BIN_FILL, 0, 1]);
+    }
+
+    #[test]
+    fn test_srec_errors() {
+        assert_matches!(
+            Image::parse_srec("S1060000C0800350"),
+            Err(ImageError::Checksum { line: 1, .. })
+        );
+        assert_matches!(
+            Image::parse_srec("S4030000FC"),
+            Err(ImageError::RecordType(1, _))
+        );
+        assert_matches!(
+            Image::parse_srec("S2050100007B7E"),
+            Err(ImageError::OutOfRange(1, 0x10000))
+        );
+        assert_matches!(
+            Image::parse_srec("S1050000C0"),
+            Err(ImageError::Syntax(1, "bad record length"))
+        );
+    }
+}
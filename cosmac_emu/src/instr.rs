@@ -3,16 +3,54 @@
 use cosmac_emu_macros::InstrSchema;
 use itertools::Itertools;
 
+use crate::chips::cdp1802::Variant;
+
+/// The size of the largest instructions, in bytes: the CDP1804/1805/1806 `68 cn hh ll` forms.
+pub const MAX_INSTR_SIZE: u16 = 4;
+
 /// The interface for a instruction.
-pub trait InstrSchema: Sized {
-    /// Decodes an instruction from bytes.
-    fn decode(bin: &[u8]) -> Option<Self>;
+pub trait InstrSchema: Sized + 'static {
+    /// Decodes an instruction from bytes, by looking up its opcode in the opcode table.
+    fn decode(bin: &[u8]) -> Option<Self> {
+        let (&first, rest) = bin.split_first()?;
+        // Fall back to the plain opcode if a prefixed one isn't recognized.
+        if let Some((&second, operands)) = rest.split_first()
+            && let Some(instr) = Self::opcode(Some(first), second).and_then(|o| o.decode(operands))
+        {
+            return Some(instr);
+        }
+        Self::opcode(None, first)?.decode(rest)
+    }
+    /// Decodes an instruction from bytes by matching on them, as a reference for `decode`.
+    #[cfg(test)]
+    fn decode_match(bin: &[u8]) -> Option<Self>;
     /// Returns a string representation of the instruction.
     fn disasm(&self) -> String;
     /// Encodes the instruction into bytes.
     fn encode(&self) -> Vec<u8>;
     /// Returns the size of the instruction, in bytes.
     fn size(&self) -> u8;
+    /// Returns true if the instruction is part of the CDP1804/1805/1806 extended instruction
+    /// set, i.e., its opcode is prefixed with 0x68.
+    fn is_extended(&self) -> bool;
+    /// Returns true for short branches, whose target is an address within the page of the
+    /// branch's immediate operand.
+    fn is_short_branch(&self) -> bool;
+    /// Returns the mnemonic of the instruction.
+    fn mnemonic(&self) -> &'static str;
+    /// Returns the fields of the instruction: the packed register (if any), followed by the
+    /// immediate operand bytes.
+    fn fields(&self) -> Vec<u8>;
+    /// Returns the operand layout of the instruction with the given mnemonic.
+    fn layout(mnemonic: &str) -> Option<Layout>;
+    /// Constructs an instruction from its mnemonic and fields: the packed register (if any),
+    /// followed by the immediate operand bytes.
+    fn from_fields(mnemonic: &str, fields: &[u8]) -> Option<Self>;
+    /// Returns the instruction's timing, control flow, and the state it accesses.
+    fn meta(&self) -> Meta;
+    /// Returns the opcode table entry for an opcode byte, following the given prefix byte, if
+    /// any.
+    fn opcode(prefix: Option<u8>, opcode: u8) -> Option<&'static Opcode<Self>>;
     /// Returns a "listing", with the encoded and disassembled instruction side-by-side.
     fn listing(&self) -> String {
         let enc = self
@@ -25,168 +63,407 @@ pub trait InstrSchema: Sized {
     }
 }
 
+/// The operands of an instruction, as they appear in its encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// A mask of the values the register packed into the opcode may take, or zero if the
+    /// instruction has no packed register.
+    pub registers: u16,
+    /// The number of immediate operand bytes that follow the opcode.
+    pub immediates: u8,
+    /// The size of the instruction, in bytes.
+    pub size: u8,
+}
+
+/// An entry in the opcode table, describing the instruction that an opcode byte encodes.
+#[derive(Debug, Clone, Copy)]
+pub struct Opcode<I> {
+    pub layout: Layout,
+    /// The register packed into the opcode, or zero if there is none.
+    pub register: u8,
+    /// Constructs the instruction from the packed register and the immediate operand bytes.
+    pub build: fn(u8, &[u8]) -> I,
+}
+impl<I> Opcode<I> {
+    /// Decodes the instruction, given the bytes that follow the opcode.
+    pub fn decode(&self, operands: &[u8]) -> Option<I> {
+        let immediates = operands.get(..self.layout.immediates.into())?;
+        Some((self.build)(self.register, immediates))
+    }
+}
+
+/// Facts about an instruction, declared alongside its schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Meta {
+    /// The number of machine cycles, including fetch.
+    pub cycles: u8,
+    pub flow: Flow,
+    /// Registers and flags the instruction reads.
+    pub reads: Operands,
+    /// Registers and flags the instruction writes.
+    pub writes: Operands,
+    /// Memory accessed by the execute cycles, including immediate operands.
+    pub mem: Mem,
+    pub io: Io,
+}
+
+/// Where control goes after an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// The next instruction.
+    Next,
+    /// The branch target.
+    Branch,
+    /// The branch target, or the next instruction.
+    BranchIf,
+    /// Past the given number of bytes following the instruction.
+    Skip(u8),
+    /// Past the given number of bytes following the instruction, or the next instruction.
+    SkipIf(u8),
+    /// A subroutine, by switching the program counter (SEP) or by a standard call (SCAL).
+    Call,
+    /// The caller, or the code that was interrupted.
+    Return,
+}
+
+/// Memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mem {
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// I/O access. OUT reads memory onto the bus, and INP writes the bus to memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Io {
+    None,
+    Out,
+    Inp,
+}
+
+/// A set of registers and flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Operands(u32);
+impl Operands {
+    pub const NONE: Self = Self(0);
+    pub const D: Self = Self(1 << 0);
+    pub const DF: Self = Self(1 << 1);
+    pub const Q: Self = Self(1 << 2);
+    pub const IE: Self = Self(1 << 3);
+    pub const T: Self = Self(1 << 4);
+    pub const X: Self = Self(1 << 5);
+    pub const P: Self = Self(1 << 6);
+    /// The register packed into the opcode.
+    pub const N: Self = Self(1 << 7);
+    /// The register selected by X.
+    pub const RX: Self = Self(1 << 8);
+    /// The register selected by P, i.e. the program counter.
+    pub const RP: Self = Self(1 << 9);
+    pub const R0: Self = Self(1 << 10);
+    pub const R2: Self = Self(1 << 11);
+    /// The EF1-4 flag inputs.
+    pub const EF: Self = Self(1 << 12);
+    /// The external interrupt input.
+    pub const INT: Self = Self(1 << 13);
+    /// The CDP1804/1805/1806 counter/timer, including its mode and interrupt.
+    pub const CNTR: Self = Self(1 << 14);
+    /// The CDP1804/1805/1806 external interrupt enable.
+    pub const XIE: Self = Self(1 << 15);
+    /// The CDP1804/1805/1806 counter interrupt enable.
+    pub const CIE: Self = Self(1 << 16);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// The instruction set. The `asm1802!` macro assembles source at compile time, e.g.
+/// `asm1802! { ldi 0x08; plo r2; loop: dec r2; glo r2; bnz loop }`, returning the encoded bytes
+/// and the labels.
 #[derive(Debug, Clone, Copy, InstrSchema)]
+#[asm_macro(asm1802)]
 pub enum Instr {
-    #[schema("00")]
+    #[schema("00", reads(r0), mem = read)]
     Idl,
-    #[schema("0n")]
+    #[schema("0n", reads(n), writes(d), mem = read)]
     Ldn(u8),
-    #[schema("1n")]
+    #[schema("1n", reads(n), writes(n))]
     Inc(u8),
-    #[schema("2n")]
+    #[schema("2n", reads(n), writes(n))]
     Dec(u8),
-    #[schema("30 nn")]
+    #[schema("30 nn", flow = branch, reads(rp), writes(rp), mem = read)]
     Br(u8),
-    #[schema("31 nn")]
+    #[schema("31 nn", flow = branch_if, reads(q, rp), writes(rp), mem = read)]
     Bq(u8),
-    #[schema("32 nn")]
+    #[schema("32 nn", flow = branch_if, reads(d, rp), writes(rp), mem = read)]
     Bz(u8),
-    #[schema("33 nn")]
+    #[schema("33 nn", flow = branch_if, reads(df, rp), writes(rp), mem = read)]
     Bdf(u8),
-    #[schema("34 nn")]
+    #[schema("34 nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     B1(u8),
-    #[schema("35 nn")]
+    #[schema("35 nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     B2(u8),
-    #[schema("36 nn")]
+    #[schema("36 nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     B3(u8),
-    #[schema("37 nn")]
+    #[schema("37 nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     B4(u8),
-    #[schema("38")]
+    #[schema("38", flow = skip(1), reads(rp), writes(rp), mem = read)]
     Skp,
-    #[schema("39 nn")]
+    #[schema("39 nn", flow = branch_if, reads(q, rp), writes(rp), mem = read)]
     Bnq(u8),
-    #[schema("3a nn")]
+    #[schema("3a nn", flow = branch_if, reads(d, rp), writes(rp), mem = read)]
     Bnz(u8),
-    #[schema("3b nn")]
+    #[schema("3b nn", flow = branch_if, reads(df, rp), writes(rp), mem = read)]
     Bnf(u8),
-    #[schema("3c nn")]
+    #[schema("3c nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     Bn1(u8),
-    #[schema("3d nn")]
+    #[schema("3d nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     Bn2(u8),
-    #[schema("3e nn")]
+    #[schema("3e nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     Bn3(u8),
-    #[schema("3f nn")]
+    #[schema("3f nn", flow = branch_if, reads(ef, rp), writes(rp), mem = read)]
     Bn4(u8),
-    #[schema("4n")]
+    #[schema("4n", reads(n), writes(d, n), mem = read)]
     Lda(u8),
-    #[schema("5n")]
+    #[schema("5n", reads(d, n), mem = write)]
     Str(u8),
-    #[schema("60")]
+    #[schema("60", reads(x, rx), writes(rx))]
     Irx,
-    #[schema("6l")]
+    #[schema("6l", reads(x, rx), writes(rx), mem = read, io = out)]
     Out(u8),
     #[schema("68")]
     Resv68,
-    #[schema("6h")]
+    #[schema("6h", reads(x, rx), writes(d), mem = write, io = inp)]
     Inp(u8),
-    #[schema("70")]
+    #[schema("70", flow = return, reads(x, rx), writes(x, p, ie, rx), mem = read)]
     Ret,
-    #[schema("71")]
+    #[schema("71", flow = return, reads(x, rx), writes(x, p, ie, rx), mem = read)]
     Dis,
-    #[schema("72")]
+    #[schema("72", reads(x, rx), writes(d, rx), mem = read)]
     Ldxa,
-    #[schema("73")]
+    #[schema("73", reads(d, x, rx), writes(rx), mem = write)]
     Stxd,
-    #[schema("74")]
+    #[schema("74", reads(d, df, x, rx), writes(d, df), mem = read)]
     Adc,
-    #[schema("75")]
+    #[schema("75", reads(d, df, x, rx), writes(d, df), mem = read)]
     Sdb,
-    #[schema("76")]
+    #[schema("76", reads(d, df), writes(d, df))]
     Shrc,
-    #[schema("77")]
+    #[schema("77", reads(d, df, x, rx), writes(d, df), mem = read)]
     Smb,
-    #[schema("78")]
+    #[schema("78", reads(t, x, rx), mem = write)]
     Sav,
-    #[schema("79")]
+    #[schema("79", reads(x, p, r2), writes(t, x, r2), mem = write)]
     Mark,
-    #[schema("7a")]
+    #[schema("7a", writes(q))]
     Req,
-    #[schema("7b")]
+    #[schema("7b", writes(q))]
     Seq,
-    #[schema("7c nn")]
+    #[schema("7c nn", reads(d, df, rp), writes(d, df, rp), mem = read)]
     Adci(u8),
-    #[schema("7d nn")]
+    #[schema("7d nn", reads(d, df, rp), writes(d, df, rp), mem = read)]
     Sdbi(u8),
-    #[schema("7e")]
+    #[schema("7e", reads(d, df), writes(d, df))]
     Shlc,
-    #[schema("7f nn")]
+    #[schema("7f nn", reads(d, df, rp), writes(d, df, rp), mem = read)]
     Smbi(u8),
-    #[schema("8n")]
+    #[schema("8n", reads(n), writes(d))]
     Glo(u8),
-    #[schema("9n")]
+    #[schema("9n", reads(n), writes(d))]
     Ghi(u8),
-    #[schema("an")]
+    #[schema("an", reads(d, n), writes(n))]
     Plo(u8),
-    #[schema("bn")]
+    #[schema("bn", reads(d, n), writes(n))]
     Phi(u8),
-    #[schema("c0 hh ll")]
+    #[schema("c0 hh ll", flow = branch, reads(rp), writes(rp), mem = read)]
     Lbr(u8, u8),
-    #[schema("c1 hh ll")]
+    #[schema("c1 hh ll", flow = branch_if, reads(q, rp), writes(rp), mem = read)]
     Lbq(u8, u8),
-    #[schema("c2 hh ll")]
+    #[schema("c2 hh ll", flow = branch_if, reads(d, rp), writes(rp), mem = read)]
     Lbz(u8, u8),
-    #[schema("c3 hh ll")]
+    #[schema("c3 hh ll", flow = branch_if, reads(df, rp), writes(rp), mem = read)]
     Lbdf(u8, u8),
     #[schema("c4")]
     Nop,
-    #[schema("c5")]
+    #[schema("c5", flow = skip_if(2), reads(q, rp), writes(rp))]
     Lsnq,
-    #[schema("c6")]
+    #[schema("c6", flow = skip_if(2), reads(d, rp), writes(rp))]
     Lsnz,
-    #[schema("c7")]
+    #[schema("c7", flow = skip_if(2), reads(df, rp), writes(rp))]
     Lsnf,
-    #[schema("c8")]
+    #[schema("c8", flow = skip(2), reads(rp), writes(rp), mem = read)]
     Lskp,
-    #[schema("c9 hh ll")]
+    #[schema("c9 hh ll", flow = branch_if, reads(q, rp), writes(rp), mem = read)]
     Lbnq(u8, u8),
-    #[schema("ca hh ll")]
+    #[schema("ca hh ll", flow = branch_if, reads(d, rp), writes(rp), mem = read)]
     Lbnz(u8, u8),
-    #[schema("cb hh ll")]
+    #[schema("cb hh ll", flow = branch_if, reads(df, rp), writes(rp), mem = read)]
     Lbnf(u8, u8),
-    #[schema("cc")]
+    #[schema("cc", flow = skip_if(2), reads(ie, rp), writes(rp))]
     Lsie,
-    #[schema("cd")]
+    #[schema("cd", flow = skip_if(2), reads(q, rp), writes(rp))]
     Lsq,
-    #[schema("ce")]
+    #[schema("ce", flow = skip_if(2), reads(d, rp), writes(rp))]
     Lsz,
-    #[schema("cf")]
+    #[schema("cf", flow = skip_if(2), reads(df, rp), writes(rp))]
     Lsdf,
-    #[schema("dn")]
+    #[schema("dn", flow = call, reads(n), writes(p))]
     Sep(u8),
-    #[schema("en")]
+    #[schema("en", writes(x))]
     Sex(u8),
-    #[schema("f0")]
+    #[schema("f0", reads(x, rx), writes(d), mem = read)]
     Ldx,
-    #[schema("f1")]
+    #[schema("f1", reads(d, x, rx), writes(d), mem = read)]
     Or,
-    #[schema("f2")]
+    #[schema("f2", reads(d, x, rx), writes(d), mem = read)]
     And,
-    #[schema("f3")]
+    #[schema("f3", reads(d, x, rx), writes(d), mem = read)]
     Xor,
-    #[schema("f4")]
+    #[schema("f4", reads(d, x, rx), writes(d, df), mem = read)]
     Add,
-    #[schema("f5")]
+    #[schema("f5", reads(d, x, rx), writes(d, df), mem = read)]
     Sd,
-    #[schema("f6")]
+    #[schema("f6", reads(d), writes(d, df))]
     Shr,
-    #[schema("f7")]
+    #[schema("f7", reads(d, x, rx), writes(d, df), mem = read)]
     Sm,
-    #[schema("f8 nn")]
+    #[schema("f8 nn", reads(rp), writes(d, rp), mem = read)]
     Ldi(u8),
-    #[schema("f9 nn")]
+    #[schema("f9 nn", reads(d, rp), writes(d, rp), mem = read)]
     Ori(u8),
-    #[schema("fa nn")]
+    #[schema("fa nn", reads(d, rp), writes(d, rp), mem = read)]
     Ani(u8),
-    #[schema("fb nn")]
+    #[schema("fb nn", reads(d, rp), writes(d, rp), mem = read)]
     Xri(u8),
-    #[schema("fc nn")]
+    #[schema("fc nn", reads(d, rp), writes(d, df, rp), mem = read)]
     Adi(u8),
-    #[schema("fd nn")]
+    #[schema("fd nn", reads(d, rp), writes(d, df, rp), mem = read)]
     Sdi(u8),
-    #[schema("fe")]
+    #[schema("fe", reads(d), writes(d, df))]
     Shl,
-    #[schema("ff nn")]
+    #[schema("ff nn", reads(d, rp), writes(d, df, rp), mem = read)]
     Smi(u8),
+
+    // CDP1804/1805/1806 extended instructions.
+    #[schema("68 00", cycles = 3, writes(cntr))]
+    Stpc,
+    #[schema("68 01", cycles = 3, reads(cntr), writes(cntr))]
+    Dtc,
+    #[schema("68 02", cycles = 3, writes(cntr))]
+    Spm2,
+    #[schema("68 03", cycles = 3, writes(cntr))]
+    Scm2,
+    #[schema("68 04", cycles = 3, writes(cntr))]
+    Spm1,
+    #[schema("68 05", cycles = 3, writes(cntr))]
+    Scm1,
+    #[schema("68 06", cycles = 3, reads(d, cntr), writes(cntr))]
+    Ldc,
+    #[schema("68 07", cycles = 3, writes(cntr))]
+    Stm,
+    #[schema("68 08", cycles = 3, reads(cntr), writes(d))]
+    Gec,
+    #[schema("68 09", cycles = 3, writes(cntr))]
+    Etq,
+    #[schema("68 0a", cycles = 3, writes(xie))]
+    Xie,
+    #[schema("68 0b", cycles = 3, writes(xie))]
+    Xid,
+    #[schema("68 0c", cycles = 3, writes(cie))]
+    Cie,
+    #[schema("68 0d", cycles = 3, writes(cie))]
+    Cid,
+    #[schema("68 2n hh ll", cycles = 5, flow = branch_if, reads(n, rp), writes(n, rp), mem = read)]
+    Dbnz(u8, u8, u8),
+    #[schema(
+        "68 3e nn",
+        cycles = 3,
+        flow = branch_if,
+        reads(cntr, rp),
+        writes(cntr, rp),
+        mem = read,
+    )]
+    Bci(u8),
+    #[schema("68 3f nn", cycles = 3, flow = branch_if, reads(int, rp), writes(rp), mem = read)]
+    Bxi(u8),
+    #[schema("68 6n", cycles = 5, reads(x, rx), writes(n, rx), mem = read)]
+    Rlxa(u8),
+    #[schema("68 74", cycles = 4, reads(d, df, x, rx), writes(d, df), mem = read)]
+    Dadc,
+    #[schema("68 76", cycles = 6, reads(d, df, t, x, rx), writes(d, df, rx), mem = write)]
+    Dsav,
+    #[schema("68 77", cycles = 4, reads(d, df, x, rx), writes(d, df), mem = read)]
+    Dsmb,
+    #[schema("68 7c nn", cycles = 4, reads(d, df, rp), writes(d, df, rp), mem = read)]
+    Daci(u8),
+    #[schema("68 7f nn", cycles = 4, reads(d, df, rp), writes(d, df, rp), mem = read)]
+    Dsbi(u8),
+    #[schema(
+        "68 8n hh ll",
+        cycles = 10,
+        flow = call,
+        reads(n, x, rx, rp),
+        writes(n, rx, rp),
+        mem = read_write,
+    )]
+    Scal(u8, u8, u8),
+    #[schema("68 9n", cycles = 8, flow = return, reads(n, x, rx), writes(n, rx, rp), mem = read)]
+    Sret(u8),
+    #[schema("68 an", cycles = 5, reads(n, x, rx), writes(rx), mem = write)]
+    Rsxd(u8),
+    #[schema("68 bn", cycles = 4, reads(n, x), writes(rx))]
+    Rnx(u8),
+    #[schema("68 cn hh ll", cycles = 5, reads(rp), writes(n, rp), mem = read)]
+    Rldi(u8, u8, u8),
+    #[schema("68 f4", cycles = 4, reads(d, x, rx), writes(d, df), mem = read)]
+    Dadd,
+    #[schema("68 f7", cycles = 4, reads(d, x, rx), writes(d, df), mem = read)]
+    Dsm,
+    #[schema("68 fc nn", cycles = 4, reads(d, rp), writes(d, df, rp), mem = read)]
+    Dadi(u8),
+    #[schema("68 ff nn", cycles = 4, reads(d, rp), writes(d, df, rp), mem = read)]
+    Dsmi(u8),
+}
+impl Instr {
+    /// Decodes an instruction from bytes, restricted to the instruction set implemented by the
+    /// given CPU variant.
+    ///
+    /// On a CDP1802, the 0x68 prefix is always decoded as [`Instr::Resv68`].
+    pub fn decode_for(bin: &[u8], variant: Variant) -> Option<Self> {
+        match Self::decode(bin) {
+            Some(instr) if instr.is_extended() && !variant.has_extended_isa() => Some(Self::Resv68),
+            instr => instr,
+        }
+    }
+
+    /// Returns the target of a branch or subroutine call located at the given address.
+    pub fn branch_target(&self, addr: u16) -> Option<u16> {
+        match *self {
+            Instr::Lbr(hh, ll)
+            | Instr::Lbq(hh, ll)
+            | Instr::Lbz(hh, ll)
+            | Instr::Lbdf(hh, ll)
+            | Instr::Lbnq(hh, ll)
+            | Instr::Lbnz(hh, ll)
+            | Instr::Lbnf(hh, ll)
+            | Instr::Dbnz(_, hh, ll)
+            | Instr::Scal(_, hh, ll) => Some(u16::from_be_bytes([hh, ll])),
+            _ if self.is_short_branch() => {
+                let [nn] = self.fields()[..] else {
+                    unreachable!()
+                };
+                let page = addr.wrapping_add(u16::from(self.size()) - 1) & 0xff00;
+                Some(page | u16::from(nn))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -202,4 +479,115 @@ mod test {
         assert!(matches!(Instr::decode(&[0x69]), Some(Instr::Inp(1))));
         assert!(matches!(Instr::decode(&[0x6f]), Some(Instr::Inp(7))));
     }
+
+    #[test]
+    fn test_extended() {
+        assert!(matches!(Instr::decode(&[0x68, 0x00]), Some(Instr::Stpc)));
+        assert!(matches!(Instr::decode(&[0x68, 0x0e]), Some(Instr::Resv68)));
+        assert!(matches!(Instr::decode(&[0x68]), Some(Instr::Resv68)));
+        assert!(matches!(
+            Instr::decode(&[0x68, 0x3e, 0x10]),
+            Some(Instr::Bci(0x10))
+        ));
+        assert!(matches!(Instr::decode(&[0x68, 0x3e]), Some(Instr::Resv68)));
+        assert!(matches!(
+            Instr::decode(&[0x68, 0x6a]),
+            Some(Instr::Rlxa(10))
+        ));
+        assert!(matches!(
+            Instr::decode(&[0x68, 0xc3, 0x12, 0x34]),
+            Some(Instr::Rldi(3, 0x12, 0x34))
+        ));
+        assert!(matches!(
+            Instr::decode_for(&[0x68, 0xc3, 0x12, 0x34], Variant::Cdp1802),
+            Some(Instr::Resv68)
+        ));
+        assert!(matches!(
+            Instr::decode_for(&[0x68, 0xc3, 0x12, 0x34], Variant::Cdp1805),
+            Some(Instr::Rldi(3, 0x12, 0x34))
+        ));
+
+        let instr = Instr::Scal(4, 0x80, 0x10);
+        assert_eq!(instr.encode(), [0x68, 0x84, 0x80, 0x10]);
+        assert_eq!(instr.size(), 4);
+        assert_eq!(instr.disasm(), "scal 4 128 16");
+        assert!(instr.is_extended());
+        assert!(!Instr::Sep(4).is_extended());
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = Instr::layout("ldn").unwrap();
+        assert_eq!(layout.registers, 0xfffe);
+        assert_eq!((layout.immediates, layout.size), (0, 1));
+        assert_eq!(Instr::layout("out").unwrap().registers, 0x00fe);
+        assert_eq!(Instr::layout("inp").unwrap().registers, 0x00fe);
+        assert_eq!(Instr::layout("sep").unwrap().registers, 0xffff);
+        let layout = Instr::layout("dbnz").unwrap();
+        assert_eq!((layout.immediates, layout.size), (2, 4));
+        assert!(Instr::layout("mov").is_none());
+
+        assert!(matches!(
+            Instr::from_fields("scal", &[4, 0x80, 0x10]),
+            Some(Instr::Scal(4, 0x80, 0x10))
+        ));
+        assert!(matches!(Instr::from_fields("irx", &[]), Some(Instr::Irx)));
+        assert!(Instr::from_fields("irx", &[1]).is_none());
+        assert!(Instr::from_fields("ldi", &[]).is_none());
+        assert_eq!(Instr::from_fields("inp", &[1]).unwrap().encode(), [0x69]);
+
+        let instr = Instr::Scal(4, 0x80, 0x10);
+        assert_eq!(instr.mnemonic(), "scal");
+        assert_eq!(instr.fields(), [4, 0x80, 0x10]);
+        assert!(Instr::Irx.fields().is_empty());
+    }
+
+    #[test]
+    fn test_branch_target() {
+        assert_eq!(Instr::Br(0x10).branch_target(0x8020), Some(0x8010));
+        assert_eq!(Instr::Br(0x10).branch_target(0x80ff), Some(0x8110));
+        assert_eq!(Instr::Bci(0x10).branch_target(0x80fe), Some(0x8110));
+        assert_eq!(Instr::Lbnz(0x12, 0x34).branch_target(0), Some(0x1234));
+        assert_eq!(Instr::Scal(4, 0x80, 0x10).branch_target(0), Some(0x8010));
+        assert_eq!(Instr::Lskp.branch_target(0), None);
+        assert_eq!(Instr::Ldi(0x10).branch_target(0), None);
+    }
+
+    #[test]
+    fn test_short_branch() {
+        // Short branches are exactly the branches with a one-byte target.
+        for first in 0..=0xff {
+            for second in 0..=0xff {
+                let Some(instr) = Instr::decode(&[first, second, 0x12, 0x34]) else {
+                    continue;
+                };
+                let branch = matches!(instr.meta().flow, Flow::Branch | Flow::BranchIf);
+                let operand = Instr::layout(instr.mnemonic()).unwrap().immediates == 1;
+                assert_eq!(instr.is_short_branch(), branch && operand, "{instr:?}");
+            }
+        }
+        assert!(Instr::Bci(0x10).is_short_branch());
+        assert!(!Instr::Lbr(0x12, 0x34).is_short_branch());
+    }
+
+    #[test]
+    fn test_decode_table() {
+        // The opcode table agrees with the match-based decoder for every opcode and prefixed
+        // opcode, including when the immediate operands are truncated.
+        for first in 0..=0xff {
+            for second in 0..=0xff {
+                let bin = [first, second, 0x12, 0x34];
+                for len in 0..=bin.len() {
+                    let table = Instr::decode(&bin[..len]);
+                    let reference = Instr::decode_match(&bin[..len]);
+                    assert_eq!(format!("{table:?}"), format!("{reference:?}"), "{bin:02x?}");
+                }
+            }
+        }
+        let entry = Instr::opcode(Some(0x68), 0x83).unwrap();
+        assert_eq!(entry.register, 3);
+        assert_eq!(entry.layout, Instr::layout("scal").unwrap());
+        assert!(Instr::opcode(Some(0x68), 0x10).is_none());
+        assert!(Instr::opcode(Some(0x30), 0x00).is_none());
+    }
 }
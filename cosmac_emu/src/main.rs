@@ -3,11 +3,20 @@
 use clap::Parser;
 use color_eyre::Result;
 
+mod asm;
+mod bus;
 mod chips;
 mod cli;
 mod debugger;
+mod disasm;
 mod event;
+mod expr;
+mod fault;
+mod image;
 mod instr;
+mod snapshot;
+mod source_map;
+mod symbols;
 mod systems;
 mod time;
 mod tui;
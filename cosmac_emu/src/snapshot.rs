@@ -0,0 +1,264 @@
+//! Machine snapshots
+//!
+//! A snapshot captures the complete state of a system, so that it can be suspended and resumed
+//! later, or inspected after the fact. Snapshots are stored as JSON, in an envelope that records
+//! the format version and the kind of system.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+/// The snapshot format version. This must be incremented whenever the serialized state of any
+/// system changes in an incompatible way.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("malformed snapshot: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unsupported snapshot version {0} (expected {VERSION})")]
+    Version(u32),
+    #[error("snapshot is for a {0} system, not {1}")]
+    System(String, &'static str),
+    #[error("snapshot doesn't match the device bus layout")]
+    DeviceBus,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    version: u32,
+    system: &'a str,
+    state: T,
+}
+
+#[derive(Deserialize)]
+struct RawEnvelope {
+    version: u32,
+    system: String,
+    state: serde_json::Value,
+}
+
+/// A system whose state can be saved to, and restored from, a snapshot.
+pub trait Snapshot {
+    /// The saved state of the system.
+    type State: Serialize + DeserializeOwned;
+
+    /// The kind of system, as recorded in the snapshot envelope.
+    const SYSTEM: &'static str;
+
+    /// Captures the state of the system.
+    fn snapshot(&self) -> Self::State;
+
+    /// Restores the state of the system.
+    fn restore(&mut self, state: Self::State) -> Result<(), SnapshotError>;
+
+    /// Writes a snapshot to a writer.
+    fn write_snapshot(&self, w: impl Write) -> Result<(), SnapshotError> {
+        let envelope = Envelope {
+            version: VERSION,
+            system: Self::SYSTEM,
+            state: self.snapshot(),
+        };
+        serde_json::to_writer(w, &envelope)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot from a reader, and restores the state of the system.
+    fn read_snapshot(&mut self, r: impl Read) -> Result<(), SnapshotError> {
+        let envelope: RawEnvelope = serde_json::from_reader(r)?;
+        if envelope.version != VERSION {
+            return Err(SnapshotError::Version(envelope.version));
+        }
+        if envelope.system != Self::SYSTEM {
+            return Err(SnapshotError::System(envelope.system, Self::SYSTEM));
+        }
+        let state = serde_json::from_value(envelope.state)?;
+        self.restore(state)
+    }
+
+    /// Saves a snapshot to a file.
+    fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_snapshot(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Loads a snapshot from a file, and restores the state of the system.
+    fn load_snapshot(&mut self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let file = BufReader::new(File::open(path)?);
+        self.read_snapshot(file)
+    }
+}
+
+/// Serializes a byte buffer as a hex string, which is considerably more compact than a JSON
+/// array. For use with `#[serde(with = "snapshot::hex")]`.
+pub mod hex {
+    use std::fmt::Write;
+
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        let mut hex = String::with_capacity(data.len() * 2);
+        for byte in data {
+            write!(hex, "{byte:02x}").expect("infallible");
+        }
+        s.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(d)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        hex.as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|s| u8::from_str_radix(s, 16).ok())
+                    .ok_or_else(|| D::Error::custom("invalid hex digit"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::bus::{Device, DeviceBus};
+    use crate::chips::ay51013::Ay51013Uart;
+    use crate::chips::cdp1802::{Cdp1802, Memory, Variant};
+    use crate::systems::basic::{BasicSystem, HistoryError};
+    use crate::systems::mc::MembershipCard;
+
+    /// A program that counts in R(3), and stores the low byte at 0x80.
+    const PROGRAM: [u8; 10] = [
+        0xf8, 0x80, // ldi 0x80
+        0xa4, //       plo 4
+        0x13, //       inc 3
+        0x83, //       glo 3
+        0x54, //       str 4
+        0x7b, //       seq
+        0x7a, //       req
+        0x30, 0x03, // br 3
+    ];
+
+    fn basic_system() -> BasicSystem {
+        let memory = Memory::builder().with_image(0, PROGRAM).build().unwrap();
+        let cpu = Cdp1802::new(Variant::Cdp1805);
+        BasicSystem::new(cpu, DeviceBus::from(memory), Duration::from_micros(1))
+    }
+
+    fn membership_card() -> MembershipCard {
+        let memory = Memory::builder().with_image(0, PROGRAM).build().unwrap();
+        let uart = Ay51013Uart::builder().with_baud(4800, 4_000_000).build();
+        MembershipCard::builder()
+            .with_bus(DeviceBus::from(memory))
+            .with_uart(uart.into_box())
+            .build()
+    }
+
+    fn state_json<T: Snapshot>(system: &T) -> serde_json::Value {
+        serde_json::to_value(system.snapshot()).unwrap()
+    }
+
+    #[test]
+    fn test_basic_round_trip() {
+        let mut a = basic_system();
+        for _ in 0..1000 {
+            a.step();
+        }
+        let mut buf = vec![];
+        a.write_snapshot(&mut buf).unwrap();
+
+        let mut b = basic_system();
+        b.read_snapshot(buf.as_slice()).unwrap();
+        assert_eq!(state_json(&a), state_json(&b));
+        assert_eq!(a.now(), b.now());
+
+        for _ in 0..1000 {
+            a.step();
+            b.step();
+        }
+        assert_eq!(state_json(&a), state_json(&b));
+        assert_eq!(a.bus().peek(0x80), b.bus().peek(0x80));
+    }
+
+    #[test]
+    fn test_restore_clears_history() {
+        let mut sys = basic_system().with_history(100);
+        let mut buf = vec![];
+        sys.write_snapshot(&mut buf).unwrap();
+        for _ in 0..10 {
+            sys.step();
+        }
+        sys.read_snapshot(buf.as_slice()).unwrap();
+        assert_matches!(sys.step_back(), Err(HistoryError::Empty));
+    }
+
+    #[test]
+    fn test_mc_round_trip() {
+        let mut a = membership_card();
+        a.uart_write(b'x');
+        for _ in 0..5000 {
+            a.tick();
+        }
+        let mut buf = vec![];
+        a.write_snapshot(&mut buf).unwrap();
+
+        let mut b = MembershipCard::default();
+        b.read_snapshot(buf.as_slice()).unwrap();
+        assert_eq!(state_json(&a), state_json(&b));
+
+        for _ in 0..5000 {
+            a.tick();
+            b.tick();
+        }
+        assert_eq!(state_json(&a), state_json(&b));
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut buf = vec![];
+        basic_system().write_snapshot(&mut buf).unwrap();
+        let mut json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        json["version"] = (VERSION + 1).into();
+        let buf = serde_json::to_vec(&json).unwrap();
+
+        let result = basic_system().read_snapshot(buf.as_slice());
+        assert_matches!(result, Err(SnapshotError::Version(v)) if v == VERSION + 1);
+    }
+
+    #[test]
+    fn test_system_mismatch() {
+        let mut buf = vec![];
+        basic_system().write_snapshot(&mut buf).unwrap();
+        let result = MembershipCard::default().read_snapshot(buf.as_slice());
+        assert_matches!(result, Err(SnapshotError::System(s, "mc")) if s == "basic");
+    }
+
+    #[test]
+    fn test_hex() {
+        let json = serde_json::json!({"data": "00ff1a", "write_protect": []});
+        let memory: Memory = serde_json::from_value(json).unwrap();
+        assert_eq!(memory.as_slice(), [0x00, 0xff, 0x1a]);
+        let json = serde_json::to_value(&memory).unwrap();
+        assert_eq!(json["data"], "00ff1a");
+
+        let json = serde_json::json!({"data": "0f0", "write_protect": []});
+        assert!(serde_json::from_value::<Memory>(json).is_err());
+        let json = serde_json::json!({"data": "0g", "write_protect": []});
+        assert!(serde_json::from_value::<Memory>(json).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Source maps
+//!
+//! A source map relates addresses to the source lines that generated them, and is read from an
+//! assembler listing. Lines are recognized in the following formats, and lines without encoded
+//! bytes are ignored:
+//!
+//! - asl: `line/addr : bytes source`, where the line may be preceded by an include depth, e.g.
+//!   `      12/     103 : 28                    loop:   dec r8`.
+//! - This project's assembler: `line addr bytes source`, e.g. `   12 0103  28           loop: dec r8`.
+//! - A18: `addr bytes source`, e.g. `0103 28          LOOP:   DEC R8`.
+//!
+//! Bytes and source are separated by at least two spaces, or a tab. Where consecutive listing
+//! lines share a line number, as asl does for macro expansions, the first line is kept as the
+//! context of the others.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+const BYTES: &str = r"(?P<bytes>(?:[0-9A-Fa-f]{2} )*[0-9A-Fa-f]{2})(?:[ \t]{2,}|\t|$)";
+
+static ASL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r"^\s*(?:\(\d+\))?\s*(?P<line>\d+)/\s*(?P<addr>[0-9A-Fa-f]{{1,4}}) : (?:{BYTES})?(?P<text>.*)$"
+    ))
+    .expect("valid regex")
+});
+
+static ASM: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r"^\s*(?P<line>\d+) (?P<addr>[0-9a-f]{{4}})  (?:{BYTES})?(?P<text>.*)$"
+    ))
+    .expect("valid regex")
+});
+
+static A18: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r"^(?P<addr>[0-9A-Fa-f]{{4}})\s+{BYTES}(?P<text>.*)$"
+    ))
+    .expect("valid regex")
+});
+
+#[derive(Debug, thiserror::Error)]
+pub enum SourceMapError {
+    #[error("no source lines found")]
+    Empty,
+}
+
+/// A line of source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    /// The line number in the source file.
+    pub number: usize,
+    pub text: String,
+    /// The line that this line was expanded from, e.g. a macro invocation.
+    pub context: Option<String>,
+    /// Identifies the statement, which is shared by the lines expanded from it.
+    statement: usize,
+}
+impl SourceLine {
+    /// Returns true if both lines belong to the same statement.
+    pub fn same_statement(&self, other: &SourceLine) -> bool {
+        self.statement == other.statement
+    }
+}
+impl Display for SourceLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.context {
+            Some(context) => write!(
+                f,
+                "{:>5}  {context}\n{:>5}+   {}",
+                self.number, "", self.text
+            ),
+            None => write!(f, "{:>5}  {}", self.number, self.text),
+        }
+    }
+}
+
+/// Source lines, by the address of their first byte.
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    lines: BTreeMap<u16, SourceLine>,
+    /// The number of statements read.
+    statements: usize,
+}
+impl SourceMap {
+    /// Parses a listing.
+    #[cfg(test)]
+    pub fn parse(text: &str) -> Result<Self, SourceMapError> {
+        let mut map = Self::default();
+        map.extend(text)?;
+        Ok(map)
+    }
+
+    /// Adds the source lines from a listing.
+    pub fn extend(&mut self, text: &str) -> Result<(), SourceMapError> {
+        let count = self.lines.len();
+        // The line number and text of the current statement.
+        let mut current: Option<(usize, String)> = None;
+        for (n, line) in text.lines().enumerate() {
+            let Some(caps) = [&*ASL, &*ASM, &*A18]
+                .into_iter()
+                .find_map(|re| re.captures(line))
+            else {
+                continue;
+            };
+            let text = expand_tabs(&caps["text"]).trim().to_string();
+            let (number, context) = match caps.name("line") {
+                Some(number) => {
+                    let number: usize = number.as_str().parse().unwrap_or_default();
+                    match &current {
+                        Some((prev, context)) if *prev == number => (number, Some(context.clone())),
+                        _ => {
+                            self.statements += 1;
+                            current = Some((number, text.clone()));
+                            (number, None)
+                        }
+                    }
+                }
+                None => {
+                    self.statements += 1;
+                    (n + 1, None)
+                }
+            };
+            if caps.name("bytes").is_none() {
+                continue;
+            }
+            let Ok(addr) = u16::from_str_radix(&caps["addr"], 16) else {
+                continue;
+            };
+            self.lines.entry(addr).or_insert(SourceLine {
+                number,
+                text,
+                context,
+                statement: self.statements,
+            });
+        }
+        if self.lines.len() == count {
+            return Err(SourceMapError::Empty);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Returns the source line that starts at an address.
+    pub fn line(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.get(&addr)
+    }
+}
+
+/// Expands tabs to 8 columns.
+fn expand_tabs(text: &str) -> String {
+    let mut expanded = String::new();
+    for c in text.chars() {
+        if c == '\t' {
+            expanded.push(' ');
+            while expanded.len() % 8 != 0 {
+                expanded.push(' ');
+            }
+        } else {
+            expanded.push(c);
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asm() {
+        let text = "
+    1                           org 0x100
+    2 0100  f8 10        start: ldi 0x10     ; count
+    3 0102  a8                  plo r8
+    4 0103  28 88        loop:  dec r8 glo r8
+    5 0105  db 01 02 03         db 1, 2, 3, 4, 5
+      0109  04 05
+    6 =0010              size = 0x10
+
+Symbols:
+loop                     0103
+";
+        let map = SourceMap::parse(text).unwrap();
+        let line = map.line(0x100).unwrap();
+        assert_eq!(line.number, 2);
+        assert_eq!(line.text, "start: ldi 0x10     ; count");
+        assert_eq!(map.line(0x103).unwrap().number, 4);
+        assert_eq!(map.line(0x105).unwrap().text, "db 1, 2, 3, 4, 5");
+        assert_eq!(map.line(0x101), None);
+        assert_eq!(map.line(0x109), None);
+        assert_eq!(map.lines.len(), 4);
+    }
+
+    #[test]
+    fn test_asl() {
+        let text = "
+ AS V1.42 Beta [Bld 212] - Source File test.asm - Page 1 - 10/16/2026 12:00:00
+
+
+       1/       0 :                     \t\tcpu\t1802
+       2/       0 :                     delay\tmacro n
+       5/       0 :                     \t\tendm
+       6/     100 : F8 10               start:\tldi\t10h
+       7/     102 :                     \t\tdelay\t5
+       7/     102 : F8 05               \t\tldi\t5
+       7/     104 : FF 01               \t\tsmi\t1
+       8/     106 : 30 00               \t\tbr\tstart
+  (1)  1/     108 : 7B                  \t\tseq
+";
+        let map = SourceMap::parse(text).unwrap();
+        assert_eq!(map.line(0x100).unwrap().text, "start:  ldi     10h");
+        let ldi = map.line(0x102).unwrap();
+        assert_eq!(ldi.number, 7);
+        assert_eq!(ldi.text, "ldi     5");
+        assert_eq!(ldi.context.as_deref(), Some("delay   5"));
+        let smi = map.line(0x104).unwrap();
+        assert!(smi.same_statement(ldi));
+        assert!(!smi.same_statement(map.line(0x106).unwrap()));
+        assert_eq!(map.line(0x108).unwrap().number, 1);
+        assert_eq!(ldi.to_string(), "    7  delay   5\n     +   ldi     5");
+    }
+
+    #[test]
+    fn test_a18() {
+        let text = "
+                    ; test
+0100 f8 10          START:  LDI  10H
+0102 a8                     PLO  R8
+";
+        let map = SourceMap::parse(text).unwrap();
+        assert_eq!(map.line(0x100).unwrap().text, "START:  LDI  10H");
+        assert_eq!(map.line(0x102).unwrap().number, 4);
+        assert!(matches!(
+            SourceMap::parse("no listing here"),
+            Err(SourceMapError::Empty)
+        ));
+    }
+}
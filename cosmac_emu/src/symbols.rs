@@ -0,0 +1,319 @@
+//! Symbol tables
+//!
+//! Symbols are read from the symbol table of an assembler listing, or from a file of simple
+//! definitions. Each line is parsed in one of the following formats, and lines that don't match
+//! any of them (e.g. the rest of the listing) are ignored:
+//!
+//! - `name = addr` or `name equ addr`, where the address is decimal, or hex as `0x1f`, `$1f` or
+//!   `1fh`.
+//! - A18 (and this project's assembler): whitespace-separated pairs of names and hex addresses,
+//!   e.g. `MAIN 0100 LOOP 0105`. Mnemonics and directives aren't taken as names, so source lines
+//!   in the listing such as `ORG 8000H` aren't mistaken for symbols.
+//! - asl: `|`-separated entries of the form `name : addr type`, where unused symbols are prefixed
+//!   with `*`, e.g. ` MAIN : 0100 C | *LOOP : 0105 C |`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::instr::{Instr, InstrSchema};
+
+/// How far past a symbol an address may be, to be described relative to it.
+const MAX_OFFSET: u16 = 0x100;
+
+/// The A18 directives that take at most one operand, and so could pass for a symbol table entry.
+const DIRECTIVES: [&str; 19] = [
+    "blk", "byte", "cpu", "db", "dc", "ds", "dw", "ejct", "else", "end", "endi", "equ", "if",
+    "incl", "load", "org", "page", "text", "word",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum SymbolError {
+    #[error("no symbols found")]
+    Empty,
+    #[error("undefined symbol '{0}'")]
+    Undefined(String),
+    #[error("invalid address '{0}'")]
+    InvalidAddr(String),
+}
+
+/// A table of symbols, by name and by address.
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    by_name: HashMap<String, u16>,
+    /// The first symbol defined at each address.
+    by_addr: BTreeMap<u16, String>,
+}
+impl Symbols {
+    /// Parses a symbol file.
+    #[cfg(test)]
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Self::default();
+        symbols.extend(text)?;
+        Ok(symbols)
+    }
+
+    /// Adds the symbols from a symbol file.
+    pub fn extend(&mut self, text: &str) -> Result<(), SymbolError> {
+        let count = self.by_name.len();
+        for line in text.lines() {
+            for (name, addr) in parse_line(line) {
+                self.insert(name, addr);
+            }
+        }
+        if self.by_name.len() == count {
+            return Err(SymbolError::Empty);
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.by_name.insert(name.to_string(), addr);
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    /// Looks up a symbol by name. Names are matched case-insensitively if there's no exact match.
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied().or_else(|| {
+            self.by_name
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, addr)| *addr)
+        })
+    }
+
+    /// Returns the name of the symbol at an address.
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(String::as_str)
+    }
+
+    /// Describes an address relative to the nearest symbol at or before it, e.g. `LOOP+0x3`.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let (base, name) = self.by_addr.range(..=addr).next_back()?;
+        match addr - base {
+            0 => Some(name.clone()),
+            offset if offset < MAX_OFFSET => Some(format!("{name}+{offset:#x}")),
+            _ => None,
+        }
+    }
+
+    /// Returns the listing for an instruction at an address, followed by the symbol for its
+    /// branch target, if any.
+    pub fn listing(&self, instr: &Instr, addr: u16) -> String {
+        let listing = instr.listing();
+        match instr.branch_target(addr).and_then(|t| self.describe(t)) {
+            Some(target) => format!("{listing} <{target}>"),
+            None => listing,
+        }
+    }
+}
+
+/// Returns true if the word is a valid symbol name.
+fn is_name(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Returns true if the word is a mnemonic or an A18 directive, rather than a symbol in a table.
+fn is_keyword(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    DIRECTIVES.contains(&word.as_str()) || Instr::layout(&word).is_some()
+}
+
+/// Parses a hex address, as printed in a symbol table.
+fn parse_hex(word: &str) -> Option<u16> {
+    let word = word.strip_suffix(['h', 'H']).unwrap_or(word);
+    if word.is_empty() || word.len() > 4 {
+        return None;
+    }
+    u16::from_str_radix(word, 16).ok()
+}
+
+/// Parses an address in a definition, which is decimal unless marked as hex.
+fn parse_value(word: &str) -> Option<u16> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn parse_line(line: &str) -> Vec<(&str, u16)> {
+    let line = line.split(';').next().unwrap_or_default();
+    if line.contains('|') {
+        return line
+            .split('|')
+            .filter_map(|entry| {
+                let (name, rest) = entry.split_once(':')?;
+                let name = name.trim().trim_start_matches('*');
+                let addr = parse_hex(rest.split_whitespace().next()?)?;
+                is_name(name).then_some((name, addr))
+            })
+            .collect();
+    }
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [name, op, value] if *op == "=" || op.eq_ignore_ascii_case("equ") => {
+            match parse_value(value) {
+                Some(addr) if is_name(name) => vec![(name, addr)],
+                _ => vec![],
+            }
+        }
+        words if !words.is_empty() && words.len() % 2 == 0 => {
+            let pairs: Option<Vec<_>> = words
+                .chunks(2)
+                .map(|pair| Some((pair[0], parse_hex(pair[1])?)))
+                .collect();
+            pairs
+                .filter(|pairs| {
+                    pairs
+                        .iter()
+                        .all(|(name, _)| is_name(name) && !is_keyword(name))
+                })
+                .unwrap_or_default()
+        }
+        _ => vec![],
+    }
+}
+
+/// An address, given as a number or as a symbol name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Addr {
+    Value(u16),
+    Symbol(String),
+}
+impl FromStr for Addr {
+    type Err = SymbolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = match s.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+        match value {
+            Some(value) => Ok(Addr::Value(value)),
+            None if is_name(s) => Ok(Addr::Symbol(s.to_string())),
+            None => Err(SymbolError::InvalidAddr(s.to_string())),
+        }
+    }
+}
+impl Addr {
+    pub fn resolve(&self, symbols: &Symbols) -> Result<u16, SymbolError> {
+        match self {
+            Addr::Value(value) => Ok(*value),
+            Addr::Symbol(name) => symbols
+                .get(name)
+                .ok_or_else(|| SymbolError::Undefined(name.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "
+; definitions
+main = 0x0100
+loop equ $0105
+count = 16
+buf = 20h
+";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.get("main"), Some(0x100));
+        assert_eq!(symbols.get("loop"), Some(0x105));
+        assert_eq!(symbols.get("count"), Some(16));
+        assert_eq!(symbols.get("buf"), Some(0x20));
+        assert_eq!(symbols.get("MAIN"), Some(0x100));
+        assert_eq!(symbols.get("nope"), None);
+        assert_eq!(symbols.name(0x105), Some("loop"));
+    }
+
+    #[test]
+    fn test_parse_a18() {
+        let text = "
+                        ; Counts down from 16.
+                                ORG     0100H
+0100   f8 10            MAIN:   LDI     10H
+0102   ff 01            LOOP:   SMI     1
+0104   3a 02                    BNZ     LOOP
+                                IF      0
+                                LDI     20
+                                ENDI
+                                ORG     8000H
+8000   00               DONE:   IDL
+                                END     MAIN
+
+0000 Error(s)
+
+Symbols:
+DONE            8000    LOOP            0102
+MAIN            0100
+";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.get("MAIN"), Some(0x100));
+        assert_eq!(symbols.get("LOOP"), Some(0x102));
+        assert_eq!(symbols.get("DONE"), Some(0x8000));
+        assert_eq!(symbols.by_name.len(), 3);
+        assert!(matches!(
+            Symbols::parse("LDI 10\nORG 8000H"),
+            Err(SymbolError::Empty)
+        ));
+    }
+
+    #[test]
+    fn test_parse_asl() {
+        let text = "
+  Symbol Table (* = unused):
+  --------------------------
+
+ MAIN :                        0100 C | *LOOP :                        0105 C |
+";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(symbols.get("MAIN"), Some(0x100));
+        assert_eq!(symbols.get("LOOP"), Some(0x105));
+        assert_eq!(symbols.by_name.len(), 2);
+        assert!(matches!(
+            Symbols::parse("nothing here"),
+            Err(SymbolError::Empty)
+        ));
+    }
+
+    #[test]
+    fn test_describe() {
+        let symbols = Symbols::parse("MAIN 0100 LOOP 0105").unwrap();
+        assert_eq!(symbols.describe(0x100).as_deref(), Some("MAIN"));
+        assert_eq!(symbols.describe(0x103).as_deref(), Some("MAIN+0x3"));
+        assert_eq!(symbols.describe(0x0ff), None);
+        assert_eq!(symbols.describe(0x300), None);
+        assert_eq!(
+            symbols.listing(&Instr::Br(0x05), 0x100),
+            "30 05    br   5 <LOOP>"
+        );
+        assert_eq!(symbols.listing(&Instr::Idl, 0x100), "00       idl ");
+    }
+
+    #[test]
+    fn test_addr() {
+        let symbols = Symbols::parse("MAIN 0100").unwrap();
+        let resolve = |s: &str| s.parse::<Addr>().unwrap().resolve(&symbols).unwrap();
+        assert_eq!(resolve("0x10"), 0x10);
+        assert_eq!(resolve("10"), 10);
+        assert_eq!(resolve("main"), 0x100);
+        assert!(
+            "main"
+                .parse::<Addr>()
+                .unwrap()
+                .resolve(&Symbols::default())
+                .is_err()
+        );
+        assert!("0xfffff".parse::<Addr>().is_err());
+    }
+}
@@ -1,16 +1,40 @@
 //! A basic CDP1802 system.
 
-use std::collections::HashSet;
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::time::Duration;
 
 use color_eyre::Result;
+use serde::{Deserialize, Serialize};
 
-use crate::chips::cdp1802::{Cdp1802, Cdp1802Pins, Memory};
+use crate::bus::{
+    Device, DeviceBus, DeviceBusState, MemoryAccess, MemoryAccessError, MemoryAccessMode, Signal,
+};
+use crate::chips::cdp1802::{Bus, Cdp1802, Cdp1802Pins, Core};
 use crate::event::{
     Event, InputEvent, InputEventLog, InputKind, OutputEvent, OutputEventLog, OutputKind,
 };
-use crate::instr::InstrSchema;
+use crate::expr::{Expr, ExprError};
+use crate::fault::{Fault, FaultLog, FaultPolicy};
+use crate::snapshot::{Snapshot, SnapshotError};
+use crate::source_map::SourceMap;
+use crate::symbols::Symbols;
+
+mod breakpoint;
+mod history;
+mod monitor;
+mod registers;
+#[cfg(test)]
+mod test_util;
+mod watch;
+
+pub use breakpoint::Breakpoint;
+pub use history::HistoryError;
+use history::{Delta, History, MemoryWrite};
+use monitor::Monitor;
+pub use monitor::{UninitPolicy, UninitRead};
+pub use registers::{Register, RegisterError};
+pub use watch::{WatchHit, WatchKind, Watchpoint};
 
 #[derive(Debug, Clone, Copy, Hash)]
 pub enum Status {
@@ -18,29 +42,53 @@ pub enum Status {
     Event,
     Ready,
     Breakpoint,
+    Watchpoint(WatchHit),
+    /// A write protection fault, under the halt or break policy.
+    Fault(Fault),
+    /// A read of uninitialized memory, under the break policy.
+    Uninit(UninitRead),
 }
 
 pub struct BasicSystem {
+    core: Core,
     cpu: Cdp1802,
-    memory: Memory,
+    bus: DeviceBus,
     pins: Cdp1802Pins,
     clock_cycle_time: Duration,
     clock_cycle: u64,
     input_events: InputEventLog,
     output_events: OutputEventLog,
-    breakpoints: HashSet<u16>,
+    breakpoints: BTreeMap<u16, Breakpoint>,
+    monitor: Monitor,
+    history: History,
+    symbols: Symbols,
+    source: SourceMap,
+    /// The address execution starts at after reset.
+    start: u16,
+    /// The address of the current instruction.
+    instr_pc: u16,
+    /// The memory access in the current machine cycle, which is only observed once.
+    cycle_access: Option<(MemoryAccessMode, u16)>,
 }
 impl BasicSystem {
-    pub fn new(cdp1802: Cdp1802, memory: Memory, clock_cycle_time: Duration) -> Self {
+    pub fn new(cdp1802: Cdp1802, bus: DeviceBus, clock_cycle_time: Duration) -> Self {
         let mut this = Self {
+            core: Core::default(),
             cpu: cdp1802,
-            memory,
+            bus,
             pins: Cdp1802Pins::default(),
             clock_cycle_time,
             clock_cycle: 0,
             input_events: InputEventLog::default(),
             output_events: OutputEventLog::default(),
-            breakpoints: HashSet::default(),
+            breakpoints: BTreeMap::default(),
+            monitor: Monitor::default(),
+            history: History::default(),
+            symbols: Symbols::default(),
+            source: SourceMap::default(),
+            start: 0,
+            instr_pc: 0,
+            cycle_access: None,
         };
         this.reset();
         this
@@ -51,6 +99,45 @@ impl BasicSystem {
         self
     }
 
+    pub fn with_core(mut self, core: Core) -> Self {
+        self.core = core;
+        self
+    }
+
+    pub fn with_fault_policy(mut self, policy: FaultPolicy) -> Self {
+        self.monitor.faults = FaultLog::new(policy);
+        self
+    }
+
+    /// Sets the policy for reads of uninitialized memory, which are only detected in RAM.
+    pub fn with_uninit_policy(mut self, policy: UninitPolicy) -> Self {
+        self.monitor.uninit = policy;
+        self
+    }
+
+    /// Starts execution at an address after reset, rather than 0x0000.
+    pub fn with_start(mut self, start: u16) -> Self {
+        self.start = start;
+        self.reset();
+        self
+    }
+
+    /// Records the given number of instructions, so that they can be undone.
+    pub fn with_history(mut self, capacity: usize) -> Self {
+        self.history.capacity = capacity;
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn with_source(mut self, source: SourceMap) -> Self {
+        self.source = source;
+        self
+    }
+
     pub fn reset(&mut self) {
         self.pins.set_wait(true);
         self.pins.set_clear(false);
@@ -59,7 +146,13 @@ impl BasicSystem {
         for _ in 0..9 {
             self.cpu.tick(&mut self.pins);
         }
+        self.cpu.r[0] = self.start;
+        self.bus.signal(Signal::Reset);
         self.clock_cycle = 0;
+        self.monitor.reset();
+        self.history.clear();
+        self.instr_pc = self.start;
+        self.cycle_access = None;
         self.input_events.reset();
         self.output_events.clear();
     }
@@ -68,16 +161,92 @@ impl BasicSystem {
         &self.cpu
     }
 
-    pub fn breakpoints(&self) -> &HashSet<u16> {
+    /// Returns the breakpoints, by address.
+    pub fn breakpoints(&self) -> &BTreeMap<u16, Breakpoint> {
         &self.breakpoints
     }
 
-    pub fn breakpoints_mut(&mut self) -> &mut HashSet<u16> {
+    pub fn breakpoints_mut(&mut self) -> &mut BTreeMap<u16, Breakpoint> {
         &mut self.breakpoints
     }
 
-    pub fn has_breakpoint(&self, addr: u16) -> bool {
-        self.breakpoints.contains(&addr)
+    /// Evaluates an expression over the state of the CPU, pins and memory.
+    pub fn eval(&self, expr: &Expr) -> Result<i64, ExprError> {
+        expr.eval(&self.cpu, self.pins, &self.bus)
+    }
+
+    /// Checks the breakpoint at an address, if any, returning true if execution should stop.
+    /// Temporary breakpoints are deleted when they stop execution, and conditions that can't be
+    /// evaluated are reported, and stop execution.
+    fn check_breakpoint(&mut self, addr: u16) -> bool {
+        let Some(bp) = self.breakpoints.get_mut(&addr) else {
+            return false;
+        };
+        let stop = bp
+            .hit(|condition| condition.eval(&self.cpu, self.pins, &self.bus))
+            .unwrap_or_else(|e| {
+                eprintln!("breakpoint {addr:04x}: {e}");
+                true
+            });
+        if stop && bp.temporary {
+            self.breakpoints.remove(&addr);
+        }
+        stop
+    }
+
+    /// Sets a register or flag. This is only allowed between instructions, or while idle.
+    pub fn set_register(&mut self, register: Register, value: u16) -> Result<(), RegisterError> {
+        if !self.cpu.is_fetch_tick0() && !self.cpu.is_waiting(self.pins) {
+            return Err(RegisterError::NotAtBoundary);
+        }
+        if register.is_extended() && !self.cpu.variant().has_extended_isa() {
+            return Err(RegisterError::RequiresExtended(register));
+        }
+        if value > register.max() {
+            return Err(RegisterError::OutOfRange { register, value });
+        }
+        let cpu = &mut self.cpu;
+        match register {
+            Register::R(n) => cpu.r[usize::from(n)] = value,
+            Register::D => cpu.d = value as u8,
+            Register::Df => cpu.df = value != 0,
+            Register::P => cpu.p = value as u8,
+            Register::X => cpu.x = value as u8,
+            Register::T => cpu.t = value as u8,
+            Register::Ie => cpu.ie = value != 0,
+            Register::Xie => cpu.xie = value != 0,
+            Register::Cie => cpu.cie = value != 0,
+            Register::Q => {
+                let q = value != 0;
+                cpu.set_q(q);
+                if q != self.pins.get_q() {
+                    self.pins.set_q(q);
+                    self.bus.signal(Signal::Q(q));
+                    self.output_events.push(OutputEvent {
+                        timestamp: self.now(),
+                        kind: OutputKind::Q,
+                        value: q as u8,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn faults(&self) -> &FaultLog {
+        &self.monitor.faults
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.monitor.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Vec<Watchpoint> {
+        &mut self.monitor.watchpoints
+    }
+
+    pub fn clock_cycle(&self) -> u64 {
+        self.clock_cycle
     }
 
     pub fn now(&self) -> Duration {
@@ -109,7 +278,7 @@ impl BasicSystem {
 
     fn print_events<K: std::fmt::Debug>(&self, events: impl Iterator<Item = Event<K>>) {
         let mut events: Vec<_> = events.collect();
-        events.sort_unstable_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        events.sort_unstable_by_key(|e| e.timestamp);
         for event in events {
             let tick = (event.timestamp.as_secs_f64() / self.clock_cycle_time.as_secs_f64()) as u64;
             println!("{tick:08x} {:?} {}", event.kind, event.value);
@@ -136,6 +305,8 @@ impl BasicSystem {
         }
     }
 
+    /// Ticks the system clock. With the instruction-level core, the system advances by a whole
+    /// instruction whenever possible.
     pub fn tick(&mut self) -> Status {
         let now = self.now();
         if let Some(e) = self.input_events.pop_next_at(now) {
@@ -143,14 +314,38 @@ impl BasicSystem {
             return Status::Event;
         }
 
+        if self.cpu.is_fetch_tick0() {
+            self.instr_pc = self.cpu.rp();
+            if self.history.is_enabled() {
+                self.history.push(Delta {
+                    cpu: self.cpu.clone(),
+                    pins: self.pins,
+                    bus: self.bus.control(),
+                    clock_cycle: self.clock_cycle,
+                    output_events: self.output_events.len(),
+                    writes: vec![],
+                });
+            }
+        }
         let q_prev = self.pins.get_q();
-        self.cpu.tick(&mut self.pins);
-
-        // TODO: Log errors, add memory breakpoints
-        let _ = self.memory.tick(&mut self.pins, true);
+        if self.core == Core::Fast && self.cpu.can_step(self.pins) {
+            let mut bus = SystemBus {
+                bus: &mut self.bus,
+                output_events: &mut self.output_events,
+                now,
+                monitor: &mut self.monitor,
+                history: &mut self.history,
+                pc: self.instr_pc,
+            };
+            self.clock_cycle += u64::from(self.cpu.step(&mut self.pins, &mut bus));
+        } else {
+            self.tick_pins(now);
+            self.clock_cycle += 1;
+        }
 
         let q = self.pins.get_q();
         if q != q_prev {
+            self.bus.signal(Signal::Q(q));
             self.output_events.push(OutputEvent {
                 timestamp: now,
                 kind: OutputKind::Q,
@@ -158,37 +353,144 @@ impl BasicSystem {
             });
         }
 
+        for read in self.monitor.take_uninit_warnings() {
+            eprintln!(
+                "{read}: {}",
+                listing_at(&self.bus, &self.cpu, &self.symbols, read.pc)
+            );
+        }
+
+        let boundary = self.cpu.is_fetch_tick0();
+        let waiting = self.cpu.is_waiting(self.pins);
+        if (boundary || waiting)
+            && let Some(status) = self.monitor.take_stop()
+        {
+            status
+        } else if waiting {
+            Status::Idle
+        } else if boundary && let Some(hit) = self.monitor.exec(&self.bus, self.cpu.rp()) {
+            Status::Watchpoint(hit)
+        } else if boundary && self.check_breakpoint(self.cpu.rp()) {
+            Status::Breakpoint
+        } else {
+            Status::Ready
+        }
+    }
+
+    /// Undoes the most recent instruction, or the current one if it's incomplete. Returns the
+    /// reason to stop reverse execution, if any: a breakpoint or exec watchpoint at the restored
+    /// instruction, or a write watchpoint hit by the undone one.
+    pub fn step_back(&mut self) -> Result<Status, HistoryError> {
+        let delta = self.history.pop().ok_or(HistoryError::Empty)?;
+        let pc = delta.cpu.rp();
+        let hit = delta.writes.iter().find_map(|write| {
+            watch::check(
+                &self.monitor.watchpoints,
+                WatchKind::Write,
+                write.addr,
+                write.old,
+                write.new,
+                pc,
+            )
+        });
+        self.undo(delta);
+        let status = if let Some(hit) = hit {
+            Status::Watchpoint(hit)
+        } else if let Some(hit) = self.monitor.exec(&self.bus, pc) {
+            Status::Watchpoint(hit)
+        } else if self.breakpoints.get(&pc).is_some_and(|bp| {
+            bp.matches(|condition| condition.eval(&self.cpu, self.pins, &self.bus))
+                .unwrap_or(true)
+        }) {
+            Status::Breakpoint
+        } else {
+            Status::Ready
+        };
+        Ok(status)
+    }
+
+    /// Undoes instructions back to the latest one that started at or before the clock cycle.
+    pub fn rewind(&mut self, cycle: u64) -> Result<(), HistoryError> {
+        for delta in self.history.pop_after(cycle)? {
+            self.undo(delta);
+        }
+        Ok(())
+    }
+
+    /// Restores the state before an instruction. Input events are replayed from that time.
+    fn undo(&mut self, delta: Delta) {
+        for write in delta.writes.iter().rev() {
+            self.bus
+                .restore_byte(write.addr, write.old, write.initialized);
+        }
+        self.bus.restore_control(delta.bus);
+        self.instr_pc = delta.cpu.rp();
+        self.cpu = delta.cpu;
+        self.pins = delta.pins;
+        self.clock_cycle = delta.clock_cycle;
+        self.cycle_access = None;
+        self.monitor.reset();
+        self.output_events.truncate(delta.output_events);
+        // Events at the start of an instruction are applied before its state is recorded.
+        let now = self.now();
+        self.input_events.seek(now, true);
+    }
+
+    /// Observes a memory access in the tick-accurate core. The CPU holds MRD and MWR for several
+    /// ticks, so only the first tick of each access is observed.
+    fn observe_access(&mut self, access: MemoryAccess) {
+        let MemoryAccess {
+            mode,
+            addr,
+            data,
+            old,
+            initialized,
+        } = access;
+        if self.cycle_access == Some((mode, addr)) {
+            return;
+        }
+        self.cycle_access = Some((mode, addr));
+        match mode {
+            MemoryAccessMode::Read => self.monitor.read(&self.bus, addr, data, self.instr_pc),
+            MemoryAccessMode::Write => {
+                self.history.write(MemoryWrite {
+                    addr,
+                    old,
+                    new: data,
+                    initialized,
+                });
+                self.monitor.write(addr, old, data, self.instr_pc);
+            }
+        }
+    }
+
+    /// Ticks the CPU and bus for a single clock cycle.
+    fn tick_pins(&mut self, now: Duration) {
+        self.cpu.tick(&mut self.pins);
+
+        match self.bus.tick(&mut self.pins, true) {
+            Ok(Some(access)) => self.observe_access(access),
+            Ok(None) => self.cycle_access = None,
+            Err(MemoryAccessError::WriteProtectionFault(addr)) => {
+                if self.cycle_access != Some((MemoryAccessMode::Write, addr)) {
+                    self.cycle_access = Some((MemoryAccessMode::Write, addr));
+                    self.monitor.fault(addr, self.instr_pc);
+                }
+            }
+        }
+
         // Output data strobe.
         match (self.pins.get_mrd(), self.pins.get_tpb(), self.pins.get_n()) {
             (false, true, n) if n > 0 => {
-                let kind = match n {
-                    1 => OutputKind::Io1,
-                    2 => OutputKind::Io2,
-                    3 => OutputKind::Io3,
-                    4 => OutputKind::Io4,
-                    5 => OutputKind::Io5,
-                    6 => OutputKind::Io6,
-                    7 => OutputKind::Io7,
-                    _ => unreachable!(),
-                };
-                let value = self.pins.get_bus();
+                self.bus.signal(Signal::Output(n));
                 self.output_events.push(OutputEvent {
                     timestamp: now,
-                    kind,
-                    value,
+                    kind: output_kind(n),
+                    value: self.pins.get_bus(),
                 })
             }
             _ => (),
         }
-
-        self.clock_cycle += 1;
-        if self.cpu.is_waiting(self.pins) {
-            Status::Idle
-        } else if self.cpu.is_fetch_tick0() && self.has_breakpoint(self.cpu.rp()) {
-            Status::Breakpoint
-        } else {
-            Status::Ready
-        }
     }
 
     fn apply_event(&mut self, e: InputEvent) {
@@ -239,28 +541,43 @@ impl BasicSystem {
         }
     }
 
+    /// Returns the listing for the instruction at the address.
+    pub fn listing_at(&self, addr: u16) -> String {
+        listing_at(&self.bus, &self.cpu, &self.symbols, addr)
+    }
+
+    /// Returns the CPU state and the listing for the next instruction, followed by its source
+    /// line if it starts one.
     pub fn display(&self) -> String {
-        let listing = if self.cpu.is_fetch_tick0() {
-            let pc = self.cpu.rp();
-            self.memory
-                .get_instr_at(pc)
-                .map_or("??".into(), |i| i.listing())
-        } else {
-            "".into()
-        };
-        format!("{}  {}", self.cpu, listing)
+        if !self.cpu.is_fetch_tick0() {
+            return format!("{}  ", self.cpu);
+        }
+        let pc = self.cpu.rp();
+        let display = format!("{}  {}", self.cpu, self.listing_at(pc));
+        match self.source.line(pc) {
+            Some(line) => format!("{display}\n{line}"),
+            None => display,
+        }
     }
 
     pub fn cpu(&self) -> &Cdp1802 {
         &self.cpu
     }
 
-    pub fn memory(&self) -> &Memory {
-        &self.memory
+    pub fn source(&self) -> &SourceMap {
+        &self.source
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
     }
 
-    pub fn memory_mut(&mut self) -> &mut Memory {
-        &mut self.memory
+    pub fn bus(&self) -> &DeviceBus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut DeviceBus {
+        &mut self.bus
     }
 
     pub fn pins(&self) -> Cdp1802Pins {
@@ -271,3 +588,109 @@ impl BasicSystem {
         &mut self.pins
     }
 }
+
+/// The saved state of a [`BasicSystem`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasicSystemState {
+    cpu: Cdp1802,
+    bus: DeviceBusState,
+    pins: Cdp1802Pins,
+    clock_cycle: u64,
+}
+
+impl Snapshot for BasicSystem {
+    type State = BasicSystemState;
+    const SYSTEM: &'static str = "basic";
+
+    fn snapshot(&self) -> BasicSystemState {
+        BasicSystemState {
+            cpu: self.cpu.clone(),
+            bus: self.bus.state(),
+            pins: self.pins,
+            clock_cycle: self.clock_cycle,
+        }
+    }
+
+    /// Restores the state of the system. Input events that occurred before the restored
+    /// timestamp are treated as expired, and the execution history is discarded.
+    fn restore(&mut self, state: BasicSystemState) -> Result<(), SnapshotError> {
+        self.bus.restore(state.bus)?;
+        self.cpu = state.cpu;
+        self.pins = state.pins;
+        self.clock_cycle = state.clock_cycle;
+        self.history.clear();
+        let now = self.now();
+        self.input_events.seek(now, false);
+        Ok(())
+    }
+}
+
+/// Returns the output event kind for the N lines.
+fn output_kind(n: u8) -> OutputKind {
+    match n {
+        1 => OutputKind::Io1,
+        2 => OutputKind::Io2,
+        3 => OutputKind::Io3,
+        4 => OutputKind::Io4,
+        5 => OutputKind::Io5,
+        6 => OutputKind::Io6,
+        7 => OutputKind::Io7,
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the listing for the instruction at the address.
+fn listing_at(bus: &DeviceBus, cpu: &Cdp1802, symbols: &Symbols, addr: u16) -> String {
+    bus.get_instr_at(addr, cpu.variant())
+        .map_or("??".into(), |i| symbols.listing(&i, addr))
+}
+
+/// Memory and I/O for the instruction-level core.
+struct SystemBus<'a> {
+    bus: &'a mut DeviceBus,
+    output_events: &'a mut OutputEventLog,
+    now: Duration,
+    monitor: &'a mut Monitor,
+    history: &'a mut History,
+    /// The address of the instruction being executed.
+    pc: u16,
+}
+impl Bus for SystemBus<'_> {
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.bus.read(addr);
+        if self.monitor.checks_reads() {
+            self.monitor.read(self.bus, addr, data, self.pc);
+        }
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        let checks = self.monitor.checks_writes() || self.history.is_enabled();
+        let old = checks.then(|| (self.bus.peek(addr), self.bus.is_initialized(addr)));
+        match self.bus.write(addr, data) {
+            Ok(()) => {
+                if let Some((old, initialized)) = old {
+                    self.history.write(MemoryWrite {
+                        addr,
+                        old,
+                        new: data,
+                        initialized,
+                    });
+                    self.monitor.write(addr, old, data, self.pc);
+                }
+            }
+            Err(MemoryAccessError::WriteProtectionFault(addr)) => {
+                self.monitor.fault(addr, self.pc);
+            }
+        }
+    }
+
+    fn output(&mut self, n: u8, data: u8) {
+        self.bus.signal(Signal::Output(n));
+        self.output_events.push(OutputEvent {
+            timestamp: self.now,
+            kind: output_kind(n),
+            value: data,
+        })
+    }
+}
@@ -0,0 +1,167 @@
+//! Breakpoints
+
+use std::fmt::Display;
+
+use crate::expr::{Expr, ExprError};
+
+/// Stops execution before the instruction at an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// Only stop when the condition is true, i.e. non-zero. A condition that can't be evaluated
+    /// stops execution.
+    pub condition: Option<Expr>,
+    /// The number of hits to ignore before stopping.
+    pub ignore: u32,
+    /// The number of times the breakpoint has been reached with its condition true.
+    pub hits: u32,
+    /// Deleted when it stops execution.
+    pub temporary: bool,
+    pub enabled: bool,
+}
+impl Default for Breakpoint {
+    fn default() -> Self {
+        Self {
+            condition: None,
+            ignore: 0,
+            hits: 0,
+            temporary: false,
+            enabled: true,
+        }
+    }
+}
+impl Breakpoint {
+    pub fn with_condition(mut self, condition: Option<Expr>) -> Self {
+        self.condition = condition;
+        self
+    }
+
+    pub fn with_temporary(mut self, temporary: bool) -> Self {
+        self.temporary = temporary;
+        self
+    }
+
+    /// Returns true if the breakpoint is enabled and its condition is true.
+    pub(super) fn matches(
+        &self,
+        eval: impl FnOnce(&Expr) -> Result<i64, ExprError>,
+    ) -> Result<bool, ExprError> {
+        if !self.enabled {
+            return Ok(false);
+        }
+        match &self.condition {
+            Some(condition) => Ok(eval(condition)? != 0),
+            None => Ok(true),
+        }
+    }
+
+    /// Counts a hit if the breakpoint matches, and returns true if execution should stop.
+    pub(super) fn hit(
+        &mut self,
+        eval: impl FnOnce(&Expr) -> Result<i64, ExprError>,
+    ) -> Result<bool, ExprError> {
+        if !self.matches(eval)? {
+            return Ok(false);
+        }
+        self.hits += 1;
+        if self.ignore > 0 {
+            self.ignore -= 1;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+impl Display for Breakpoint {
+    /// Describes the condition and state, e.g. `if df, hits 2, ignore 3`. This is empty for an
+    /// enabled, unconditional breakpoint that hasn't been hit.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(condition) = &self.condition {
+            parts.push(format!("if {condition}"));
+        }
+        if self.hits > 0 {
+            parts.push(format!("hits {}", self.hits));
+        }
+        if self.ignore > 0 {
+            parts.push(format!("ignore {}", self.ignore));
+        }
+        if self.temporary {
+            parts.push("temporary".to_string());
+        }
+        if !self.enabled {
+            parts.push("disabled".to_string());
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::chips::cdp1802::Core;
+    use crate::symbols::Symbols;
+    use crate::systems::basic::Status;
+    use crate::systems::basic::test_util::{counter, run};
+
+    fn condition(source: &str) -> Option<Expr> {
+        Some(Expr::parse(source, &Symbols::default()).unwrap())
+    }
+
+    #[test]
+    fn test_condition() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = counter(core);
+            let bp = Breakpoint::default().with_condition(condition("r4 == 0x82 && m[0x81] == 1"));
+            sys.breakpoints_mut().insert(0x0007, bp);
+            assert_matches!(run(&mut sys), Status::Breakpoint, "{core:?}");
+            assert_eq!(sys.cpu().r[4], 0x82);
+            assert_eq!(sys.breakpoints()[&0x0007].hits, 1);
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+        }
+    }
+
+    #[test]
+    fn test_ignore() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = counter(core);
+            let bp = Breakpoint {
+                ignore: 1,
+                ..Breakpoint::default()
+            };
+            sys.breakpoints_mut().insert(0x0003, bp);
+            assert_matches!(run(&mut sys), Status::Breakpoint, "{core:?}");
+            assert_eq!(sys.cpu().r[4], 0x81);
+            let bp = &sys.breakpoints()[&0x0003];
+            assert_eq!((bp.hits, bp.ignore), (2, 0));
+            assert_eq!(bp.to_string(), "hits 2");
+        }
+    }
+
+    #[test]
+    fn test_temporary_and_disabled() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = counter(core);
+            let bp = Breakpoint::default().with_temporary(true);
+            sys.breakpoints_mut().insert(0x0003, bp);
+            let bp = Breakpoint {
+                enabled: false,
+                ..Breakpoint::default()
+            };
+            sys.breakpoints_mut().insert(0x0004, bp);
+            assert_matches!(run(&mut sys), Status::Breakpoint, "{core:?}");
+            assert!(!sys.breakpoints().contains_key(&0x0003));
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            assert_eq!(sys.breakpoints()[&0x0004].to_string(), "disabled");
+        }
+    }
+
+    #[test]
+    fn test_condition_error() {
+        let mut sys = counter(Core::Fast);
+        let bp = Breakpoint::default().with_condition(condition("1 / (r4 - 0x81)"));
+        sys.breakpoints_mut().insert(0x0004, bp);
+        assert_matches!(run(&mut sys), Status::Breakpoint);
+        assert_eq!(sys.cpu().r[4], 0x81);
+    }
+}
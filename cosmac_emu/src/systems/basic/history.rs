@@ -0,0 +1,279 @@
+//! Execution history, for stepping backwards
+//!
+//! Before each instruction, the system records the CPU state, pins, and the state of the bus apart
+//! from memory (such as whether a reset overlay is mapped), and then the memory that the
+//! instruction writes, so that the instruction can be undone. Input events are replayed from
+//! their timestamps.
+
+use std::collections::VecDeque;
+
+use crate::bus::DeviceBusControl;
+use crate::chips::cdp1802::{Cdp1802, Cdp1802Pins};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("at the start of history")]
+    Empty,
+    #[error("cycle {cycle:08x} is before the start of history, at {start:08x}")]
+    BeforeStart { cycle: u64, start: u64 },
+}
+
+/// The state before an instruction, and the memory it wrote.
+#[derive(Debug, Clone)]
+pub(super) struct Delta {
+    pub cpu: Cdp1802,
+    pub pins: Cdp1802Pins,
+    pub bus: DeviceBusControl,
+    pub clock_cycle: u64,
+    /// The number of output events that had been logged.
+    pub output_events: usize,
+    /// The writes made by the instruction, in order.
+    pub writes: Vec<MemoryWrite>,
+}
+
+/// A write to memory, with what it replaced.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct MemoryWrite {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+    /// Whether the address was initialized before the write.
+    pub initialized: bool,
+}
+
+/// A bounded history of instructions, oldest first.
+#[derive(Debug, Default)]
+pub(super) struct History {
+    deltas: VecDeque<Delta>,
+    /// The number of instructions to keep, or zero to disable recording.
+    pub capacity: usize,
+}
+impl History {
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Records the state before an instruction, dropping the oldest if the history is full.
+    pub fn push(&mut self, delta: Delta) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.deltas.len() == self.capacity {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+
+    /// Records a write by the current instruction.
+    pub fn write(&mut self, write: MemoryWrite) {
+        if let Some(delta) = self.deltas.back_mut() {
+            delta.writes.push(write);
+        }
+    }
+
+    /// Removes the most recent instruction.
+    pub fn pop(&mut self) -> Option<Delta> {
+        self.deltas.pop_back()
+    }
+
+    /// Removes the instructions after the latest one that started at or before the cycle, and
+    /// returns them, most recent first.
+    pub fn pop_after(&mut self, cycle: u64) -> Result<Vec<Delta>, HistoryError> {
+        let start = self.deltas.front().ok_or(HistoryError::Empty)?.clock_cycle;
+        if cycle < start {
+            return Err(HistoryError::BeforeStart { cycle, start });
+        }
+        let idx = self.deltas.partition_point(|d| d.clock_cycle <= cycle) - 1;
+        Ok(self.deltas.drain(idx..).rev().collect())
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::bus::{Device, DeviceBus, OverlayTrigger, ResetOverlay};
+    use crate::chips::cdp1802::{Core, Memory, Variant};
+    use crate::event::{InputEvent, InputKind};
+    use crate::instr::asm1802;
+    use crate::systems::basic::test_util::{run, system_with_bus};
+    use crate::systems::basic::{BasicSystem, Breakpoint, Status, WatchHit, WatchKind, Watchpoint};
+
+    fn system(core: Core) -> BasicSystem {
+        // Stores 1, 2, 3 at 0x81..0x83, then waits for EF1 and sets Q.
+        let (program, _) = asm1802! {
+            ldi 0x80;           // 0000
+            plo r4;             // 0002
+            loop: inc r4;       // 0003
+            glo r4;             // 0004
+            ani 3;              // 0005
+            str r4;             // 0007
+            xri 3;              // 0008
+            bnz loop;           // 000a
+            wait: bn1 wait;     // 000c
+            seq;                // 000e
+            idl                 // 000f
+        };
+        let memory = Memory::builder()
+            .with_capacity(0x100)
+            .unwrap()
+            .with_image(0, program)
+            .build()
+            .unwrap();
+        let mut sys =
+            system_with_bus(core, Variant::Cdp1802, DeviceBus::from(memory)).with_history(1000);
+        sys.add_event(InputEvent::new(
+            Duration::from_micros(0x180),
+            InputKind::Ef1,
+            0,
+        ));
+        sys
+    }
+
+    #[test]
+    fn test_step_back() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = system(core);
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            assert!(sys.pins().get_q());
+            let end = sys.clock_cycle();
+
+            // Undo idl and seq, and then the loop until the last store.
+            assert_matches!(sys.step_back(), Ok(Status::Ready));
+            assert_eq!(sys.cpu().rp(), 0x000f, "{core:?}");
+            assert!(sys.pins().get_q(), "{core:?}");
+            assert_matches!(sys.step_back(), Ok(Status::Ready));
+            assert_eq!(sys.cpu().rp(), 0x000e, "{core:?}");
+            assert!(!sys.pins().get_q(), "{core:?}");
+            while sys.cpu().rp() != 0x0008 {
+                sys.step_back().unwrap();
+            }
+            assert_eq!(sys.bus().peek(0x83), 0x03, "{core:?}");
+            assert!(sys.bus().is_initialized(0x83), "{core:?}");
+            sys.step_back().unwrap();
+            assert_eq!(sys.cpu().rp(), 0x0007, "{core:?}");
+            assert_eq!(sys.bus().peek(0x83), 0x00, "{core:?}");
+            assert!(!sys.bus().is_initialized(0x83), "{core:?}");
+            sys.step_back().unwrap();
+            sys.step_back().unwrap();
+            sys.step_back().unwrap();
+            assert_eq!(sys.cpu().rp(), 0x0003, "{core:?}");
+            assert_eq!(sys.cpu().r[4], 0x82, "{core:?}");
+
+            // Replaying reaches the same state, including the input event.
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            assert_eq!(sys.clock_cycle(), end, "{core:?}");
+            assert_eq!(sys.bus().peek(0x83), 0x03, "{core:?}");
+            assert!(sys.pins().get_q(), "{core:?}");
+        }
+    }
+
+    #[test]
+    fn test_reverse_stop() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = system(core);
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            sys.watchpoints_mut().push(Watchpoint {
+                kind: WatchKind::Write,
+                range: 0x82..=0x82,
+                value: None,
+            });
+            let hit = WatchHit {
+                kind: WatchKind::Write,
+                addr: 0x82,
+                old: 0x00,
+                new: 0x02,
+                pc: 0x0007,
+            };
+            let status = loop {
+                match sys.step_back() {
+                    Ok(Status::Ready) => (),
+                    status => break status,
+                }
+            };
+            assert_matches!(status, Ok(Status::Watchpoint(h)) if h == hit, "{core:?}");
+            assert_eq!(sys.cpu().rp(), 0x0007, "{core:?}");
+            assert_eq!(sys.bus().peek(0x82), 0x00, "{core:?}");
+
+            sys.breakpoints_mut().insert(0x0004, Breakpoint::default());
+            assert_matches!(sys.step_back(), Ok(Status::Ready));
+            assert_matches!(sys.step_back(), Ok(Status::Breakpoint));
+            assert_eq!(sys.cpu().r[4], 0x82, "{core:?}");
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = system(core);
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            sys.rewind(0x48).unwrap();
+            assert_eq!(sys.clock_cycle(), 0x40, "{core:?}");
+            assert_eq!(sys.cpu().rp(), 0x0005, "{core:?}");
+            sys.rewind(0x10).unwrap();
+            assert_eq!(sys.cpu().rp(), 0x0002, "{core:?}");
+            assert_eq!(sys.history.len(), 1);
+            sys.rewind(0x00).unwrap();
+            assert_eq!(sys.cpu().rp(), 0x0000, "{core:?}");
+            assert_matches!(sys.step_back(), Err(HistoryError::Empty));
+
+            // Only the most recent instructions are kept.
+            let mut sys = system(core).with_history(4);
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            assert_eq!(sys.history.len(), 4);
+            assert_matches!(
+                sys.rewind(0x00),
+                Err(HistoryError::BeforeStart { cycle: 0, .. })
+            );
+            assert_eq!(sys.history.len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_reset_overlay() {
+        for core in [Core::Tick, Core::Fast] {
+            // The overlay sets Q, which removes it and exposes the rest of the program in RAM.
+            let (rom, _) = asm1802! { seq; idl };
+            let (ram, _) = asm1802! { idl; req; idl };
+            let memory = Memory::builder()
+                .with_capacity(0x100)
+                .unwrap()
+                .with_image(0, ram)
+                .build()
+                .unwrap();
+            let overlay = ResetOverlay::new(rom, OverlayTrigger::Q);
+            let bus = DeviceBus::from(memory).with_device(0x0000..=0x0001, overlay);
+            let mut sys = system_with_bus(core, Variant::Cdp1802, bus).with_history(100);
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            assert!(!sys.pins().get_q(), "{core:?}");
+
+            // Undoing req leaves the overlay removed, and undoing seq maps it again.
+            sys.step_back().unwrap();
+            sys.step_back().unwrap();
+            assert_eq!(sys.cpu().rp(), 0x0001, "{core:?}");
+            assert!(sys.pins().get_q(), "{core:?}");
+            assert_eq!(sys.bus().peek(0x0001), 0x7a, "{core:?}");
+            sys.step_back().unwrap();
+            assert_eq!(sys.cpu().rp(), 0x0000, "{core:?}");
+            assert!(!sys.pins().get_q(), "{core:?}");
+            assert_eq!(sys.bus().peek(0x0001), 0x00, "{core:?}");
+
+            // Replaying removes it again.
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+            assert!(!sys.pins().get_q(), "{core:?}");
+            assert_eq!(sys.bus().peek(0x0001), 0x7a, "{core:?}");
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Memory access checks
+
+use std::collections::HashSet;
+use std::fmt::Display;
+
+use super::Status;
+use super::watch::{self, WatchHit, WatchKind, Watchpoint};
+use crate::bus::{Device, DeviceBus};
+use crate::fault::{Fault, FaultLog, FaultPolicy};
+
+/// What to do about reads of uninitialized memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UninitPolicy {
+    /// Don't check.
+    #[default]
+    Ignore,
+    /// Print a warning.
+    Warn,
+    /// Stop running, and break into the debugger.
+    Break,
+}
+
+/// A read of memory that has never been written, and wasn't loaded from an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UninitRead {
+    pub addr: u16,
+    /// The address of the instruction that made the read.
+    pub pc: u16,
+}
+impl Display for UninitRead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "uninitialized read at {:04x} (pc {:04x})",
+            self.addr, self.pc
+        )
+    }
+}
+
+/// Checks the memory accesses made by either core against watchpoints, the write protection fault
+/// policy, and the uninitialized read policy. Anything that stops execution is held until the end
+/// of the current instruction.
+#[derive(Debug, Default)]
+pub(super) struct Monitor {
+    pub watchpoints: Vec<Watchpoint>,
+    pub faults: FaultLog,
+    pub uninit: UninitPolicy,
+    /// Uninitialized reads that have already been reported, by PC and address.
+    uninit_seen: HashSet<(u16, u16)>,
+    /// Uninitialized reads reported under the warn policy that haven't been printed yet.
+    uninit_warnings: Vec<UninitRead>,
+    stop: Option<Status>,
+}
+impl Monitor {
+    /// Returns true if reads need to be checked.
+    pub fn checks_reads(&self) -> bool {
+        !self.watchpoints.is_empty() || self.uninit != UninitPolicy::Ignore
+    }
+
+    /// Returns true if writes need to be checked.
+    pub fn checks_writes(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    pub fn read(&mut self, bus: &DeviceBus, addr: u16, data: u8, pc: u16) {
+        self.watch(WatchKind::Read, addr, data, data, pc);
+        if self.uninit != UninitPolicy::Ignore
+            && !bus.is_initialized(addr)
+            && self.uninit_seen.insert((pc, addr))
+        {
+            let read = UninitRead { addr, pc };
+            match self.uninit {
+                UninitPolicy::Warn => self.uninit_warnings.push(read),
+                _ => self.stop(Status::Uninit(read)),
+            }
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, old: u8, data: u8, pc: u16) {
+        self.watch(WatchKind::Write, addr, old, data, pc);
+    }
+
+    pub fn fault(&mut self, addr: u16, pc: u16) {
+        let fault = Fault { addr, pc };
+        if self.faults.policy() == FaultPolicy::Log {
+            eprintln!("{fault}");
+        }
+        if self.faults.record(fault) {
+            self.stop(Status::Fault(fault));
+        }
+    }
+
+    /// Checks for exec watchpoints on the instruction about to be executed.
+    pub fn exec(&self, bus: &DeviceBus, pc: u16) -> Option<WatchHit> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        let opcode = bus.peek(pc);
+        watch::check(&self.watchpoints, WatchKind::Exec, pc, opcode, opcode, pc)
+    }
+
+    fn watch(&mut self, kind: WatchKind, addr: u16, old: u8, new: u8, pc: u16) {
+        if let Some(hit) = watch::check(&self.watchpoints, kind, addr, old, new, pc) {
+            self.stop(Status::Watchpoint(hit));
+        }
+    }
+
+    /// Holds the first reason to stop execution.
+    fn stop(&mut self, status: Status) {
+        self.stop.get_or_insert(status);
+    }
+
+    pub fn take_stop(&mut self) -> Option<Status> {
+        self.stop.take()
+    }
+
+    /// Removes and returns the warnings that haven't been taken yet.
+    pub fn take_uninit_warnings(&mut self) -> Vec<UninitRead> {
+        std::mem::take(&mut self.uninit_warnings)
+    }
+
+    pub fn reset(&mut self) {
+        self.stop = None;
+        self.uninit_warnings.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::chips::cdp1802::{Core, Memory, Variant};
+    use crate::instr::asm1802;
+    use crate::systems::basic::BasicSystem;
+    use crate::systems::basic::test_util::{run, system_with_bus};
+
+    /// Loads from 0x80 before it's written, then from 0x81 after it's written, and then idles.
+    const PROGRAM: (&[u8], &[(&str, u16)]) = asm1802! {
+        ldi 0x80;   // 0000
+        plo r4;     // 0002
+        ldn r4;     // 0003
+        inc r4;     // 0004
+        str r4;     // 0005
+        ldn r4;     // 0006
+        idl         // 0007
+    };
+
+    fn memory() -> Memory {
+        Memory::builder()
+            .with_image(0, PROGRAM.0)
+            .with_random()
+            .build()
+            .unwrap()
+    }
+
+    fn system(core: Core, policy: UninitPolicy) -> BasicSystem {
+        system_with_bus(core, Variant::Cdp1802, memory().into()).with_uninit_policy(policy)
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut memory = Memory::builder().with_image(0x10, [1, 2]).build().unwrap();
+        assert!(!memory.is_initialized(0x0f));
+        assert!(memory.is_initialized(0x10));
+        assert!(memory.is_initialized(0x11));
+        assert!(!memory.is_initialized(0x12));
+        memory.write(0x12, 3).unwrap();
+        assert!(memory.is_initialized(0x12));
+        memory.poke(0xffff, 4);
+        assert!(memory.is_initialized(0xffff));
+
+        // Snapshots that predate shadow tracking are fully initialized.
+        let memory: Memory =
+            serde_json::from_str(r#"{"data": "00000000", "write_protect": []}"#).unwrap();
+        assert!(memory.is_initialized(0));
+    }
+
+    #[test]
+    fn test_break() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = system(core, UninitPolicy::Break);
+            let read = UninitRead {
+                addr: 0x80,
+                pc: 0x03,
+            };
+            assert_matches!(run(&mut sys), Status::Uninit(r) if r == read, "{core:?}");
+            assert_eq!(sys.cpu().rp(), 0x0004, "{core:?}");
+            assert!(sys.listing_at(read.pc).ends_with("ldn  4"));
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+        }
+    }
+
+    #[test]
+    fn test_warn() {
+        let bus = DeviceBus::from(memory());
+        let read = UninitRead {
+            addr: 0x80,
+            pc: 0x03,
+        };
+
+        let mut monitor = Monitor::default();
+        monitor.read(&bus, read.addr, 0, read.pc);
+        assert_eq!(monitor.take_uninit_warnings(), []);
+
+        let mut monitor = Monitor {
+            uninit: UninitPolicy::Warn,
+            ..Monitor::default()
+        };
+        monitor.read(&bus, 0x03, PROGRAM.0[3], 0x03);
+        monitor.read(&bus, read.addr, 0, read.pc);
+        assert_eq!(monitor.take_uninit_warnings(), [read]);
+        assert_eq!(monitor.take_uninit_warnings(), []);
+
+        // The same read isn't reported again.
+        monitor.reset();
+        monitor.read(&bus, read.addr, 0, read.pc);
+        assert_eq!(monitor.take_uninit_warnings(), []);
+        monitor.read(&bus, 0x81, 0, 0x06);
+        assert_eq!(
+            monitor.take_uninit_warnings(),
+            [UninitRead {
+                addr: 0x81,
+                pc: 0x06
+            }]
+        );
+    }
+}
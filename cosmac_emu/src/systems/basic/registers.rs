@@ -0,0 +1,185 @@
+//! Registers and flags that the debugger may set
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A register or flag of the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    R(u8),
+    D,
+    Df,
+    P,
+    X,
+    T,
+    Ie,
+    Q,
+    Xie,
+    Cie,
+}
+impl Register {
+    /// Returns the largest value the register may hold.
+    pub fn max(self) -> u16 {
+        match self {
+            Register::R(_) => u16::MAX,
+            Register::D | Register::T => 0xff,
+            Register::P | Register::X => 0xf,
+            Register::Df | Register::Ie | Register::Q | Register::Xie | Register::Cie => 1,
+        }
+    }
+
+    /// Returns true for the counter interrupt enables, which only exist on extended CPUs.
+    pub fn is_extended(self) -> bool {
+        matches!(self, Register::Xie | Register::Cie)
+    }
+}
+impl Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Register::R(n) => write!(f, "r{n:x}"),
+            Register::D => f.write_str("d"),
+            Register::Df => f.write_str("df"),
+            Register::P => f.write_str("p"),
+            Register::X => f.write_str("x"),
+            Register::T => f.write_str("t"),
+            Register::Ie => f.write_str("ie"),
+            Register::Q => f.write_str("q"),
+            Register::Xie => f.write_str("xie"),
+            Register::Cie => f.write_str("cie"),
+        }
+    }
+}
+impl FromStr for Register {
+    type Err = RegisterError;
+
+    /// Parses a register name, e.g. `d`, `r3` or `r[3]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        let register = match lower.as_str() {
+            "d" => Register::D,
+            "df" => Register::Df,
+            "p" => Register::P,
+            "x" => Register::X,
+            "t" => Register::T,
+            "ie" => Register::Ie,
+            "q" => Register::Q,
+            "xie" => Register::Xie,
+            "cie" => Register::Cie,
+            _ => {
+                let n = lower
+                    .strip_prefix("r[")
+                    .and_then(|n| n.strip_suffix(']'))
+                    .or_else(|| lower.strip_prefix('r'))
+                    .ok_or_else(|| RegisterError::Unknown(s.to_string()))?;
+                let n = n.strip_prefix("0x").unwrap_or(n);
+                match u8::from_str_radix(n, 16) {
+                    Ok(n) if n < 16 => Register::R(n),
+                    _ => return Err(RegisterError::Unknown(s.to_string())),
+                }
+            }
+        };
+        Ok(register)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegisterError {
+    #[error("unknown register '{0}'")]
+    Unknown(String),
+    #[error("{value:#x} is out of range for {register}, which is at most {max:#x}", max = register.max())]
+    OutOfRange { register: Register, value: u16 },
+    #[error("{0} requires an extended CPU (--cpu 1805)")]
+    RequiresExtended(Register),
+    #[error("registers can only be set between instructions")]
+    NotAtBoundary,
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::chips::cdp1802::{Core, Memory, Variant};
+    use crate::instr::asm1802;
+    use crate::systems::basic::BasicSystem;
+    use crate::systems::basic::test_util::system_with_bus;
+
+    fn system(core: Core) -> BasicSystem {
+        system_with_variant(core, Variant::Cdp1802)
+    }
+
+    fn system_with_variant(core: Core, variant: Variant) -> BasicSystem {
+        let (program, _) = asm1802! { ldi 0x12; idl };
+        let memory = Memory::builder().with_image(0, program).build().unwrap();
+        system_with_bus(core, variant, memory.into())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("r3".parse::<Register>().unwrap(), Register::R(3));
+        assert_eq!("RF".parse::<Register>().unwrap(), Register::R(15));
+        assert_eq!("r[0xa]".parse::<Register>().unwrap(), Register::R(10));
+        assert_eq!("df".parse::<Register>().unwrap(), Register::Df);
+        assert!("r16".parse::<Register>().is_err());
+        assert!("r".parse::<Register>().is_err());
+        assert!("a".parse::<Register>().is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = system(core);
+            sys.set_register(Register::R(3), 0x8000).unwrap();
+            sys.set_register(Register::D, 0x3e).unwrap();
+            sys.set_register(Register::Df, 1).unwrap();
+            sys.set_register(Register::X, 3).unwrap();
+            sys.set_register(Register::Q, 1).unwrap();
+            assert_eq!(sys.cpu().r[3], 0x8000);
+            assert!(sys.cpu().df);
+            assert!(sys.pins().get_q());
+            assert!(sys.display().contains("q=1 d=3e.1 x=03:8000"), "{core:?}");
+            assert_matches!(
+                sys.set_register(Register::P, 0x10),
+                Err(RegisterError::OutOfRange { .. })
+            );
+
+            // Q stays set as the CPU runs, and the new program counter is used.
+            sys.set_register(Register::R(4), 0x0000).unwrap();
+            sys.set_register(Register::P, 4).unwrap();
+            sys.step();
+            assert!(sys.pins().get_q(), "{core:?}");
+            assert_eq!(sys.cpu().r[4], 0x0002, "{core:?}");
+            assert_eq!(sys.cpu().r[0], 0x0000, "{core:?}");
+            assert_eq!(sys.cpu().d, 0x12, "{core:?}");
+        }
+    }
+
+    #[test]
+    fn test_set_extended() {
+        let mut sys = system(Core::Tick);
+        assert_matches!(
+            sys.set_register(Register::Xie, 0),
+            Err(RegisterError::RequiresExtended(Register::Xie))
+        );
+        assert_matches!(
+            sys.set_register(Register::Cie, 0),
+            Err(RegisterError::RequiresExtended(Register::Cie))
+        );
+        assert!(sys.cpu().xie && sys.cpu().cie);
+
+        let mut sys = system_with_variant(Core::Tick, Variant::Cdp1805);
+        sys.set_register(Register::Xie, 0).unwrap();
+        sys.set_register(Register::Cie, 0).unwrap();
+        assert!(!sys.cpu().xie && !sys.cpu().cie);
+    }
+
+    #[test]
+    fn test_set_between_instructions() {
+        let mut sys = system(Core::Tick);
+        sys.tick();
+        assert_matches!(
+            sys.set_register(Register::D, 0),
+            Err(RegisterError::NotAtBoundary)
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Systems and programs shared by the basic system's tests
+
+use std::time::Duration;
+
+use super::{BasicSystem, Status};
+use crate::bus::DeviceBus;
+use crate::chips::cdp1802::{Cdp1802, Core, Memory, Variant};
+use crate::instr::asm1802;
+
+/// Returns a system with the program loaded at address 0.
+pub fn system(core: Core, program: &[u8]) -> BasicSystem {
+    let memory = Memory::builder().with_image(0, program).build().unwrap();
+    system_with_bus(core, Variant::Cdp1802, DeviceBus::from(memory))
+}
+
+pub fn system_with_bus(core: Core, variant: Variant, bus: DeviceBus) -> BasicSystem {
+    let cpu = Cdp1802::new(variant);
+    BasicSystem::new(cpu, bus, Duration::from_micros(1)).with_core(core)
+}
+
+/// Returns a system running a loop that counts r4 up from 0x80 to 0x83, storing the low bits at
+/// each address (so 1, 2, 3 at 0x81..=0x83), and then idles.
+pub fn counter(core: Core) -> BasicSystem {
+    let (program, _) = asm1802! {
+        ldi 0x80;           // 0000
+        plo r4;             // 0002
+        loop: inc r4;       // 0003
+        glo r4;             // 0004
+        ani 3;              // 0005
+        str r4;             // 0007
+        xri 3;              // 0008
+        bnz loop            // 000a
+    };
+    system(core, program)
+}
+
+/// Steps until the system stops for anything other than an input event.
+pub fn run(sys: &mut BasicSystem) -> Status {
+    loop {
+        match sys.step() {
+            Status::Ready | Status::Event => (),
+            status => return status,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Memory watchpoints
+
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+
+/// The kind of memory access a watchpoint observes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WatchKind {
+    /// Memory reads, including instruction fetches and operands.
+    Read,
+    /// Memory writes.
+    Write,
+    /// Instructions about to be executed.
+    Exec,
+}
+impl Display for WatchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Exec => "exec",
+        })
+    }
+}
+
+/// Stops execution when memory in the range is accessed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub range: RangeInclusive<u16>,
+    /// Only trigger when the value read, written, or executed matches.
+    pub value: Option<u8>,
+}
+impl Watchpoint {
+    fn matches(&self, kind: WatchKind, addr: u16, value: u8) -> bool {
+        self.kind == kind && self.range.contains(&addr) && self.value.is_none_or(|v| v == value)
+    }
+}
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:04x}", self.kind, self.range.start())?;
+        if self.range.start() != self.range.end() {
+            write!(f, "..{:04x}", self.range.end())?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " == {value:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A triggered watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub addr: u16,
+    /// The value at the address before the access.
+    pub old: u8,
+    /// The value read, written, or executed.
+    pub new: u8,
+    /// The address of the instruction that made the access.
+    pub pc: u16,
+}
+impl Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            kind,
+            addr,
+            old,
+            new,
+            pc,
+        } = self;
+        match kind {
+            WatchKind::Write => write!(f, "write {addr:04x}: {old:02x} -> {new:02x} (pc {pc:04x})"),
+            _ => write!(f, "{kind} {addr:04x}: {new:02x} (pc {pc:04x})"),
+        }
+    }
+}
+
+/// Returns the first watchpoint hit by an access, if any.
+pub(super) fn check(
+    watchpoints: &[Watchpoint],
+    kind: WatchKind,
+    addr: u16,
+    old: u8,
+    new: u8,
+    pc: u16,
+) -> Option<WatchHit> {
+    watchpoints
+        .iter()
+        .any(|w| w.matches(kind, addr, new))
+        .then_some(WatchHit {
+            kind,
+            addr,
+            old,
+            new,
+            pc,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::chips::cdp1802::Core;
+    use crate::systems::basic::Status;
+    use crate::systems::basic::test_util::{counter, run};
+
+    #[test]
+    fn test_write() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = counter(core);
+            sys.watchpoints_mut().push(Watchpoint {
+                kind: WatchKind::Write,
+                range: 0x80..=0x8f,
+                value: None,
+            });
+            let status = run(&mut sys);
+            let hit = WatchHit {
+                kind: WatchKind::Write,
+                addr: 0x81,
+                old: 0x00,
+                new: 0x01,
+                pc: 0x0007,
+            };
+            assert_matches!(status, Status::Watchpoint(h) if h == hit, "{core:?}");
+            assert_eq!(sys.cpu().rp(), 0x0008);
+        }
+    }
+
+    #[test]
+    fn test_write_value() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = counter(core);
+            sys.watchpoints_mut().push(Watchpoint {
+                kind: WatchKind::Write,
+                range: 0x80..=0x8f,
+                value: Some(3),
+            });
+            assert_matches!(
+                run(&mut sys),
+                Status::Watchpoint(WatchHit {
+                    addr: 0x83,
+                    new: 3,
+                    ..
+                }),
+                "{core:?}"
+            );
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = counter(core);
+            sys.watchpoints_mut().push(Watchpoint {
+                kind: WatchKind::Read,
+                range: 0x0b..=0x0b,
+                value: None,
+            });
+            assert_matches!(
+                run(&mut sys),
+                Status::Watchpoint(WatchHit {
+                    kind: WatchKind::Read,
+                    addr: 0x0b,
+                    new: 0x03,
+                    pc: 0x000a,
+                    ..
+                }),
+                "{core:?}"
+            );
+            assert_eq!(sys.cpu().rp(), 0x0003);
+        }
+    }
+
+    #[test]
+    fn test_exec() {
+        for core in [Core::Tick, Core::Fast] {
+            let mut sys = counter(core);
+            sys.watchpoints_mut().push(Watchpoint {
+                kind: WatchKind::Exec,
+                range: 0x07..=0x07,
+                value: Some(0x54),
+            });
+            let hit = WatchHit {
+                kind: WatchKind::Exec,
+                addr: 0x07,
+                old: 0x54,
+                new: 0x54,
+                pc: 0x07,
+            };
+            for _ in 0..3 {
+                assert_matches!(run(&mut sys), Status::Watchpoint(h) if h == hit, "{core:?}");
+            }
+            assert_matches!(run(&mut sys), Status::Idle, "{core:?}");
+        }
+    }
+}
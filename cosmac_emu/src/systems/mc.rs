@@ -5,11 +5,16 @@ use std::{
     time::{Duration, Instant},
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    chips::cdp1802::{Cdp1802, Cdp1802Pins, Memory},
-    instr::InstrSchema as _,
+    bus::{Device, DeviceBus, DeviceBusState, MemoryAccessError, Signal},
+    chips::cdp1802::{Bus, Cdp1802, Cdp1802Pins, Core, Memory, Variant},
+    fault::{Fault, FaultLog, FaultPolicy},
+    instr::{InstrSchema as _, MAX_INSTR_SIZE},
+    snapshot::{Snapshot, SnapshotError},
     time::TimeTracker,
-    uart::{Uart, UartRxError},
+    uart::{Uart, UartRxError, UartState},
 };
 
 /// The length of the execution opcode history, which we use for heuristics.
@@ -26,30 +31,46 @@ pub enum Status {
 }
 
 pub struct Builder {
-    memory: Memory,
+    core: Core,
+    cpu: Variant,
+    bus: DeviceBus,
     front: FrontPanel,
     invert_ef: bool,
     invert_q: bool,
     clk_freq: u32,
     speed: Option<f64>,
     uart: Option<Box<dyn Uart>>,
+    fault_policy: FaultPolicy,
+    start: u16,
 }
 impl Default for Builder {
     fn default() -> Self {
         Self {
-            memory: Memory::default(),
+            core: Core::default(),
+            cpu: Variant::default(),
+            bus: DeviceBus::from(Memory::default()),
             front: FrontPanel::default(),
             invert_ef: false,
             invert_q: false,
             clk_freq: 4_000_000,
             speed: None,
             uart: None,
+            fault_policy: FaultPolicy::Log,
+            start: 0,
         }
     }
 }
 impl Builder {
-    pub fn with_memory(self, memory: Memory) -> Self {
-        Self { memory, ..self }
+    pub fn with_core(self, core: Core) -> Self {
+        Self { core, ..self }
+    }
+
+    pub fn with_cpu(self, cpu: Variant) -> Self {
+        Self { cpu, ..self }
+    }
+
+    pub fn with_bus(self, bus: DeviceBus) -> Self {
+        Self { bus, ..self }
     }
 
     pub fn with_invert_ef(self, invert_ef: bool) -> Self {
@@ -74,37 +95,61 @@ impl Builder {
         Self { uart, ..self }
     }
 
+    /// Sets the write protection fault policy. The halt and break policies both pause the CPU
+    /// by engaging the front panel wait switch.
+    pub fn with_fault_policy(self, fault_policy: FaultPolicy) -> Self {
+        Self {
+            fault_policy,
+            ..self
+        }
+    }
+
+    /// Starts execution at an address at power on, rather than 0x0000. Resetting with the front
+    /// panel starts from 0x0000, as usual.
+    pub fn with_start(self, start: u16) -> Self {
+        Self { start, ..self }
+    }
+
     pub fn build(self) -> MembershipCard {
-        let mut cpu = Cdp1802::default();
+        let mut cpu = Cdp1802::new(self.cpu);
         let mut cpu_pins = Cdp1802Pins::default();
         if self.invert_ef {
             cpu_pins.set_ef(0);
         }
         cpu.reset(&mut cpu_pins);
+        // Run the initialization cycle, which clears R0, before setting the start address.
+        while !cpu.is_fetch_tick0() {
+            cpu.tick(&mut cpu_pins);
+        }
+        cpu.r[0] = self.start;
 
         let clock_freq_f64 = self.clk_freq as f64;
         let tick_duration = Duration::from_secs_f64(clock_freq_f64.recip());
         let time_tracker = self.speed.map(TimeTracker::new);
 
         MembershipCard {
+            core: self.core,
             now: Duration::default(),
             tick_duration,
             time_tracker,
             cpu_pins,
             cpu,
-            memory: self.memory,
+            bus: self.bus,
             front_panel: self.front,
             last_front_panel: self.front,
             uart: self.uart,
             invert_ef: self.invert_ef,
             invert_q: self.invert_q,
-            last_pc: 0,
+            last_pc: self.start,
+            exec_opcode: None,
             opcode_history: VecDeque::with_capacity(OPCODE_HISTORY_LEN),
+            faults: FaultLog::new(self.fault_policy),
+            cycle_fault: None,
         }
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FrontPanel {
     pub out_buffer: u8,
     pub inp_buffer: u8,
@@ -116,19 +161,25 @@ pub struct FrontPanel {
 
 #[derive(Debug)]
 pub struct MembershipCard {
+    core: Core,
     now: Duration,
     tick_duration: Duration,
     time_tracker: Option<TimeTracker>,
     cpu_pins: Cdp1802Pins,
     cpu: Cdp1802,
-    memory: Memory,
+    bus: DeviceBus,
     front_panel: FrontPanel,
     last_front_panel: FrontPanel,
     uart: Option<Box<dyn Uart>>,
     invert_ef: bool,
     invert_q: bool,
     last_pc: u16,
+    exec_opcode: Option<u8>,
     opcode_history: VecDeque<u8>,
+    faults: FaultLog,
+    /// The address of a write protection fault in the current machine cycle, which is only
+    /// recorded once.
+    cycle_fault: Option<u16>,
 }
 impl Default for MembershipCard {
     fn default() -> Self {
@@ -152,9 +203,9 @@ impl MembershipCard {
         &self.cpu
     }
 
-    /// Returns a reference to memory.
-    pub fn memory(&self) -> &Memory {
-        &self.memory
+    /// Returns a reference to the device bus.
+    pub fn bus(&self) -> &DeviceBus {
+        &self.bus
     }
 
     /// Returns a reference to the front panel.
@@ -167,8 +218,11 @@ impl MembershipCard {
         &mut self.front_panel
     }
 
-    /// Ticks the clock.
-    pub fn tick(&mut self) {
+    /// Ticks the clock, and returns the number of clock cycles elapsed.
+    ///
+    /// With the instruction-level core, the system advances by a whole instruction at a time
+    /// whenever the CPU is running.
+    pub fn tick(&mut self) -> u32 {
         let start = Instant::now();
 
         // Propagate signals from front panel.
@@ -183,8 +237,51 @@ impl MembershipCard {
         if load && self.last_front_panel.inp && !self.front_panel.inp {
             self.cpu_pins.set_dma_in(false);
         }
+        if self.front_panel.clear && !self.last_front_panel.clear {
+            self.bus.signal(Signal::Reset);
+        }
         self.last_front_panel = self.front_panel;
 
+        let q_prev = self.cpu_pins.get_q();
+
+        let cycles = if self.core == Core::Fast && self.cpu.can_step(self.cpu_pins) {
+            self.step_cpu(write_enable)
+        } else {
+            self.tick_cpu(load, write_enable);
+            1
+        };
+
+        let q = self.cpu_pins.get_q();
+        if q != q_prev {
+            self.bus.signal(Signal::Q(q));
+        }
+
+        if let Some(uart) = &mut self.uart {
+            // Tick or reset the UART as necessary.
+            if self.front_panel.clear {
+                uart.reset();
+            } else if !self.front_panel.wait {
+                // Propagate Q to UART rx pin.
+                uart.set_rx_pin(self.invert_q ^ !self.cpu_pins.get_q());
+                for _ in 0..cycles {
+                    uart.tick();
+                }
+            }
+            // Propagate UART tx pin to EF3.
+            self.cpu_pins.set_ef3(self.invert_ef ^ uart.get_tx_pin());
+        }
+
+        // Update clock, and sleep if we're too far ahead of schedule.
+        let elapsed = self.tick_duration * cycles;
+        if let Some(tt) = &mut self.time_tracker {
+            tt.tick(start.elapsed(), elapsed);
+        }
+        self.now += elapsed;
+        cycles
+    }
+
+    /// Ticks the CPU and bus for a single clock cycle.
+    fn tick_cpu(&mut self, load: bool, write_enable: bool) {
         // Tick cpu.
         self.cpu.tick(&mut self.cpu_pins);
 
@@ -196,10 +293,10 @@ impl MembershipCard {
             // the instruction at last_pc to determine its size to determine whether it has
             // been completely written.
             let rp = self.cpu.rp().saturating_sub(1);
-            if rp > self.last_pc && rp < self.last_pc + 3 {
-                let instr = self.memory.get_instr_at(self.last_pc);
+            if rp > self.last_pc && rp < self.last_pc.saturating_add(MAX_INSTR_SIZE) {
+                let instr = self.bus.get_instr_at(self.last_pc, self.cpu.variant());
                 let size = instr.map_or(1, |i| i.size() as u16);
-                if rp >= self.last_pc + size {
+                if rp >= self.last_pc.saturating_add(size) {
                     self.last_pc = rp;
                 }
             } else if rp != self.last_pc {
@@ -208,11 +305,8 @@ impl MembershipCard {
         }
 
         // Update opcode history.
-        if self.front_panel.clear {
-            self.opcode_history.truncate(0);
-        } else if let Some(opcode) = self.cpu.get_exec_opcode() {
-            self.push_opcode_history(opcode);
-        }
+        self.exec_opcode = self.cpu.get_exec_opcode();
+        self.update_opcode_history();
 
         // Reset /DmaIn in S2.
         if !self.cpu_pins.get_dma_in() && self.cpu_pins.get_sc1() {
@@ -225,35 +319,58 @@ impl MembershipCard {
             self.cpu_pins.set_bus(self.front_panel.inp_buffer);
         }
 
-        // Tick memory.
-        let result = self.memory.tick(&mut self.cpu_pins, write_enable);
-        if let Err(err) = result {
-            log::warn!("memory: {err}")
+        // Tick the bus.
+        match self.bus.tick(&mut self.cpu_pins, write_enable) {
+            Ok(_) => self.cycle_fault = None,
+            Err(MemoryAccessError::WriteProtectionFault(addr)) => {
+                if self.cycle_fault != Some(addr) {
+                    self.cycle_fault = Some(addr);
+                    let fault = Fault {
+                        addr,
+                        pc: self.last_pc,
+                    };
+                    record_fault(&mut self.faults, &mut self.front_panel, fault);
+                }
+            }
         }
 
         // Latch output on data strobe when either N2 is set or we're in load mode.
-        if !self.cpu_pins.get_mrd() && self.cpu_pins.get_tpb() && n2_or_load {
+        let strobe = !self.cpu_pins.get_mrd() && self.cpu_pins.get_tpb();
+        if strobe && n2_or_load {
             self.front_panel.out_buffer = self.cpu_pins.get_bus();
         }
+        if strobe && self.cpu_pins.get_n() > 0 {
+            self.bus.signal(Signal::Output(self.cpu_pins.get_n()));
+        }
+    }
 
-        if let Some(uart) = &mut self.uart {
-            // Tick or reset the UART as necessary.
-            if self.front_panel.clear {
-                uart.reset();
-            } else if !self.front_panel.wait {
-                // Propagate Q to UART rx pin.
-                uart.set_rx_pin(self.invert_q ^ !self.cpu_pins.get_q());
-                uart.tick();
-            }
-            // Propagate UART tx pin to EF3.
-            self.cpu_pins.set_ef3(self.invert_ef ^ uart.get_tx_pin());
+    /// Steps the CPU with the instruction-level core, and returns the number of clock cycles
+    /// elapsed.
+    fn step_cpu(&mut self, write_enable: bool) -> u32 {
+        let fetch = self.cpu.is_fetch_tick0();
+        let mut bus = McBus {
+            bus: &mut self.bus,
+            front_panel: &mut self.front_panel,
+            write_enable,
+            faults: &mut self.faults,
+            pc: self.last_pc,
+        };
+        let cycles = self.cpu.step(&mut self.cpu_pins, &mut bus);
+        self.exec_opcode = fetch.then_some(self.cpu.instr);
+        self.update_opcode_history();
+        if self.cpu.is_fetch_tick0() {
+            self.last_pc = self.cpu.rp();
         }
+        cycles
+    }
 
-        // Update clock, and sleep if we're too far ahead of schedule.
-        if let Some(tt) = &mut self.time_tracker {
-            tt.tick(start.elapsed(), self.tick_duration);
+    /// Records the opcode of the instruction being executed, if any.
+    fn update_opcode_history(&mut self) {
+        if self.front_panel.clear {
+            self.opcode_history.truncate(0);
+        } else if let Some(opcode) = self.exec_opcode {
+            self.push_opcode_history(opcode);
         }
-        self.now += self.tick_duration;
     }
 
     /// Records the current opcode, if the CPU has just entered S1.
@@ -271,8 +388,9 @@ impl MembershipCard {
             .as_mut()
             .map(|uart| (uart.rx(), uart.rx_hold_cycles()))
             .expect("no uart");
-        for _ in 0..hold_cycles {
-            self.tick();
+        let mut cycles = 0;
+        while cycles < hold_cycles {
+            cycles += self.tick();
         }
         result
     }
@@ -287,8 +405,9 @@ impl MembershipCard {
                 uart.tx_hold_cycles()
             })
             .expect("no uart");
-        for _ in 0..hold_cycles {
-            self.tick();
+        let mut cycles = 0;
+        while cycles < hold_cycles {
+            cycles += self.tick();
         }
     }
 
@@ -326,7 +445,7 @@ impl MembershipCard {
     ///  - The CPU is running.
     ///  - The system has a UART configured.
     ///  - The UART's transmit side is idle.
-    ///  - The CPU has just entered S1 and is branching on EF3.
+    ///  - The CPU is branching on EF3.
     ///  - The CPU executed the same branch opcode recently.
     ///
     /// This is an approximation, and it's not perfect. Just because the program is probing the EF
@@ -347,7 +466,7 @@ impl MembershipCard {
         {
             return false;
         }
-        match self.cpu.get_exec_opcode() {
+        match self.exec_opcode {
             Some(opcode @ (0x36 | 0x3e)) => {
                 self.opcode_history
                     .iter()
@@ -364,3 +483,171 @@ impl MembershipCard {
         self.front_panel != self.last_front_panel
     }
 }
+
+/// The saved state of a [`MembershipCard`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipCardState {
+    now: Duration,
+    cpu_pins: Cdp1802Pins,
+    cpu: Cdp1802,
+    bus: DeviceBusState,
+    front_panel: FrontPanel,
+    last_front_panel: FrontPanel,
+    uart: Option<UartState>,
+    last_pc: u16,
+    exec_opcode: Option<u8>,
+    opcode_history: VecDeque<u8>,
+}
+
+impl Snapshot for MembershipCard {
+    type State = MembershipCardState;
+    const SYSTEM: &'static str = "mc";
+
+    fn snapshot(&self) -> MembershipCardState {
+        MembershipCardState {
+            now: self.now,
+            cpu_pins: self.cpu_pins,
+            cpu: self.cpu.clone(),
+            bus: self.bus.state(),
+            front_panel: self.front_panel,
+            last_front_panel: self.last_front_panel,
+            uart: self.uart.as_ref().map(|uart| uart.state()),
+            last_pc: self.last_pc,
+            exec_opcode: self.exec_opcode,
+            opcode_history: self.opcode_history.clone(),
+        }
+    }
+
+    fn restore(&mut self, state: MembershipCardState) -> Result<(), SnapshotError> {
+        self.bus.restore(state.bus)?;
+        self.now = state.now;
+        self.cpu_pins = state.cpu_pins;
+        self.cpu = state.cpu;
+        self.front_panel = state.front_panel;
+        self.last_front_panel = state.last_front_panel;
+        self.uart = state.uart.map(UartState::into_box);
+        self.last_pc = state.last_pc;
+        self.exec_opcode = state.exec_opcode;
+        self.opcode_history = state.opcode_history;
+        if let Some(tt) = &mut self.time_tracker {
+            tt.reset();
+        }
+        Ok(())
+    }
+}
+
+/// Records a write protection fault according to the fault policy.
+fn record_fault(faults: &mut FaultLog, front_panel: &mut FrontPanel, fault: Fault) {
+    let stop = faults.record(fault);
+    if stop {
+        log::warn!("{fault}: waiting");
+        front_panel.wait = true;
+    } else if faults.policy() == FaultPolicy::Log {
+        log::warn!("{fault}");
+    }
+}
+
+/// Memory and front panel I/O for the instruction-level core.
+struct McBus<'a> {
+    bus: &'a mut DeviceBus,
+    front_panel: &'a mut FrontPanel,
+    write_enable: bool,
+    faults: &'a mut FaultLog,
+    /// The address of the instruction being executed.
+    pc: u16,
+}
+impl Bus for McBus<'_> {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.bus.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if self.write_enable
+            && let Err(MemoryAccessError::WriteProtectionFault(addr)) = self.bus.write(addr, data)
+        {
+            let fault = Fault { addr, pc: self.pc };
+            record_fault(self.faults, self.front_panel, fault);
+        }
+    }
+
+    // The front panel buffers are selected by N2.
+    fn output(&mut self, n: u8, data: u8) {
+        self.bus.signal(Signal::Output(n));
+        if n & 4 != 0 {
+            self.front_panel.out_buffer = data;
+        }
+    }
+
+    fn input(&mut self, n: u8) -> Option<u8> {
+        (n & 4 != 0).then_some(self.front_panel.inp_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Toggles a byte in with the front panel in load mode.
+    fn load_byte(mc: &mut MembershipCard, byte: u8) {
+        mc.front_panel_mut().inp_buffer = byte;
+        mc.front_panel_mut().inp = true;
+        for _ in 0..16 {
+            mc.tick();
+        }
+        mc.front_panel_mut().inp = false;
+        for _ in 0..32 {
+            mc.tick();
+        }
+    }
+
+    #[test]
+    fn test_load_last_pc() {
+        let mut mc = MembershipCard::builder()
+            .with_cpu(Variant::Cdp1805)
+            .with_bus(DeviceBus::from(Memory::builder().build().unwrap()))
+            .build();
+        mc.front_panel_mut().clear = true;
+        mc.front_panel_mut().wait = true;
+        mc.tick();
+
+        // rldi 4, 0x1234 stays the last instruction until all four bytes are written.
+        for byte in [0x68, 0xc4, 0x12, 0x34] {
+            load_byte(&mut mc, byte);
+            assert_eq!(mc.last_pc(), 0x0000);
+        }
+        load_byte(&mut mc, 0xc4);
+        assert_eq!(mc.last_pc(), 0x0004);
+        assert_eq!(mc.bus().peek(0x0003), 0x34);
+
+        // lbr at the top of memory, whose end is past 0xffff.
+        mc.cpu.r[0] = 0xfffd;
+        for byte in [0xc0, 0x12] {
+            load_byte(&mut mc, byte);
+            assert_eq!(mc.last_pc(), 0xfffd);
+        }
+    }
+
+    #[test]
+    fn test_start() {
+        // seq at 0x0000, which is skipped, and req; idl at the start address. idl doesn't
+        // advance R[P].
+        let memory = Memory::builder()
+            .with_image(0, [0x7b])
+            .with_image(0x10, [0x7a, 0x00])
+            .build()
+            .unwrap();
+        for core in [Core::Tick, Core::Fast] {
+            let mut mc = MembershipCard::builder()
+                .with_core(core)
+                .with_bus(DeviceBus::from(memory.clone()))
+                .with_start(0x0010)
+                .build();
+            assert_eq!(mc.last_pc(), 0x0010);
+            for _ in 0..64 {
+                mc.tick();
+            }
+            assert_eq!(mc.cpu().rp(), 0x0011, "{core:?}");
+            assert_eq!(mc.last_pc(), 0x0011, "{core:?}");
+        }
+    }
+}
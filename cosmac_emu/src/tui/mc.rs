@@ -14,6 +14,8 @@ use ratatui::{
     widgets::{Block, Borders, Paragraph, Widget},
 };
 
+use crate::source_map::SourceMap;
+use crate::symbols::Symbols;
 use crate::systems::mc::{MembershipCard, Status};
 
 mod widgets;
@@ -77,6 +79,8 @@ impl Focus {
 
 pub struct MembershipCardTui {
     mc: MembershipCard,
+    symbols: Symbols,
+    source: SourceMap,
     focus: Focus,
     front_panel: FrontPanelWidget,
     terminal: TerminalWidget,
@@ -103,6 +107,8 @@ impl MembershipCardTui {
     pub fn new(mc: MembershipCard) -> Self {
         Self {
             mc,
+            symbols: Symbols::default(),
+            source: SourceMap::default(),
             focus: Default::default(),
             front_panel: Default::default(),
             terminal: Default::default(),
@@ -111,6 +117,16 @@ impl MembershipCardTui {
         }
     }
 
+    pub fn with_symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn with_source(mut self, source: SourceMap) -> Self {
+        self.source = source;
+        self
+    }
+
     fn min_term_size(&self) -> (u16, u16) {
         let width = 4
             + TerminalWidget::width()
@@ -209,7 +225,13 @@ impl MembershipCardTui {
             right_chunks[2],
             ListingWidget::width(),
             ListingWidget::height(),
-            ListingWidget::as_text(self.mc.memory(), self.mc.last_pc()),
+            ListingWidget::as_text(
+                self.mc.bus(),
+                self.mc.cpu().variant(),
+                &self.symbols,
+                &self.source,
+                self.mc.last_pc(),
+            ),
         );
         self.render_block(
             f,
@@ -242,7 +264,12 @@ impl MembershipCardTui {
         f.render_widget(w, inner);
     }
 
-    pub fn run(mut self) -> Result<()> {
+    /// Returns a reference to the membership card.
+    pub fn mc(&self) -> &MembershipCard {
+        &self.mc
+    }
+
+    pub fn run(&mut self) -> Result<()> {
         let mut terminal = ratatui::init();
         log::set_logger(&*LOG_BUFFER).unwrap();
         log::set_max_level(log::LevelFilter::Info);
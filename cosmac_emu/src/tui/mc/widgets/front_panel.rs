@@ -47,10 +47,8 @@ impl FrontPanelWidget {
 
     pub fn handle_input(&mut self, fp: &mut FrontPanel, key: KeyEvent) {
         match key.code {
-            KeyCode::Left => {
-                if self.selected_col > 0 {
-                    self.selected_col -= 1;
-                }
+            KeyCode::Left if self.selected_col > 0 => {
+                self.selected_col -= 1;
             }
             KeyCode::Right => {
                 let max_col = match self.selected_row {
@@ -62,17 +60,13 @@ impl FrontPanelWidget {
                     self.selected_col += 1;
                 }
             }
-            KeyCode::Up => {
-                if self.selected_row > 1 {
-                    self.selected_row -= 1;
-                    self.selected_col = 0;
-                }
+            KeyCode::Up if self.selected_row > 1 => {
+                self.selected_row -= 1;
+                self.selected_col = 0;
             }
-            KeyCode::Down => {
-                if self.selected_row < 2 {
-                    self.selected_row += 1;
-                    self.selected_col = 0;
-                }
+            KeyCode::Down if self.selected_row < 2 => {
+                self.selected_row += 1;
+                self.selected_col = 0;
             }
             KeyCode::Char(' ') | KeyCode::Enter => match self.selected_row {
                 1 => fp.inp_buffer ^= 1 << (7 - self.selected_col),
@@ -1,6 +1,12 @@
 use ratatui::text::{Line, Text};
 
-use crate::{chips::cdp1802::Memory, instr::InstrSchema as _};
+use crate::{
+    bus::{Device, DeviceBus},
+    chips::cdp1802::Variant,
+    instr::InstrSchema as _,
+    source_map::SourceMap,
+    symbols::Symbols,
+};
 
 #[derive(Default, Clone, Copy)]
 pub struct ListingWidget {}
@@ -12,12 +18,31 @@ impl ListingWidget {
     pub const fn width() -> u16 {
         30
     }
-    pub fn as_text(mem: &Memory, mut pc: u16) -> Text<'_> {
+    /// Lists instructions from the PC, showing the source line for instructions that start one,
+    /// and the disassembly for the rest.
+    pub fn as_text<'a>(
+        bus: &'a DeviceBus,
+        variant: Variant,
+        symbols: &Symbols,
+        source: &SourceMap,
+        mut pc: u16,
+    ) -> Text<'a> {
         let mut lines = Vec::new();
-        for i in 0..Self::height() {
-            let instr = mem.get_instr_at(pc);
-            let (listing, size) = instr.map_or(("??".into(), 1), |i| (i.listing(), i.size()));
-            let sigil = if i == 0 { ">" } else { " " };
+        let mut first = true;
+        while lines.len() < usize::from(Self::height()) {
+            let line = source.line(pc);
+            if let (None, Some(name)) = (line, symbols.name(pc)) {
+                lines.push(Line::from(format!(" {name}:")));
+                if lines.len() == usize::from(Self::height()) {
+                    break;
+                }
+            }
+            let instr = bus.get_instr_at(pc, variant);
+            let (listing, size) =
+                instr.map_or(("??".into(), 1), |i| (symbols.listing(&i, pc), i.size()));
+            let listing = line.map_or(listing, |line| line.text.clone());
+            let sigil = if first { ">" } else { " " };
+            first = false;
             lines.push(Line::from(format!(" {sigil}{pc:04x} {listing}")));
             match pc.overflowing_add(size as u16) {
                 (next, false) => pc = next,
@@ -1,5 +1,9 @@
 use std::{fmt::Display, str::FromStr};
 
+use serde::{Deserialize, Serialize};
+
+use crate::chips::ay51013::Ay51013Uart;
+
 #[derive(thiserror::Error, Debug, Clone, Copy)]
 pub enum UartRxError {
     #[error("framing error")]
@@ -10,13 +14,13 @@ pub enum UartRxError {
     Overrun,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Parity {
     Even,
     Odd,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UartMode {
     pub char_bits: u8,
     pub parity: Option<Parity>,
@@ -101,4 +105,22 @@ pub trait Uart: std::fmt::Debug {
     fn is_rx_ready(&self) -> bool;
     fn is_tx_ready(&self) -> bool;
     fn is_tx_idle(&self) -> bool;
+
+    /// Captures the internal state of the UART, for a snapshot.
+    fn state(&self) -> UartState;
+}
+
+/// The saved state of a [`Uart`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UartState {
+    Ay51013(Ay51013Uart),
+}
+impl UartState {
+    /// Restores the UART from its saved state.
+    pub fn into_box(self) -> Box<dyn Uart> {
+        match self {
+            Self::Ay51013(uart) => uart.into_box(),
+        }
+    }
 }
@@ -0,0 +1,545 @@
+//! Compile-time assembler
+//!
+//! The `#[asm_macro(name)]` attribute on an `InstrSchema` enum generates a `macro_rules!` macro
+//! that forwards the enum's schema table to [`expand`], followed by the source to assemble:
+//!
+//! ```text
+//! let (program, labels) = asm1802! { ldi 0x08; plo r2; loop: dec r2; glo r2; bnz loop };
+//! ```
+//!
+//! Statements are separated by semicolons, and have the form `[label:] mnemonic [operand, ...]`.
+//! Operands are written with Rust's integer, character and string literals, and are otherwise
+//! evaluated as by the text assembler (see [`cosmac_asm`]): labels, the unary operators `-`, `~`
+//! (or `!`), `high` and `low`, and the binary operators `* / % + - << >> & ^ |`. Registers may be
+//! written as `r0`..`r15` or `ra`..`rf`. The directives `org`, `db`, `dw` and `ds` are supported.
+//! The program is assembled from address zero, and `org` may only move forward, padding with
+//! zeros.
+//!
+//! The macro expands to a tuple of the encoded bytes and the labels, as `(&[u8], &[(&str, u16)])`.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use cosmac_asm::{
+    Expr, Token as AsmToken, parse_register, short_branch, to_addr, to_byte, to_word, tokenize,
+};
+use proc_macro2::{Delimiter, Ident, Spacing, Span, TokenStream as TokenStream2, TokenTree};
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::parse::{ParseStream, Parser};
+use syn::{Error, Lit, LitStr, Result, Token};
+
+use crate::schema::Schema;
+
+/// An instruction in the schema table.
+struct Entry {
+    mnemonic: String,
+    schema: Schema,
+}
+
+/// An operand, with its tokens for errors.
+struct Operand {
+    expr: Expr,
+    tokens: TokenStream2,
+}
+
+/// An operand of `db`.
+enum Data {
+    Expr(Operand),
+    Str(Vec<u8>),
+}
+
+/// A directive or instruction.
+enum Op {
+    Org(Operand),
+    Db(Vec<Data>),
+    Dw(Vec<Operand>),
+    Ds(Operand),
+    Instr {
+        mnemonic: Ident,
+        schema: Schema,
+        registers: u16,
+        operands: Vec<Operand>,
+    },
+}
+
+struct Stmt {
+    label: Option<Ident>,
+    op: Op,
+}
+
+/// An assembled program.
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub bytes: Vec<u8>,
+    pub labels: Vec<(String, u16)>,
+}
+
+pub fn expand(input: TokenStream2) -> Result<TokenStream2> {
+    let Program { bytes, labels } = assemble(input)?;
+    let names = labels.iter().map(|(name, _)| name);
+    let addrs = labels.iter().map(|(_, addr)| addr);
+    Ok(quote! {
+        (
+            &[#(#bytes),*] as &[u8],
+            &[#((#names, #addrs)),*] as &[(&str, u16)],
+        )
+    })
+}
+
+/// Assembles the source following the schema table.
+pub fn assemble(input: TokenStream2) -> Result<Program> {
+    let mut tokens = input.into_iter();
+    let table = match tokens.next() {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Bracket => g.stream(),
+        _ => return Err(Error::new(Span::call_site(), "expected schema table")),
+    };
+    let table = parse_table.parse2(table)?;
+    let stmts = parse_source(&table, tokens.collect())?;
+    Assembler::default().assemble(&stmts)
+}
+
+fn parse_table(input: ParseStream) -> Result<Vec<Entry>> {
+    let mut table = vec![];
+    while !input.is_empty() {
+        let mnemonic = Ident::parse_any(input)?.to_string();
+        let lit: LitStr = input.parse()?;
+        let schema = Schema::parse_from_lit(&lit)?;
+        table.push(Entry { mnemonic, schema });
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+        }
+    }
+    Ok(table)
+}
+
+/// Splits tokens on a punctuation character.
+fn split(tokens: &[TokenTree], ch: char) -> Vec<&[TokenTree]> {
+    tokens
+        .split(|tt| matches!(tt, TokenTree::Punct(p) if p.as_char() == ch))
+        .collect()
+}
+
+/// Returns the span of a statement, for errors that don't belong to a particular token.
+fn span_of(tokens: &[TokenTree]) -> Span {
+    let first = tokens.first().map_or(Span::call_site(), TokenTree::span);
+    let last = tokens.last().map_or(first, TokenTree::span);
+    first.join(last).unwrap_or(first)
+}
+
+fn parse_source(table: &[Entry], tokens: Vec<TokenTree>) -> Result<Vec<Stmt>> {
+    let mut stmts = vec![];
+    let mut errors: Option<Error> = None;
+    for tokens in split(&tokens, ';') {
+        if tokens.is_empty() {
+            continue;
+        }
+        match parse_stmt(table, tokens) {
+            Ok(stmt) => stmts.push(stmt),
+            Err(err) => combine(&mut errors, err),
+        }
+    }
+    match errors {
+        Some(err) => Err(err),
+        None => Ok(stmts),
+    }
+}
+
+fn combine(errors: &mut Option<Error>, err: Error) {
+    match errors {
+        Some(errors) => errors.combine(err),
+        None => *errors = Some(err),
+    }
+}
+
+fn parse_stmt(table: &[Entry], tokens: &[TokenTree]) -> Result<Stmt> {
+    let (label, rest) = match tokens {
+        [TokenTree::Ident(label), TokenTree::Punct(p), rest @ ..]
+            if p.as_char() == ':' && p.spacing() == Spacing::Alone =>
+        {
+            (Some(label.clone()), rest)
+        }
+        _ => (None, tokens),
+    };
+    let (mnemonic, args) = match rest {
+        [TokenTree::Ident(mnemonic), args @ ..] => (mnemonic, args),
+        [tt, ..] => return Err(Error::new(tt.span(), "expected mnemonic")),
+        [] => return Err(Error::new(span_of(tokens), "expected mnemonic after label")),
+    };
+    let operands = if args.is_empty() {
+        vec![]
+    } else {
+        split(args, ',')
+    };
+    let single = || match operands.as_slice() {
+        [tokens] => parse_operand(tokens, mnemonic, Expr::parse),
+        _ => Err(Error::new(
+            mnemonic.span(),
+            format!("'{mnemonic}' takes one operand"),
+        )),
+    };
+    let name = mnemonic.to_string().to_lowercase();
+    let op = match name.as_str() {
+        "org" => Op::Org(single()?),
+        "ds" => Op::Ds(single()?),
+        "db" => Op::Db(
+            operands
+                .iter()
+                .map(|tokens| match lex(tokens, mnemonic)?.as_slice() {
+                    [AsmToken::Str(s)] => Ok(Data::Str(s.clone())),
+                    lexed => Operand::parse(lexed, tokens, Expr::parse).map(Data::Expr),
+                })
+                .collect::<Result<_>>()?,
+        ),
+        "dw" => Op::Dw(
+            operands
+                .iter()
+                .map(|tokens| parse_operand(tokens, mnemonic, Expr::parse))
+                .collect::<Result<_>>()?,
+        ),
+        _ => {
+            let entry = table.iter().find(|e| e.mnemonic == name).ok_or_else(|| {
+                Error::new(mnemonic.span(), format!("unknown instruction '{mnemonic}'"))
+            })?;
+            let schema = entry.schema;
+            let expected =
+                usize::from(schema.packed.is_some()) + usize::from(schema.immediates() > 0);
+            if operands.len() != expected {
+                return Err(Error::new(
+                    mnemonic.span(),
+                    format!("'{mnemonic}' takes {expected} operand(s)"),
+                ));
+            }
+            let operands = operands
+                .iter()
+                .enumerate()
+                .map(|(i, tokens)| {
+                    let parse = if i == 0 && schema.packed.is_some() {
+                        parse_register
+                    } else {
+                        Expr::parse
+                    };
+                    parse_operand(tokens, mnemonic, parse)
+                })
+                .collect::<Result<_>>()?;
+            Op::Instr {
+                mnemonic: mnemonic.clone(),
+                schema,
+                registers: schema.registers(table.iter().map(|e| &e.schema)),
+                operands,
+            }
+        }
+    };
+    Ok(Stmt { label, op })
+}
+
+/// A parser for an operand's tokens.
+type Parse = fn(&[AsmToken]) -> std::result::Result<Expr, String>;
+
+impl Operand {
+    fn parse(lexed: &[AsmToken], tokens: &[TokenTree], parse: Parse) -> Result<Operand> {
+        let tokens = tokens.iter().cloned().collect();
+        match parse(lexed) {
+            Ok(expr) => Ok(Operand { expr, tokens }),
+            Err(err) => Err(Error::new_spanned(tokens, err)),
+        }
+    }
+
+    /// Returns an error spanning the operand.
+    fn error(&self, message: impl Display) -> Error {
+        Error::new_spanned(&self.tokens, message)
+    }
+}
+
+fn parse_operand(tokens: &[TokenTree], mnemonic: &Ident, parse: Parse) -> Result<Operand> {
+    Operand::parse(&lex(tokens, mnemonic)?, tokens, parse)
+}
+
+/// Converts an operand's tokens to the assembler's tokens.
+fn lex(tokens: &[TokenTree], mnemonic: &Ident) -> Result<Vec<AsmToken>> {
+    if tokens.is_empty() {
+        return Err(Error::new(mnemonic.span(), "missing operand"));
+    }
+    convert(tokens.iter().cloned().collect())
+}
+
+/// Converts Rust tokens to the assembler's tokens. Literals are parsed as Rust literals, and
+/// identifiers are taken as written, so that labels may be keywords, e.g. `loop`. Punctuation is
+/// tokenized by the assembler, with `!` meaning `~`.
+fn convert(tokens: TokenStream2) -> Result<Vec<AsmToken>> {
+    let mut out = vec![];
+    let mut punct = String::new();
+    let mut punct_span = Span::call_site();
+    for tt in tokens {
+        if let TokenTree::Punct(p) = &tt {
+            if punct.is_empty() {
+                punct_span = p.span();
+            }
+            punct.push(match p.as_char() {
+                '!' => '~',
+                c => c,
+            });
+            if p.spacing() == Spacing::Joint {
+                continue;
+            }
+        }
+        if !punct.is_empty() {
+            out.extend(tokenize(&punct).map_err(|err| Error::new(punct_span, err))?);
+            punct.clear();
+        }
+        let span = tt.span();
+        match tt {
+            TokenTree::Punct(_) => (),
+            TokenTree::Ident(ident) => out.push(AsmToken::Ident(ident.unraw().to_string())),
+            TokenTree::Literal(lit) => out.push(match Lit::new(lit) {
+                Lit::Int(int) => AsmToken::Number(int.base10_parse()?),
+                Lit::Char(c) => AsmToken::Number(u32::from(c.value()).into()),
+                Lit::Byte(b) => AsmToken::Number(b.value().into()),
+                Lit::Str(s) => AsmToken::Str(s.value().into_bytes()),
+                Lit::ByteStr(s) => AsmToken::Str(s.value()),
+                _ => return Err(Error::new(span, "expected a number or string")),
+            }),
+            TokenTree::Group(g) => match g.delimiter() {
+                Delimiter::Parenthesis => {
+                    out.push(AsmToken::Punct("("));
+                    out.extend(convert(g.stream())?);
+                    out.push(AsmToken::Punct(")"));
+                }
+                Delimiter::None => out.extend(convert(g.stream())?),
+                _ => return Err(Error::new(span, "unexpected brackets")),
+            },
+        }
+    }
+    if !punct.is_empty() {
+        out.extend(tokenize(&punct).map_err(|err| Error::new(punct_span, err))?);
+    }
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Assembler {
+    labels: HashMap<String, u16>,
+    order: Vec<(String, u16)>,
+}
+impl Assembler {
+    fn assemble(mut self, stmts: &[Stmt]) -> Result<Program> {
+        let addrs = self.define_labels(stmts)?;
+        let mut bytes = vec![];
+        let mut errors = None;
+        for (stmt, &addr) in stmts.iter().zip(&addrs) {
+            bytes.resize(usize::from(addr), 0);
+            match self.encode(addr, &stmt.op) {
+                Ok(encoded) => bytes.extend(encoded),
+                Err(err) => combine(&mut errors, err),
+            }
+        }
+        match errors {
+            Some(err) => Err(err),
+            None => Ok(Program {
+                bytes,
+                labels: self.order,
+            }),
+        }
+    }
+
+    /// The first pass, which assigns an address to each statement and defines labels.
+    fn define_labels(&mut self, stmts: &[Stmt]) -> Result<Vec<u16>> {
+        let mut addrs = vec![];
+        let mut pc: u32 = 0;
+        for stmt in stmts {
+            if let Op::Org(operand) = &stmt.op {
+                let addr = u32::from(self.eval(operand, pc as u16, to_addr)?);
+                if addr < pc {
+                    return Err(operand.error("org may not move backwards"));
+                }
+                pc = addr;
+            }
+            addrs.push(pc as u16);
+            if let Some(label) = &stmt.label {
+                let name = label.unraw().to_string();
+                if self.labels.insert(name.clone(), pc as u16).is_some() {
+                    return Err(Error::new(
+                        label.span(),
+                        format!("label '{name}' already defined"),
+                    ));
+                }
+                self.order.push((name, pc as u16));
+            }
+            pc += match &stmt.op {
+                Op::Org(_) => 0,
+                Op::Db(data) => data
+                    .iter()
+                    .map(|d| match d {
+                        Data::Str(s) => s.len() as u32,
+                        Data::Expr(_) => 1,
+                    })
+                    .sum(),
+                Op::Dw(operands) => 2 * operands.len() as u32,
+                Op::Ds(operand) => u32::from(self.eval(operand, pc as u16, to_addr)?),
+                Op::Instr { schema, .. } => schema.size.into(),
+            };
+            if pc > 0x10000 {
+                return Err(Error::new(Span::call_site(), "program exceeds 64KiB"));
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// The second pass, which encodes a statement.
+    fn encode(&self, addr: u16, op: &Op) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        match op {
+            Op::Org(_) => (),
+            Op::Ds(operand) => bytes.resize(usize::from(self.eval(operand, addr, to_addr)?), 0),
+            Op::Db(data) => {
+                for d in data {
+                    match d {
+                        Data::Str(s) => bytes.extend(s),
+                        Data::Expr(operand) => bytes.push(self.eval(operand, addr, to_byte)?),
+                    }
+                }
+            }
+            Op::Dw(operands) => {
+                for operand in operands {
+                    bytes.extend(self.eval(operand, addr, to_word)?.to_be_bytes());
+                }
+            }
+            Op::Instr {
+                mnemonic,
+                schema,
+                registers,
+                operands,
+            } => {
+                let mut operands = operands.iter();
+                let mut n = 0;
+                if schema.packed.is_some() {
+                    let operand = operands.next().unwrap();
+                    let value = self.eval(operand, addr, Ok)?;
+                    if !(0..16).contains(&value) || registers & (1 << value) == 0 {
+                        return Err(
+                            operand.error(format!("invalid register {value} for '{mnemonic}'"))
+                        );
+                    }
+                    n = value as u8;
+                }
+                let immediates = match (schema.immediates(), operands.next()) {
+                    (0, _) | (_, None) => vec![],
+                    (1, Some(operand)) if schema.is_short_branch() => {
+                        vec![self.eval(operand, addr, |v| short_branch(addr, schema.size, v))?]
+                    }
+                    (1, Some(operand)) => vec![self.eval(operand, addr, to_byte)?],
+                    (_, Some(operand)) => self.eval(operand, addr, to_word)?.to_be_bytes().to_vec(),
+                };
+                bytes = schema.encode(n, &immediates);
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Evaluates an operand and converts its value, reporting errors at the operand.
+    fn eval<T>(
+        &self,
+        operand: &Operand,
+        here: u16,
+        convert: impl FnOnce(i64) -> std::result::Result<T, String>,
+    ) -> Result<T> {
+        operand
+            .expr
+            .eval(&self.labels, here)
+            .and_then(convert)
+            .map_err(|err| operand.error(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(source: TokenStream2) -> Result<Program> {
+        super::assemble(quote! {
+            [
+                idl "00", ldn "0n", dec "2n", br "30 nn", bnz "3a nn", glo "8n", plo "an",
+                out "6l", inp "6h", ldi "f8 nn", lbr "c0 hh ll", rldi "68 cn hh ll"
+            ]
+            #source
+        })
+    }
+
+    fn error(source: TokenStream2) -> String {
+        assemble(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_assemble() {
+        let program = assemble(quote! {
+            ldi 0x08; plo r2;
+            loop: dec 2; glo 2; bnz loop;
+            lbr end;
+            db 1, 'a', "bc"; dw end - 1; ds 2;
+            org 0x20;
+            end: rldi rf, high(end) << 8 | low(end);
+            inp 1; out 7
+        })
+        .unwrap();
+        assert_eq!(
+            program.bytes[..0x12],
+            [
+                0xf8, 0x08, 0xa2, 0x22, 0x82, 0x3a, 0x03, 0xc0, 0x00, 0x20, 0x01, 0x61, 0x62, 0x63,
+                0x00, 0x1f, 0x00, 0x00,
+            ]
+        );
+        assert!(program.bytes[0x12..0x20].iter().all(|b| *b == 0));
+        assert_eq!(program.bytes[0x20..], [0x68, 0xcf, 0x00, 0x20, 0x69, 0x67]);
+        assert_eq!(
+            program.labels,
+            [("loop".to_string(), 0x03), ("end".to_string(), 0x20)]
+        );
+    }
+
+    #[test]
+    fn test_operators() {
+        let program = assemble(quote! {
+            ldi !0 & 0x0f; ldi -1; ldi (1 << 4) >> 1; ldi 7 % 4 * 3 / 2 ^ 1;
+            ldi b'x'; ldi low 0x1234; db b"ok"
+        })
+        .unwrap();
+        assert_eq!(
+            program.bytes,
+            [
+                0xf8, 0x0f, 0xf8, 0xff, 0xf8, 0x08, 0xf8, 0x05, 0xf8, 0x78, 0xf8, 0x34, 0x6f, 0x6b
+            ]
+        );
+    }
+
+    #[test]
+    fn test_short_branch_page() {
+        let program = assemble(quote! { org 0xff; br 0x1ff }).unwrap();
+        assert_eq!(program.bytes[0xff..], [0x30, 0xff]);
+        assert_eq!(
+            error(quote! { org 0xfe; br 0x1ff }),
+            "short branch target 01ff is not on page 00"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error(quote! { foo 1 }), "unknown instruction 'foo'");
+        assert_eq!(
+            error(quote! { ldi 256 }),
+            "value 256 out of range for a byte"
+        );
+        assert_eq!(error(quote! { ldn r0 }), "invalid register 0 for 'ldn'");
+        assert_eq!(error(quote! { out 8 }), "invalid register 8 for 'out'");
+        assert_eq!(error(quote! { glo }), "'glo' takes 1 operand(s)");
+        assert_eq!(error(quote! { br nowhere }), "undefined symbol 'nowhere'");
+        assert_eq!(error(quote! { ldi 1 / 0 }), "division by zero");
+        assert_eq!(error(quote! { ldi 1 2 }), "unexpected '2'");
+        assert_eq!(error(quote! { ldi 1 @ 2 }), "unexpected character '@'");
+        assert_eq!(
+            error(quote! { a: idl; a: idl }),
+            "label 'a' already defined"
+        );
+        assert_eq!(error(quote! { org 2; org 1 }), "org may not move backwards");
+        assert_eq!(error(quote! { 1 }), "expected mnemonic");
+    }
+}
@@ -1,5 +1,6 @@
-use syn::{Data, DataEnum, DeriveInput, Error, Ident, Result};
+use syn::{Data, DataEnum, DeriveInput, Error, Ident, LitStr, Result};
 
+use crate::meta::Meta;
 use crate::schema::Schema;
 
 pub enum Input {
@@ -9,10 +10,15 @@ pub enum Input {
 pub struct Enum {
     pub ident: Ident,
     pub variants: Vec<Variant>,
+    /// The name of the assembler macro to generate, from `#[asm_macro(name)]`.
+    pub asm_macro: Option<Ident>,
 }
 
 pub struct Variant {
     pub schema: Schema,
+    /// The schema as written, which is forwarded to the assembler macro.
+    pub lit: LitStr,
+    pub meta: Meta,
     pub ident: Ident,
 }
 
@@ -32,24 +38,34 @@ impl Enum {
             .iter()
             .map(Variant::from_syn)
             .collect::<Result<_>>()?;
+        let mut asm_macro = None;
+        for attr in &node.attrs {
+            if attr.path().is_ident("asm_macro") {
+                asm_macro.replace(attr.parse_args()?);
+            }
+        }
         Ok(Enum {
             ident: node.ident.clone(),
             variants,
+            asm_macro,
         })
     }
 }
 
 impl Variant {
     fn from_syn(node: &syn::Variant) -> Result<Self> {
-        let mut schema: Option<Schema> = None;
+        let mut schema: Option<(Schema, LitStr, Meta)> = None;
         for attr in &node.attrs {
             if attr.path().is_ident("schema") {
                 schema.replace(Schema::parse_from_attribute(attr)?);
             }
         }
-        let schema = schema.ok_or_else(|| Error::new_spanned(node, "missing schema"))?;
+        let (schema, lit, meta) =
+            schema.ok_or_else(|| Error::new_spanned(node, "missing schema"))?;
         Ok(Variant {
             schema,
+            lit,
+            meta,
             ident: node.ident.clone(),
         })
     }
@@ -1,8 +1,9 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{DeriveInput, Result};
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{DeriveInput, Ident, Result};
 
-use crate::ast::{Enum, Input};
+use crate::ast::{Enum, Input, Variant};
 use crate::schema::Packed;
 
 pub fn derive(node: &DeriveInput) -> Result<TokenStream> {
@@ -12,84 +13,203 @@ pub fn derive(node: &DeriveInput) -> Result<TokenStream> {
     })
 }
 
-fn impl_enum(input: Enum) -> TokenStream {
-    let ty = &input.ident;
+/// Returns the field bindings for a variant: the packed register (if any), followed by the
+/// immediate operand bytes.
+fn fields(v: &Variant) -> Vec<Ident> {
+    let mut fields = vec![];
+    if v.schema.packed.is_some() {
+        fields.push(format_ident!("n"));
+    }
+    match v.schema.immediates() {
+        0 => (),
+        1 => fields.push(format_ident!("nn")),
+        2 => fields.extend([format_ident!("hh"), format_ident!("ll")]),
+        _ => unreachable!(),
+    }
+    fields
+}
 
-    let mut decode_packed_arms = vec![];
-    let mut decode_plain_arms = vec![];
-    for v in &input.variants {
+/// Returns a pattern that matches the variant, binding its fields.
+fn pattern(ty: &Ident, v: &Variant) -> TokenStream2 {
+    let ident = &v.ident;
+    let fields = fields(v);
+    if fields.is_empty() {
+        quote! { #ty::#ident }
+    } else {
+        quote! { #ty::#ident(#(#fields),*) }
+    }
+}
+
+/// Returns a decoder for the given variants, which matches on the first byte of `bin`.
+fn decode_body<'a>(variants: impl Iterator<Item = &'a Variant>) -> TokenStream2 {
+    let mut packed_arms = vec![];
+    let mut plain_arms = vec![];
+    for v in variants {
         let ident = &v.ident;
         let opcode = v.schema.opcode;
-        if let Some(packed) = v.schema.packed {
-            let arm = match packed {
-                Packed::N => quote! {
-                    #opcode => Some(Self::#ident(opcode_lo)),
-                },
-                Packed::L => quote! {
-                    #opcode if opcode_lo < 8 => Some(Self::#ident(opcode_lo)),
-                },
-                Packed::H => quote! {
-                    #opcode if opcode_lo >= 8 => Some(Self::#ident(opcode_lo & 0x7)),
-                },
+
+        // Immediate operands follow the opcode.
+        let args: Vec<_> = (1..=v.schema.immediates() as usize)
+            .map(|i| quote! { bin.get(#i) })
+            .collect();
+        let binds: Vec<_> = (0..args.len()).map(|i| format_ident!("arg{i}")).collect();
+        let build = |packed: Option<TokenStream2>| {
+            let fields = packed
+                .into_iter()
+                .chain(binds.iter().map(|b| quote! { *#b }));
+            let ctor = if binds.is_empty() && v.schema.packed.is_none() {
+                quote! { Self::#ident }
+            } else {
+                quote! { Self::#ident(#(#fields),*) }
             };
-            decode_packed_arms.push(arm);
-        } else if v.schema.size == 1 {
-            decode_plain_arms.push(quote! {
-                #opcode => Some(Self::#ident),
-            });
-        } else if v.schema.size == 2 {
-            decode_plain_arms.push(quote! {
-                #opcode => {
-                    match arg1 {
-                        Some(arg1) => Some(Self::#ident(*arg1)),
-                        _=> None,
-                    }
-                }
-            });
-        } else if v.schema.size == 3 {
-            decode_plain_arms.push(quote! {
-                #opcode => {
-                    match (arg1, arg2) {
-                        (Some(arg1), Some(arg2)) => Some(Self::#ident(*arg1, *arg2)),
+            if binds.is_empty() {
+                quote! { Some(#ctor) }
+            } else {
+                quote! {
+                    match (#(#args,)*) {
+                        (#(Some(#binds),)*) => Some(#ctor),
                         _ => None,
                     }
                 }
+            }
+        };
+
+        if let Some(packed) = v.schema.packed {
+            let arm = match packed {
+                Packed::N => {
+                    let body = build(Some(quote! { opcode_lo }));
+                    quote! { #opcode => #body, }
+                }
+                Packed::L => {
+                    let body = build(Some(quote! { opcode_lo }));
+                    quote! { #opcode if opcode_lo < 8 => #body, }
+                }
+                Packed::H => {
+                    let body = build(Some(quote! { opcode_lo & 0x7 }));
+                    quote! { #opcode if opcode_lo >= 8 => #body, }
+                }
+            };
+            packed_arms.push(arm);
+        } else {
+            let body = build(None);
+            plain_arms.push(quote! { #opcode => #body, });
+        }
+    }
+    quote! {
+        if let Some(opcode) = bin.first() {
+            let opcode_lo = opcode & 0x0f;
+            match opcode {
+                #(#plain_arms)*
+                _ => match opcode & 0xf0 {
+                    #(#packed_arms)*
+                    _ => None,
+                },
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns a 256-entry opcode table for the given variants. Where several variants share an
+/// opcode byte, the entry is the one that `decode_body` would match: plain opcodes take precedence
+/// over packed ones, then the first declared.
+fn opcode_table<'a>(
+    ty: &Ident,
+    variants: impl Iterator<Item = &'a Variant> + Clone,
+    all: &[Variant],
+) -> TokenStream2 {
+    let mut entries: Vec<Option<TokenStream2>> = vec![None; 256];
+    let (plain, packed): (Vec<_>, Vec<_>) = variants.partition(|v| v.schema.packed.is_none());
+    for v in plain.into_iter().chain(packed) {
+        let ident = &v.ident;
+        let registers = v.schema.registers(all.iter().map(|v| &v.schema));
+        let immediates = v.schema.immediates();
+        let size = v.schema.size;
+        let fields = v
+            .schema
+            .packed
+            .map(|_| quote! { n })
+            .into_iter()
+            .chain((0..immediates as usize).map(|i| quote! { bin[#i] }))
+            .collect::<Vec<_>>();
+        let ctor = if fields.is_empty() {
+            quote! { #ty::#ident }
+        } else {
+            quote! { #ty::#ident(#(#fields),*) }
+        };
+        for (opcode, n) in v.schema.opcodes() {
+            entries[opcode as usize].get_or_insert_with(|| {
+                quote! {
+                    Some(Opcode {
+                        layout: Layout {
+                            registers: #registers,
+                            immediates: #immediates,
+                            size: #size,
+                        },
+                        register: #n,
+                        build: |n, bin| {
+                            let _ = (n, bin);
+                            #ctor
+                        },
+                    })
+                }
             });
         }
     }
-    let decode = quote! {
-        fn decode(bin: &[u8]) -> Option<Self> {
-            if let Some(opcode) = bin.get(0) {
-                let opcode_lo = opcode & 0x0f;
-                let arg1 = bin.get(1);
-                let arg2 = bin.get(2);
-                match opcode {
-                    #(#decode_plain_arms)*
-                    _ => match opcode & 0xf0 {
-                        #(#decode_packed_arms)*
-                        _ => None,
-                    },
+    let entries = entries
+        .into_iter()
+        .map(|entry| entry.unwrap_or_else(|| quote! { None }));
+    quote! { [#(#entries),*] }
+}
+
+fn impl_enum(input: Enum) -> TokenStream {
+    let ty = &input.ident;
+
+    // Prefixed opcodes are decoded first, falling back to the plain opcode table if the bytes
+    // following the prefix aren't recognized.
+    let mut prefixes: Vec<u8> = input
+        .variants
+        .iter()
+        .filter_map(|v| v.schema.prefix)
+        .collect();
+    prefixes.sort_unstable();
+    prefixes.dedup();
+    let decode_prefixed = prefixes.iter().map(|&prefix| {
+        let body = decode_body(
+            input
+                .variants
+                .iter()
+                .filter(|v| v.schema.prefix == Some(prefix)),
+        );
+        quote! {
+            if let Some((#prefix, bin)) = bin.split_first() {
+                let instr = #body;
+                if instr.is_some() {
+                    return instr;
                 }
-            } else {
-                None
             }
         }
+    });
+    let decode_plain = decode_body(input.variants.iter().filter(|v| v.schema.prefix.is_none()));
+    let decode_match = quote! {
+        #[cfg(test)]
+        fn decode_match(bin: &[u8]) -> Option<Self> {
+            #(#decode_prefixed)*
+            #decode_plain
+        }
     };
 
     let disasm_arms = input.variants.iter().map(|v| {
-        let ident = &v.ident;
-        let mnemonic = ident.to_string().to_lowercase();
-        match v.schema.arity() {
-            0 => quote! {
-                #ty::#ident => format!("{:<4}", #mnemonic),
-            },
-            1 => quote! {
-                #ty::#ident(n) => format!("{:<4} {}", #mnemonic, n),
-            },
-            2 => quote! {
-                #ty::#ident(hh, ll) => format!("{:<4} {} {}", #mnemonic, hh, ll),
-            },
-            _ => unreachable!(),
+        let mnemonic = v.ident.to_string().to_lowercase();
+        let pattern = pattern(ty, v);
+        let fields = fields(v);
+        let fmt = std::iter::once("{:<4}")
+            .chain(fields.iter().map(|_| "{}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        quote! {
+            #pattern => format!(#fmt, #mnemonic, #(#fields),*),
         }
     });
     let disasm = quote! {
@@ -101,30 +221,23 @@ fn impl_enum(input: Enum) -> TokenStream {
     };
 
     let encode_arms = input.variants.iter().map(|v| {
-        let ident = &v.ident;
         let opcode = v.schema.opcode;
-        if let Some(packed) = v.schema.packed {
-            match packed {
-                Packed::N | Packed::L => quote! {
-                    #ty::#ident(n) => vec![#opcode | n],
-                },
-                Packed::H => quote! {
-                    #ty::#ident(n) => vec![#opcode | n | 0x08],
-                },
-            }
-        } else if v.schema.size == 1 {
-            quote! {
-                #ty::#ident => vec![#opcode],
-            }
-        } else if v.schema.size == 2 {
-            quote! {
-                #ty::#ident(nn) => vec![#opcode, *nn],
-            }
-        } else {
-            assert_eq!(v.schema.size, 3);
-            quote! {
-                #ty::#ident(hh, ll) => vec![#opcode, *hh, *ll],
-            }
+        let pattern = pattern(ty, v);
+        let mut bytes = vec![];
+        if let Some(prefix) = v.schema.prefix {
+            bytes.push(quote! { #prefix });
+        }
+        bytes.push(match v.schema.packed {
+            None => quote! { #opcode },
+            Some(Packed::N | Packed::L) => quote! { #opcode | n },
+            Some(Packed::H) => quote! { #opcode | n | 0x08 },
+        });
+        let immediates = fields(v)
+            .into_iter()
+            .skip(v.schema.packed.is_some() as usize);
+        bytes.extend(immediates.map(|f| quote! { *#f }));
+        quote! {
+            #pattern => vec![#(#bytes),*],
         }
     });
     let encode = quote! {
@@ -142,29 +255,215 @@ fn impl_enum(input: Enum) -> TokenStream {
             0 => quote! {
                 #ty::#ident => #size,
             },
-            1 => quote! {
-                #ty::#ident(_) => #size,
-            },
-            2 => quote! {
-                #ty::#ident(_, _) => #size,
+            _ => quote! {
+                #ty::#ident(..) => #size,
             },
-            _ => unreachable!(),
         }
     });
     let size = quote! {
-        fn size(&self) -> u8{
+        fn size(&self) -> u8 {
             match self {
                 #(#size_arms)*
             }
         }
     };
 
+    let extended_arms = input.variants.iter().map(|v| {
+        let ident = &v.ident;
+        let extended = v.schema.prefix.is_some();
+        match v.schema.arity() {
+            0 => quote! {
+                #ty::#ident => #extended,
+            },
+            _ => quote! {
+                #ty::#ident(..) => #extended,
+            },
+        }
+    });
+    let is_extended = quote! {
+        fn is_extended(&self) -> bool {
+            match self {
+                #(#extended_arms)*
+            }
+        }
+    };
+
+    let short_branch_arms = input.variants.iter().map(|v| {
+        let ident = &v.ident;
+        let short_branch = v.schema.is_short_branch();
+        match v.schema.arity() {
+            0 => quote! {
+                #ty::#ident => #short_branch,
+            },
+            _ => quote! {
+                #ty::#ident(..) => #short_branch,
+            },
+        }
+    });
+    let is_short_branch = quote! {
+        fn is_short_branch(&self) -> bool {
+            match self {
+                #(#short_branch_arms)*
+            }
+        }
+    };
+
+    let mnemonic_arms = input.variants.iter().map(|v| {
+        let ident = &v.ident;
+        let mnemonic = ident.to_string().to_lowercase();
+        match v.schema.arity() {
+            0 => quote! { #ty::#ident => #mnemonic, },
+            _ => quote! { #ty::#ident(..) => #mnemonic, },
+        }
+    });
+    let mnemonic = quote! {
+        fn mnemonic(&self) -> &'static str {
+            match self {
+                #(#mnemonic_arms)*
+            }
+        }
+    };
+
+    let fields_arms = input.variants.iter().map(|v| {
+        let pattern = pattern(ty, v);
+        let fields = fields(v);
+        quote! { #pattern => vec![#(*#fields),*], }
+    });
+    let get_fields = quote! {
+        fn fields(&self) -> Vec<u8> {
+            match self {
+                #(#fields_arms)*
+            }
+        }
+    };
+
+    let layout_arms = input.variants.iter().map(|v| {
+        let mnemonic = v.ident.to_string().to_lowercase();
+        let registers = v.schema.registers(input.variants.iter().map(|v| &v.schema));
+        let immediates = v.schema.immediates();
+        let size = v.schema.size;
+        quote! {
+            #mnemonic => Some(Layout {
+                registers: #registers,
+                immediates: #immediates,
+                size: #size,
+            }),
+        }
+    });
+    let layout = quote! {
+        fn layout(mnemonic: &str) -> Option<Layout> {
+            match mnemonic {
+                #(#layout_arms)*
+                _ => None,
+            }
+        }
+    };
+
+    let from_fields_arms = input.variants.iter().map(|v| {
+        let mnemonic = v.ident.to_string().to_lowercase();
+        let ident = &v.ident;
+        let fields = fields(v);
+        if fields.is_empty() {
+            quote! { (#mnemonic, []) => Some(#ty::#ident), }
+        } else {
+            quote! { (#mnemonic, &[#(#fields),*]) => Some(#ty::#ident(#(#fields),*)), }
+        }
+    });
+    let from_fields = quote! {
+        fn from_fields(mnemonic: &str, fields: &[u8]) -> Option<Self> {
+            match (mnemonic, fields) {
+                #(#from_fields_arms)*
+                _ => None,
+            }
+        }
+    };
+
+    let meta_arms = input.variants.iter().map(|v| {
+        let ident = &v.ident;
+        let meta = v.meta.to_tokens();
+        match v.schema.arity() {
+            0 => quote! { #ty::#ident => #meta, },
+            _ => quote! { #ty::#ident(..) => #meta, },
+        }
+    });
+    let meta = quote! {
+        fn meta(&self) -> Meta {
+            match self {
+                #(#meta_arms)*
+            }
+        }
+    };
+
+    // Each table is a static, so that lookups index it in place.
+    let plain_table = opcode_table(
+        ty,
+        input.variants.iter().filter(|v| v.schema.prefix.is_none()),
+        &input.variants,
+    );
+    let prefixed_tables = prefixes.iter().map(|&prefix| {
+        let table = opcode_table(
+            ty,
+            input
+                .variants
+                .iter()
+                .filter(|v| v.schema.prefix == Some(prefix)),
+            &input.variants,
+        );
+        quote! {
+            Some(#prefix) => {
+                static TABLE: [Option<Opcode<#ty>>; 256] = #table;
+                TABLE[opcode as usize].as_ref()
+            }
+        }
+    });
+    let opcode = quote! {
+        fn opcode(prefix: Option<u8>, opcode: u8) -> Option<&'static Opcode<Self>> {
+            match prefix {
+                None => {
+                    static TABLE: [Option<Opcode<#ty>>; 256] = #plain_table;
+                    TABLE[opcode as usize].as_ref()
+                }
+                #(#prefixed_tables)*
+                _ => None,
+            }
+        }
+    };
+
+    // The assembler macro forwards the schema table to `assemble`, along with the source.
+    let asm_macro = input.asm_macro.as_ref().map(|name| {
+        let entries = input.variants.iter().map(|v| {
+            let mnemonic = format_ident!("{}", v.ident.to_string().to_lowercase());
+            let lit = &v.lit;
+            quote! { #mnemonic #lit }
+        });
+        quote! {
+            #[allow(unused_macros)]
+            macro_rules! #name {
+                ($($source:tt)*) => {
+                    ::cosmac_emu_macros::assemble!([#(#entries),*] $($source)*)
+                };
+            }
+            #[allow(unused_imports)]
+            pub(crate) use #name;
+        }
+    });
+
     quote! {
+        #asm_macro
+
         impl InstrSchema for #ty {
-            #decode
+            #decode_match
             #disasm
             #encode
             #size
+            #is_extended
+            #is_short_branch
+            #mnemonic
+            #get_fields
+            #layout
+            #from_fields
+            #meta
+            #opcode
         }
     }
     .into()
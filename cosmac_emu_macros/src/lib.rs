@@ -1,14 +1,32 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use syn::{parse_macro_input, DeriveInput};
+use quote::quote;
+use syn::{DeriveInput, parse_macro_input};
 
+mod asm;
 mod ast;
 mod expand;
+mod meta;
 mod schema;
 
-#[proc_macro_derive(InstrSchema, attributes(schema))]
+#[proc_macro_derive(InstrSchema, attributes(schema, asm_macro))]
 pub fn derive_instr_schema(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     expand::derive(&input).unwrap_or_else(|err| err.to_compile_error().into())
 }
+
+/// Assembles 1802 source at compile time, given a schema table. This is invoked by the macro
+/// generated by `#[asm_macro(name)]`, rather than directly.
+#[doc(hidden)]
+#[proc_macro]
+pub fn assemble(input: TokenStream) -> TokenStream {
+    // The errors are wrapped in a block, since there may be several of them, and the macro is
+    // used as an expression.
+    asm::expand(input.into())
+        .unwrap_or_else(|err| {
+            let errors = err.to_compile_error();
+            quote! { { #errors } }
+        })
+        .into()
+}
@@ -0,0 +1,245 @@
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::{Error, Ident, LitInt, Result, Token, parenthesized};
+
+use crate::schema::Schema;
+
+/// Registers and flags that may be named in `reads(..)` and `writes(..)`.
+const OPERANDS: &[&str] = &[
+    "d", "df", "q", "ie", "t", "x", "p", "n", "rx", "rp", "r0", "r2", "ef", "int", "cntr", "xie",
+    "cie",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Next,
+    Branch,
+    BranchIf,
+    Skip(u8),
+    SkipIf(u8),
+    Call,
+    Return,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mem {
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Io {
+    None,
+    Out,
+    Inp,
+}
+
+/// Facts about an instruction, declared after its schema, e.g.
+/// `#[schema("7c nn", reads(d, df, rp), writes(d, df, rp), mem = read)]`.
+///
+/// - `cycles = N`: machine cycles, including fetch. Defaults to 3 for the long branches and
+///   skips (`cx`), and 2 for other instructions. Required for extended instructions.
+/// - `flow = ..`: one of `branch`, `branch_if`, `skip(N)`, `skip_if(N)` (where N is the number
+///   of bytes skipped), `call`, or `return`. Defaults to continuing with the next instruction.
+/// - `reads(..)`, `writes(..)`: registers and flags, where `n` is the packed register, and
+///   `rx`/`rp` are the registers selected by X and P.
+/// - `mem = ..`: memory accessed by the execute cycles, including immediate operands. One of
+///   `read`, `write` or `read_write`.
+/// - `io = ..`: `out` or `inp`.
+#[derive(Debug, Clone)]
+pub struct Meta {
+    pub cycles: u8,
+    pub flow: Flow,
+    pub reads: Vec<Ident>,
+    pub writes: Vec<Ident>,
+    pub mem: Mem,
+    pub io: Io,
+}
+
+impl Meta {
+    /// Parses the metadata following the schema in a `#[schema(..)]` attribute.
+    pub fn parse(input: ParseStream, schema: &Schema) -> Result<Meta> {
+        let mut cycles = None;
+        let mut meta = Meta {
+            cycles: 0,
+            flow: Flow::Next,
+            reads: vec![],
+            writes: vec![],
+            mem: Mem::None,
+            io: Io::None,
+        };
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let key = Ident::parse_any(input)?;
+            match key.to_string().as_str() {
+                "cycles" => {
+                    input.parse::<Token![=]>()?;
+                    cycles = Some(input.parse::<LitInt>()?.base10_parse()?);
+                }
+                "flow" => {
+                    input.parse::<Token![=]>()?;
+                    let value = Ident::parse_any(input)?;
+                    meta.flow = match value.to_string().as_str() {
+                        "branch" => Flow::Branch,
+                        "branch_if" => Flow::BranchIf,
+                        "skip" => Flow::Skip(parse_count(input)?),
+                        "skip_if" => Flow::SkipIf(parse_count(input)?),
+                        "call" => Flow::Call,
+                        "return" => Flow::Return,
+                        _ => return Err(Error::new_spanned(value, "unknown flow")),
+                    };
+                }
+                "reads" => meta.reads = parse_operands(input)?,
+                "writes" => meta.writes = parse_operands(input)?,
+                "mem" => {
+                    input.parse::<Token![=]>()?;
+                    let value: Ident = input.parse()?;
+                    meta.mem = match value.to_string().as_str() {
+                        "read" => Mem::Read,
+                        "write" => Mem::Write,
+                        "read_write" => Mem::ReadWrite,
+                        _ => return Err(Error::new_spanned(value, "unknown memory access")),
+                    };
+                }
+                "io" => {
+                    input.parse::<Token![=]>()?;
+                    let value: Ident = input.parse()?;
+                    meta.io = match value.to_string().as_str() {
+                        "out" => Io::Out,
+                        "inp" => Io::Inp,
+                        _ => return Err(Error::new_spanned(value, "unknown i/o")),
+                    };
+                }
+                _ => return Err(Error::new_spanned(key, "unknown key")),
+            }
+        }
+        meta.cycles = match (cycles, schema.prefix) {
+            (Some(cycles), _) => cycles,
+            (None, None) if schema.opcode & 0xf0 == 0xc0 => 3,
+            (None, None) => 2,
+            (None, Some(_)) => {
+                return Err(Error::new(
+                    input.span(),
+                    "extended instructions must declare cycles",
+                ));
+            }
+        };
+        Ok(meta)
+    }
+
+    /// Returns an expression that constructs the metadata.
+    pub fn to_tokens(&self) -> TokenStream2 {
+        let cycles = self.cycles;
+        let flow = match self.flow {
+            Flow::Next => quote! { Flow::Next },
+            Flow::Branch => quote! { Flow::Branch },
+            Flow::BranchIf => quote! { Flow::BranchIf },
+            Flow::Skip(n) => quote! { Flow::Skip(#n) },
+            Flow::SkipIf(n) => quote! { Flow::SkipIf(#n) },
+            Flow::Call => quote! { Flow::Call },
+            Flow::Return => quote! { Flow::Return },
+        };
+        let reads = operands(&self.reads);
+        let writes = operands(&self.writes);
+        let mem = match self.mem {
+            Mem::None => quote! { Mem::None },
+            Mem::Read => quote! { Mem::Read },
+            Mem::Write => quote! { Mem::Write },
+            Mem::ReadWrite => quote! { Mem::ReadWrite },
+        };
+        let io = match self.io {
+            Io::None => quote! { Io::None },
+            Io::Out => quote! { Io::Out },
+            Io::Inp => quote! { Io::Inp },
+        };
+        quote! {
+            Meta {
+                cycles: #cycles,
+                flow: #flow,
+                reads: #reads,
+                writes: #writes,
+                mem: #mem,
+                io: #io,
+            }
+        }
+    }
+}
+
+/// Parses the number of bytes skipped, e.g. `(2)`.
+fn parse_count(input: ParseStream) -> Result<u8> {
+    let content;
+    parenthesized!(content in input);
+    content.parse::<LitInt>()?.base10_parse()
+}
+
+/// Parses a parenthesized list of registers and flags, e.g. `(d, df)`.
+fn parse_operands(input: ParseStream) -> Result<Vec<Ident>> {
+    let content;
+    parenthesized!(content in input);
+    let idents = content.parse_terminated(Ident::parse, Token![,])?;
+    for ident in &idents {
+        if !OPERANDS.contains(&ident.to_string().as_str()) {
+            return Err(Error::new_spanned(ident, "unknown register or flag"));
+        }
+    }
+    Ok(idents.into_iter().collect())
+}
+
+/// Returns an expression for the union of the given operands.
+fn operands(idents: &[Ident]) -> TokenStream2 {
+    let consts = idents
+        .iter()
+        .map(|i| Ident::new(&i.to_string().to_uppercase(), Span::call_site()));
+    quote! { Operands::NONE #(.union(Operands::#consts))* }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse::Parser;
+
+    fn parse(schema: &str, meta: &str) -> Result<Meta> {
+        let schema = Schema::parse(schema).unwrap();
+        let parser = |input: ParseStream| Meta::parse(input, &schema);
+        parser.parse_str(meta)
+    }
+
+    #[test]
+    fn test_meta() {
+        let meta = parse("30 nn", "").unwrap();
+        assert_eq!(
+            (meta.cycles, meta.flow, meta.mem),
+            (2, Flow::Next, Mem::None)
+        );
+        assert_eq!(parse("c4", "").unwrap().cycles, 3);
+
+        let meta = parse(
+            "68 8n hh ll",
+            ", cycles = 10, flow = call, reads(n, x, rx, rp), writes(n, rx, rp), mem = read_write",
+        )
+        .unwrap();
+        assert_eq!(meta.cycles, 10);
+        assert_eq!(meta.flow, Flow::Call);
+        assert_eq!(meta.reads.len(), 4);
+        assert_eq!(meta.writes.len(), 3);
+        assert_eq!(meta.mem, Mem::ReadWrite);
+        assert_eq!(meta.io, Io::None);
+
+        let meta = parse("c5", ", flow = skip_if(2), reads(q, rp), writes(rp),").unwrap();
+        assert_eq!(meta.flow, Flow::SkipIf(2));
+        assert_eq!(parse("6l", ", io = out").unwrap().io, Io::Out);
+        assert_eq!(parse("70", ", flow = return").unwrap().flow, Flow::Return);
+
+        assert!(parse("68 74", "").is_err());
+        assert!(parse("f4", ", reads(e)").is_err());
+        assert!(parse("f4", ", flow = jump").is_err());
+        assert!(parse("f4", ", speed = 1").is_err());
+    }
+}
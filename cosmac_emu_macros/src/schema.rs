@@ -3,6 +3,8 @@ use std::sync::OnceLock;
 use syn::parse::ParseStream;
 use syn::{Attribute, Error, LitStr, Result};
 
+use crate::meta::Meta;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Packed {
     N,
@@ -12,29 +14,51 @@ pub enum Packed {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Schema {
+    pub prefix: Option<u8>,
     pub opcode: u8,
     pub size: u8,
     pub packed: Option<Packed>,
 }
 
 impl Schema {
-    pub fn parse_from_attribute(attr: &Attribute) -> Result<Schema> {
+    /// Parses a `#[schema(..)]` attribute: the schema, followed by the instruction's metadata.
+    pub fn parse_from_attribute(attr: &Attribute) -> Result<(Schema, LitStr, Meta)> {
         attr.parse_args_with(|input: ParseStream| {
             let lit: LitStr = input.parse()?;
-            Self::parse(&lit.value()).ok_or_else(|| Error::new_spanned(attr, "invalid schema"))
+            let schema = Self::parse(&lit.value())
+                .ok_or_else(|| Error::new_spanned(attr, "invalid schema"))?;
+            let meta = Meta::parse(input, &schema)?;
+            Ok((schema, lit, meta))
         })
     }
 
-    fn parse(input: &str) -> Option<Schema> {
+    pub fn parse_from_lit(lit: &LitStr) -> Result<Schema> {
+        Self::parse(&lit.value()).ok_or_else(|| Error::new_spanned(lit, "invalid schema"))
+    }
+
+    pub fn parse(input: &str) -> Option<Schema> {
         struct Patterns {
+            prefix: Regex,
             packed: Regex,
             plain: Regex,
         }
         static PATTERNS: OnceLock<Patterns> = OnceLock::new();
         let pat = PATTERNS.get_or_init(|| Patterns {
-            packed: Regex::new(r"^([0-9a-f])(n|h|l)$").unwrap(),
-            plain: Regex::new(r"^([0-9a-f]{2})(| nn| hh ll| xx xx)$").unwrap(),
+            prefix: Regex::new(r"^([0-9a-f]{2}) ([0-9a-f][0-9a-fnhl].*)$").unwrap(),
+            packed: Regex::new(r"^([0-9a-f])(n|h|l)(| hh ll)$").unwrap(),
+            plain: Regex::new(r"^([0-9a-f]{2})(| nn| hh ll)$").unwrap(),
         });
+
+        // Extended opcodes are preceded by a prefix byte, e.g. "68 cn hh ll".
+        let (prefix, input) = match pat.prefix.captures(input) {
+            Some(c) => {
+                let prefix = u8::from_str_radix(c.get(1).unwrap().as_str(), 16).unwrap();
+                (Some(prefix), c.get(2).unwrap().as_str())
+            }
+            None => (None, input),
+        };
+        let prefix_size = prefix.is_some() as u8;
+
         if let Some(c) = pat.packed.captures(input) {
             let opcode = u8::from_str_radix(c.get(1).unwrap().as_str(), 16).unwrap() << 4;
             let packed = match c.get(2).unwrap().as_str() {
@@ -43,9 +67,15 @@ impl Schema {
                 "h" => Packed::H,
                 _ => unreachable!(),
             };
+            let size = match c.get(3).unwrap().as_str() {
+                "" => 1,
+                " hh ll" => 3,
+                _ => unreachable!(),
+            };
             Some(Schema {
+                prefix,
                 opcode,
-                size: 1,
+                size: prefix_size + size,
                 packed: Some(packed),
             })
         } else if let Some(c) = pat.plain.captures(input) {
@@ -57,8 +87,9 @@ impl Schema {
                 _ => unreachable!(),
             };
             Some(Schema {
+                prefix,
                 opcode,
-                size,
+                size: prefix_size + size,
                 packed: None,
             })
         } else {
@@ -66,19 +97,79 @@ impl Schema {
         }
     }
 
+    /// Returns the number of immediate operand bytes that follow the opcode.
+    pub fn immediates(&self) -> u8 {
+        self.size - 1 - (self.prefix.is_some() as u8)
+    }
+
     pub fn arity(&self) -> u8 {
-        if self.packed.is_some() {
-            1
-        } else {
-            self.size - 1
+        (self.packed.is_some() as u8) + self.immediates()
+    }
+
+    /// Returns true for short branches, whose target is within the page of the immediate operand.
+    pub fn is_short_branch(&self) -> bool {
+        self.packed.is_none() && self.immediates() == 1 && self.opcode & 0xf0 == 0x30
+    }
+
+    /// Returns a mask of the values the packed register may take, given the schemas of all
+    /// instructions. Values whose encoding collides with a plain opcode are excluded, e.g. `ldn 0`
+    /// is `idl`.
+    pub fn registers<'a>(&self, all: impl Iterator<Item = &'a Schema> + Clone) -> u16 {
+        let Some(packed) = self.packed else {
+            return 0;
+        };
+        let (values, base) = match packed {
+            Packed::N => (0..16, self.opcode),
+            Packed::L => (0..8, self.opcode),
+            Packed::H => (0..8, self.opcode | 0x08),
+        };
+        values
+            .filter(|n| {
+                !all.clone().any(|other| {
+                    other.packed.is_none()
+                        && other.prefix == self.prefix
+                        && other.opcode == base | n
+                })
+            })
+            .fold(0, |mask, n| mask | (1 << n))
+    }
+
+    /// Returns the opcode bytes that encode the instruction, each with the packed register it
+    /// encodes, or zero.
+    pub fn opcodes(&self) -> Vec<(u8, u8)> {
+        match self.packed {
+            None => vec![(self.opcode, 0)],
+            Some(Packed::N) => (0..16).map(|n| (self.opcode | n, n)).collect(),
+            Some(Packed::L) => (0..8).map(|n| (self.opcode | n, n)).collect(),
+            Some(Packed::H) => (0..8).map(|n| (self.opcode | n | 0x08, n)).collect(),
         }
     }
+
+    /// Encodes an instruction with the given packed register and immediate operand bytes.
+    pub fn encode(&self, n: u8, immediates: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.prefix.into_iter().collect();
+        bytes.push(match self.packed {
+            None => self.opcode,
+            Some(Packed::N | Packed::L) => self.opcode | n,
+            Some(Packed::H) => self.opcode | n | 0x08,
+        });
+        bytes.extend(immediates);
+        bytes
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_opcodes() {
+        assert_eq!(Schema::parse("f8 nn").unwrap().opcodes(), [(0xf8, 0)]);
+        assert_eq!(Schema::parse("68 8n hh ll").unwrap().opcodes().len(), 16);
+        assert_eq!(Schema::parse("6l").unwrap().opcodes()[7], (0x67, 7));
+        assert_eq!(Schema::parse("6h").unwrap().opcodes()[1], (0x69, 1));
+    }
+
     #[test]
     fn test_schema_from_str() {
         assert!(Schema::parse("").is_none());
@@ -87,9 +178,12 @@ mod tests {
         assert!(Schema::parse("1m").is_none());
         assert!(Schema::parse("1n xx").is_none());
         assert!(Schema::parse("1n nn").is_none());
+        assert!(Schema::parse("68 1n nn").is_none());
+        assert!(Schema::parse("68 68 00").is_none());
         assert_eq!(
             Schema::parse("0n").unwrap(),
             Schema {
+                prefix: None,
                 opcode: 0x00,
                 size: 1,
                 packed: Some(Packed::N),
@@ -98,6 +192,7 @@ mod tests {
         assert_eq!(
             Schema::parse("0l").unwrap(),
             Schema {
+                prefix: None,
                 opcode: 0x00,
                 size: 1,
                 packed: Some(Packed::L),
@@ -106,6 +201,7 @@ mod tests {
         assert_eq!(
             Schema::parse("2h").unwrap(),
             Schema {
+                prefix: None,
                 opcode: 0x20,
                 size: 1,
                 packed: Some(Packed::H),
@@ -114,6 +210,7 @@ mod tests {
         assert_eq!(
             Schema::parse("f1").unwrap(),
             Schema {
+                prefix: None,
                 opcode: 0xf1,
                 size: 1,
                 packed: None,
@@ -122,6 +219,7 @@ mod tests {
         assert_eq!(
             Schema::parse("30 nn").unwrap(),
             Schema {
+                prefix: None,
                 opcode: 0x30,
                 size: 2,
                 packed: None,
@@ -130,10 +228,47 @@ mod tests {
         assert_eq!(
             Schema::parse("c3 hh ll").unwrap(),
             Schema {
+                prefix: None,
                 opcode: 0xc3,
                 size: 3,
                 packed: None,
             }
         );
+        assert_eq!(
+            Schema::parse("68 74").unwrap(),
+            Schema {
+                prefix: Some(0x68),
+                opcode: 0x74,
+                size: 2,
+                packed: None,
+            }
+        );
+        assert_eq!(
+            Schema::parse("68 3e nn").unwrap(),
+            Schema {
+                prefix: Some(0x68),
+                opcode: 0x3e,
+                size: 3,
+                packed: None,
+            }
+        );
+        assert_eq!(
+            Schema::parse("68 6n").unwrap(),
+            Schema {
+                prefix: Some(0x68),
+                opcode: 0x60,
+                size: 2,
+                packed: Some(Packed::N),
+            }
+        );
+        assert_eq!(
+            Schema::parse("68 cn hh ll").unwrap(),
+            Schema {
+                prefix: Some(0x68),
+                opcode: 0xc0,
+                size: 4,
+                packed: Some(Packed::N),
+            }
+        );
     }
 }
@@ -0,0 +1,6 @@
+//! Compile errors from the assembler macro, which must point at the offending tokens.
+
+#[test]
+fn ui() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
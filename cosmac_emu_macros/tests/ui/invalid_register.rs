@@ -0,0 +1,3 @@
+fn main() {
+    let _ = cosmac_emu_macros::assemble!([out "6l", plo "an"] plo r16; out 1 + 7);
+}
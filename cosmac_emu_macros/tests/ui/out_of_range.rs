@@ -0,0 +1,3 @@
+fn main() {
+    let _ = cosmac_emu_macros::assemble!([ldi "f8 nn", lbr "c0 hh ll"] ldi 1; ldi 0x100 + 1; lbr -0x8001);
+}
@@ -0,0 +1,3 @@
+fn main() {
+    let _ = cosmac_emu_macros::assemble!([br "30 nn"] org 0xfe; br end; org 0x100; end: br end);
+}
@@ -0,0 +1,3 @@
+fn main() {
+    let _ = cosmac_emu_macros::assemble!([br "30 nn", plo "an"] start: plo r2; br strat);
+}
@@ -0,0 +1,3 @@
+fn main() {
+    let _ = cosmac_emu_macros::assemble!([ldi "f8 nn"] ldi 1; lid 2);
+}